@@ -1,102 +1,542 @@
-use baton::cli::Config;
-use baton::errors::BatonError;
+//! Assuan file sockets, as GnuPG uses them on Windows.
+//!
+//! Instead of a Unix socket, gpg-agent listens on a localhost TCP port and
+//! writes a socket file holding that port on the first line, followed by a
+//! 16-byte nonce. A client proves it could read the file by sending the
+//! nonce as the first bytes on the connection.
+//!
+//! A socket file can instead redirect to another socket, which GnuPG writes
+//! for non-standard home directories:
+//!
+//! ```text
+//! %Assuan%
+//! socket=${LOCALAPPDATA}/gnupg/S.gpg-agent
+//! ```
+//!
+//! `${NAME}` in the target is replaced by the environment variable `NAME`,
+//! and a relative target is taken relative to the redirecting file.
+//!
+//! Parsing the socket file ([`parse_assuan_file`], [`AssuanSocketFile::read_from`])
+//! and connecting ([`AssuanSocketInfo::connect`]) are separate steps, so
+//! tooling can inspect a socket file without connecting, or connect with
+//! details it got elsewhere.
+//!
+//! An agent that does not accept the nonce, e.g. because it restarted and
+//! wrote a new socket file, just closes the connection. [`connect_assuan_verified`]
+//! catches that by waiting for the agent's `OK` greeting before handing the
+//! connection over.
+//!
+//! [`AssuanClient`] speaks just enough of the protocol to send commands and
+//! collect their `D`, `S` and `OK`/`ERR` replies, for diagnostics such as
+//! `baton gpg-status`.
+
+use crate::decode::assuan::unescape;
+use crate::endpoint::{connect_tcp, Endpoint, EndpointReader, EndpointWriter, HalfClose};
+use crate::errors::BatonError;
+use crate::retry::RetryPolicy;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
-use std::net::TcpStream;
-use std::thread;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-const NONCE_SIZE: usize = 16;
-const POLL_INTERVAL_MS: u64 = 200;
-const MAX_POLL_ATTEMPTS: u32 = 300;
+/// Length of the nonce that follows the port in a socket file.
+pub const NONCE_SIZE: usize = 16;
+
+/// First line of a socket file that redirects to another socket.
+const REDIRECT_MAGIC: &str = "%Assuan%";
+
+/// The longest line Assuan allows, newline included.
+const MAX_LINE_LENGTH: usize = 1000;
+
+/// How long to wait for the greeting when no connect timeout is set.
+pub const GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Redirects [`parse_assuan_file`] follows before giving up, so files that
+/// redirect to each other fail rather than loop.
+pub const MAX_REDIRECTS: usize = 8;
+
+/// What an Assuan socket file holds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssuanSocketFile {
+    Socket(AssuanSocketInfo),
+    /// A `%Assuan%` file pointing at another socket file, with environment
+    /// variables already expanded.
+    Redirect(PathBuf),
+}
+
+impl AssuanSocketFile {
+    /// Parse a socket file's contents: either the port on its own line, then
+    /// the nonce, or a `%Assuan%` redirect.
+    pub fn read_from(mut reader: impl BufRead) -> Result<Self, BatonError> {
+        let mut port_line = String::new();
+        reader
+            .read_line(&mut port_line)
+            .map_err(|e| BatonError::AssuanParse(format!("cannot read port line: {}", e)))?;
 
-// Validate constants at compile time
-const _: () = assert!(NONCE_SIZE > 0);
-const _: () = assert!(POLL_INTERVAL_MS > 0);
-const _: () = assert!(MAX_POLL_ATTEMPTS > 0);
+        let port_str = port_line.trim_end_matches(['\r', '\n']);
+        if port_str == REDIRECT_MAGIC {
+            return read_redirect(reader, |name| std::env::var(name).ok()).map(Self::Redirect);
+        }
+        let port: u16 = port_str.parse().map_err(|e| {
+            BatonError::AssuanParse(format!("invalid port number '{}': {}", port_str, e))
+        })?;
 
-pub fn connect_assuan(config: &Config) -> Result<TcpStream, BatonError> {
-    let (port, nonce) = parse_assuan_file(&config.pipe_name)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        reader.read_exact(&mut nonce).map_err(|e| {
+            BatonError::AssuanParse(format!(
+                "cannot read nonce (need {} bytes): {}",
+                NONCE_SIZE, e
+            ))
+        })?;
 
-    log::debug!("Assuan port: {}, nonce length: {}", port, nonce.len());
+        Ok(Self::Socket(AssuanSocketInfo::new(port, nonce)))
+    }
+}
 
-    let addr = format!("127.0.0.1:{}", port);
-    let mut stream = connect_with_retry(&addr, config)?;
+/// Parse the lines after `%Assuan%`, looking up variables with `lookup`.
+fn read_redirect(
+    mut reader: impl Read,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<PathBuf, BatonError> {
+    let mut contents = String::new();
+    reader.read_to_string(&mut contents).map_err(|e| {
+        BatonError::AssuanParse(format!("cannot read redirect (is it UTF-8?): {}", e))
+    })?;
 
-    use std::io::Write;
-    stream
-        .write_all(&nonce)
-        .map_err(BatonError::AssuanConnection)?;
+    // Other keys may follow in later GnuPG versions; only socket= matters.
+    let target = contents
+        .lines()
+        .find_map(|line| line.trim_end_matches('\r').strip_prefix("socket="))
+        .ok_or_else(|| {
+            BatonError::AssuanParse(format!("{} redirect has no socket= line", REDIRECT_MAGIC))
+        })?;
+    if target.is_empty() {
+        return Err(BatonError::AssuanParse(
+            "redirect has an empty socket= path".to_string(),
+        ));
+    }
+    expand_env(target, lookup).map(PathBuf::from)
+}
 
-    log::debug!("Assuan nonce sent successfully");
+/// Replace each `${NAME}` in `s` with the value `lookup` gives for `NAME`.
+/// A `$` without a brace is kept as is.
+fn expand_env(s: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, BatonError> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| {
+            BatonError::AssuanParse(format!("unterminated ${{ in redirect path '{}'", s))
+        })?;
+        let name = &after[..end];
+        if name.is_empty() {
+            return Err(BatonError::AssuanParse(format!(
+                "empty ${{}} in redirect path '{}'",
+                s
+            )));
+        }
+        let value = lookup(name).ok_or_else(|| {
+            BatonError::AssuanParse(format!(
+                "environment variable {} in redirect path '{}' is not set",
+                name, s
+            ))
+        })?;
+        expanded.push_str(&value);
+        rest = &after[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
 
-    Ok(stream)
+/// The contents of an Assuan socket file: where to connect and the nonce to
+/// send first.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AssuanSocketInfo {
+    /// Socket files only carry a port; GnuPG always listens on localhost.
+    pub host: IpAddr,
+    pub port: u16,
+    pub nonce: [u8; NONCE_SIZE],
 }
 
-fn parse_assuan_file(path: &str) -> Result<(u16, Vec<u8>), BatonError> {
-    let file = File::open(path).map_err(|e| BatonError::AssuanParse(format!("cannot open file: {}", e)))?;
-    let mut reader = BufReader::new(file);
+impl AssuanSocketInfo {
+    /// A socket on localhost.
+    pub fn new(port: u16, nonce: [u8; NONCE_SIZE]) -> Self {
+        Self {
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port,
+            nonce,
+        }
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
 
-    let mut port_line = String::new();
-    reader
-        .read_line(&mut port_line)
-        .map_err(|e| BatonError::AssuanParse(format!("cannot read port line: {}", e)))?;
+    /// Connect, retrying according to `policy`, and send the nonce.
+    pub fn connect(&self, policy: &RetryPolicy) -> Result<TcpStream, BatonError> {
+        log::debug!(
+            "Assuan port: {}, nonce length: {}",
+            self.port,
+            self.nonce.len()
+        );
 
-    let port_str = port_line.trim_end_matches(['\r', '\n']);
-    let port: u16 = port_str
-        .parse()
-        .map_err(|e| BatonError::AssuanParse(format!("invalid port number '{}': {}", port_str, e)))?;
+        let mut stream = connect_with_retry(&self.socket_addr().to_string(), policy)?;
+        self.send_nonce(&mut stream)?;
+        Ok(stream)
+    }
 
-    let mut nonce = vec![0u8; NONCE_SIZE];
-    reader
-        .read_exact(&mut nonce)
-        .map_err(|e| BatonError::AssuanParse(format!("cannot read nonce (need {} bytes): {}", NONCE_SIZE, e)))?;
+    fn send_nonce(&self, stream: &mut TcpStream) -> Result<(), BatonError> {
+        stream
+            .write_all(&self.nonce)
+            .map_err(BatonError::AssuanConnection)?;
 
-    Ok((port, nonce))
+        log::debug!("Assuan nonce sent successfully");
+        Ok(())
+    }
 }
 
-fn connect_with_retry(addr: &str, config: &Config) -> Result<TcpStream, BatonError> {
-    let max_attempts = if config.limited_poll {
-        MAX_POLL_ATTEMPTS
-    } else {
-        u32::MAX
+impl fmt::Debug for AssuanSocketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The nonce is what grants access to the agent; keep it out of logs.
+        f.debug_struct("AssuanSocketInfo")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("nonce", &"<redacted>")
+            .finish()
+    }
+}
+
+/// Assuan file socket as written by GnuPG: a TCP port and a nonce.
+#[derive(Debug, Clone)]
+pub struct AssuanEndpoint {
+    pub path: String,
+    /// Wait for the greeting before relaying (`--verify-greeting`).
+    pub verify_greeting: bool,
+}
+
+impl AssuanEndpoint {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self {
+            path: path.into(),
+            verify_greeting: false,
+        }
+    }
+
+    pub fn with_verify_greeting(mut self, verify: bool) -> Self {
+        self.verify_greeting = verify;
+        self
+    }
+}
+
+impl Endpoint for AssuanEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        if !self.verify_greeting {
+            let stream = connect_assuan(&self.path, policy)?;
+            let reader = stream.try_clone()?;
+            return Ok((Box::new(reader), Box::new(HalfClose(stream))));
+        }
+
+        // The client still expects the greeting, so it comes first.
+        let (stream, greeting) = connect_assuan_verified(&self.path, policy)?;
+        let reader = io::Cursor::new(greeting).chain(stream.try_clone()?);
+        Ok((Box::new(reader), Box::new(HalfClose(stream))))
+    }
+}
+
+/// Read the socket file at `path`, following up to [`MAX_REDIRECTS`]
+/// redirects.
+pub fn parse_assuan_file(path: impl AsRef<Path>) -> Result<AssuanSocketInfo, BatonError> {
+    let mut path = path.as_ref().to_path_buf();
+    for redirects in 0..=MAX_REDIRECTS {
+        let file = File::open(&path).map_err(|e| {
+            let what = if redirects == 0 {
+                "file"
+            } else {
+                "redirect target"
+            };
+            BatonError::AssuanParse(format!("cannot open {} {}: {}", what, path.display(), e))
+        })?;
+        match AssuanSocketFile::read_from(BufReader::new(file))? {
+            AssuanSocketFile::Socket(info) => return Ok(info),
+            AssuanSocketFile::Redirect(target) => {
+                log::debug!(
+                    "Assuan socket file {} redirects to {}",
+                    path.display(),
+                    target.display()
+                );
+                // Joining keeps an absolute target as it is.
+                path = match path.parent() {
+                    Some(dir) => dir.join(target),
+                    None => target,
+                };
+            }
+        }
+    }
+    Err(BatonError::AssuanParse(format!(
+        "more than {} redirects, last to {}",
+        MAX_REDIRECTS,
+        path.display()
+    )))
+}
+
+/// Read the socket file at `path`, connect and send the nonce.
+pub fn connect_assuan(
+    path: impl AsRef<Path>,
+    policy: &RetryPolicy,
+) -> Result<TcpStream, BatonError> {
+    parse_assuan_file(path)?.connect(policy)
+}
+
+/// Connect to the socket in the file at `path`, send the nonce and wait for
+/// the greeting. Every attempt reads the file again, so when `policy` polls,
+/// an agent that restarted with a new port and nonce is still reached.
+///
+/// Returns the connection and the greeting line, newline included, which has
+/// been read from it.
+pub fn connect_assuan_verified(
+    path: impl AsRef<Path>,
+    policy: &RetryPolicy,
+) -> Result<(TcpStream, Vec<u8>), BatonError> {
+    let path = path.as_ref();
+    let attempt = |timeout: Option<Duration>| {
+        let info = parse_assuan_file(path)?;
+        let mut stream = connect_tcp(&info.socket_addr().to_string(), timeout)
+            .map_err(BatonError::AssuanConnection)?;
+        info.send_nonce(&mut stream)?;
+
+        stream.set_read_timeout(Some(timeout.unwrap_or(GREETING_TIMEOUT)))?;
+        let greeting = read_greeting(&mut stream)?;
+        stream.set_read_timeout(None)?;
+        Ok((stream, greeting))
     };
+    policy
+        .run(&path.display().to_string(), attempt, |_: &BatonError| true)
+        .map_err(|e| e.into_baton(|e| e))
+}
 
-    let mut attempts = 0;
-    loop {
-        match TcpStream::connect(addr) {
-            Ok(stream) => {
-                log::debug!("Connected to Assuan TCP socket at {}", addr);
-                return Ok(stream);
+/// Read the line an Assuan server greets with and check it is `OK`. Reads
+/// byte by byte, so nothing after the greeting is consumed.
+pub fn read_greeting(reader: &mut impl Read) -> Result<Vec<u8>, BatonError> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+    while line.last() != Some(&b'\n') {
+        if line.len() == MAX_LINE_LENGTH {
+            return Err(BatonError::AssuanGreeting(format!(
+                "greeting longer than {} bytes",
+                MAX_LINE_LENGTH
+            )));
+        }
+        match reader.read(&mut byte) {
+            Ok(0) => {
+                return Err(BatonError::AssuanGreeting(
+                    "connection closed before the greeting; the socket file may be stale"
+                        .to_string(),
+                ))
+            }
+            Ok(_) => line.push(byte[0]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Err(BatonError::AssuanGreeting(
+                    "timed out waiting for the greeting".to_string(),
+                ))
             }
             Err(e) => {
-                if !config.poll {
-                    return Err(BatonError::AssuanConnection(e));
-                }
+                return Err(BatonError::AssuanGreeting(format!(
+                    "cannot read greeting: {}",
+                    e
+                )))
+            }
+        }
+    }
+
+    let text = &line[..line.len() - 1];
+    if text == b"OK" || text.starts_with(b"OK ") {
+        log::debug!("Assuan greeting: {}", String::from_utf8_lossy(text));
+        Ok(line)
+    } else {
+        Err(BatonError::AssuanGreeting(format!(
+            "expected OK, got '{}'",
+            String::from_utf8_lossy(text)
+        )))
+    }
+}
+
+/// The server's answer to one command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AssuanResponse {
+    /// The `D` lines, unescaped and joined.
+    pub data: Vec<u8>,
+    /// The `S` lines, as keyword and unescaped arguments.
+    pub status: Vec<(String, String)>,
+    /// Whatever followed `OK`.
+    pub ok: String,
+}
+
+/// A client that sends one command at a time and collects the reply.
+/// Inquiries are cancelled, since it has nothing to answer them with.
+pub struct AssuanClient<R, W> {
+    reader: R,
+    writer: W,
+    greeting: String,
+}
+
+impl AssuanClient<BufReader<TcpStream>, TcpStream> {
+    /// Connect to the socket file at `path` and read the greeting. Each read
+    /// gives up after `timeout`, so a hung agent is reported rather than
+    /// waited on.
+    pub fn connect(
+        path: impl AsRef<Path>,
+        policy: &RetryPolicy,
+        timeout: Duration,
+    ) -> Result<Self, BatonError> {
+        let stream = connect_assuan(path, policy)?;
+        stream.set_read_timeout(Some(timeout))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Self::new(reader, stream)
+    }
+}
+
+impl<R: BufRead, W: Write> AssuanClient<R, W> {
+    /// Start a session on a connection the server is about to greet on.
+    pub fn new(mut reader: R, writer: W) -> Result<Self, BatonError> {
+        let greeting = read_greeting(&mut reader)?;
+        let greeting = String::from_utf8_lossy(&greeting).trim_end().to_string();
+        Ok(Self {
+            reader,
+            writer,
+            greeting,
+        })
+    }
+
+    /// The `OK` line the server greeted with.
+    pub fn greeting(&self) -> &str {
+        &self.greeting
+    }
 
-                attempts += 1;
-                if attempts >= max_attempts {
-                    return Err(BatonError::PollingLimitReached(attempts));
+    /// Send `command` and read lines up to its `OK`. An `ERR` reply becomes
+    /// [`BatonError::AssuanCommand`].
+    pub fn transact(&mut self, command: &str) -> Result<AssuanResponse, BatonError> {
+        self.send(command)?;
+        let mut response = AssuanResponse::default();
+        loop {
+            let line = self.read_line()?;
+            let (keyword, rest) = match line.iter().position(|&b| b == b' ') {
+                Some(space) => (&line[..space], &line[space + 1..]),
+                None => (&line[..], &[][..]),
+            };
+            match keyword {
+                b"OK" => {
+                    response.ok = String::from_utf8_lossy(rest).into_owned();
+                    return Ok(response);
+                }
+                b"ERR" => return Err(command_error(rest)),
+                b"D" => response.data.extend(unescape(rest)),
+                b"S" => {
+                    let rest = String::from_utf8_lossy(rest);
+                    let (keyword, args) = rest.split_once(' ').unwrap_or((&rest, ""));
+                    let args = String::from_utf8_lossy(&unescape(args.as_bytes())).into_owned();
+                    response.status.push((keyword.to_string(), args));
+                }
+                b"INQUIRE" => {
+                    log::debug!("Cancelling inquiry: {}", String::from_utf8_lossy(rest));
+                    self.send("CAN")?;
                 }
+                b"#" => {}
+                _ => {
+                    return Err(BatonError::AssuanProtocol(format!(
+                        "'{}' in reply to {}",
+                        line.escape_ascii(),
+                        command
+                    )))
+                }
+            }
+        }
+    }
 
-                log::debug!(
-                    "Connection attempt {} failed: {}, retrying in {}ms",
-                    attempts,
-                    e,
-                    POLL_INTERVAL_MS
-                );
-                thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    fn send(&mut self, line: &str) -> Result<(), BatonError> {
+        log::debug!("Assuan command: {}", line);
+        self.writer
+            .write_all(format!("{}\n", line).as_bytes())
+            .and_then(|()| self.writer.flush())
+            .map_err(BatonError::AssuanConnection)
+    }
+
+    /// One line, without its newline.
+    fn read_line(&mut self) -> Result<Vec<u8>, BatonError> {
+        let mut line = Vec::new();
+        let read = (&mut self.reader)
+            .take(MAX_LINE_LENGTH as u64)
+            .read_until(b'\n', &mut line);
+        match read {
+            Ok(_) if line.last() == Some(&b'\n') => {
+                line.pop();
+                Ok(line)
+            }
+            Ok(_) if line.len() == MAX_LINE_LENGTH => Err(BatonError::AssuanProtocol(format!(
+                "line longer than {} bytes",
+                MAX_LINE_LENGTH
+            ))),
+            Ok(_) => Err(BatonError::AssuanConnection(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a reply",
+            ))),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                Err(BatonError::AssuanConnection(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for a reply",
+                )))
             }
+            Err(e) => Err(BatonError::AssuanConnection(e)),
         }
     }
 }
 
+/// Turn the rest of an `ERR code description` line into an error.
+fn command_error(rest: &[u8]) -> BatonError {
+    let rest = String::from_utf8_lossy(rest);
+    let (code, description) = rest.split_once(' ').unwrap_or((&rest, ""));
+    match code.parse() {
+        Ok(code) => BatonError::AssuanCommand {
+            code,
+            description: description.to_string(),
+        },
+        Err(_) => BatonError::AssuanProtocol(format!("malformed ERR line 'ERR {}'", rest)),
+    }
+}
+
+/// Connect to `addr` over TCP, retrying according to `policy`.
+pub fn connect_with_retry(addr: &str, policy: &RetryPolicy) -> Result<TcpStream, BatonError> {
+    policy
+        .run(addr, |timeout| connect_tcp(addr, timeout), |_| true)
+        .map_err(|e| e.into_baton(BatonError::AssuanConnection))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::thread;
     use tempfile::NamedTempFile;
 
+    const NONCE: [u8; NONCE_SIZE] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+
     fn create_test_assuan_file(port: u16, nonce: &[u8]) -> NamedTempFile {
         let mut file = NamedTempFile::new().unwrap();
         writeln!(file, "{}", port).unwrap();
@@ -107,12 +547,12 @@ mod tests {
 
     #[test]
     fn test_parse_assuan_file_valid() {
-        let nonce = [1u8, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
-        let file = create_test_assuan_file(8080, &nonce);
+        let file = create_test_assuan_file(8080, &NONCE);
 
-        let (port, parsed_nonce) = parse_assuan_file(file.path().to_str().unwrap()).unwrap();
-        assert_eq!(port, 8080);
-        assert_eq!(parsed_nonce, nonce);
+        let info = parse_assuan_file(file.path()).unwrap();
+        assert_eq!(info.port, 8080);
+        assert_eq!(info.nonce, NONCE);
+        assert_eq!(info.socket_addr().to_string(), "127.0.0.1:8080");
     }
 
     #[test]
@@ -121,7 +561,7 @@ mod tests {
         writeln!(file, "not_a_number").unwrap();
         file.flush().unwrap();
 
-        let result = parse_assuan_file(file.path().to_str().unwrap());
+        let result = parse_assuan_file(file.path());
         assert!(result.is_err());
     }
 
@@ -132,7 +572,340 @@ mod tests {
         file.write_all(&[1, 2, 3]).unwrap(); // Only 3 bytes, need 16
         file.flush().unwrap();
 
-        let result = parse_assuan_file(file.path().to_str().unwrap());
+        let result = parse_assuan_file(file.path());
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_assuan_file_missing() {
+        let dir = tempfile::tempdir().unwrap();
+        let err = parse_assuan_file(dir.path().join("S.gpg-agent")).unwrap_err();
+        assert!(matches!(err, BatonError::AssuanParse(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_read_from_crlf_port_line() {
+        let mut contents = b"4711\r\n".to_vec();
+        contents.extend_from_slice(&NONCE);
+
+        let file = AssuanSocketFile::read_from(Cursor::new(contents)).unwrap();
+        assert_eq!(
+            file,
+            AssuanSocketFile::Socket(AssuanSocketInfo::new(4711, NONCE))
+        );
+    }
+
+    fn redirect(contents: &str) -> Result<PathBuf, BatonError> {
+        read_redirect(contents.as_bytes(), |name| match name {
+            "HOME" => Some("/home/me".to_string()),
+            _ => None,
+        })
+    }
+
+    fn redirect_error(contents: &str) -> String {
+        match redirect(contents).unwrap_err() {
+            BatonError::AssuanParse(msg) => msg,
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_read_redirect() {
+        assert_eq!(
+            redirect("socket=${HOME}/.gnupg/S.gpg-agent\n").unwrap(),
+            PathBuf::from("/home/me/.gnupg/S.gpg-agent")
+        );
+        // CRLF line endings, other keys and a literal $ are all fine.
+        assert_eq!(
+            redirect("flags=x\r\nsocket=C:/$gpg/${HOME}\r\n").unwrap(),
+            PathBuf::from("C:/$gpg//home/me")
+        );
+    }
+
+    #[test]
+    fn test_read_redirect_malformed() {
+        assert!(redirect_error("").contains("no socket= line"));
+        assert!(redirect_error("sockets=/x\n").contains("no socket= line"));
+        assert!(redirect_error("socket=\n").contains("empty socket= path"));
+        assert!(redirect_error("socket=${HOME/x").contains("unterminated ${"));
+        assert!(redirect_error("socket=${}/x").contains("empty ${}"));
+        assert_eq!(
+            redirect_error("socket=${GNUPGHOME}/S.gpg-agent"),
+            "environment variable GNUPGHOME in redirect path \
+             '${GNUPGHOME}/S.gpg-agent' is not set"
+        );
+    }
+
+    #[test]
+    fn test_read_from_redirect_not_utf8() {
+        let err = AssuanSocketFile::read_from(Cursor::new(b"%Assuan%\nsocket=\xff".to_vec()))
+            .unwrap_err();
+        assert!(err.to_string().contains("UTF-8"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_assuan_file_follows_redirects() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut socket = b"4711\n".to_vec();
+        socket.extend_from_slice(&NONCE);
+        std::fs::write(dir.path().join("S.real"), socket).unwrap();
+        // One absolute redirect, then one relative to the redirecting file.
+        std::fs::write(dir.path().join("S.next"), "%Assuan%\nsocket=S.real\n").unwrap();
+        let first = format!("%Assuan%\nsocket={}\n", dir.path().join("S.next").display());
+        std::fs::write(dir.path().join("S.gpg-agent"), first).unwrap();
+
+        let info = parse_assuan_file(dir.path().join("S.gpg-agent")).unwrap();
+        assert_eq!(info, AssuanSocketInfo::new(4711, NONCE));
+    }
+
+    #[test]
+    fn test_parse_assuan_file_redirect_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("S.a"), "%Assuan%\nsocket=S.b\n").unwrap();
+        std::fs::write(dir.path().join("S.b"), "%Assuan%\nsocket=S.a\n").unwrap();
+
+        let err = parse_assuan_file(dir.path().join("S.a")).unwrap_err();
+        assert!(err.to_string().contains("more than 8 redirects"), "{}", err);
+    }
+
+    #[test]
+    fn test_parse_assuan_file_missing_redirect_target() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("S.a"), "%Assuan%\nsocket=S.gone\n").unwrap();
+
+        let err = parse_assuan_file(dir.path().join("S.a")).unwrap_err();
+        assert!(
+            err.to_string().contains("cannot open redirect target"),
+            "{}",
+            err
+        );
+        assert!(err.to_string().contains("S.gone"), "{}", err);
+    }
+
+    #[test]
+    fn test_debug_redacts_nonce() {
+        let debug = format!("{:?}", AssuanSocketInfo::new(4711, NONCE));
+        assert!(debug.contains("4711"), "{}", debug);
+        assert!(debug.contains("<redacted>"), "{}", debug);
+        assert!(!debug.contains("[1, 2"), "{}", debug);
+    }
+
+    /// A fake agent that reads the nonce and answers with `greeting`, once.
+    fn fake_agent(greeting: &'static [u8]) -> (NamedTempFile, thread::JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let file = create_test_assuan_file(listener.local_addr().unwrap().port(), &NONCE);
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut nonce = [0u8; NONCE_SIZE];
+            stream.read_exact(&mut nonce).unwrap();
+            assert_eq!(nonce, NONCE);
+            stream.write_all(greeting).unwrap();
+            stream
+        });
+        (file, server)
+    }
+
+    fn greeting_error(err: BatonError) -> String {
+        match err {
+            BatonError::AssuanGreeting(msg) => msg,
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_read_greeting() {
+        let mut reader = Cursor::new(b"OK Pleased to meet you\nD data\n".to_vec());
+        assert_eq!(
+            read_greeting(&mut reader).unwrap(),
+            b"OK Pleased to meet you\n"
+        );
+        assert_eq!(reader.position(), 23);
+        assert_eq!(read_greeting(&mut Cursor::new(b"OK\n")).unwrap(), b"OK\n");
+    }
+
+    #[test]
+    fn test_read_greeting_rejects() {
+        let err = read_greeting(&mut Cursor::new(b"ERR 67109139 Bad nonce\n")).unwrap_err();
+        assert_eq!(
+            greeting_error(err),
+            "expected OK, got 'ERR 67109139 Bad nonce'"
+        );
+        let err = read_greeting(&mut Cursor::new(b"OKAY\n")).unwrap_err();
+        assert!(greeting_error(err).contains("expected OK"));
+        let err = read_greeting(&mut Cursor::new(b"OK Pleased")).unwrap_err();
+        assert!(greeting_error(err).contains("connection closed"));
+        let err = read_greeting(&mut Cursor::new(vec![b'O'; 2000])).unwrap_err();
+        assert!(greeting_error(err).contains("longer than 1000 bytes"));
+    }
+
+    #[test]
+    fn test_connect_verified_returns_greeting() {
+        let (file, server) = fake_agent(b"OK Pleased to meet you\n");
+
+        let (_stream, greeting) =
+            connect_assuan_verified(file.path(), &RetryPolicy::default()).unwrap();
+
+        assert_eq!(greeting, b"OK Pleased to meet you\n");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_verified_stale_nonce() {
+        // gpg-agent closes the connection when the nonce does not match.
+        let (file, server) = fake_agent(b"");
+        let closer = thread::spawn(move || drop(server.join().unwrap()));
+
+        let err = connect_assuan_verified(file.path(), &RetryPolicy::default()).unwrap_err();
+
+        assert!(greeting_error(err).contains("may be stale"));
+        closer.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_verified_times_out() {
+        let (file, server) = fake_agent(b"");
+        let policy = RetryPolicy {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let err = connect_assuan_verified(file.path(), &policy).unwrap_err();
+
+        assert!(greeting_error(err).contains("timed out"));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_verified_rereads_socket_file() {
+        let stale = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (fresh_file, fresh) = fake_agent(b"OK fresh\n");
+        let file = create_test_assuan_file(stale.local_addr().unwrap().port(), &NONCE);
+        let path = file.path().to_path_buf();
+
+        // The stale agent points the socket file at the fresh one, then
+        // hangs up, as if gpg-agent had restarted.
+        let restart = thread::spawn(move || {
+            let mut stream = stale.accept().unwrap().0;
+            let mut nonce = [0u8; NONCE_SIZE];
+            stream.read_exact(&mut nonce).unwrap();
+            std::fs::copy(fresh_file.path(), &path).unwrap();
+        });
+        let policy = RetryPolicy {
+            poll: true,
+            interval: Duration::from_millis(10),
+            max_attempts: Some(5),
+            ..Default::default()
+        };
+
+        let (_stream, greeting) = connect_assuan_verified(file.path(), &policy).unwrap();
+
+        assert_eq!(greeting, b"OK fresh\n");
+        restart.join().unwrap();
+        fresh.join().unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_replays_greeting() {
+        let (file, server) = fake_agent(b"OK hello\n");
+        let endpoint =
+            AssuanEndpoint::new(file.path().to_str().unwrap()).with_verify_greeting(true);
+
+        let (mut reader, writer) = endpoint.connect(&RetryPolicy::default()).unwrap();
+        let mut stream = server.join().unwrap();
+        stream.write_all(b"OK done\n").unwrap();
+        drop(stream);
+        drop(writer);
+
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).unwrap();
+        assert_eq!(received, b"OK hello\nOK done\n");
+    }
+
+    /// A client whose server sends `replies`, and what it sent the server.
+    fn client(replies: &[u8]) -> AssuanClient<Cursor<Vec<u8>>, Vec<u8>> {
+        let mut script = b"OK Pleased to meet you\n".to_vec();
+        script.extend_from_slice(replies);
+        AssuanClient::new(Cursor::new(script), Vec::new()).unwrap()
+    }
+
+    fn sent(client: &AssuanClient<Cursor<Vec<u8>>, Vec<u8>>) -> &str {
+        std::str::from_utf8(&client.writer).unwrap()
+    }
+
+    #[test]
+    fn test_client_transact() {
+        let mut client = client(b"# comment\nS PROGRESS a%25b\nD 2.4%0A\nD .5\nOK done\nD x\nOK\n");
+        assert_eq!(client.greeting(), "OK Pleased to meet you");
+
+        let response = client.transact("GETINFO version").unwrap();
+        assert_eq!(response.data, b"2.4\n.5");
+        assert_eq!(
+            response.status,
+            [("PROGRESS".to_string(), "a%b".to_string())]
+        );
+        assert_eq!(response.ok, "done");
+        assert_eq!(client.transact("NOP").unwrap().data, b"x");
+        assert_eq!(sent(&client), "GETINFO version\nNOP\n");
+    }
+
+    #[test]
+    fn test_client_err_reply() {
+        let mut client = client(b"ERR 67109139 Unknown IPC command <GPG Agent>\nERR x\n");
+        match client.transact("FOO").unwrap_err() {
+            BatonError::AssuanCommand { code, description } => {
+                assert_eq!(code, 67109139);
+                assert_eq!(description, "Unknown IPC command <GPG Agent>");
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = client.transact("BAR").unwrap_err();
+        assert!(matches!(err, BatonError::AssuanProtocol(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_client_cancels_inquiry() {
+        let mut client =
+            client(b"INQUIRE PINENTRY_LAUNCHED 1234\nERR 83886179 Operation cancelled\n");
+        assert!(client.transact("PKSIGN").is_err());
+        assert_eq!(sent(&client), "PKSIGN\nCAN\n");
+    }
+
+    #[test]
+    fn test_client_malformed_replies() {
+        let err = client(b"HELLO\n").transact("NOP").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unexpected Assuan response: 'HELLO' in reply to NOP"
+        );
+        let err = client(b"D partial").transact("NOP").unwrap_err();
+        assert!(err.to_string().contains("middle of a reply"), "{}", err);
+        let err = client(&[b'D'; 1200]).transact("NOP").unwrap_err();
+        assert!(
+            err.to_string().contains("longer than 1000 bytes"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_connect_sends_nonce() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let mut received = [0u8; NONCE_SIZE];
+            listener
+                .accept()
+                .unwrap()
+                .0
+                .read_exact(&mut received)
+                .unwrap();
+            received
+        });
+
+        AssuanSocketInfo::new(port, NONCE)
+            .connect(&RetryPolicy::default())
+            .unwrap();
+
+        assert_eq!(server.join().unwrap(), NONCE);
+    }
 }
@@ -1,3 +1,4 @@
+#[cfg(windows)]
 use anyhow::{Context, Result};
 use clap::Parser;
 
@@ -0,0 +1,162 @@
+//! Observing relay traffic without changing it.
+//!
+//! A [`Capture`] sees every chunk a copy loop reads, in the order it was
+//! read, plus the EOF or error that ended the loop. [`CapturingReader`] sits
+//! between a source and the relay so the copy loops themselves stay unaware
+//! of recording: they get exactly the bytes, EOFs and errors they would have
+//! got without it.
+
+use crate::relay::Direction;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+/// One observation from a copy loop.
+#[derive(Debug, Clone, Copy)]
+pub enum Event<'a> {
+    /// A chunk as returned by one `read` call.
+    Data(&'a [u8]),
+    /// The source returned 0 bytes.
+    Eof,
+    /// The source failed. `Interrupted` is retried by the relay and is not
+    /// reported.
+    Error(&'a io::Error),
+}
+
+/// Receives relay traffic. Implementations must not block for long or
+/// panic: they run on the relay threads.
+pub trait Capture: Send + Sync {
+    fn record(&self, direction: Direction, event: Event<'_>);
+}
+
+/// Every capture in the list sees every event, in order.
+impl Capture for Vec<Arc<dyn Capture>> {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        for capture in self {
+            capture.record(direction, event);
+        }
+    }
+}
+
+/// File formats `--record` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RecordFormat {
+    /// baton transcript, for `baton replay` and `baton inspect`
+    Transcript,
+    /// pcapng with synthetic TCP segments, for Wireshark
+    Pcapng,
+}
+
+impl RecordFormat {
+    /// The format a file name asks for: pcapng for `.pcapng` files, a
+    /// transcript otherwise.
+    pub fn for_path(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("pcapng") => RecordFormat::Pcapng,
+            _ => RecordFormat::Transcript,
+        }
+    }
+}
+
+/// Reader that reports everything read through it to a [`Capture`].
+pub struct CapturingReader<R> {
+    inner: R,
+    direction: Direction,
+    capture: Arc<dyn Capture>,
+}
+
+impl<R: Read> CapturingReader<R> {
+    pub fn new(inner: R, direction: Direction, capture: Arc<dyn Capture>) -> Self {
+        Self {
+            inner,
+            direction,
+            capture,
+        }
+    }
+}
+
+impl<R: Read> Read for CapturingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.inner.read(buf);
+        match &result {
+            Ok(0) => self.capture.record(self.direction, Event::Eof),
+            Ok(n) => self.capture.record(self.direction, Event::Data(&buf[..*n])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => self.capture.record(self.direction, Event::Error(e)),
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_record_format_for_path() {
+        let format = |path: &str| RecordFormat::for_path(Path::new(path));
+        assert_eq!(format("docker.pcapng"), RecordFormat::Pcapng);
+        assert_eq!(format("/tmp/DOCKER.PCAPNG"), RecordFormat::Pcapng);
+        assert_eq!(format("docker.rec"), RecordFormat::Transcript);
+        assert_eq!(format("docker"), RecordFormat::Transcript);
+    }
+
+    #[derive(Default)]
+    struct Log(Mutex<Vec<String>>);
+
+    impl Capture for Log {
+        fn record(&self, direction: Direction, event: Event<'_>) {
+            let line = match event {
+                Event::Data(data) => format!("{:?} {:?}", direction, data),
+                Event::Eof => format!("{:?} eof", direction),
+                Event::Error(e) => format!("{:?} error {}", direction, e),
+            };
+            self.0.lock().unwrap().push(line);
+        }
+    }
+
+    struct Failing;
+
+    impl Read for Failing {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "gone"))
+        }
+    }
+
+    #[test]
+    fn test_capturing_reader_passes_data_through() {
+        let log = Arc::new(Log::default());
+        let mut reader = CapturingReader::new(&b"hello"[..], Direction::StdinToPipe, log.clone());
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+
+        assert_eq!(out, b"hello");
+        let lines = log.0.lock().unwrap();
+        assert_eq!(
+            *lines,
+            ["StdinToPipe [104, 101, 108, 108, 111]", "StdinToPipe eof"]
+        );
+    }
+
+    #[test]
+    fn test_capture_list_records_to_each() {
+        let first = Arc::new(Log::default());
+        let second = Arc::new(Log::default());
+        let list: Vec<Arc<dyn Capture>> = vec![first.clone(), second.clone()];
+        list.record(Direction::PipeToStdout, Event::Eof);
+
+        assert_eq!(*first.0.lock().unwrap(), ["PipeToStdout eof"]);
+        assert_eq!(*second.0.lock().unwrap(), ["PipeToStdout eof"]);
+    }
+
+    #[test]
+    fn test_capturing_reader_reports_errors() {
+        let log = Arc::new(Log::default());
+        let mut reader = CapturingReader::new(Failing, Direction::PipeToStdout, log.clone());
+        let err = reader.read(&mut [0; 8]).unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(*log.0.lock().unwrap(), ["PipeToStdout error gone"]);
+    }
+}
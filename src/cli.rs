@@ -1,82 +1,758 @@
-use clap::Parser;
+use crate::capture::RecordFormat;
+use crate::decode::Protocol;
+use crate::errors::BatonError;
+use crate::gpg_status::StatusFormat;
+use crate::logging::{self, LogFile, LogFormat};
+use crate::rate::RateLimiter;
+use crate::relay::EndOfInput;
+use crate::replay::{ReplayOptions, Timing};
+use crate::retry::{Backoff, RetryPolicy};
+use crate::stats::StatsFormat;
+use crate::target::Target;
+use clap::{ArgAction, Args, CommandFactory, Parser, Subcommand};
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(name = "baton", version = env!("CARGO_PKG_VERSION"))]
 #[command(about = "Relay data between stdin/stdout and Windows named pipes")]
+#[command(subcommand_negates_reqs = true)]
 pub struct CliArgs {
     /// Poll every 200ms until the named pipe exists and is not busy
-    #[arg(short = 'p')]
+    #[arg(short = 'p', global = true)]
     pub poll: bool,
 
     /// When polling, limit attempts to 300 (~60 seconds)
-    #[arg(short = 'l')]
+    #[arg(short = 'l', global = true)]
     pub limited_poll: bool,
 
     /// Send a 0-byte message to the pipe after EOF on stdin
-    #[arg(short = 's')]
+    #[arg(short = 's', global = true)]
     pub send_zero: bool,
 
+    /// How to tell the pipe side that stdin ended: close (shut a socket down
+    /// for writing, close a command's stdin), zero (as -s), keep (leave it
+    /// open), or sentinel:BYTES to send BYTES first, with \n, \r, \t, \0, \\
+    /// and \xHH escapes [default: close for commands, keep otherwise]
+    #[arg(long, global = true, value_name = "STRATEGY", value_parser = parse_end_of_input, conflicts_with = "send_zero")]
+    pub eof: Option<EndOfInput>,
+
     /// Exit immediately on EOF when reading from the pipe
-    #[arg(long = "ep")]
+    #[arg(long = "ep", global = true)]
     pub exit_on_pipe_eof: bool,
 
     /// Exit immediately on EOF when reading from stdin
-    #[arg(long = "ei")]
+    #[arg(long = "ei", global = true)]
     pub exit_on_stdin_eof: bool,
 
     /// Hide the console window and run in the background
-    #[arg(long = "bg")]
+    #[arg(long = "bg", global = true)]
     pub bg: bool,
 
-    /// Treat the target as an Assuan file socket (for GnuPG)
-    #[arg(short = 'a')]
+    /// Treat a bare target path as an Assuan file socket (for GnuPG)
+    #[arg(short = 'a', global = true)]
     pub assuan: bool,
 
-    /// Enable verbose output on stderr for debugging
-    #[arg(short = 'v')]
-    pub verbose: bool,
+    /// After sending the Assuan nonce, wait for the agent's OK greeting, so a
+    /// stale socket file fails clearly (and is re-read when polling)
+    #[arg(long, global = true)]
+    pub verify_greeting: bool,
+
+    /// Enable verbose output on stderr for debugging; -vv also hex dumps the
+    /// relayed data, with secrets redacted
+    #[arg(short = 'v', global = true, action = ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log as plain text or as JSON lines carrying the session ID, target
+    /// and PID
+    #[arg(long, global = true, value_enum, default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Log to this file instead of stderr, rotating it by size. `{session}`
+    /// and `{pid}` in the name are replaced, for one file per session
+    #[arg(long, global = true, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Rotate the log file before it grows past this size, e.g. 512K or 10M
+    #[arg(long, global = true, value_name = "SIZE", default_value = "10M", value_parser = parse_size)]
+    pub log_max_size: u64,
+
+    /// How many rotated log files to keep
+    #[arg(long, global = true, value_name = "N", default_value_t = logging::DEFAULT_LOG_KEEP)]
+    pub log_keep: u32,
+
+    #[command(flatten)]
+    pub retry: RetryArgs,
+
+    /// Multiplex many connections over one stdin/stdout stream. With
+    /// `listen`, the target is started once and every connection becomes a
+    /// channel to it; otherwise each channel is connected to the target
+    #[arg(long, global = true)]
+    pub mux: bool,
+
+    /// Record every chunk read in either direction, with timestamps, to a
+    /// binary transcript file
+    #[arg(long, value_name = "FILE", conflicts_with = "mux")]
+    pub record: Option<PathBuf>,
+
+    /// Stop recording once the transcript reaches this size, e.g. 512K or 64M
+    #[arg(long, value_name = "SIZE", default_value = "64M", value_parser = parse_size)]
+    pub record_limit: u64,
+
+    /// Format of the --record file [default: pcapng for *.pcapng files,
+    /// transcript otherwise]
+    #[arg(long, value_name = "FORMAT", value_enum, requires = "record")]
+    pub record_format: Option<RecordFormat>,
+
+    /// Log the traffic decoded as this protocol (shown with -v). Also picks
+    /// the redaction rules for -vv, which are otherwise guessed from the target
+    #[arg(long, value_name = "PROTOCOL", value_enum, conflicts_with = "mux")]
+    pub decode: Option<Protocol>,
+
+    /// Stop hex dumping (-vv) after this many bytes in total, e.g. 1M
+    #[arg(long, value_name = "SIZE", default_value = "64K", value_parser = parse_size)]
+    pub trace_limit: u64,
+
+    /// End the session, with exit status 3, once no data has been read in
+    /// either direction for this long, e.g. 30s or 10m. Applies to each
+    /// connection of `listen` and --mux
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration)]
+    pub idle_timeout: Option<Duration>,
+
+    /// End the session, with exit status 4, this long after it started
+    #[arg(long, global = true, value_name = "DURATION", value_parser = parse_duration)]
+    pub max_duration: Option<Duration>,
+
+    /// End the session, with exit status 5, rather than relay more than this
+    /// many bytes in both directions together, e.g. 100M
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    pub max_bytes: Option<u64>,
+
+    /// Limit stdin to pipe to this many bytes per second, e.g. 10M. Shared by
+    /// all connections of `listen` and --mux
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_rate)]
+    pub rate_in: Option<u64>,
+
+    /// Limit pipe to stdout to this many bytes per second, e.g. 10M. Shared
+    /// by all connections of `listen` and --mux
+    #[arg(long, global = true, value_name = "RATE", value_parser = parse_rate)]
+    pub rate_out: Option<u64>,
+
+    /// Bytes either rate limit lets through at full speed after a pause
+    /// [default: one second's worth]
+    #[arg(long, global = true, value_name = "SIZE", value_parser = parse_size)]
+    pub rate_burst: Option<u64>,
+
+    /// Report byte and chunk counts, timings and how the session ended, as
+    /// one line when it ends
+    #[arg(long, value_name = "FORMAT", value_enum, conflicts_with = "mux")]
+    pub stats: Option<StatsFormat>,
+
+    /// Append the --stats line to this file instead of stderr [default
+    /// format: text]
+    #[arg(long, value_name = "PATH", conflicts_with = "mux")]
+    pub stats_file: Option<PathBuf>,
+
+    /// Target: a named pipe or Assuan socket path, or a URL such as
+    /// npipe:////./pipe/docker_engine, assuan://PATH, tcp://HOST:PORT,
+    /// unix:///PATH or exec:COMMAND
+    #[arg(required = true)]
+    pub target: Option<String>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+/// Fine-grained retry settings. Any of these except `--connect-timeout`
+/// turns polling on, as `-p` does.
+#[derive(Args, Debug, Default)]
+#[command(next_help_heading = "Retry")]
+pub struct RetryArgs {
+    /// How the delay between attempts grows
+    #[arg(long, global = true, value_enum)]
+    pub retry_backoff: Option<Backoff>,
+
+    /// Delay after the first failed attempt, e.g. 200ms or 1s
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub retry_interval: Option<Duration>,
+
+    /// Longest delay between attempts with exponential backoff
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub retry_max_interval: Option<Duration>,
+
+    /// Randomly shorten each delay by up to this fraction (0.0 to 1.0)
+    #[arg(long, global = true, value_parser = parse_fraction)]
+    pub retry_jitter: Option<f64>,
+
+    /// Give up after this many attempts
+    #[arg(long, global = true, value_parser = clap::value_parser!(u32).range(1..))]
+    pub retry_max_attempts: Option<u32>,
+
+    /// Give up once this much time has passed, e.g. 30s or 2m
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub retry_deadline: Option<Duration>,
+
+    /// Time limit for each TCP or Assuan connection attempt
+    #[arg(long, global = true, value_parser = parse_duration)]
+    pub connect_timeout: Option<Duration>,
+}
+
+impl RetryArgs {
+    fn any_retry_flag(&self) -> bool {
+        self.retry_backoff.is_some()
+            || self.retry_interval.is_some()
+            || self.retry_max_interval.is_some()
+            || self.retry_jitter.is_some()
+            || self.retry_max_attempts.is_some()
+            || self.retry_deadline.is_some()
+    }
+
+    /// Combine with `-p` and `-l`. An explicit attempt limit wins over `-l`.
+    fn policy(&self, poll: bool, limited: bool) -> RetryPolicy {
+        let mut policy = if poll || self.any_retry_flag() {
+            RetryPolicy::polling(limited)
+        } else {
+            RetryPolicy::default()
+        };
+        policy.backoff = self.retry_backoff.unwrap_or(policy.backoff);
+        policy.interval = self.retry_interval.unwrap_or(policy.interval);
+        policy.max_interval = self.retry_max_interval.unwrap_or(policy.max_interval);
+        policy.jitter = self.retry_jitter.unwrap_or(policy.jitter);
+        policy.max_attempts = self.retry_max_attempts.or(policy.max_attempts);
+        policy.deadline = self.retry_deadline;
+        policy.connect_timeout = self.connect_timeout;
+        policy
+    }
+}
+
+impl CliArgs {
+    fn log_file(&self) -> Option<LogFile> {
+        self.log_file.as_ref().map(|path| LogFile {
+            path: path.clone(),
+            max_size: self.log_max_size,
+            keep: self.log_keep,
+        })
+    }
+}
+
+/// Parse a duration such as `200ms`, `1.5s`, `2m` or `1h`.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "" => return Err(format!("'{}' needs a unit: ms, s, m or h", s)),
+        _ => return Err(format!("unknown unit '{}' in '{}'", unit, s)),
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("'{}' is not a duration like 200ms or 5s", s))?;
+    Duration::try_from_secs_f64(number * scale).map_err(|e| format!("'{}': {}", s, e))
+}
+
+/// Parse a byte count such as `4096`, `512K`, `64M` or `1G` (powers of 1024).
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("'{}' is not a size like 4096, 512K or 64M", s))
+}
 
-    /// Named pipe name or Assuan socket path
-    pub pipe_name: String,
+/// Parse an `--eof` strategy: `close`, `zero`, `keep` or `sentinel:BYTES`.
+pub fn parse_end_of_input(s: &str) -> Result<EndOfInput, String> {
+    match s {
+        "close" => Ok(EndOfInput::Close),
+        "zero" => Ok(EndOfInput::ZeroMessage),
+        "keep" => Ok(EndOfInput::Keep),
+        _ => match s.strip_prefix("sentinel:") {
+            Some("") => Err("the sentinel is empty".to_string()),
+            Some(bytes) => unescape(bytes).map(EndOfInput::Sentinel),
+            None => Err(format!(
+                "'{}' is not one of close, zero, keep or sentinel:BYTES",
+                s
+            )),
+        },
+    }
+}
+
+/// Decode `\n`, `\r`, `\t`, `\0`, `\\` and `\xHH` escapes.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let (escaped, tail) = rest
+            .split_first()
+            .ok_or_else(|| format!("'{}' ends in a lone backslash", s))?;
+        rest = tail;
+        bytes.push(match escaped {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'0' => 0,
+            b'\\' => b'\\',
+            b'x' => {
+                let byte = rest
+                    .get(..2)
+                    .and_then(|hex| std::str::from_utf8(hex).ok())
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| format!("'{}' has a \\x escape without two hex digits", s))?;
+                rest = &rest[2..];
+                byte
+            }
+            other => return Err(format!("unknown escape '\\{}' in '{}'", *other as char, s)),
+        });
+    }
+    Ok(bytes)
+}
+
+fn parse_rate(s: &str) -> Result<u64, String> {
+    match parse_size(s.strip_suffix("/s").unwrap_or(s))? {
+        0 => Err(format!("'{}' is not a positive rate", s)),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(f),
+        _ => Err(format!("'{}' is not a number between 0.0 and 1.0", s)),
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Accept connections on a Unix socket and relay each one to a target
+    Listen(ListenArgs),
+    /// Play the client side of a --record transcript against a target and
+    /// report where the responses differ from the recording
+    Replay(ReplayArgs),
+    /// Show a --record transcript as decoded protocol messages
+    Inspect(InspectArgs),
+    /// Ask a gpg-agent, over its Assuan socket file, for its version, pid
+    /// and keys
+    GpgStatus(GpgStatusArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ListenArgs {
+    /// Path of the Unix socket to create
+    pub socket: PathBuf,
+
+    /// Permissions of the socket file, in octal
+    #[arg(long, default_value = "600", value_parser = parse_octal_mode)]
+    pub mode: u32,
+
+    /// Target for each connection: a single target (as for the relay), or a
+    /// command and its arguments to spawn per connection
+    #[arg(last = true, required = true, num_args = 1..)]
+    pub target: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// Transcript written by --record
+    pub transcript: PathBuf,
+
+    /// Target to replay against; defaults to the one recorded in the transcript
+    pub target: Option<String>,
+
+    /// Play back this many times faster than recorded, e.g. 10 or 0.5
+    #[arg(long, default_value = "1", value_parser = parse_speed)]
+    pub speed: f64,
+
+    /// Never pause longer than this between chunks; 0s sends without pausing
+    #[arg(long, value_parser = parse_duration)]
+    pub max_gap: Option<Duration>,
+
+    /// How long to wait for each recorded response
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    pub timeout: Duration,
+}
+
+#[derive(Args, Debug)]
+pub struct InspectArgs {
+    /// Transcript written by --record
+    pub transcript: PathBuf,
+
+    /// Protocol to decode; guessed from the recorded target if not given
+    #[arg(long, value_enum)]
+    pub protocol: Option<Protocol>,
+}
+
+#[derive(Args, Debug)]
+pub struct GpgStatusArgs {
+    /// The agent's Assuan socket file, e.g.
+    /// C:/Users/me/AppData/Local/gnupg/S.gpg-agent
+    pub socket: String,
+
+    /// Print the report as text or as one JSON object
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: StatusFormat,
+
+    /// How long to wait for each reply
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    pub timeout: Duration,
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(f) if f.is_finite() && f > 0.0 => Ok(f),
+        _ => Err(format!("'{}' is not a positive speed factor", s)),
+    }
+}
+
+fn parse_octal_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("'{}' is not an octal permission mode like 600", s)),
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Config {
-    pub pipe_name: String,
+    pub target: Target,
     pub poll: bool,
     pub limited_poll: bool,
     pub send_zero: bool,
+    /// What to do to the pipe side on stdin EOF, from `-s` or `--eof`.
+    pub end_of_input: EndOfInput,
     pub exit_on_pipe_eof: bool,
     pub exit_on_stdin_eof: bool,
     pub bg: bool,
-    pub assuan: bool,
     pub verbose: bool,
+    /// `-vv`: hex dump relayed data at trace level.
+    pub trace: bool,
+    pub log_format: LogFormat,
+    /// `--log-file` and its rotation settings.
+    pub log_file: Option<LogFile>,
+    pub mux: bool,
+    /// Connection retry policy built from `-p`, `-l` and the retry flags.
+    pub retry: RetryPolicy,
+    /// Transcript file for `--record`.
+    pub record: Option<PathBuf>,
+    pub record_limit: u64,
+    /// `--record-format`, or the format the `--record` file name implies.
+    pub record_format: RecordFormat,
+    /// Protocol to decode live traffic as, for `--decode`.
+    pub decode: Option<Protocol>,
+    /// Bytes `-vv` hex dumps per session, for `--trace-limit`.
+    pub trace_limit: u64,
+    /// Session limits: `--idle-timeout`, `--max-duration`, `--max-bytes`.
+    pub idle_timeout: Option<Duration>,
+    pub max_duration: Option<Duration>,
+    pub max_bytes: Option<u64>,
+    /// Bandwidth limits in bytes per second, and the burst size for both.
+    pub rate_in: Option<u64>,
+    pub rate_out: Option<u64>,
+    pub rate_burst: Option<u64>,
+    /// Format of the per-session statistics, if `--stats` or `--stats-file`
+    /// asked for them.
+    pub stats: Option<StatsFormat>,
+    pub stats_file: Option<PathBuf>,
+    /// `--verify-greeting`: wait for the Assuan greeting before relaying.
+    pub verify_greeting: bool,
 }
 
-impl From<CliArgs> for Config {
-    fn from(args: CliArgs) -> Self {
+impl Config {
+    fn new(args: &CliArgs, target: Target) -> Self {
+        let end_of_input = match (&args.eof, args.send_zero) {
+            (Some(eof), _) => eof.clone(),
+            (None, true) => EndOfInput::ZeroMessage,
+            (None, false) => default_end_of_input(&target),
+        };
         Config {
-            pipe_name: args.pipe_name,
+            target,
             poll: args.poll,
             limited_poll: args.limited_poll,
             send_zero: args.send_zero,
+            end_of_input,
             exit_on_pipe_eof: args.exit_on_pipe_eof,
             exit_on_stdin_eof: args.exit_on_stdin_eof,
             bg: args.bg,
-            assuan: args.assuan,
-            verbose: args.verbose,
+            verbose: args.verbose > 0,
+            trace: args.verbose > 1,
+            log_format: args.log_format,
+            log_file: args.log_file(),
+            mux: args.mux,
+            retry: args.retry.policy(args.poll, args.limited_poll),
+            record: args.record.clone(),
+            record_limit: args.record_limit,
+            record_format: args.record_format.unwrap_or_else(|| match &args.record {
+                Some(path) => RecordFormat::for_path(path),
+                None => RecordFormat::Transcript,
+            }),
+            decode: args.decode,
+            trace_limit: args.trace_limit,
+            idle_timeout: args.idle_timeout,
+            max_duration: args.max_duration,
+            max_bytes: args.max_bytes,
+            rate_in: args.rate_in,
+            rate_out: args.rate_out,
+            rate_burst: args.rate_burst,
+            stats: args
+                .stats
+                .or(args.stats_file.as_ref().map(|_| StatsFormat::Text)),
+            stats_file: args.stats_file.clone(),
+            verify_greeting: args.verify_greeting,
+        }
+    }
+
+    /// A limiter for `rate` bytes per second with the configured burst.
+    pub fn rate_limiter(&self, rate: Option<u64>) -> Option<Arc<RateLimiter>> {
+        rate.map(|rate| Arc::new(RateLimiter::new(rate, self.rate_burst.unwrap_or(rate))))
+    }
+}
+
+impl TryFrom<CliArgs> for Config {
+    type Error = BatonError;
+
+    fn try_from(args: CliArgs) -> Result<Self, Self::Error> {
+        let target = args
+            .target
+            .as_deref()
+            .ok_or_else(|| BatonError::InvalidTarget("no target given".to_string()))?;
+        let target = Target::parse(target, args.assuan)?;
+        check_verify_greeting(&args, &target)?;
+        Ok(Config::new(&args, target))
+    }
+}
+
+/// What stdin EOF does without `--eof` or `-s`: a command's stdin is closed,
+/// since that is the only way it learns the input ended, but a socket is
+/// left open, as some servers take a half-close as the end of the session.
+fn default_end_of_input(target: &Target) -> EndOfInput {
+    match target {
+        Target::Exec { .. } => EndOfInput::Close,
+        _ => EndOfInput::Keep,
+    }
+}
+
+/// `--verify-greeting` only means something for Assuan targets.
+fn check_verify_greeting(args: &CliArgs, target: &Target) -> Result<(), BatonError> {
+    if args.verify_greeting && !matches!(target, Target::Assuan(_)) {
+        return Err(BatonError::InvalidArgument(format!(
+            "--verify-greeting needs an Assuan target, not '{}'",
+            target
+        )));
+    }
+    Ok(())
+}
+
+/// Settings for `baton listen`. `relay` holds the target and relay flags
+/// applied to every accepted connection.
+#[derive(Debug, Clone)]
+pub struct ListenConfig {
+    pub socket: PathBuf,
+    pub socket_mode: u32,
+    pub relay: Config,
+}
+
+/// Settings for `baton replay`. Without `target`, the target recorded in
+/// the transcript is used.
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub transcript: PathBuf,
+    pub target: Option<Target>,
+    pub options: ReplayOptions,
+    pub retry: RetryPolicy,
+    pub verify_greeting: bool,
+    pub verbose: bool,
+    pub log_format: LogFormat,
+    pub log_file: Option<LogFile>,
+}
+
+/// Settings for `baton inspect`.
+#[derive(Debug, Clone)]
+pub struct InspectConfig {
+    pub transcript: PathBuf,
+    pub protocol: Option<Protocol>,
+}
+
+/// Settings for `baton gpg-status`.
+#[derive(Debug, Clone)]
+pub struct GpgStatusConfig {
+    /// Path of the Assuan socket file.
+    pub socket: String,
+    pub format: StatusFormat,
+    pub timeout: Duration,
+    pub retry: RetryPolicy,
+    pub verbose: bool,
+    pub log_format: LogFormat,
+    pub log_file: Option<LogFile>,
+}
+
+/// What the binary was asked to do.
+#[derive(Debug, Clone)]
+pub enum Command {
+    Relay(Config),
+    Listen(ListenConfig),
+    Replay(ReplayConfig),
+    Inspect(InspectConfig),
+    GpgStatus(GpgStatusConfig),
+}
+
+impl TryFrom<CliArgs> for Command {
+    type Error = BatonError;
+
+    fn try_from(mut args: CliArgs) -> Result<Self, Self::Error> {
+        if args.command.is_some() {
+            let relay_only = [
+                ("--record", args.record.is_some()),
+                ("--decode", args.decode.is_some()),
+                ("--stats", args.stats.is_some()),
+                ("--stats-file", args.stats_file.is_some()),
+                ("-vv", args.verbose > 1),
+            ];
+            if let Some((flag, _)) = relay_only.iter().find(|(_, given)| *given) {
+                return Err(BatonError::InvalidArgument(format!(
+                    "{} is only supported when relaying stdin/stdout",
+                    flag
+                )));
+            }
+        }
+        if args.mux && args.verbose > 1 {
+            return Err(BatonError::InvalidArgument(
+                "-vv is not supported with --mux".to_string(),
+            ));
+        }
+
+        match args.command.take() {
+            None => Ok(Command::Relay(Config::try_from(args)?)),
+            Some(CliCommand::Listen(listen)) => {
+                let target = listen_target(&listen.target, args.assuan)?;
+                check_verify_greeting(&args, &target)?;
+                Ok(Command::Listen(ListenConfig {
+                    socket: listen.socket,
+                    socket_mode: listen.mode,
+                    relay: Config::new(&args, target),
+                }))
+            }
+            Some(CliCommand::Replay(replay)) => {
+                let target = replay
+                    .target
+                    .as_deref()
+                    .map(|t| Target::parse(t, args.assuan))
+                    .transpose()?;
+                if let Some(target) = &target {
+                    check_verify_greeting(&args, target)?;
+                }
+                Ok(Command::Replay(ReplayConfig {
+                    transcript: replay.transcript,
+                    target,
+                    options: ReplayOptions {
+                        timing: Timing {
+                            speed: replay.speed,
+                            max_gap: replay.max_gap,
+                        },
+                        response_timeout: replay.timeout,
+                    },
+                    retry: args.retry.policy(args.poll, args.limited_poll),
+                    verify_greeting: args.verify_greeting,
+                    verbose: args.verbose > 0,
+                    log_format: args.log_format,
+                    log_file: args.log_file(),
+                }))
+            }
+            Some(CliCommand::Inspect(inspect)) => Ok(Command::Inspect(InspectConfig {
+                transcript: inspect.transcript,
+                protocol: inspect.protocol,
+            })),
+            Some(CliCommand::GpgStatus(status)) => {
+                // Accept assuan:// as well as a bare path.
+                let Target::Assuan(socket) = Target::parse(&status.socket, true)? else {
+                    unreachable!("-a only parses to Assuan targets");
+                };
+                Ok(Command::GpgStatus(GpgStatusConfig {
+                    socket,
+                    format: status.format,
+                    timeout: status.timeout,
+                    retry: args.retry.policy(args.poll, args.limited_poll),
+                    verbose: args.verbose > 0,
+                    log_format: args.log_format,
+                    log_file: args.log_file(),
+                }))
+            }
         }
     }
 }
 
-pub fn parse() -> Config {
-    CliArgs::parse().into()
+/// A single word after `--` is a target; several words are a command line.
+fn listen_target(words: &[String], assuan: bool) -> Result<Target, BatonError> {
+    match words {
+        [target] => Target::parse(target, assuan),
+        [program, args @ ..] => Ok(Target::Exec {
+            program: program.clone(),
+            args: args.to_vec(),
+        }),
+        [] => Err(BatonError::InvalidTarget("no target given".to_string())),
+    }
+}
+
+/// npiperelay's Go-style single-dash long flags and their clap spelling.
+const GO_STYLE_FLAGS: [(&str, &str); 3] = [("-ep", "--ep"), ("-ei", "--ei"), ("-bg", "--bg")];
+
+/// Rewrite npiperelay's single-dash long flags (`-ep`, `-ei`, `-bg`) to the
+/// `--` form, since clap would read `-ep` as `-e -p`. Arguments after `--`
+/// are left alone: they belong to the command `baton listen` runs.
+pub fn normalize_args<I, T>(args: I) -> Vec<OsString>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let mut in_options = true;
+    args.into_iter()
+        .map(|arg| {
+            let arg = arg.into();
+            if !in_options {
+                return arg;
+            }
+            if arg == "--" {
+                in_options = false;
+                return arg;
+            }
+            GO_STYLE_FLAGS
+                .iter()
+                .find(|(go, _)| arg == *go)
+                .map_or(arg, |(_, clap)| OsString::from(clap))
+        })
+        .collect()
+}
+
+pub fn parse() -> Command {
+    let args = CliArgs::parse_from(normalize_args(std::env::args_os()));
+    Command::try_from(args).unwrap_or_else(|e| {
+        CliArgs::command()
+            .error(clap::error::ErrorKind::ValueValidation, e)
+            .exit()
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use clap::CommandFactory;
+    use crate::relay::RelayOptions;
+    use crate::replay::DEFAULT_RESPONSE_TIMEOUT;
+    use crate::retry::LIMITED_ATTEMPTS;
 
     #[test]
     fn test_cli_args_valid() {
@@ -86,9 +762,9 @@ mod tests {
     #[test]
     fn test_parse_basic() {
         let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
-        assert_eq!(args.pipe_name, "//./pipe/test");
+        assert_eq!(args.target.as_deref(), Some("//./pipe/test"));
         assert!(!args.poll);
-        assert!(!args.verbose);
+        assert_eq!(args.verbose, 0);
     }
 
     #[test]
@@ -104,7 +780,7 @@ mod tests {
         assert!(args.exit_on_stdin_eof);
         assert!(args.bg);
         assert!(args.assuan);
-        assert!(args.verbose);
+        assert_eq!(args.verbose, 1);
     }
 
     #[test]
@@ -116,24 +792,91 @@ mod tests {
     #[test]
     fn test_config_from_args() {
         let args = CliArgs::try_parse_from(["baton", "-p", "-v", "//./pipe/test"]).unwrap();
-        let config: Config = args.into();
-        assert_eq!(config.pipe_name, "//./pipe/test");
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(
+            config.target,
+            Target::NamedPipe("//./pipe/test".to_string())
+        );
         assert!(config.poll);
         assert!(config.verbose);
         assert!(!config.limited_poll);
-        assert!(!config.assuan);
+    }
+
+    #[test]
+    fn test_config_assuan_flag_selects_assuan_target() {
+        let args = CliArgs::try_parse_from(["baton", "-a", "C:/gnupg/S.gpg-agent"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(
+            config.target,
+            Target::Assuan("C:/gnupg/S.gpg-agent".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_verify_greeting() {
+        let args =
+            CliArgs::try_parse_from(["baton", "--verify-greeting", "-a", "C:/gnupg/S.gpg-agent"])
+                .unwrap();
+        assert!(Config::try_from(args).unwrap().verify_greeting);
+
+        let args = CliArgs::try_parse_from(["baton", "C:/gnupg/S.gpg-agent"]).unwrap();
+        assert!(!Config::try_from(args).unwrap().verify_greeting);
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--verify-greeting", "tcp://127.0.0.1:1"]).unwrap();
+        let err = Config::try_from(args).unwrap_err();
+        assert!(
+            err.to_string().contains("needs an Assuan target"),
+            "{}",
+            err
+        );
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "--verify-greeting",
+            "/tmp/S.gpg-agent",
+            "--",
+            "assuan://C:/gnupg/S.gpg-agent",
+        ])
+        .unwrap();
+        match Command::try_from(args).unwrap() {
+            Command::Listen(listen) => assert!(listen.relay.verify_greeting),
+            command => panic!("unexpected command: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn test_config_url_target() {
+        let args =
+            CliArgs::try_parse_from(["baton", "--ep", "-s", "tcp://127.0.0.1:2375"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.target, Target::Tcp("127.0.0.1:2375".to_string()));
+        assert!(config.exit_on_pipe_eof);
+    }
+
+    #[test]
+    fn test_config_invalid_target() {
+        let args = CliArgs::try_parse_from(["baton", "ftp://example.com"]).unwrap();
+        assert!(matches!(
+            Config::try_from(args),
+            Err(BatonError::InvalidTarget(_))
+        ));
     }
 
     #[test]
     fn test_parse_windows_style_path() {
         let args = CliArgs::try_parse_from(["baton", "\\\\.\\pipe\\openssh-ssh-agent"]).unwrap();
-        assert_eq!(args.pipe_name, "\\\\.\\pipe\\openssh-ssh-agent");
+        assert_eq!(
+            args.target.as_deref(),
+            Some("\\\\.\\pipe\\openssh-ssh-agent")
+        );
     }
 
     #[test]
     fn test_parse_unix_style_path() {
         let args = CliArgs::try_parse_from(["baton", "//./pipe/docker_engine"]).unwrap();
-        assert_eq!(args.pipe_name, "//./pipe/docker_engine");
+        assert_eq!(args.target.as_deref(), Some("//./pipe/docker_engine"));
     }
 
     #[test]
@@ -142,7 +885,7 @@ mod tests {
             CliArgs::try_parse_from(["baton", "-a", "C:\\Users\\test\\AppData\\Roaming\\gnupg\\S.gpg-agent"])
                 .unwrap();
         assert!(args.assuan);
-        assert!(args.pipe_name.contains("gnupg"));
+        assert!(args.target.unwrap().contains("gnupg"));
     }
 
     #[test]
@@ -162,4 +905,804 @@ mod tests {
         let result = CliArgs::try_parse_from(["baton", "--unknown", "//./pipe/test"]);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_listen_single_target() {
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "/tmp/docker.sock",
+            "--",
+            "tcp://127.0.0.1:2375",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert_eq!(listen.socket, PathBuf::from("/tmp/docker.sock"));
+        assert_eq!(listen.socket_mode, 0o600);
+        assert_eq!(
+            listen.relay.target,
+            Target::Tcp("127.0.0.1:2375".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_listen_command_target() {
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "--mode",
+            "660",
+            "/tmp/docker.sock",
+            "--",
+            "baton.exe",
+            "-ep",
+            "-s",
+            "//./pipe/docker_engine",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert_eq!(listen.socket_mode, 0o660);
+        assert_eq!(
+            listen.relay.target,
+            Target::Exec {
+                program: "baton.exe".to_string(),
+                args: vec![
+                    "-ep".to_string(),
+                    "-s".to_string(),
+                    "//./pipe/docker_engine".to_string()
+                ],
+            }
+        );
+        // Flags after `--` belong to the command, not to the listener
+        assert!(!listen.relay.send_zero);
+    }
+
+    #[test]
+    fn test_parse_listen_relay_flags() {
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "-v",
+            "--ep",
+            "/tmp/agent.sock",
+            "--",
+            "unix:///run/agent.sock",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert!(listen.relay.verbose);
+        assert!(listen.relay.exit_on_pipe_eof);
+    }
+
+    #[test]
+    fn test_parse_listen_requires_target() {
+        assert!(CliArgs::try_parse_from(["baton", "listen", "/tmp/a.sock"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_listen_invalid_mode() {
+        let result = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "--mode",
+            "999",
+            "/tmp/a.sock",
+            "--",
+            "exec:cat",
+        ]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_mux() {
+        let args = CliArgs::try_parse_from(["baton", "--mux", "--ep", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(config.mux);
+        assert!(config.exit_on_pipe_eof);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "--mux",
+            "/tmp/docker.sock",
+            "--",
+            "baton.exe",
+            "--mux",
+            "//./pipe/docker_engine",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert!(listen.relay.mux);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.record, None);
+        assert_eq!(config.record_limit, 64 * 1024 * 1024);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--record",
+            "/tmp/session.rec",
+            "--record-limit",
+            "512K",
+            "//./pipe/test",
+        ])
+        .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.record, Some(PathBuf::from("/tmp/session.rec")));
+        assert_eq!(config.record_limit, 512 * 1024);
+        assert_eq!(config.record_format, RecordFormat::Transcript);
+
+        let args = CliArgs::try_parse_from(["baton", "--record", "docker.pcapng", "//./pipe/test"])
+            .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.record_format, RecordFormat::Pcapng);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--record",
+            "docker.cap",
+            "--record-format",
+            "pcapng",
+            "//./pipe/test",
+        ])
+        .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.record_format, RecordFormat::Pcapng);
+
+        let result =
+            CliArgs::try_parse_from(["baton", "--record-format", "pcapng", "//./pipe/test"]);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_trace() {
+        let args = CliArgs::try_parse_from(["baton", "-v", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(config.verbose);
+        assert!(!config.trace);
+        assert_eq!(config.trace_limit, 64 * 1024);
+
+        let args =
+            CliArgs::try_parse_from(["baton", "-vv", "--trace-limit", "1M", "//./pipe/test"])
+                .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(config.verbose);
+        assert!(config.trace);
+        assert_eq!(config.trace_limit, 1024 * 1024);
+
+        let args = CliArgs::try_parse_from(normalize_args([
+            "baton",
+            "-v",
+            "-ep",
+            "-v",
+            "//./pipe/test",
+        ]))
+        .unwrap();
+        assert_eq!(args.verbose, 2);
+
+        // Only the plain relay hex dumps, so -vv is refused elsewhere
+        // rather than silently doing nothing.
+        for argv in [
+            &["baton", "-vv", "--mux", "//./pipe/test"][..],
+            &["baton", "-vv", "listen", "/tmp/a.sock", "--", "exec:cat"],
+            &["baton", "-vv", "replay", "a.rec"],
+        ] {
+            let args = CliArgs::try_parse_from(argv).unwrap();
+            let err = Command::try_from(args).unwrap_err();
+            assert!(err.to_string().contains("-vv"), "{}", err);
+        }
+        let args =
+            CliArgs::try_parse_from(["baton", "-v", "listen", "/tmp/a.sock", "--", "x"]).unwrap();
+        assert!(Command::try_from(args).is_ok());
+    }
+
+    #[test]
+    fn test_parse_log_format() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        assert_eq!(args.log_format, LogFormat::Text);
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--log-format", "json", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.log_format, LogFormat::Json);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "/tmp/a.sock",
+            "--log-format",
+            "json",
+            "--",
+            "exec:cat",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert_eq!(listen.relay.log_format, LogFormat::Json);
+
+        assert!(CliArgs::try_parse_from(["baton", "--log-format", "xml", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_log_file() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.log_file, None);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--bg",
+            "--log-file",
+            "C:/Temp/baton-{session}.log",
+            "--log-max-size",
+            "1M",
+            "--log-keep",
+            "2",
+            "//./pipe/test",
+        ])
+        .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(
+            config.log_file,
+            Some(LogFile {
+                path: PathBuf::from("C:/Temp/baton-{session}.log"),
+                max_size: 1024 * 1024,
+                keep: 2,
+            })
+        );
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--log-file", "baton.log", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        let log_file = config.log_file.unwrap();
+        assert_eq!(log_file.max_size, logging::DEFAULT_LOG_MAX_SIZE);
+        assert_eq!(log_file.keep, logging::DEFAULT_LOG_KEEP);
+    }
+
+    #[test]
+    fn test_parse_session_limits() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.idle_timeout, None);
+        assert_eq!(config.max_duration, None);
+        assert_eq!(config.max_bytes, None);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--idle-timeout",
+            "30s",
+            "--max-duration",
+            "2h",
+            "--max-bytes",
+            "100M",
+            "//./pipe/test",
+        ])
+        .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.max_duration, Some(Duration::from_secs(7200)));
+        assert_eq!(config.max_bytes, Some(100 * 1024 * 1024));
+
+        assert!(CliArgs::try_parse_from(["baton", "--idle-timeout", "30", "x"]).is_err());
+
+        // Each connection of listen and each --mux channel is limited alike.
+        let args = CliArgs::try_parse_from(["baton", "--max-bytes", "1M", "--mux", "x"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.max_bytes, Some(1024 * 1024));
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "/tmp/a.sock",
+            "--idle-timeout",
+            "1m",
+            "--max-duration",
+            "1h",
+            "--",
+            "exec:cat",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert_eq!(listen.relay.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(listen.relay.max_duration, Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_parse_eof() {
+        let end_of_input = |args: &[&str]| {
+            let args = CliArgs::try_parse_from(args).unwrap();
+            Config::try_from(args).unwrap().end_of_input
+        };
+        assert_eq!(end_of_input(&["baton", "x"]), EndOfInput::Keep);
+        assert_eq!(
+            end_of_input(&["baton", "tcp://127.0.0.1:2375"]),
+            EndOfInput::Keep
+        );
+        assert_eq!(end_of_input(&["baton", "exec:cat"]), EndOfInput::Close);
+        assert_eq!(
+            end_of_input(&["baton", "--eof", "close", "x"]),
+            EndOfInput::Close
+        );
+        assert_eq!(end_of_input(&["baton", "-s", "x"]), EndOfInput::ZeroMessage);
+        assert_eq!(
+            end_of_input(&["baton", "--eof", "zero", "x"]),
+            EndOfInput::ZeroMessage
+        );
+        assert_eq!(
+            end_of_input(&["baton", "--eof", "keep", "x"]),
+            EndOfInput::Keep
+        );
+        assert_eq!(
+            end_of_input(&["baton", "--eof", r"sentinel:BYE\r\n\x04\\", "x"]),
+            EndOfInput::Sentinel(b"BYE\r\n\x04\\".to_vec())
+        );
+
+        for bad in [
+            "sentinel:",
+            r"sentinel:\q",
+            r"sentinel:\x4",
+            r"sentinel:a\",
+            "shut",
+        ] {
+            assert!(
+                CliArgs::try_parse_from(["baton", "--eof", bad, "x"]).is_err(),
+                "{}",
+                bad
+            );
+        }
+        assert!(CliArgs::try_parse_from(["baton", "-s", "--eof", "keep", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limits() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(RelayOptions::from(&config).rate_in.is_none());
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--rate-in",
+            "10M",
+            "--rate-out",
+            "512K/s",
+            "//./pipe/test",
+        ])
+        .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.rate_in, Some(10 * 1024 * 1024));
+        assert_eq!(config.rate_out, Some(512 * 1024));
+        let opts = RelayOptions::from(&config);
+        let rate_out = opts.rate_out.unwrap();
+        assert_eq!(rate_out.rate(), 512 * 1024);
+        assert_eq!(rate_out.burst(), 512 * 1024);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "listen",
+            "/tmp/a.sock",
+            "--rate-in",
+            "1M",
+            "--rate-burst",
+            "64K",
+            "--",
+            "exec:cat",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        let rate_in = RelayOptions::from(&listen.relay).rate_in.unwrap();
+        assert_eq!(rate_in.burst(), 64 * 1024);
+
+        assert!(CliArgs::try_parse_from(["baton", "--rate-in", "0", "x"]).is_err());
+        assert!(CliArgs::try_parse_from(["baton", "--rate-in", "fast", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_stats() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.stats, None);
+
+        let args = CliArgs::try_parse_from(["baton", "--stats", "json", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.stats, Some(StatsFormat::Json));
+        assert_eq!(config.stats_file, None);
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--stats-file", "s.log", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.stats, Some(StatsFormat::Text));
+        assert_eq!(config.stats_file, Some(PathBuf::from("s.log")));
+
+        // The format is required, so a target is never taken for it.
+        assert!(CliArgs::try_parse_from(["baton", "--stats", "//./pipe/test"]).is_err());
+        assert!(CliArgs::try_parse_from(["baton", "--stats", "text", "--mux", "x"]).is_err());
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--stats", "text", "replay", "a.rec"]).unwrap();
+        let err = Command::try_from(args).unwrap_err();
+        assert!(err.to_string().contains("--stats"));
+    }
+
+    #[test]
+    fn test_parse_record_conflicts() {
+        let result =
+            CliArgs::try_parse_from(["baton", "--mux", "--record", "a.rec", "//./pipe/test"]);
+        assert!(result.is_err());
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "--record",
+            "a.rec",
+            "listen",
+            "/tmp/a.sock",
+            "--",
+            "exec:cat",
+        ])
+        .unwrap();
+        let err = Command::try_from(args).unwrap_err();
+        assert!(err.to_string().contains("--record"));
+    }
+
+    #[test]
+    fn test_parse_replay() {
+        let args = CliArgs::try_parse_from(["baton", "replay", "session.rec"]).unwrap();
+        let Command::Replay(replay) = Command::try_from(args).unwrap() else {
+            panic!("expected replay command");
+        };
+        assert_eq!(replay.transcript, PathBuf::from("session.rec"));
+        assert_eq!(replay.target, None);
+        assert_eq!(replay.options.timing, Timing::default());
+        assert_eq!(replay.options.response_timeout, DEFAULT_RESPONSE_TIMEOUT);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "replay",
+            "-p",
+            "--speed",
+            "10",
+            "--max-gap",
+            "0s",
+            "--timeout",
+            "1s",
+            "session.rec",
+            "tcp://127.0.0.1:2375",
+        ])
+        .unwrap();
+        let Command::Replay(replay) = Command::try_from(args).unwrap() else {
+            panic!("expected replay command");
+        };
+        assert_eq!(
+            replay.target,
+            Some(Target::Tcp("127.0.0.1:2375".to_string()))
+        );
+        assert_eq!(replay.options.timing.speed, 10.0);
+        assert_eq!(replay.options.timing.max_gap, Some(Duration::ZERO));
+        assert_eq!(replay.options.response_timeout, Duration::from_secs(1));
+        assert!(replay.retry.poll);
+    }
+
+    #[test]
+    fn test_parse_replay_invalid_speed() {
+        for speed in ["0", "-1", "fast", "inf"] {
+            let result =
+                CliArgs::try_parse_from(["baton", "replay", "--speed", speed, "session.rec"]);
+            assert!(result.is_err(), "{}", speed);
+        }
+    }
+
+    #[test]
+    fn test_parse_gpg_status() {
+        let args =
+            CliArgs::try_parse_from(["baton", "gpg-status", "C:/gnupg/S.gpg-agent"]).unwrap();
+        let Command::GpgStatus(status) = Command::try_from(args).unwrap() else {
+            panic!("expected gpg-status command");
+        };
+        assert_eq!(status.socket, "C:/gnupg/S.gpg-agent");
+        assert_eq!(status.format, StatusFormat::Text);
+        assert_eq!(status.timeout, Duration::from_secs(5));
+        assert!(!status.retry.poll);
+
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "-p",
+            "gpg-status",
+            "--format",
+            "json",
+            "--timeout",
+            "1s",
+            "assuan://C:/gnupg/S.gpg-agent",
+        ])
+        .unwrap();
+        let Command::GpgStatus(status) = Command::try_from(args).unwrap() else {
+            panic!("expected gpg-status command");
+        };
+        assert_eq!(status.socket, "C:/gnupg/S.gpg-agent");
+        assert_eq!(status.format, StatusFormat::Json);
+        assert_eq!(status.timeout, Duration::from_secs(1));
+        assert!(status.retry.poll);
+
+        let args = CliArgs::try_parse_from(["baton", "gpg-status", "tcp://127.0.0.1:1"]).unwrap();
+        assert!(Command::try_from(args).is_err());
+        let args =
+            CliArgs::try_parse_from(["baton", "--stats", "json", "gpg-status", "S.gpg-agent"])
+                .unwrap();
+        assert!(Command::try_from(args).is_err());
+    }
+
+    #[test]
+    fn test_parse_inspect_and_decode() {
+        let args = CliArgs::try_parse_from(["baton", "inspect", "a.rec"]).unwrap();
+        let Command::Inspect(inspect) = Command::try_from(args).unwrap() else {
+            panic!("expected inspect command");
+        };
+        assert_eq!(inspect.transcript, PathBuf::from("a.rec"));
+        assert_eq!(inspect.protocol, None);
+
+        let args =
+            CliArgs::try_parse_from(["baton", "inspect", "--protocol", "ssh-agent", "a.rec"])
+                .unwrap();
+        let Command::Inspect(inspect) = Command::try_from(args).unwrap() else {
+            panic!("expected inspect command");
+        };
+        assert_eq!(inspect.protocol, Some(Protocol::SshAgent));
+
+        let args = CliArgs::try_parse_from(["baton", "-v", "--decode", "assuan", "//./pipe/test"])
+            .unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert_eq!(config.decode, Some(Protocol::Assuan));
+
+        let args =
+            CliArgs::try_parse_from(["baton", "--decode", "http", "inspect", "a.rec"]).unwrap();
+        let err = Command::try_from(args).unwrap_err();
+        assert!(err.to_string().contains("--decode"));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_size("64m"), Ok(64 * 1024 * 1024));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("99999999999999999G").is_err());
+    }
+
+    #[test]
+    fn test_retry_policy_from_poll_flags() {
+        let args = CliArgs::try_parse_from(["baton", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(!config.retry.poll);
+
+        let args = CliArgs::try_parse_from(["baton", "-p", "-l", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(config.retry.poll);
+        assert_eq!(config.retry.max_attempts, Some(LIMITED_ATTEMPTS));
+        assert_eq!(config.retry.interval, Duration::from_millis(200));
+        assert_eq!(config.retry.backoff, Backoff::Fixed);
+    }
+
+    #[test]
+    fn test_retry_flags() {
+        let args = CliArgs::try_parse_from([
+            "baton",
+            "-l",
+            "--retry-backoff",
+            "exponential",
+            "--retry-interval",
+            "50ms",
+            "--retry-max-interval",
+            "5s",
+            "--retry-jitter",
+            "0.25",
+            "--retry-max-attempts",
+            "10",
+            "--retry-deadline",
+            "2m",
+            "--connect-timeout",
+            "1.5s",
+            "tcp://127.0.0.1:2375",
+        ])
+        .unwrap();
+        let retry = Config::try_from(args).unwrap().retry;
+        // Retry flags imply -p, and an explicit limit overrides -l.
+        assert!(retry.poll);
+        assert_eq!(retry.backoff, Backoff::Exponential);
+        assert_eq!(retry.interval, Duration::from_millis(50));
+        assert_eq!(retry.max_interval, Duration::from_secs(5));
+        assert_eq!(retry.jitter, 0.25);
+        assert_eq!(retry.max_attempts, Some(10));
+        assert_eq!(retry.deadline, Some(Duration::from_secs(120)));
+        assert_eq!(retry.connect_timeout, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn test_connect_timeout_alone_does_not_poll() {
+        let args =
+            CliArgs::try_parse_from(["baton", "--connect-timeout", "2s", "tcp://h:1"]).unwrap();
+        let retry = Config::try_from(args).unwrap().retry;
+        assert!(!retry.poll);
+        assert_eq!(retry.connect_timeout, Some(Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_retry_flags_invalid() {
+        for args in [
+            ["--retry-interval", "200"],
+            ["--retry-interval", "5 days"],
+            ["--retry-deadline", "-1s"],
+            ["--retry-jitter", "1.5"],
+            ["--retry-max-attempts", "0"],
+            ["--retry-backoff", "linear"],
+        ] {
+            let mut argv = vec!["baton"];
+            argv.extend(args);
+            argv.push("//./pipe/test");
+            assert!(CliArgs::try_parse_from(argv).is_err(), "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("200ms"), Ok(Duration::from_millis(200)));
+        assert_eq!(parse_duration("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(3600)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("s").is_err());
+        assert!(parse_duration("1.2.3s").is_err());
+    }
+
+    fn parse_go_style(args: &[&str]) -> Result<CliArgs, clap::Error> {
+        CliArgs::try_parse_from(normalize_args(args.iter().copied()))
+    }
+
+    #[test]
+    fn test_npiperelay_invocations_parse_like_clap_forms() {
+        // Every invocation documented for npiperelay, next to its clap form.
+        let cases: &[(&[&str], &[&str])] = &[
+            (
+                &["baton", "-ep", "-s", "//./pipe/docker_engine"],
+                &["baton", "--ep", "-s", "//./pipe/docker_engine"],
+            ),
+            (
+                &["baton", "-ei", "-s", "//./pipe/openssh-ssh-agent"],
+                &["baton", "--ei", "-s", "//./pipe/openssh-ssh-agent"],
+            ),
+            (
+                &["baton", "-ei", "-ep", "-a", "C:/Users/me/S.gpg-agent"],
+                &["baton", "--ei", "--ep", "-a", "C:/Users/me/S.gpg-agent"],
+            ),
+            (
+                &["baton", "-p", "-l", "-s", "//./pipe/MSSQL$SQLEXPRESS"],
+                &["baton", "-p", "-l", "-s", "//./pipe/MSSQL$SQLEXPRESS"],
+            ),
+            (
+                &["baton", "-p", "-s", "//./pipe/hyperv-serial"],
+                &["baton", "-p", "-s", "//./pipe/hyperv-serial"],
+            ),
+            (
+                &["baton", "-bg", "-ep", "-s", "//./pipe/docker_engine"],
+                &["baton", "--bg", "--ep", "-s", "//./pipe/docker_engine"],
+            ),
+            (
+                &[
+                    "baton",
+                    "-v",
+                    "-ep",
+                    "-ei",
+                    "-bg",
+                    "-p",
+                    "-l",
+                    "-s",
+                    "//./pipe/x",
+                ],
+                &[
+                    "baton",
+                    "-v",
+                    "--ep",
+                    "--ei",
+                    "--bg",
+                    "-p",
+                    "-l",
+                    "-s",
+                    "//./pipe/x",
+                ],
+            ),
+        ];
+
+        for (go_style, clap_style) in cases {
+            let go_args = parse_go_style(go_style).unwrap();
+            let clap_args = CliArgs::try_parse_from(*clap_style).unwrap();
+            assert_eq!(
+                format!("{:?}", go_args),
+                format!("{:?}", clap_args),
+                "{:?}",
+                go_style
+            );
+        }
+    }
+
+    #[test]
+    fn test_go_style_flags_set_config() {
+        let args = parse_go_style(&["baton", "-ep", "-ei", "-bg", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        assert!(config.exit_on_pipe_eof);
+        assert!(config.exit_on_stdin_eof);
+        assert!(config.bg);
+        assert!(!config.poll);
+    }
+
+    #[test]
+    fn test_clap_forms_still_accepted_after_normalizing() {
+        let args = parse_go_style(&["baton", "--ep", "--ei", "--bg", "//./pipe/test"]).unwrap();
+        assert!(args.exit_on_pipe_eof && args.exit_on_stdin_eof && args.bg);
+    }
+
+    #[test]
+    fn test_go_style_flags_with_listen() {
+        let args = parse_go_style(&[
+            "baton",
+            "listen",
+            "-ep",
+            "/tmp/docker.sock",
+            "--",
+            "baton.exe",
+            "-ep",
+            "-s",
+            "//./pipe/docker_engine",
+        ])
+        .unwrap();
+        let Command::Listen(listen) = Command::try_from(args).unwrap() else {
+            panic!("expected listen command");
+        };
+        assert!(listen.relay.exit_on_pipe_eof);
+        // The command after `--` keeps its own spelling.
+        assert_eq!(
+            listen.relay.target,
+            Target::Exec {
+                program: "baton.exe".to_string(),
+                args: vec![
+                    "-ep".to_string(),
+                    "-s".to_string(),
+                    "//./pipe/docker_engine".to_string()
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_normalize_args_leaves_other_arguments_alone() {
+        let argv = ["baton", "-e", "-epx", "exec:baton.exe -ep //./pipe/x"];
+        assert_eq!(normalize_args(argv), argv);
+    }
+
+    #[test]
+    fn test_relay_command_without_subcommand() {
+        let args = CliArgs::try_parse_from(["baton", "--ei", "//./pipe/test"]).unwrap();
+        let Command::Relay(config) = Command::try_from(args).unwrap() else {
+            panic!("expected relay command");
+        };
+        assert!(config.exit_on_stdin_eof);
+    }
 }
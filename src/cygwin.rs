@@ -0,0 +1,453 @@
+//! Unix sockets as emulated by Cygwin and MSYS2, which is what Git for
+//! Windows' `ssh-agent` listens on.
+//!
+//! The "socket" is a file holding a localhost TCP port and a secret GUID:
+//!
+//! ```text
+//! !<socket >49734 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809
+//! ```
+//!
+//! Connecting takes two exchanges before any data flows. The client sends
+//! the GUID as four little-endian 32-bit words and the server echoes them
+//! back; then each side sends its pid, uid and gid as three little-endian
+//! 32-bit integers, client first.
+//!
+//! A native process has no Cygwin uid, so baton sends uid and gid 0. OpenSSH's
+//! `ssh-agent` accepts peers that claim to be root, as it does on Unix.
+
+use crate::assuan::GREETING_TIMEOUT;
+use crate::endpoint::{connect_tcp, Endpoint, EndpointReader, EndpointWriter, HalfClose};
+use crate::errors::BatonError;
+use crate::retry::RetryPolicy;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+/// What every Cygwin socket file starts with.
+pub const SOCKET_COOKIE: &str = "!<socket >";
+
+/// Length of the GUID sent at the start of a connection.
+pub const GUID_SIZE: usize = 16;
+
+/// The contents of a Cygwin socket file.
+#[derive(Clone, PartialEq, Eq)]
+pub struct CygwinSocketInfo {
+    pub port: u16,
+    /// The GUID in the byte order it goes over the wire.
+    pub guid: [u8; GUID_SIZE],
+}
+
+impl CygwinSocketInfo {
+    /// Parse a socket file's contents: the cookie, the port, `s` for a stream
+    /// socket and the GUID, possibly followed by a NUL. Errors never quote
+    /// the GUID, since it is what grants access.
+    pub fn parse(contents: &[u8]) -> Result<Self, BatonError> {
+        let contents = std::str::from_utf8(contents)
+            .map_err(|_| invalid("not a Cygwin socket file (not text)"))?;
+        let rest = contents
+            .strip_prefix(SOCKET_COOKIE)
+            .ok_or_else(|| invalid(format!("not a Cygwin socket file (no '{}')", SOCKET_COOKIE)))?;
+
+        let fields: Vec<&str> = rest
+            .trim_end_matches(['\0', '\r', '\n'])
+            .split(' ')
+            .collect();
+        let [port, kind, guid] = fields[..] else {
+            return Err(invalid(format!(
+                "expected '{}PORT s GUID', found {} fields",
+                SOCKET_COOKIE,
+                fields.len()
+            )));
+        };
+
+        let port: u16 = port
+            .parse()
+            .map_err(|e| invalid(format!("invalid port number '{}': {}", port, e)))?;
+        match kind {
+            "s" => {}
+            "d" => return Err(invalid("datagram sockets are not supported")),
+            _ => return Err(invalid(format!("unknown socket type '{}'", kind))),
+        }
+        let guid = parse_guid(guid)?;
+
+        Ok(Self { port, guid })
+    }
+
+    pub fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::LOCALHOST.into(), self.port)
+    }
+
+    /// Connect and complete the handshake, retrying both according to
+    /// `policy`. A server that accepts but never answers fails the attempt
+    /// after the connect timeout, or [`GREETING_TIMEOUT`] without one.
+    pub fn connect(&self, policy: &RetryPolicy) -> Result<TcpStream, BatonError> {
+        let addr = self.socket_addr().to_string();
+        let attempt = |timeout: Option<Duration>| {
+            let mut stream = connect_tcp(&addr, timeout)?;
+            stream.set_read_timeout(Some(timeout.unwrap_or(GREETING_TIMEOUT)))?;
+            let peer = handshake(&mut stream, &self.guid, PeerCredentials::current()).map_err(
+                |e| match e.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "timed out waiting for the handshake",
+                    ),
+                    _ => e,
+                },
+            )?;
+            stream.set_read_timeout(None)?;
+            Ok((stream, peer))
+        };
+        let (stream, peer) = policy
+            .run(&addr, attempt, |_: &io::Error| true)
+            .map_err(|e| e.into_baton(BatonError::CygwinConnection))?;
+        log::debug!("Cygwin socket handshake done, server pid {}", peer.pid);
+
+        Ok(stream)
+    }
+}
+
+impl fmt::Debug for CygwinSocketInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Like an Assuan nonce, the GUID is what grants access.
+        f.debug_struct("CygwinSocketInfo")
+            .field("port", &self.port)
+            .field("guid", &"<redacted>")
+            .finish()
+    }
+}
+
+/// The pid, uid and gid each side of a connection claims.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: i32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+impl PeerCredentials {
+    /// This process, claiming uid and gid 0.
+    pub fn current() -> Self {
+        Self {
+            pid: std::process::id() as i32,
+            uid: 0,
+            gid: 0,
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 12] {
+        let mut bytes = [0u8; 12];
+        bytes[..4].copy_from_slice(&self.pid.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.uid.to_le_bytes());
+        bytes[8..].copy_from_slice(&self.gid.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: [u8; 12]) -> Self {
+        let word = |i: usize| [bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]];
+        Self {
+            pid: i32::from_le_bytes(word(0)),
+            uid: u32::from_le_bytes(word(4)),
+            gid: u32::from_le_bytes(word(8)),
+        }
+    }
+}
+
+/// Run the client side of the handshake on a connected `stream` and return
+/// the server's credentials.
+pub fn handshake<S: Read + Write>(
+    stream: &mut S,
+    guid: &[u8; GUID_SIZE],
+    credentials: PeerCredentials,
+) -> io::Result<PeerCredentials> {
+    stream.write_all(guid)?;
+    let mut echoed = [0u8; GUID_SIZE];
+    stream.read_exact(&mut echoed)?;
+    if echoed != *guid {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server answered with a different GUID",
+        ));
+    }
+
+    stream.write_all(&credentials.to_bytes())?;
+    let mut peer = [0u8; 12];
+    stream.read_exact(&mut peer)?;
+    Ok(PeerCredentials::from_bytes(peer))
+}
+
+/// Cygwin or MSYS2 emulated Unix socket file.
+#[derive(Debug, Clone)]
+pub struct CygwinEndpoint {
+    pub path: String,
+}
+
+impl CygwinEndpoint {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl Endpoint for CygwinEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let stream = parse_cygwin_socket_file(&self.path)?.connect(policy)?;
+        let reader = stream.try_clone()?;
+        Ok((Box::new(reader), Box::new(HalfClose(stream))))
+    }
+}
+
+/// Read the socket file at `path`.
+pub fn parse_cygwin_socket_file(path: impl AsRef<Path>) -> Result<CygwinSocketInfo, BatonError> {
+    let contents = std::fs::read(path).map_err(|e| invalid(format!("cannot open file: {}", e)))?;
+    CygwinSocketInfo::parse(&contents)
+}
+
+/// Turn `XXXXXXXX-XXXXXXXX-XXXXXXXX-XXXXXXXX` into the bytes sent on the
+/// wire: each group is a 32-bit word, sent little-endian.
+fn parse_guid(s: &str) -> Result<[u8; GUID_SIZE], BatonError> {
+    // Even a malformed GUID is mostly the secret, so it is not quoted.
+    let bad = || invalid("invalid GUID");
+    let mut guid = [0u8; GUID_SIZE];
+    let mut groups = s.split('-');
+    for chunk in guid.chunks_exact_mut(4) {
+        let group = groups.next().filter(|g| g.len() == 8).ok_or_else(bad)?;
+        let word = u32::from_str_radix(group, 16).map_err(|_| bad())?;
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    if groups.next().is_some() {
+        return Err(bad());
+    }
+    Ok(guid)
+}
+
+fn invalid(msg: impl Into<String>) -> BatonError {
+    BatonError::CygwinParse(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    const FILE: &[u8] = b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809\0";
+
+    fn guid() -> [u8; GUID_SIZE] {
+        CygwinSocketInfo::parse(FILE).unwrap().guid
+    }
+
+    /// Server side of the handshake, answering with `echo` instead of the
+    /// GUID it received. Returns the client's credentials.
+    fn fake_server(
+        listener: TcpListener,
+        echo: Option<[u8; GUID_SIZE]>,
+    ) -> thread::JoinHandle<(TcpStream, [u8; GUID_SIZE], PeerCredentials)> {
+        thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut received = [0u8; GUID_SIZE];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(&echo.unwrap_or(received)).unwrap();
+            let mut credentials = [0u8; 12];
+            // The client hangs up here if the GUID did not match.
+            let _ = stream.read_exact(&mut credentials);
+            let server = PeerCredentials {
+                pid: 99,
+                uid: 197609,
+                gid: 197121,
+            };
+            let _ = stream.write_all(&server.to_bytes());
+            (stream, received, PeerCredentials::from_bytes(credentials))
+        })
+    }
+
+    #[test]
+    fn test_parse_socket_file() {
+        let info = CygwinSocketInfo::parse(FILE).unwrap();
+        assert_eq!(info.port, 4711);
+        assert_eq!(
+            info.guid,
+            [
+                0x4D, 0x3C, 0x2B, 0x1A, 0x81, 0x70, 0x6F, 0x5E, 0xC5, 0xB4, 0xA3, 0x92, 0x09, 0xF8,
+                0xE7, 0xD6
+            ]
+        );
+        assert_eq!(info.socket_addr().to_string(), "127.0.0.1:4711");
+        // Without the trailing NUL, and with a lower-case GUID, too.
+        let lower =
+            CygwinSocketInfo::parse(b"!<socket >4711 s 1a2b3c4d-5e6f7081-92a3b4c5-d6e7f809");
+        assert_eq!(lower.unwrap(), info);
+    }
+
+    #[test]
+    fn test_parse_socket_file_malformed() {
+        for (contents, expected) in [
+            (&b"4711\n0123456789abcdef"[..], "no '!<socket >'"),
+            (b"!<socket >4711 s", "expected '!<socket >PORT s GUID'"),
+            (b"!<socket >4711 s A-B-C-D extra", "expected"),
+            (
+                b"!<socket >port s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809",
+                "invalid port",
+            ),
+            (
+                b"!<socket >4711 d 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809",
+                "datagram",
+            ),
+            (
+                b"!<socket >4711 x 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809",
+                "socket type 'x'",
+            ),
+            (
+                b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5",
+                "invalid GUID",
+            ),
+            (
+                b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F8",
+                "invalid GUID",
+            ),
+            (
+                b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F8ZZ",
+                "invalid GUID",
+            ),
+            (
+                b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809-00",
+                "invalid GUID",
+            ),
+        ] {
+            let err = CygwinSocketInfo::parse(contents).unwrap_err();
+            assert!(matches!(err, BatonError::CygwinParse(_)), "{:?}", err);
+            assert!(err.to_string().contains(expected), "{}", err);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors_redact_guid() {
+        for contents in [
+            &b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809 extra"[..],
+            b"!<socket >4711  1A2B3C4D-5E6F7081-92A3B4C5-D6E7F809",
+            b"!<socket >4711 s 1A2B3C4D-5E6F7081-92A3B4C5-D6E7F8ZZ",
+        ] {
+            let err = CygwinSocketInfo::parse(contents).unwrap_err().to_string();
+            assert!(!err.contains("1A2B3C4D"), "{}", err);
+            assert!(!err.contains("D6E7F8"), "{}", err);
+        }
+        let err = CygwinSocketInfo::parse(b"!<socket >4711 s A-B-C-D extra").unwrap_err();
+        assert!(err.to_string().contains("found 4 fields"), "{}", err);
+    }
+
+    #[test]
+    fn test_debug_redacts_guid() {
+        let debug = format!("{:?}", CygwinSocketInfo::parse(FILE).unwrap());
+        assert!(debug.contains("4711"), "{}", debug);
+        assert!(debug.contains("<redacted>"), "{}", debug);
+    }
+
+    #[test]
+    fn test_connect_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let info = CygwinSocketInfo {
+            port: listener.local_addr().unwrap().port(),
+            guid: guid(),
+        };
+        let server = fake_server(listener, None);
+
+        let mut stream = info.connect(&RetryPolicy::default()).unwrap();
+        let (mut accepted, received, client) = server.join().unwrap();
+
+        assert_eq!(received, guid());
+        assert_eq!(client, PeerCredentials::current());
+        // Data flows once the handshake is done.
+        stream.write_all(b"ping").unwrap();
+        let mut data = [0u8; 4];
+        accepted.read_exact(&mut data).unwrap();
+        assert_eq!(&data, b"ping");
+    }
+
+    #[test]
+    fn test_handshake_returns_server_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = fake_server(listener, None);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let client = PeerCredentials {
+            pid: 1234,
+            uid: 1000,
+            gid: 1000,
+        };
+        let peer = handshake(&mut stream, &guid(), client).unwrap();
+
+        assert_eq!(peer.pid, 99);
+        assert_eq!(peer.uid, 197609);
+        assert_eq!(server.join().unwrap().2, client);
+    }
+
+    #[test]
+    fn test_handshake_rejects_wrong_guid() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let info = CygwinSocketInfo {
+            port: listener.local_addr().unwrap().port(),
+            guid: guid(),
+        };
+        let server = fake_server(listener, Some([0u8; GUID_SIZE]));
+
+        let err = info.connect(&RetryPolicy::default()).unwrap_err();
+        assert!(matches!(err, BatonError::CygwinConnection(_)), "{:?}", err);
+        assert!(err.to_string().contains("different GUID"), "{}", err);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_times_out_on_silent_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let info = CygwinSocketInfo {
+            port: listener.local_addr().unwrap().port(),
+            guid: guid(),
+        };
+        // Accept and read the GUID, but never echo it.
+        let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut received = [0u8; GUID_SIZE];
+            stream.read_exact(&mut received).unwrap();
+            let _ = done_rx.recv();
+        });
+        let policy = RetryPolicy {
+            connect_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let err = info.connect(&policy).unwrap_err();
+
+        assert!(matches!(err, BatonError::CygwinConnection(_)), "{:?}", err);
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        drop(done_tx);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_retries_failed_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let info = CygwinSocketInfo {
+            port: listener.local_addr().unwrap().port(),
+            guid: guid(),
+        };
+        // The first connection is dropped before the echo; the second one
+        // completes the handshake.
+        let server = thread::spawn(move || {
+            drop(listener.accept().unwrap());
+            fake_server(listener, None).join().unwrap()
+        });
+        let policy = RetryPolicy {
+            max_attempts: Some(2),
+            interval: Duration::from_millis(10),
+            ..RetryPolicy::polling(true)
+        };
+
+        info.connect(&policy).unwrap();
+        assert_eq!(server.join().unwrap().1, guid());
+    }
+}
@@ -0,0 +1,314 @@
+//! GnuPG's Assuan protocol: newline-terminated lines of at most 1000 bytes.
+//!
+//! Requests are `COMMAND args`; responses are `OK`, `ERR`, `S` (status),
+//! `INQUIRE` and `#` comments. Either side sends `D` lines, whose data is
+//! percent-escaped on the wire and shown unescaped here.
+//!
+//! Passphrases are redacted: the `D` lines and `OK` argument answering
+//! `GET_PASSPHRASE` or `GETPIN`, the `D` lines answering `INQUIRE
+//! PASSPHRASE` or `INQUIRE NEW_PASSPHRASE`, and `PRESET_PASSPHRASE`'s
+//! arguments.
+
+use super::{Message, ProtocolDecoder, Redactor, Streams, REDACTED};
+use crate::relay::Direction;
+
+/// Longest line the protocol allows, without its newline.
+const MAX_LINE: usize = 1000;
+
+pub struct AssuanDecoder {
+    streams: Streams,
+}
+
+impl AssuanDecoder {
+    pub fn new() -> Self {
+        Self {
+            streams: Streams::default(),
+        }
+    }
+}
+
+impl Default for AssuanDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolDecoder for AssuanDecoder {
+    fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<Message> {
+        let buf = self.streams.get(direction);
+        buf.extend_from_slice(data);
+
+        let mut messages = Vec::new();
+        while let Some(end) = buf.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = buf.drain(..=end).collect();
+            let line = line.strip_suffix(b"\n").unwrap_or(&line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            messages.push(Message::new(direction, render(line)));
+        }
+        if buf.len() > MAX_LINE {
+            messages.push(Message::new(
+                direction,
+                format!(
+                    "line longer than {} bytes: {}",
+                    MAX_LINE,
+                    super::preview(buf)
+                ),
+            ));
+            buf.clear();
+        }
+        messages
+    }
+
+    fn finish(&mut self, direction: Direction) -> Vec<Message> {
+        let buf = self.streams.get(direction);
+        if buf.is_empty() {
+            return Vec::new();
+        }
+        let note = format!("incomplete line: {}", super::preview(buf));
+        buf.clear();
+        vec![Message::new(direction, note)]
+    }
+}
+
+/// Longest line start kept to recognize a command or response.
+const MAX_PREFIX: usize = 64;
+
+pub struct AssuanRedactor {
+    client: LineState,
+    server: LineState,
+    /// The client is answering a passphrase inquiry.
+    client_secret: bool,
+    /// The server is answering a passphrase request.
+    server_secret: bool,
+}
+
+#[derive(Default)]
+struct LineState {
+    prefix: Vec<u8>,
+    redacting: bool,
+}
+
+impl AssuanRedactor {
+    pub fn new() -> Self {
+        Self {
+            client: LineState::default(),
+            server: LineState::default(),
+            client_secret: false,
+            server_secret: false,
+        }
+    }
+
+    /// Track passphrase exchanges across a finished line.
+    fn end_line(&mut self, direction: Direction, line: &[u8]) {
+        let mut words = line
+            .split(|&b| b == b' ' || b == b'\r')
+            .filter(|w| !w.is_empty());
+        let first = words.next().unwrap_or_default();
+        let is = |word: &[u8], name: &str| word.eq_ignore_ascii_case(name.as_bytes());
+        match direction {
+            Direction::StdinToPipe => {
+                if is(first, "GET_PASSPHRASE") || is(first, "GETPIN") {
+                    self.server_secret = true;
+                } else if is(first, "END") || is(first, "CAN") {
+                    self.client_secret = false;
+                }
+            }
+            Direction::PipeToStdout => {
+                if is(first, "INQUIRE") {
+                    let keyword = words.next().unwrap_or_default();
+                    self.client_secret = is(keyword, "PASSPHRASE") || is(keyword, "NEW_PASSPHRASE");
+                } else if is(first, "OK") || is(first, "ERR") {
+                    self.server_secret = false;
+                }
+            }
+        }
+    }
+
+    /// The line being read in `direction`, and whether that direction is
+    /// sending a passphrase.
+    fn line(&mut self, direction: Direction) -> (&mut LineState, bool) {
+        match direction {
+            Direction::StdinToPipe => (&mut self.client, self.client_secret),
+            Direction::PipeToStdout => (&mut self.server, self.server_secret),
+        }
+    }
+}
+
+impl Default for AssuanRedactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Redactor for AssuanRedactor {
+    fn redact(&mut self, direction: Direction, data: &mut [u8]) {
+        for byte in data {
+            let (line, sending_secret) = self.line(direction);
+            if *byte == b'\n' {
+                let line = std::mem::take(line);
+                self.end_line(direction, &line.prefix);
+            } else if line.redacting {
+                if !byte.is_ascii_whitespace() {
+                    *byte = REDACTED;
+                }
+            } else if line.prefix.len() < MAX_PREFIX {
+                line.prefix.push(*byte);
+                line.redacting = secret_from(direction, sending_secret, &line.prefix);
+            }
+        }
+    }
+}
+
+/// Whether the rest of a line starting with `prefix` is secret.
+fn secret_from(direction: Direction, sending_secret: bool, prefix: &[u8]) -> bool {
+    match direction {
+        Direction::StdinToPipe => {
+            (sending_secret && prefix == b"D ")
+                || prefix.eq_ignore_ascii_case(b"PRESET_PASSPHRASE ")
+        }
+        Direction::PipeToStdout => sending_secret && (prefix == b"D " || prefix == b"OK "),
+    }
+}
+
+fn render(line: &[u8]) -> String {
+    match line.strip_prefix(b"D ") {
+        Some(data) => format!("D {}", unescape(data).escape_ascii()),
+        None => line.escape_ascii().to_string(),
+    }
+}
+
+/// Undo Assuan's `%XX` escaping. A `%` not followed by two hex digits is
+/// kept as it is.
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let escaped = (data[i] == b'%')
+            .then(|| data.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(data[i]);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::tests::{decode_split, redact_split};
+    use crate::decode::Protocol;
+
+    fn summaries(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.summary.as_str()).collect()
+    }
+
+    #[test]
+    fn test_unescape() {
+        assert_eq!(unescape(b"a%0Ab%25c"), b"a\nb%c");
+        assert_eq!(unescape(b"100%"), b"100%");
+        assert_eq!(unescape(b"%zz%4"), b"%zz%4");
+        assert_eq!(unescape(b"%00%ff"), [0, 0xff]);
+    }
+
+    #[test]
+    fn test_session() {
+        let messages = decode_split(
+            Protocol::Assuan,
+            &[
+                (Direction::PipeToStdout, b"OK Pleased to meet you\n"),
+                (Direction::StdinToPipe, b"GETINFO version\r\n"),
+                (
+                    Direction::PipeToStdout,
+                    b"D 2.4.0%0Aextra%25\nS PROGRESS need_entropy\nOK\n",
+                ),
+                (Direction::StdinToPipe, b"BYE\n"),
+            ],
+        );
+
+        assert_eq!(
+            summaries(&messages),
+            [
+                "OK Pleased to meet you",
+                "GETINFO version",
+                "D 2.4.0\\nextra%",
+                "S PROGRESS need_entropy",
+                "OK",
+                "BYE",
+            ]
+        );
+        assert_eq!(messages[2].direction, Direction::PipeToStdout);
+    }
+
+    #[test]
+    fn test_incomplete_and_overlong_lines() {
+        let mut decoder = AssuanDecoder::new();
+        assert!(decoder.feed(Direction::StdinToPipe, b"GETI").is_empty());
+        assert_eq!(
+            summaries(&decoder.finish(Direction::StdinToPipe)),
+            ["incomplete line: \"GETI\""]
+        );
+
+        let messages = decoder.feed(Direction::PipeToStdout, &[b'x'; MAX_LINE + 1]);
+        assert!(messages[0]
+            .summary
+            .starts_with("line longer than 1000 bytes"));
+        assert_eq!(
+            summaries(&decoder.feed(Direction::PipeToStdout, b"OK\n")),
+            ["OK"]
+        );
+    }
+
+    #[test]
+    fn test_redact_passphrases() {
+        let chunks = redact_split(
+            Protocol::Assuan,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"GET_PASSPHRASE --data X%20 Err Prompt Desc\n",
+                ),
+                (Direction::PipeToStdout, b"S PROGRESS x\nD hunter2%0A\nOK\n"),
+                (
+                    Direction::StdinToPipe,
+                    b"GET_PASSPHRASE X Err Prompt Desc\n",
+                ),
+                (Direction::PipeToStdout, b"OK 68756E74657232\n"),
+                (Direction::StdinToPipe, b"GETINFO version\nPKDECRYPT\n"),
+                (
+                    Direction::PipeToStdout,
+                    b"D 2.4.0\nOK\nINQUIRE PASSPHRASE\n",
+                ),
+                (Direction::StdinToPipe, b"D swordfish\nEND\nD public\n"),
+                (
+                    Direction::StdinToPipe,
+                    b"PRESET_PASSPHRASE ABCD -1 73656372\n",
+                ),
+            ],
+        );
+
+        let chunks: Vec<_> = chunks.iter().map(|c| String::from_utf8_lossy(c)).collect();
+        assert_eq!(
+            chunks,
+            [
+                "GET_PASSPHRASE --data X%20 Err Prompt Desc\n",
+                "S PROGRESS x\nD **********\nOK\n",
+                "GET_PASSPHRASE X Err Prompt Desc\n",
+                "OK **************\n",
+                "GETINFO version\nPKDECRYPT\n",
+                "D 2.4.0\nOK\nINQUIRE PASSPHRASE\n",
+                "D *********\nEND\nD public\n",
+                "PRESET_PASSPHRASE **** ** ********\n",
+            ]
+        );
+    }
+}
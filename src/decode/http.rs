@@ -0,0 +1,754 @@
+//! HTTP/1.1 as spoken by the Docker CLI and daemon.
+//!
+//! Request and response heads are shown line by line. Bodies are delimited
+//! as HTTP does (`Content-Length`, chunked, or until the connection closes)
+//! and summarized rather than shown. Docker's attach and logs endpoints
+//! (`application/vnd.docker.multiplexed-stream` or `raw-stream`, possibly
+//! after `101 UPGRADED`) send frames of `stream (u8) | 0 0 0 | length (u32
+//! BE) | payload`, which are shown one per frame.
+//!
+//! Credential headers (`Authorization`, `Proxy-Authorization`,
+//! `X-Registry-Auth`, `X-Registry-Config`) are redacted. The redactor
+//! looks at every line rather than tracking bodies, so a body line that
+//! happens to look like one of these headers is redacted too.
+
+use super::{preview, Message, ProtocolDecoder, Redactor, PREVIEW_LEN, REDACTED};
+use crate::relay::Direction;
+use std::collections::VecDeque;
+
+/// Longest head accepted before giving up on the stream.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// Longest chunk-size or trailer line accepted.
+const MAX_CHUNK_LINE: usize = 1024;
+
+const DOCKER_FRAME_HEADER: usize = 8;
+
+/// Headers whose values are redacted. `X-Registry-Auth` and
+/// `X-Registry-Config` carry Docker registry credentials.
+const SECRET_HEADERS: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "x-registry-auth",
+    "x-registry-config",
+];
+
+/// Longest header name looked at for redaction.
+const MAX_HEADER_NAME: usize = 64;
+
+pub struct HttpDecoder {
+    client: Half,
+    server: Half,
+    /// Methods of requests still waiting for a final response, oldest first.
+    pending: VecDeque<String>,
+}
+
+impl HttpDecoder {
+    pub fn new() -> Self {
+        Self {
+            client: Half::new(Direction::StdinToPipe),
+            server: Half::new(Direction::PipeToStdout),
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn half(&mut self, direction: Direction) -> (&mut Half, &mut VecDeque<String>) {
+        match direction {
+            Direction::StdinToPipe => (&mut self.client, &mut self.pending),
+            Direction::PipeToStdout => (&mut self.server, &mut self.pending),
+        }
+    }
+}
+
+impl Default for HttpDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolDecoder for HttpDecoder {
+    fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<Message> {
+        let (half, pending) = self.half(direction);
+        half.buf.extend_from_slice(data);
+        let mut messages = Vec::new();
+        half.process(pending, &mut messages);
+        messages
+    }
+
+    fn finish(&mut self, direction: Direction) -> Vec<Message> {
+        let (half, _) = self.half(direction);
+        let note = match &half.state {
+            State::Head if !half.buf.is_empty() => {
+                Some(format!("incomplete head: {}", preview(&half.buf)))
+            }
+            State::Body(body) => body.unfinished(),
+            _ => None,
+        };
+        half.buf.clear();
+        half.state = State::Head;
+        note.map(|note| Message::new(direction, note))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// One direction of the connection.
+struct Half {
+    direction: Direction,
+    buf: Vec<u8>,
+    state: State,
+}
+
+enum State {
+    Head,
+    Body(Body),
+    /// Not HTTP, or out of step; nothing more is decoded.
+    Lost,
+}
+
+impl Half {
+    fn new(direction: Direction) -> Self {
+        Self {
+            direction,
+            buf: Vec::new(),
+            state: State::Head,
+        }
+    }
+
+    /// Decode as much of `buf` as is complete.
+    fn process(&mut self, pending: &mut VecDeque<String>, out: &mut Vec<Message>) {
+        loop {
+            match &mut self.state {
+                State::Lost => {
+                    self.buf.clear();
+                    return;
+                }
+                State::Head => {
+                    let Some(end) = find(&self.buf, b"\r\n\r\n") else {
+                        if self.buf.len() > MAX_HEAD {
+                            self.lose(format!("no end of head in {} bytes", MAX_HEAD), out);
+                        }
+                        return;
+                    };
+                    let head: Vec<u8> = self.buf.drain(..end + 4).collect();
+                    match self.parse_head(&head[..end], pending) {
+                        Ok((message, state)) => {
+                            out.push(message);
+                            self.state = state;
+                        }
+                        Err(e) => self.lose(e, out),
+                    }
+                }
+                State::Body(body) => match body.step(&mut self.buf, self.direction, out) {
+                    Ok(true) => self.state = State::Head,
+                    Ok(false) => return,
+                    Err(e) => self.lose(e, out),
+                },
+            }
+        }
+    }
+
+    fn lose(&mut self, reason: String, out: &mut Vec<Message>) {
+        out.push(Message::new(
+            self.direction,
+            format!("{}; not decoding this direction", reason),
+        ));
+        self.state = State::Lost;
+    }
+
+    /// The message for a head and the state that follows it.
+    fn parse_head(
+        &self,
+        head: &[u8],
+        pending: &mut VecDeque<String>,
+    ) -> Result<(Message, State), String> {
+        let text = String::from_utf8_lossy(head);
+        let mut lines = text.split("\r\n");
+        let start = lines.next().unwrap_or_default();
+        let header_lines: Vec<String> = lines.map(str::to_string).collect();
+        let headers = Headers::parse(&header_lines);
+        let mut words = start.splitn(3, ' ');
+        let (first, second, third) = (words.next(), words.next(), words.next());
+
+        let framing = match self.direction {
+            Direction::StdinToPipe => {
+                let (Some(method), Some(_), Some(version)) = (first, second, third) else {
+                    return Err(format!("not an HTTP request: {}", preview(head)));
+                };
+                if !version.starts_with("HTTP/") {
+                    return Err(format!("not an HTTP request: {}", preview(head)));
+                }
+                pending.push_back(method.to_string());
+                if headers.get("upgrade").is_some() {
+                    // Docker attach: after the head the client writes the
+                    // container's stdin as it is.
+                    Some(Framing::UntilClose)
+                } else {
+                    headers.body_framing()?
+                }
+            }
+            Direction::PipeToStdout => {
+                let status = first
+                    .filter(|version| version.starts_with("HTTP/"))
+                    .and(second)
+                    .and_then(|code| code.parse::<u16>().ok())
+                    .ok_or_else(|| format!("not an HTTP response: {}", preview(head)))?;
+                let method = if (100..200).contains(&status) && status != 101 {
+                    // Interim response; the final one is still to come.
+                    pending.front().cloned()
+                } else {
+                    pending.pop_front()
+                };
+                if status == 101 {
+                    Some(Framing::UntilClose)
+                } else if (100..200).contains(&status)
+                    || status == 204
+                    || status == 304
+                    || method.as_deref() == Some("HEAD")
+                {
+                    None
+                } else {
+                    Some(headers.body_framing()?.unwrap_or(Framing::UntilClose))
+                }
+            }
+        };
+
+        let state = match framing {
+            Some(Framing::Length(0)) | None => State::Head,
+            Some(framing) => State::Body(Body::new(framing, headers.is_docker_stream())),
+        };
+        let message = Message::new(self.direction, start.escape_default().to_string())
+            .with_details(header_lines);
+        Ok((message, state))
+    }
+}
+
+struct Headers<'a>(Vec<(String, &'a str)>);
+
+impl<'a> Headers<'a> {
+    fn parse(lines: &'a [String]) -> Self {
+        Self(
+            lines
+                .iter()
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim()))
+                .collect(),
+        )
+    }
+
+    fn get(&self, name: &str) -> Option<&'a str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| *v)
+    }
+
+    /// Framing given by the headers, or `None` if they give none.
+    fn body_framing(&self) -> Result<Option<Framing>, String> {
+        let chunked = self
+            .get("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"));
+        if chunked {
+            return Ok(Some(Framing::Chunked(Chunk::Size)));
+        }
+        match self.get("content-length") {
+            Some(len) => len
+                .parse()
+                .map(|len| Some(Framing::Length(len)))
+                .map_err(|_| format!("invalid Content-Length '{}'", len)),
+            None => Ok(None),
+        }
+    }
+
+    fn is_docker_stream(&self) -> bool {
+        self.get("content-type").is_some_and(|v| {
+            v.starts_with("application/vnd.docker.multiplexed-stream")
+                || v.starts_with("application/vnd.docker.raw-stream")
+        })
+    }
+}
+
+enum Framing {
+    /// This many bytes are still to come.
+    Length(u64),
+    Chunked(Chunk),
+    /// Until the connection closes: close-delimited and upgraded streams.
+    UntilClose,
+}
+
+/// Position within a chunked body.
+enum Chunk {
+    Size,
+    Data(u64),
+    DataEnd,
+    Trailer,
+}
+
+struct Body {
+    framing: Framing,
+    /// Buffered frame data while the body still looks like a Docker stream.
+    docker: Option<Vec<u8>>,
+    bytes: u64,
+    preview: Vec<u8>,
+}
+
+impl Body {
+    fn new(framing: Framing, docker: bool) -> Self {
+        Self {
+            framing,
+            docker: docker.then(Vec::new),
+            bytes: 0,
+            preview: Vec::new(),
+        }
+    }
+
+    /// Consume what is available of the body. `Ok(true)` once it is complete.
+    fn step(
+        &mut self,
+        buf: &mut Vec<u8>,
+        direction: Direction,
+        out: &mut Vec<Message>,
+    ) -> Result<bool, String> {
+        loop {
+            match &mut self.framing {
+                Framing::Length(remaining) => {
+                    let take = (*remaining).min(buf.len() as u64);
+                    *remaining -= take;
+                    let done = *remaining == 0;
+                    let data: Vec<u8> = buf.drain(..take as usize).collect();
+                    self.content(&data, direction, out);
+                    if done {
+                        out.push(self.summary(direction));
+                    }
+                    return Ok(done);
+                }
+                Framing::UntilClose => {
+                    if buf.is_empty() {
+                        return Ok(false);
+                    }
+                    let data: Vec<u8> = std::mem::take(buf);
+                    if self.docker.is_some() {
+                        self.content(&data, direction, out);
+                    } else {
+                        self.bytes += data.len() as u64;
+                        out.push(
+                            Message::new(direction, format!("data, {} bytes", data.len()))
+                                .with_details(vec![preview(&data)]),
+                        );
+                    }
+                    return Ok(false);
+                }
+                Framing::Chunked(Chunk::Size) => {
+                    let Some(line) = take_line(buf)? else {
+                        return Ok(false);
+                    };
+                    let size = line.split(';').next().unwrap_or_default().trim();
+                    let size = u64::from_str_radix(size, 16)
+                        .map_err(|_| format!("invalid chunk size '{}'", line.escape_default()))?;
+                    self.framing = Framing::Chunked(match size {
+                        0 => Chunk::Trailer,
+                        size => Chunk::Data(size),
+                    });
+                }
+                Framing::Chunked(Chunk::Data(remaining)) => {
+                    let take = (*remaining).min(buf.len() as u64);
+                    *remaining -= take;
+                    let done = *remaining == 0;
+                    let data: Vec<u8> = buf.drain(..take as usize).collect();
+                    self.content(&data, direction, out);
+                    if !done {
+                        return Ok(false);
+                    }
+                    self.framing = Framing::Chunked(Chunk::DataEnd);
+                }
+                Framing::Chunked(Chunk::DataEnd) => {
+                    if buf.len() < 2 {
+                        return Ok(false);
+                    }
+                    if buf.drain(..2).as_slice() != b"\r\n" {
+                        return Err("chunk not followed by CRLF".to_string());
+                    }
+                    self.framing = Framing::Chunked(Chunk::Size);
+                }
+                Framing::Chunked(Chunk::Trailer) => {
+                    let Some(line) = take_line(buf)? else {
+                        return Ok(false);
+                    };
+                    if line.is_empty() {
+                        out.push(self.summary(direction));
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Account for body data and report any Docker frames it completes.
+    fn content(&mut self, data: &[u8], direction: Direction, out: &mut Vec<Message>) {
+        self.bytes += data.len() as u64;
+        let room = PREVIEW_LEN + 1 - self.preview.len().min(PREVIEW_LEN + 1);
+        self.preview
+            .extend_from_slice(&data[..data.len().min(room)]);
+
+        let Some(frames) = self.docker.as_mut() else {
+            return;
+        };
+        frames.extend_from_slice(data);
+        while frames.len() >= DOCKER_FRAME_HEADER {
+            let stream = match frames[..4] {
+                [0, 0, 0, 0] => "stdin",
+                [1, 0, 0, 0] => "stdout",
+                [2, 0, 0, 0] => "stderr",
+                [3, 0, 0, 0] => "systemerr",
+                _ => {
+                    // A TTY stream is not framed; show it as plain data.
+                    out.push(
+                        Message::new(direction, format!("data, {} bytes", frames.len()))
+                            .with_details(vec![preview(frames)]),
+                    );
+                    self.docker = None;
+                    return;
+                }
+            };
+            let len = u32::from_be_bytes([frames[4], frames[5], frames[6], frames[7]]) as usize;
+            if frames.len() < DOCKER_FRAME_HEADER + len {
+                return;
+            }
+            let frame: Vec<u8> = frames.drain(..DOCKER_FRAME_HEADER + len).collect();
+            out.push(
+                Message::new(direction, format!("{} frame, {} bytes", stream, len))
+                    .with_details(vec![preview(&frame[DOCKER_FRAME_HEADER..])]),
+            );
+        }
+    }
+
+    fn summary(&self, direction: Direction) -> Message {
+        let message = Message::new(direction, format!("body, {} bytes", self.bytes));
+        if self.docker.is_some() || self.bytes == 0 {
+            message
+        } else {
+            message.with_details(vec![preview(&self.preview)])
+        }
+    }
+
+    /// What was still missing when the direction ended, if anything.
+    fn unfinished(&self) -> Option<String> {
+        match &self.framing {
+            Framing::Length(remaining) => Some(format!(
+                "body ended after {} bytes, {} missing",
+                self.bytes, remaining
+            )),
+            Framing::Chunked(_) => Some(format!(
+                "chunked body ended after {} bytes, before its last chunk",
+                self.bytes
+            )),
+            Framing::UntilClose => match &self.docker {
+                Some(frames) if !frames.is_empty() => {
+                    Some(format!("incomplete frame ({} bytes)", frames.len()))
+                }
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Take one CRLF-terminated line off the front of `buf`.
+fn take_line(buf: &mut Vec<u8>) -> Result<Option<String>, String> {
+    match find(buf, b"\r\n") {
+        Some(end) => {
+            let line: Vec<u8> = buf.drain(..end + 2).collect();
+            Ok(Some(String::from_utf8_lossy(&line[..end]).into_owned()))
+        }
+        None if buf.len() > MAX_CHUNK_LINE => Err("chunk line too long".to_string()),
+        None => Ok(None),
+    }
+}
+
+pub struct HttpRedactor {
+    client: HeaderLine,
+    server: HeaderLine,
+}
+
+/// The start of the current line, up to the end of a possible header name.
+#[derive(Default)]
+struct HeaderLine {
+    name: Vec<u8>,
+    /// A `:` ended the name; the rest of the line is its value.
+    in_value: bool,
+    redacting: bool,
+}
+
+impl HttpRedactor {
+    pub fn new() -> Self {
+        Self {
+            client: HeaderLine::default(),
+            server: HeaderLine::default(),
+        }
+    }
+}
+
+impl Default for HttpRedactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Redactor for HttpRedactor {
+    fn redact(&mut self, direction: Direction, data: &mut [u8]) {
+        let line = match direction {
+            Direction::StdinToPipe => &mut self.client,
+            Direction::PipeToStdout => &mut self.server,
+        };
+        for byte in data {
+            if *byte == b'\n' {
+                *line = HeaderLine::default();
+            } else if line.redacting {
+                if !byte.is_ascii_whitespace() {
+                    *byte = REDACTED;
+                }
+            } else if !line.in_value {
+                if *byte == b':' {
+                    let name = String::from_utf8_lossy(&line.name)
+                        .trim()
+                        .to_ascii_lowercase();
+                    line.in_value = true;
+                    line.redacting = SECRET_HEADERS.contains(&name.as_str());
+                } else if line.name.len() < MAX_HEADER_NAME {
+                    line.name.push(*byte);
+                } else {
+                    line.in_value = true;
+                }
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::tests::{decode_split, redact_split};
+    use crate::decode::Protocol;
+
+    fn summaries(messages: &[Message]) -> Vec<&str> {
+        messages.iter().map(|m| m.summary.as_str()).collect()
+    }
+
+    fn docker_frame(stream: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![stream, 0, 0, 0];
+        frame.extend((payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn test_request_and_response_with_length() {
+        let messages = decode_split(
+            Protocol::Http,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"GET /_ping HTTP/1.1\r\nHost: docker\r\n\r\n",
+                ),
+                (
+                    Direction::PipeToStdout,
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nApi-Version: 1.43\r\n\r\nOK",
+                ),
+            ],
+        );
+
+        assert_eq!(
+            summaries(&messages),
+            ["GET /_ping HTTP/1.1", "HTTP/1.1 200 OK", "body, 2 bytes"]
+        );
+        assert_eq!(messages[0].details, ["Host: docker"]);
+        assert_eq!(
+            messages[1].details,
+            ["Content-Length: 2", "Api-Version: 1.43"]
+        );
+        assert_eq!(messages[2].details, ["\"OK\""]);
+    }
+
+    #[test]
+    fn test_pipelined_requests_and_chunked_response() {
+        let messages = decode_split(
+            Protocol::Http,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"POST /images/create HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc\
+                      HEAD /_ping HTTP/1.1\r\n\r\n",
+                ),
+                (
+                    Direction::PipeToStdout,
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      5\r\n{\"a\":\r\n3;ext=1\r\n1}\n\r\n0\r\nX-Trailer: 1\r\n\r\n\
+                      HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n",
+                ),
+            ],
+        );
+
+        assert_eq!(
+            summaries(&messages),
+            [
+                "POST /images/create HTTP/1.1",
+                "body, 3 bytes",
+                "HEAD /_ping HTTP/1.1",
+                "HTTP/1.1 200 OK",
+                "body, 8 bytes",
+                // The reply to HEAD has no body despite its Content-Length.
+                "HTTP/1.1 200 OK",
+            ]
+        );
+        assert_eq!(messages[4].details, ["\"{\\\"a\\\":1}\\n\""]);
+    }
+
+    #[test]
+    fn test_docker_attach_stream() {
+        let mut frames = docker_frame(1, b"hello\n");
+        frames.extend(docker_frame(2, b"oops\n"));
+        let mut response = b"HTTP/1.1 101 UPGRADED\r\n\
+            Content-Type: application/vnd.docker.multiplexed-stream\r\n\
+            Connection: Upgrade\r\nUpgrade: tcp\r\n\r\n"
+            .to_vec();
+        response.extend(&frames);
+
+        let messages = decode_split(
+            Protocol::Http,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"POST /containers/abc/attach?stream=1&stdout=1 HTTP/1.1\r\n\
+                      Connection: Upgrade\r\nUpgrade: tcp\r\n\r\n",
+                ),
+                (Direction::PipeToStdout, &response),
+            ],
+        );
+
+        assert_eq!(
+            summaries(&messages),
+            [
+                "POST /containers/abc/attach?stream=1&stdout=1 HTTP/1.1",
+                "HTTP/1.1 101 UPGRADED",
+                "stdout frame, 6 bytes",
+                "stderr frame, 5 bytes",
+            ]
+        );
+        assert_eq!(messages[2].details, ["\"hello\\n\""]);
+    }
+
+    #[test]
+    fn test_docker_logs_in_chunked_body() {
+        let frame = docker_frame(1, b"log line\n");
+        let mut response = b"HTTP/1.1 200 OK\r\n\
+            Content-Type: application/vnd.docker.multiplexed-stream\r\n\
+            Transfer-Encoding: chunked\r\n\r\n"
+            .to_vec();
+        // Split the frame across two chunks.
+        response.extend(format!("{:x}\r\n", 5).as_bytes());
+        response.extend(&frame[..5]);
+        response.extend(format!("\r\n{:x}\r\n", frame.len() - 5).as_bytes());
+        response.extend(&frame[5..]);
+        response.extend(b"\r\n0\r\n\r\n");
+
+        let messages = decode_split(
+            Protocol::Http,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"GET /containers/abc/logs?stdout=1 HTTP/1.1\r\n\r\n",
+                ),
+                (Direction::PipeToStdout, &response),
+            ],
+        );
+
+        assert_eq!(
+            summaries(&messages)[1..],
+            ["HTTP/1.1 200 OK", "stdout frame, 9 bytes", "body, 17 bytes"]
+        );
+    }
+
+    #[test]
+    fn test_tty_raw_stream_is_not_framed() {
+        let mut decoder = HttpDecoder::new();
+        decoder.feed(
+            Direction::StdinToPipe,
+            b"POST /containers/abc/attach HTTP/1.1\r\nUpgrade: tcp\r\n\r\n",
+        );
+        let messages = decoder.feed(
+            Direction::PipeToStdout,
+            b"HTTP/1.1 101 UPGRADED\r\n\
+              Content-Type: application/vnd.docker.raw-stream\r\n\r\n\
+              root@abc:/# ",
+        );
+        assert_eq!(
+            summaries(&messages),
+            ["HTTP/1.1 101 UPGRADED", "data, 12 bytes"]
+        );
+
+        // Client keystrokes after the upgrade are passed through as data.
+        let messages = decoder.feed(Direction::StdinToPipe, b"ls\r");
+        assert_eq!(summaries(&messages), ["data, 3 bytes"]);
+        assert!(decoder.finish(Direction::PipeToStdout).is_empty());
+    }
+
+    #[test]
+    fn test_close_delimited_body_and_early_end() {
+        let mut decoder = HttpDecoder::new();
+        decoder.feed(Direction::StdinToPipe, b"GET /events HTTP/1.0\r\n\r\n");
+        let messages = decoder.feed(Direction::PipeToStdout, b"HTTP/1.0 200 OK\r\n\r\n{}");
+        assert_eq!(summaries(&messages), ["HTTP/1.0 200 OK", "data, 2 bytes"]);
+
+        decoder.feed(
+            Direction::StdinToPipe,
+            b"PUT /x HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc",
+        );
+        let messages = decoder.finish(Direction::StdinToPipe);
+        assert_eq!(
+            summaries(&messages),
+            ["body ended after 3 bytes, 7 missing"]
+        );
+    }
+
+    #[test]
+    fn test_not_http() {
+        let mut decoder = HttpDecoder::new();
+        let messages = decoder.feed(Direction::StdinToPipe, b"\x00\x00\x00\x01\x0b\r\n\r\n");
+        assert!(messages[0].summary.starts_with("not an HTTP request"));
+        assert!(decoder
+            .feed(Direction::StdinToPipe, b"GET / HTTP/1.1\r\n\r\n")
+            .is_empty());
+    }
+
+    #[test]
+    fn test_redact_credential_headers() {
+        let chunks = redact_split(
+            Protocol::Http,
+            &[
+                (
+                    Direction::StdinToPipe,
+                    b"POST /images/create?fromImage=app HTTP/1.1\r\n\
+                      Host: docker\r\n\
+                      X-Registry-Auth: eyJ1c2VyIjoiYSJ9\r\n\
+                      authorization : Bearer abc\r\n\r\n",
+                ),
+                (
+                    Direction::PipeToStdout,
+                    b"HTTP/1.1 200 OK\r\nContent-Length: 23\r\n\r\nAuthorization: in body\n",
+                ),
+            ],
+        );
+
+        let chunks: Vec<_> = chunks.iter().map(|c| String::from_utf8_lossy(c)).collect();
+        assert_eq!(
+            chunks,
+            [
+                "POST /images/create?fromImage=app HTTP/1.1\r\n\
+                 Host: docker\r\n\
+                 X-Registry-Auth: ****************\r\n\
+                 authorization : ****** ***\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 23\r\n\r\nAuthorization: ** ****\n",
+            ]
+        );
+    }
+}
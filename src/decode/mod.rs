@@ -0,0 +1,490 @@
+//! Decoding relay traffic into protocol messages (`baton inspect`,
+//! `--decode`).
+//!
+//! A [`ProtocolDecoder`] is fed chunks exactly as the relay read them, from
+//! a transcript or live. Reads of up to [`BUFFER_SIZE`] bytes never line up
+//! with protocol frames, so decoders buffer each direction separately and
+//! only report a message once all of it has arrived.
+//!
+//! Before anything reaches the log, a [`Redactor`] blanks out the secrets
+//! each protocol carries: passphrases, private keys and credentials.
+//!
+//! [`BUFFER_SIZE`]: crate::relay::BUFFER_SIZE
+
+pub mod assuan;
+pub mod http;
+pub mod ssh_agent;
+
+use crate::capture::{Capture, Event};
+use crate::relay::Direction;
+use crate::transcript::{Record, RecordEvent};
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+/// Most bytes of opaque data shown with a message.
+pub const PREVIEW_LEN: usize = 64;
+
+/// Bytes shown by `-vv` when `--trace-limit` is not given.
+pub const DEFAULT_TRACE_LIMIT: u64 = 64 * 1024;
+
+/// What redacted bytes are replaced with.
+pub(crate) const REDACTED: u8 = b'*';
+
+/// One decoded protocol message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub direction: Direction,
+    /// One line naming the message, e.g. `SIGN_REQUEST`.
+    pub summary: String,
+    /// Further lines, such as headers or key fingerprints.
+    pub details: Vec<String>,
+}
+
+impl Message {
+    pub fn new(direction: Direction, summary: impl Into<String>) -> Self {
+        Self {
+            direction,
+            summary: summary.into(),
+            details: Vec::new(),
+        }
+    }
+
+    pub fn with_details(mut self, details: Vec<String>) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::StdinToPipe => "-->",
+            Direction::PipeToStdout => "<--",
+        };
+        write!(f, "{} {}", arrow, self.summary)?;
+        for detail in &self.details {
+            write!(f, "\n      {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Turns a byte stream in each direction into messages.
+pub trait ProtocolDecoder: Send {
+    /// Feed the next chunk read in `direction` and return the messages it
+    /// completed, if any.
+    fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<Message>;
+
+    /// `direction` has ended. Returns a note about anything left
+    /// half-received.
+    fn finish(&mut self, direction: Direction) -> Vec<Message>;
+}
+
+/// Blanks out secrets in a byte stream, byte for byte, so that lengths and
+/// framing stay intact for the decoders that run after it. Secrets split
+/// across chunks are handled like the decoders handle messages.
+pub trait Redactor: Send {
+    /// Redact the next chunk read in `direction` in place.
+    fn redact(&mut self, direction: Direction, data: &mut [u8]);
+}
+
+/// Protocols `baton inspect` and `--decode` understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Protocol {
+    /// OpenSSH agent protocol
+    SshAgent,
+    /// GnuPG's Assuan line protocol
+    Assuan,
+    /// HTTP/1.1, including Docker attach streams
+    Http,
+    /// Hex dump of every chunk
+    Raw,
+}
+
+impl Protocol {
+    pub fn decoder(self) -> Box<dyn ProtocolDecoder> {
+        match self {
+            Protocol::SshAgent => Box::new(ssh_agent::SshAgentDecoder::new()),
+            Protocol::Assuan => Box::new(assuan::AssuanDecoder::new()),
+            Protocol::Http => Box::new(http::HttpDecoder::new()),
+            Protocol::Raw => Box::new(RawDecoder),
+        }
+    }
+
+    /// The protocol's redaction rules; raw streams have none.
+    pub fn redactor(self) -> Option<Box<dyn Redactor>> {
+        match self {
+            Protocol::SshAgent => Some(Box::new(ssh_agent::SshAgentRedactor::new())),
+            Protocol::Assuan => Some(Box::new(assuan::AssuanRedactor::new())),
+            Protocol::Http => Some(Box::new(http::HttpRedactor::new())),
+            Protocol::Raw => None,
+        }
+    }
+
+    /// Best guess from a target such as a transcript label.
+    pub fn guess(target: &str) -> Self {
+        let target = target.to_ascii_lowercase();
+        if target.contains("ssh-agent")
+            || target.contains("ssh_agent")
+            || target.starts_with("cygwin://")
+        {
+            Protocol::SshAgent
+        } else if target.starts_with("assuan://") || target.contains("gpg-agent") {
+            Protocol::Assuan
+        } else if target.contains("docker") || target.ends_with(":2375") {
+            Protocol::Http
+        } else {
+            Protocol::Raw
+        }
+    }
+}
+
+/// Fallback decoder: every chunk as a hex dump.
+pub struct RawDecoder;
+
+impl ProtocolDecoder for RawDecoder {
+    fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<Message> {
+        vec![Message::new(direction, format!("{} bytes", data.len())).with_details(hexdump(data))]
+    }
+
+    fn finish(&mut self, _direction: Direction) -> Vec<Message> {
+        Vec::new()
+    }
+}
+
+/// Classic 16-bytes-per-line hex dump with an ASCII column.
+pub fn hexdump(data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, line)| {
+            let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+            let ascii: String = line
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:04x}  {:<47}  |{}|", i * 16, hex.join(" "), ascii)
+        })
+        .collect()
+}
+
+/// The start of `data`, escaped for display.
+pub(crate) fn preview(data: &[u8]) -> String {
+    let shown = &data[..data.len().min(PREVIEW_LEN)];
+    let ellipsis = if shown.len() < data.len() { "..." } else { "" };
+    format!("\"{}\"{}", shown.escape_ascii(), ellipsis)
+}
+
+/// Bytes received but not yet decoded, one buffer per direction.
+#[derive(Debug, Default)]
+pub(crate) struct Streams {
+    stdin_to_pipe: Vec<u8>,
+    pipe_to_stdout: Vec<u8>,
+}
+
+impl Streams {
+    pub(crate) fn get(&mut self, direction: Direction) -> &mut Vec<u8> {
+        match direction {
+            Direction::StdinToPipe => &mut self.stdin_to_pipe,
+            Direction::PipeToStdout => &mut self.pipe_to_stdout,
+        }
+    }
+}
+
+/// Write a transcript as decoded messages, one block per message, each
+/// prefixed with the time since the recording started.
+pub fn inspect(
+    records: &[Record],
+    decoder: &mut dyn ProtocolDecoder,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    for record in records {
+        let seconds = record.at.as_secs_f64();
+        let messages = match &record.event {
+            RecordEvent::Data(data) => decoder.feed(record.direction, data),
+            RecordEvent::Eof => {
+                let mut messages = decoder.finish(record.direction);
+                messages.push(Message::new(record.direction, "EOF"));
+                messages
+            }
+            RecordEvent::Error(e) => {
+                let mut messages = decoder.finish(record.direction);
+                messages.push(Message::new(record.direction, format!("error: {}", e)));
+                messages
+            }
+            RecordEvent::Truncated => {
+                writeln!(out, "{:>11.6}  (recording truncated here)", seconds)?;
+                break;
+            }
+        };
+        for message in messages {
+            writeln!(out, "{:>11.6}  {}", seconds, message)?;
+        }
+    }
+    Ok(())
+}
+
+/// Logs decoded live traffic at debug level (`-v`).
+pub struct DecodeCapture {
+    decoder: Mutex<Box<dyn ProtocolDecoder>>,
+}
+
+impl DecodeCapture {
+    pub fn new(protocol: Protocol) -> Self {
+        Self {
+            decoder: Mutex::new(protocol.decoder()),
+        }
+    }
+}
+
+impl Capture for DecodeCapture {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        let mut decoder = self.decoder.lock().unwrap_or_else(|e| e.into_inner());
+        let messages = match event {
+            Event::Data(data) => decoder.feed(direction, data),
+            Event::Eof | Event::Error(_) => decoder.finish(direction),
+        };
+        for message in messages {
+            log::debug!("{}", message);
+        }
+    }
+}
+
+/// Logs each chunk as a hex dump at trace level (`-vv`), up to a number of
+/// bytes per session.
+pub struct TraceCapture {
+    limit: u64,
+    remaining: Mutex<u64>,
+}
+
+impl TraceCapture {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            remaining: Mutex::new(limit),
+        }
+    }
+}
+
+impl Capture for TraceCapture {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        let Event::Data(data) = event else { return };
+        if !log::log_enabled!(log::Level::Trace) {
+            return;
+        }
+        let mut remaining = self.remaining.lock().unwrap_or_else(|e| e.into_inner());
+        if *remaining == 0 {
+            return;
+        }
+        let shown = data.len().min(*remaining as usize);
+        *remaining -= shown as u64;
+
+        let mut lines = hexdump(&data[..shown]);
+        if shown < data.len() {
+            lines.push(format!(
+                "... {} more bytes; trace limit of {} bytes reached",
+                data.len() - shown,
+                self.limit
+            ));
+        }
+        let source = match direction {
+            Direction::StdinToPipe => "stdin",
+            Direction::PipeToStdout => "pipe",
+        };
+        log::trace!(
+            "{} bytes from {}:\n{}",
+            data.len(),
+            source,
+            lines.join("\n")
+        );
+    }
+}
+
+/// Redacts data before passing it on to captures that log it.
+pub struct RedactingCapture {
+    redactor: Mutex<Option<Box<dyn Redactor>>>,
+    inner: Arc<dyn Capture>,
+}
+
+impl RedactingCapture {
+    /// Redact with `protocol`'s rules before `inner` sees the data.
+    pub fn new(protocol: Protocol, inner: Arc<dyn Capture>) -> Self {
+        Self {
+            redactor: Mutex::new(protocol.redactor()),
+            inner,
+        }
+    }
+}
+
+impl Capture for RedactingCapture {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        let mut redactor = self.redactor.lock().unwrap_or_else(|e| e.into_inner());
+        match (event, redactor.as_mut()) {
+            (Event::Data(data), Some(redactor)) => {
+                let mut data = data.to_vec();
+                redactor.redact(direction, &mut data);
+                self.inner.record(direction, Event::Data(&data));
+            }
+            _ => self.inner.record(direction, event),
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Feed `stream` (a list of chunks per direction, in order) once as
+    /// given and once a byte at a time; both must decode the same.
+    pub(crate) fn decode_split(protocol: Protocol, stream: &[(Direction, &[u8])]) -> Vec<Message> {
+        let mut whole = protocol.decoder();
+        let mut expected = Vec::new();
+        for (direction, data) in stream {
+            expected.extend(whole.feed(*direction, data));
+        }
+
+        let mut bytewise = protocol.decoder();
+        let mut actual = Vec::new();
+        for (direction, data) in stream {
+            for byte in data.iter() {
+                actual.extend(bytewise.feed(*direction, std::slice::from_ref(byte)));
+            }
+        }
+        assert_eq!(actual, expected);
+        expected
+    }
+
+    /// Redact `stream` once as given and once a byte at a time; both must
+    /// agree. Returns the redacted chunks.
+    pub(crate) fn redact_split(protocol: Protocol, stream: &[(Direction, &[u8])]) -> Vec<Vec<u8>> {
+        let mut whole = protocol.redactor().unwrap();
+        let mut bytewise = protocol.redactor().unwrap();
+        stream
+            .iter()
+            .map(|(direction, data)| {
+                let mut expected = data.to_vec();
+                whole.redact(*direction, &mut expected);
+                let mut actual = data.to_vec();
+                for byte in actual.chunks_mut(1) {
+                    bytewise.redact(*direction, byte);
+                }
+                assert_eq!(actual, expected);
+                expected
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let lines = hexdump(b"hello, world\n\x00\x01\x02\x03");
+        assert_eq!(
+            lines,
+            [
+                "0000  68 65 6c 6c 6f 2c 20 77 6f 72 6c 64 0a 00 01 02  |hello, world....|",
+                "0010  03                                               |.|",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_preview() {
+        assert_eq!(preview(b"ok\n"), "\"ok\\n\"");
+        assert!(preview(&[b'a'; 100]).ends_with("\"..."));
+    }
+
+    #[test]
+    fn test_protocol_guess() {
+        assert_eq!(
+            Protocol::guess("npipe:////./pipe/openssh-ssh-agent"),
+            Protocol::SshAgent
+        );
+        assert_eq!(
+            Protocol::guess("assuan://C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent"),
+            Protocol::Assuan
+        );
+        assert_eq!(
+            Protocol::guess("npipe:////./pipe/docker_engine"),
+            Protocol::Http
+        );
+        assert_eq!(Protocol::guess("tcp://127.0.0.1:2375"), Protocol::Http);
+        assert_eq!(Protocol::guess("exec:cat"), Protocol::Raw);
+    }
+
+    #[test]
+    fn test_redacting_capture() {
+        #[derive(Default)]
+        struct Seen(Mutex<Vec<u8>>);
+
+        impl Capture for Seen {
+            fn record(&self, _direction: Direction, event: Event<'_>) {
+                if let Event::Data(data) = event {
+                    self.0.lock().unwrap().extend_from_slice(data);
+                }
+            }
+        }
+
+        let seen = Arc::new(Seen::default());
+        let capture = RedactingCapture::new(Protocol::Http, seen.clone());
+        let original = b"GET / HTTP/1.1\r\nAuthorization: Basic c2VjcmV0\r\n\r\n".to_vec();
+        capture.record(Direction::StdinToPipe, Event::Data(&original));
+
+        assert_eq!(
+            *seen.0.lock().unwrap(),
+            b"GET / HTTP/1.1\r\nAuthorization: ***** ********\r\n\r\n"
+        );
+        assert!(original.ends_with(b"c2VjcmV0\r\n\r\n"));
+
+        let raw = RedactingCapture::new(Protocol::Raw, seen.clone());
+        raw.record(Direction::StdinToPipe, Event::Data(b"secret"));
+        assert!(seen.0.lock().unwrap().ends_with(b"secret"));
+    }
+
+    #[test]
+    fn test_message_display() {
+        let message = Message::new(Direction::PipeToStdout, "HTTP/1.1 200 OK")
+            .with_details(vec!["Content-Length: 2".to_string()]);
+        assert_eq!(
+            message.to_string(),
+            "<-- HTTP/1.1 200 OK\n      Content-Length: 2"
+        );
+    }
+
+    #[test]
+    fn test_inspect_transcript() {
+        let at = Duration::from_millis;
+        let records = vec![
+            Record {
+                direction: Direction::StdinToPipe,
+                at: at(0),
+                event: RecordEvent::Data(b"GETINFO version\n".to_vec()),
+            },
+            Record {
+                direction: Direction::PipeToStdout,
+                at: at(1500),
+                event: RecordEvent::Data(b"D 2.4.0\nOK\n".to_vec()),
+            },
+            Record {
+                direction: Direction::PipeToStdout,
+                at: at(1501),
+                event: RecordEvent::Eof,
+            },
+        ];
+        let mut out = Vec::new();
+        inspect(&records, Protocol::Assuan.decoder().as_mut(), &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "   0.000000  --> GETINFO version\n\
+             \x20  1.500000  <-- D 2.4.0\n\
+             \x20  1.500000  <-- OK\n\
+             \x20  1.501000  <-- EOF\n"
+        );
+    }
+}
@@ -0,0 +1,510 @@
+//! The OpenSSH agent protocol (draft-miller-ssh-agent).
+//!
+//! Every message is `length (u32 BE) | type (u8) | contents`. Keys are shown
+//! by type and SHA-256 fingerprint, as `ssh-add -l` prints them; passphrases
+//! and private keys are never shown.
+//!
+//! The redactor blanks the same secrets in the client's bytes: everything
+//! after the key type in `ADD_IDENTITY` and `ADD_ID_CONSTRAINED`, after the
+//! reader ID in the smartcard requests (the PIN), and the whole of `LOCK`
+//! and `UNLOCK` (the passphrase).
+
+use super::{Message, ProtocolDecoder, Redactor, Streams, REDACTED};
+use crate::relay::Direction;
+use sha2::{Digest, Sha256};
+
+/// Largest message OpenSSH's agent accepts.
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+
+const SIGN_FLAGS: [(u32, &str); 2] = [(2, "rsa-sha2-256"), (4, "rsa-sha2-512")];
+
+pub struct SshAgentDecoder {
+    streams: Streams,
+    /// Set per direction after an impossible length: the stream is not
+    /// ssh-agent traffic, or a message was lost.
+    lost: [bool; 2],
+}
+
+impl SshAgentDecoder {
+    pub fn new() -> Self {
+        Self {
+            streams: Streams::default(),
+            lost: [false; 2],
+        }
+    }
+}
+
+impl Default for SshAgentDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProtocolDecoder for SshAgentDecoder {
+    fn feed(&mut self, direction: Direction, data: &[u8]) -> Vec<Message> {
+        if self.lost[direction as usize] {
+            return Vec::new();
+        }
+        let buf = self.streams.get(direction);
+        buf.extend_from_slice(data);
+
+        let mut messages = Vec::new();
+        while buf.len() >= 4 {
+            let len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+            if len == 0 || len > MAX_MESSAGE_LEN {
+                messages.push(Message::new(
+                    direction,
+                    format!(
+                        "invalid message length {}; not decoding this direction",
+                        len
+                    ),
+                ));
+                buf.clear();
+                self.lost[direction as usize] = true;
+                break;
+            }
+            if buf.len() < 4 + len {
+                break;
+            }
+            let message: Vec<u8> = buf.drain(..4 + len).skip(4).collect();
+            let (summary, details) = describe(message[0], &message[1..]);
+            messages.push(Message::new(direction, summary).with_details(details));
+        }
+        messages
+    }
+
+    fn finish(&mut self, direction: Direction) -> Vec<Message> {
+        let buf = self.streams.get(direction);
+        if buf.is_empty() {
+            return Vec::new();
+        }
+        let note = format!("incomplete message ({} bytes)", buf.len());
+        buf.clear();
+        vec![Message::new(direction, note)]
+    }
+}
+
+/// Redacts requests from the client (stdin→pipe); the agent's replies hold
+/// only public keys and signatures.
+pub struct SshAgentRedactor {
+    /// Length and type of the current message, as far as read.
+    header: Vec<u8>,
+    /// Body bytes of the current message still to come.
+    body_left: usize,
+    /// Body bytes read so far.
+    position: usize,
+    /// Body bytes shown before redaction starts; `None` until the length of
+    /// the leading string is known.
+    shown: Option<usize>,
+    /// Set after an impossible length: everything after it is redacted.
+    lost: bool,
+}
+
+impl SshAgentRedactor {
+    pub fn new() -> Self {
+        Self {
+            header: Vec::with_capacity(5),
+            body_left: 0,
+            position: 0,
+            shown: None,
+            lost: false,
+        }
+    }
+
+    /// Start the body of a message of type `kind` and `len` body bytes.
+    fn start(&mut self, kind: u8, len: usize) {
+        self.body_left = len;
+        self.position = 0;
+        self.shown = match kind {
+            // Key type or reader ID, then the secret.
+            17 | 20 | 25 | 26 => None,
+            22 | 23 => Some(0),
+            _ => Some(usize::MAX),
+        };
+        if len == 0 {
+            self.header.clear();
+        }
+    }
+}
+
+impl Default for SshAgentRedactor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Redactor for SshAgentRedactor {
+    fn redact(&mut self, direction: Direction, data: &mut [u8]) {
+        if direction != Direction::StdinToPipe {
+            return;
+        }
+        for byte in data {
+            if self.lost {
+                *byte = REDACTED;
+                continue;
+            }
+            if self.header.len() < 5 {
+                self.header.push(*byte);
+                let len = || length(&self.header[..4]);
+                if self.header.len() == 4 {
+                    self.lost = len() == 0 || len() > MAX_MESSAGE_LEN;
+                } else if self.header.len() == 5 {
+                    self.start(*byte, len() - 1);
+                }
+                continue;
+            }
+
+            match self.shown {
+                None => {
+                    // The leading string's length, which is shown.
+                    self.header.push(*byte);
+                    if self.header.len() == 9 {
+                        self.shown = Some(4 + length(&self.header[5..]));
+                    }
+                }
+                Some(shown) if self.position >= shown => *byte = REDACTED,
+                Some(_) => {}
+            }
+            self.position += 1;
+            self.body_left -= 1;
+            if self.body_left == 0 {
+                self.header.clear();
+            }
+        }
+    }
+}
+
+/// A big-endian u32 length from four bytes.
+fn length(bytes: &[u8]) -> usize {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+/// Name and details of a message of type `kind` with `body`.
+fn describe(kind: u8, body: &[u8]) -> (String, Vec<String>) {
+    let mut r = Reader(body);
+    let (name, details) = match kind {
+        5 => ("FAILURE", None),
+        6 => ("SUCCESS", None),
+        11 => ("REQUEST_IDENTITIES", None),
+        12 => ("IDENTITIES_ANSWER", identities(&mut r)),
+        13 => ("SIGN_REQUEST", sign_request(&mut r)),
+        14 => ("SIGN_RESPONSE", signature(&mut r)),
+        17 => ("ADD_IDENTITY", r.string().map(key_type_line)),
+        18 => ("REMOVE_IDENTITY", r.string().map(|key| vec![key_line(key)])),
+        19 => ("REMOVE_ALL_IDENTITIES", None),
+        20 => ("ADD_SMARTCARD_KEY", r.string().map(reader_line)),
+        21 => ("REMOVE_SMARTCARD_KEY", r.string().map(reader_line)),
+        22 => ("LOCK", None),
+        23 => ("UNLOCK", None),
+        25 => ("ADD_ID_CONSTRAINED", r.string().map(key_type_line)),
+        26 => ("ADD_SMARTCARD_KEY_CONSTRAINED", r.string().map(reader_line)),
+        27 => ("EXTENSION", r.string().map(|name| vec![text(name)])),
+        28 => ("EXTENSION_FAILURE", None),
+        _ => return (format!("unknown message type {}", kind), Vec::new()),
+    };
+    let details = match details {
+        Some(details) => details,
+        // A message that carries nothing needs no body to be well formed.
+        None if matches!(kind, 5 | 6 | 11 | 19 | 22 | 23 | 28) => Vec::new(),
+        None => vec!["malformed body".to_string()],
+    };
+    (name.to_string(), details)
+}
+
+fn identities(r: &mut Reader<'_>) -> Option<Vec<String>> {
+    let count = r.u32()?;
+    let mut lines = vec![format!("{} key(s)", count)];
+    for _ in 0..count {
+        let key = r.string()?;
+        let comment = r.string()?;
+        lines.push(format!("{} {}", key_line(key), text(comment)));
+    }
+    Some(lines)
+}
+
+fn sign_request(r: &mut Reader<'_>) -> Option<Vec<String>> {
+    let key = r.string()?;
+    let data = r.string()?;
+    let flags = r.u32()?;
+    let mut flag_names: Vec<&str> = SIGN_FLAGS
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| *name)
+        .collect();
+    if flag_names.is_empty() {
+        flag_names.push("none");
+    }
+    Some(vec![
+        key_line(key),
+        format!("data: {} bytes", data.len()),
+        format!("flags: {}", flag_names.join(", ")),
+    ])
+}
+
+fn signature(r: &mut Reader<'_>) -> Option<Vec<String>> {
+    let signature = r.string()?;
+    let algorithm = Reader(signature).string()?;
+    Some(vec![format!(
+        "{}, {} bytes",
+        text(algorithm),
+        signature.len()
+    )])
+}
+
+/// `ssh-ed25519 SHA256:...` for a public key blob.
+fn key_line(key: &[u8]) -> String {
+    let key_type = Reader(key).string().map_or("unknown".to_string(), text);
+    format!("{} {}", key_type, fingerprint(key))
+}
+
+fn key_type_line(key_type: &[u8]) -> Vec<String> {
+    vec![format!("key type: {}", text(key_type))]
+}
+
+fn reader_line(id: &[u8]) -> Vec<String> {
+    vec![format!("reader: {}", text(id))]
+}
+
+/// OpenSSH's `SHA256:` fingerprint: unpadded base64 of the blob's SHA-256.
+pub fn fingerprint(key: &[u8]) -> String {
+    format!("SHA256:{}", base64_unpadded(&Sha256::digest(key)))
+}
+
+fn base64_unpadded(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn text(bytes: &[u8]) -> String {
+    bytes.escape_ascii().to_string()
+}
+
+/// Reads the SSH wire types out of a message body.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn u32(&mut self) -> Option<u32> {
+        let (head, rest) = self.0.split_first_chunk::<4>()?;
+        self.0 = rest;
+        Some(u32::from_be_bytes(*head))
+    }
+
+    fn string(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()? as usize;
+        if self.0.len() < len {
+            return None;
+        }
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decode::tests::{decode_split, redact_split};
+    use crate::decode::Protocol;
+
+    fn string(value: &[u8]) -> Vec<u8> {
+        let mut out = (value.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(value);
+        out
+    }
+
+    fn message(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 1) as u32).to_be_bytes().to_vec();
+        out.push(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn ed25519_key() -> Vec<u8> {
+        let mut key = string(b"ssh-ed25519");
+        key.extend(string(&[7u8; 32]));
+        key
+    }
+
+    #[test]
+    fn test_base64_unpadded() {
+        assert_eq!(base64_unpadded(b""), "");
+        assert_eq!(base64_unpadded(b"f"), "Zg");
+        assert_eq!(base64_unpadded(b"fo"), "Zm8");
+        assert_eq!(base64_unpadded(b"foo"), "Zm9v");
+        assert_eq!(base64_unpadded(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        // sha256("") is e3b0c442...; OpenSSH would print it like this.
+        assert_eq!(
+            fingerprint(b""),
+            "SHA256:47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+
+    #[test]
+    fn test_list_keys_session() {
+        let mut answer = 1u32.to_be_bytes().to_vec();
+        answer.extend(string(&ed25519_key()));
+        answer.extend(string(b"me@laptop"));
+        let request = message(11, &[]);
+        let response = message(12, &answer);
+
+        let messages = decode_split(
+            Protocol::SshAgent,
+            &[
+                (Direction::StdinToPipe, &request),
+                (Direction::PipeToStdout, &response),
+            ],
+        );
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].summary, "REQUEST_IDENTITIES");
+        assert_eq!(messages[1].summary, "IDENTITIES_ANSWER");
+        assert_eq!(messages[1].details[0], "1 key(s)");
+        assert_eq!(
+            messages[1].details[1],
+            format!("ssh-ed25519 {} me@laptop", fingerprint(&ed25519_key()))
+        );
+    }
+
+    #[test]
+    fn test_sign_request_and_response() {
+        let mut request = string(&ed25519_key());
+        request.extend(string(&[0u8; 100]));
+        request.extend(4u32.to_be_bytes());
+        let mut sig = string(b"rsa-sha2-512");
+        sig.extend(string(&[1u8; 8]));
+        let response = string(&sig);
+
+        let mut decoder = SshAgentDecoder::new();
+        let messages = decoder.feed(Direction::StdinToPipe, &message(13, &request));
+        assert_eq!(messages[0].summary, "SIGN_REQUEST");
+        assert_eq!(
+            messages[0].details[1..],
+            ["data: 100 bytes", "flags: rsa-sha2-512"]
+        );
+
+        let messages = decoder.feed(Direction::PipeToStdout, &message(14, &response));
+        assert_eq!(messages[0].summary, "SIGN_RESPONSE");
+        assert_eq!(messages[0].details, ["rsa-sha2-512, 28 bytes"]);
+    }
+
+    #[test]
+    fn test_several_messages_in_one_chunk() {
+        let mut chunk = message(6, &[]);
+        chunk.extend(message(5, &[]));
+        chunk.extend(&message(27, &string(b"session-bind@openssh.com"))[..3]);
+
+        let mut decoder = SshAgentDecoder::new();
+        let messages = decoder.feed(Direction::PipeToStdout, &chunk);
+        let summaries: Vec<_> = messages.iter().map(|m| m.summary.as_str()).collect();
+        assert_eq!(summaries, ["SUCCESS", "FAILURE"]);
+
+        let rest = decoder.finish(Direction::PipeToStdout);
+        assert_eq!(rest[0].summary, "incomplete message (3 bytes)");
+    }
+
+    #[test]
+    fn test_malformed_and_unknown() {
+        let mut decoder = SshAgentDecoder::new();
+        let messages = decoder.feed(Direction::StdinToPipe, &message(13, &[0, 0]));
+        assert_eq!(messages[0].details, ["malformed body"]);
+
+        let messages = decoder.feed(Direction::StdinToPipe, &message(99, &[]));
+        assert_eq!(messages[0].summary, "unknown message type 99");
+    }
+
+    #[test]
+    fn test_invalid_length_stops_direction() {
+        let mut decoder = SshAgentDecoder::new();
+        let messages = decoder.feed(Direction::StdinToPipe, b"GET / HTTP/1.1\r\n");
+        assert!(messages[0].summary.starts_with("invalid message length"));
+        assert!(decoder
+            .feed(Direction::StdinToPipe, &message(11, &[]))
+            .is_empty());
+        // The other direction is unaffected.
+        assert_eq!(
+            decoder.feed(Direction::PipeToStdout, &message(6, &[]))[0].summary,
+            "SUCCESS"
+        );
+    }
+
+    #[test]
+    fn test_redact_private_keys_and_passphrases() {
+        let mut add = string(b"ssh-ed25519");
+        add.extend(string(&[0xaa; 32]));
+        add.extend(string(&[0xbb; 64]));
+        add.extend(string(b"me@host"));
+        let add = message(17, &add);
+        let lock = message(22, &string(b"hunter2"));
+        let list = message(11, &[]);
+        let mut smartcard = string(b"pkcs11.so");
+        smartcard.extend(string(b"1234"));
+        let smartcard = message(20, &smartcard);
+
+        let mut client = add.clone();
+        client.extend(&lock);
+        client.extend(&list);
+        client.extend(&smartcard);
+        let reply = message(12, &string(b"ssh-ed25519 key"));
+        let chunks = redact_split(
+            Protocol::SshAgent,
+            &[
+                (Direction::StdinToPipe, &client),
+                (Direction::PipeToStdout, &reply),
+            ],
+        );
+
+        // Lengths, types and the key type stay; the key and comment go.
+        let redacted = &chunks[0][..];
+        let key_start = 4 + 1 + 4 + b"ssh-ed25519".len();
+        assert_eq!(redacted[..key_start], add[..key_start]);
+        assert!(redacted[key_start..add.len()]
+            .iter()
+            .all(|&b| b == REDACTED));
+        let redacted = &redacted[add.len()..];
+        assert_eq!(redacted[..5], lock[..5]);
+        assert!(redacted[5..lock.len()].iter().all(|&b| b == REDACTED));
+        let redacted = &redacted[lock.len()..];
+        assert_eq!(redacted[..list.len()], list[..]);
+        let redacted = &redacted[list.len()..];
+        assert_eq!(redacted[..18], smartcard[..18]);
+        assert_eq!(&redacted[18..], b"********");
+        assert_eq!(chunks[1], reply);
+
+        // The decoder still makes sense of the redacted stream.
+        let mut decoder = SshAgentDecoder::new();
+        let messages = decoder.feed(Direction::StdinToPipe, &chunks[0]);
+        let summaries: Vec<_> = messages.iter().map(|m| m.summary.as_str()).collect();
+        assert_eq!(
+            summaries,
+            [
+                "ADD_IDENTITY",
+                "LOCK",
+                "REQUEST_IDENTITIES",
+                "ADD_SMARTCARD_KEY"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_redact_after_invalid_length() {
+        let chunks = redact_split(
+            Protocol::SshAgent,
+            &[(Direction::StdinToPipe, b"\xff\xff\xff\xffsecret")],
+        );
+        assert_eq!(chunks[0][4..], *b"******");
+    }
+}
@@ -0,0 +1,452 @@
+//! Transport-neutral connection targets.
+//!
+//! An [`Endpoint`] knows how to reach one kind of target (named pipe, Assuan
+//! socket, TCP, Unix socket, child process) and hands back independent read
+//! and write halves, so the relay can drive each direction from its own
+//! thread regardless of the transport underneath.
+//!
+//! The relay passes the end of stdin on by dropping the writer, so each
+//! writer closes its own way: sockets shut down their sending half, a child
+//! process's stdin is closed, and a mux channel sends EOF.
+
+use crate::errors::BatonError;
+use crate::logging;
+use crate::retry::RetryPolicy;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+
+pub type EndpointReader = Box<dyn Read + Send>;
+pub type EndpointWriter = Box<dyn Write + Send>;
+
+/// A target the relay can connect to. Endpoints are plain descriptions of a
+/// target, so one can be shared by every connection a listener accepts.
+pub trait Endpoint: Send + Sync {
+    /// Connect, retrying according to `policy`, and split the connection into
+    /// a reader and a writer that can be moved to separate threads.
+    fn connect(&self, policy: &RetryPolicy)
+        -> Result<(EndpointReader, EndpointWriter), BatonError>;
+}
+
+/// A socket whose sending half can be shut down on its own.
+pub trait ShutdownWrite {
+    fn shutdown_write(&self) -> io::Result<()>;
+}
+
+impl ShutdownWrite for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+#[cfg(unix)]
+impl ShutdownWrite for UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+}
+
+/// Writer that shuts down the sending half of the socket when dropped, so
+/// the peer sees EOF while its data can still be read.
+pub struct HalfClose<S: ShutdownWrite>(pub S);
+
+impl<S: ShutdownWrite + Write> Write for HalfClose<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<S: ShutdownWrite> Drop for HalfClose<S> {
+    fn drop(&mut self) {
+        // Intentionally ignore: the peer may already be gone.
+        let _ = self.0.shutdown_write();
+    }
+}
+
+/// Plain TCP connection, e.g. `127.0.0.1:2375`.
+#[derive(Debug, Clone)]
+pub struct TcpEndpoint {
+    pub addr: String,
+}
+
+impl TcpEndpoint {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self { addr: addr.into() }
+    }
+}
+
+impl Endpoint for TcpEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let stream = poll_connect(policy, &self.addr, |timeout| {
+            connect_tcp(&self.addr, timeout)
+        })?;
+        let reader = stream.try_clone()?;
+        Ok((Box::new(reader), Box::new(HalfClose(stream))))
+    }
+}
+
+/// Unix domain stream socket.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixEndpoint {
+    pub path: PathBuf,
+}
+
+#[cfg(unix)]
+impl UnixEndpoint {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+#[cfg(unix)]
+impl Endpoint for UnixEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let target = self.path.display().to_string();
+        let stream = poll_connect(policy, &target, |_| UnixStream::connect(&self.path))?;
+        let reader = stream.try_clone()?;
+        Ok((Box::new(reader), Box::new(HalfClose(stream))))
+    }
+}
+
+/// Child process whose stdin and stdout become the two halves. Its stderr is
+/// inherited so diagnostics still reach the user.
+#[derive(Debug, Clone)]
+pub struct ExecEndpoint {
+    pub program: String,
+    pub args: Vec<String>,
+}
+
+impl ExecEndpoint {
+    pub fn new(program: impl Into<String>, args: Vec<String>) -> Self {
+        Self {
+            program: program.into(),
+            args,
+        }
+    }
+}
+
+impl Endpoint for ExecEndpoint {
+    /// Spawning is not retried: a missing executable will not appear by
+    /// polling for it.
+    fn connect(
+        &self,
+        _policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .envs(session_env())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(BatonError::ProcessSpawn)?;
+        log::debug!("Started {} (pid {})", self.program, child.id());
+
+        let stdin = child.stdin.take().expect("stdin was piped");
+        let stdout = child.stdout.take().expect("stdout was piped");
+
+        // Reap the child once it exits so it never lingers as a zombie.
+        thread::spawn(move || match child.wait() {
+            Ok(status) => log::debug!("Child process exited: {}", status),
+            Err(e) => log::warn!("Failed to wait for child process: {}", e),
+        });
+
+        Ok((Box::new(stdout), Box::new(stdin)))
+    }
+}
+
+/// Environment that hands the session ID to a child, so its logs can be
+/// matched with ours. WSL only passes variables listed in `WSLENV` on to
+/// Windows programs, such as `baton.exe` started from a Linux listener.
+fn session_env() -> Vec<(&'static str, String)> {
+    let mut env = vec![(logging::SESSION_ENV, logging::session_id())];
+    if cfg!(unix) {
+        let wslenv = std::env::var("WSLENV").unwrap_or_default();
+        let listed = wslenv
+            .split(':')
+            .any(|entry| entry.split('/').next() == Some(logging::SESSION_ENV));
+        if !listed {
+            let wslenv = match wslenv.as_str() {
+                "" => logging::SESSION_ENV.to_string(),
+                _ => format!("{}:{}", wslenv, logging::SESSION_ENV),
+            };
+            env.push(("WSLENV", wslenv));
+        }
+    }
+    env
+}
+
+/// Connect to `addr`, giving up after `timeout` if one is set. Every address
+/// `addr` resolves to is tried in turn.
+pub fn connect_tcp(addr: &str, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let Some(timeout) = timeout else {
+        return TcpStream::connect(addr);
+    };
+
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "address resolved to nothing")
+    }))
+}
+
+/// Retry `connect` as `policy` allows. Every failure is treated as
+/// retryable, since a socket that is not listening yet looks the same as one
+/// that never will be.
+fn poll_connect<T>(
+    policy: &RetryPolicy,
+    target: &str,
+    connect: impl FnMut(Option<Duration>) -> io::Result<T>,
+) -> Result<T, BatonError> {
+    policy
+        .run(target, connect, |_| true)
+        .map_err(|e| e.into_baton(BatonError::SocketConnection))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{run_relay_between, EndOfInput, RelayOptions};
+    use std::io::Cursor;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Answer a single connection by echoing its input back in upper case.
+    fn serve_upper<S: Read + Write>(mut stream: S) {
+        let mut request = [0u8; 5];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(&request.to_ascii_uppercase()).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_endpoint_connect_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0));
+
+        let policy = RetryPolicy {
+            connect_timeout: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+        let (mut reader, mut writer) = TcpEndpoint::new(addr).connect(&policy).unwrap();
+        writer.write_all(b"quick").unwrap();
+        let mut reply = [0u8; 5];
+        reader.read_exact(&mut reply).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(&reply, b"QUICK");
+    }
+
+    #[test]
+    fn test_tcp_endpoint_polling_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let policy = RetryPolicy {
+            interval: Duration::from_millis(1),
+            max_attempts: Some(3),
+            ..RetryPolicy::polling(false)
+        };
+        let result = TcpEndpoint::new(addr).connect(&policy);
+        assert!(matches!(
+            result,
+            Err(BatonError::PollingLimitReached { attempts: 3, .. })
+        ));
+    }
+
+    #[test]
+    fn test_tcp_endpoint_relay() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0));
+
+        let (reader, writer) = TcpEndpoint::new(addr)
+            .connect(&RetryPolicy::default())
+            .unwrap();
+        let stdout = SharedBuf::default();
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+        run_relay_between(
+            Cursor::new(b"hello".to_vec()),
+            stdout.clone(),
+            reader,
+            writer,
+            opts,
+        );
+
+        server.join().unwrap();
+        assert_eq!(*stdout.0.lock().unwrap(), b"HELLO");
+    }
+
+    #[test]
+    fn test_tcp_endpoint_half_closes_at_end_of_input() {
+        // A server that only answers once the request has ended.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut request = Vec::new();
+            stream.read_to_end(&mut request).unwrap();
+            stream.write_all(&request.to_ascii_uppercase()).unwrap();
+        });
+
+        let (reader, writer) = TcpEndpoint::new(addr)
+            .connect(&RetryPolicy::default())
+            .unwrap();
+        let stdout = SharedBuf::default();
+        run_relay_between(
+            Cursor::new(b"hello".to_vec()),
+            stdout.clone(),
+            reader,
+            writer,
+            RelayOptions {
+                end_of_input: EndOfInput::Close,
+                ..Default::default()
+            },
+        );
+
+        server.join().unwrap();
+        assert_eq!(*stdout.0.lock().unwrap(), b"HELLO");
+    }
+
+    #[test]
+    fn test_tcp_endpoint_refused_without_poll() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let result = TcpEndpoint::new(addr).connect(&RetryPolicy::default());
+        assert!(matches!(result, Err(BatonError::SocketConnection(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_endpoint_relay() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("relay.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0));
+
+        let (reader, writer) = UnixEndpoint::new(&path)
+            .connect(&RetryPolicy::default())
+            .unwrap();
+        let stdout = SharedBuf::default();
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+        run_relay_between(
+            Cursor::new(b"world".to_vec()),
+            stdout.clone(),
+            reader,
+            writer,
+            opts,
+        );
+
+        server.join().unwrap();
+        assert_eq!(*stdout.0.lock().unwrap(), b"WORLD");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_endpoint_polls_until_listening() {
+        use std::os::unix::net::UnixListener;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("late.sock");
+        let server_path = path.clone();
+        let server = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            let listener = UnixListener::bind(&server_path).unwrap();
+            serve_upper(listener.accept().unwrap().0);
+        });
+
+        let policy = RetryPolicy::polling(true);
+        let (mut reader, mut writer) = UnixEndpoint::new(&path).connect(&policy).unwrap();
+        writer.write_all(b"later").unwrap();
+        let mut reply = [0u8; 5];
+        reader.read_exact(&mut reply).unwrap();
+
+        server.join().unwrap();
+        assert_eq!(&reply, b"LATER");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exec_endpoint_relay() {
+        let endpoint = ExecEndpoint::new("tr", vec!["a-z".to_string(), "A-Z".to_string()]);
+        let (reader, writer) = endpoint.connect(&RetryPolicy::default()).unwrap();
+        let stdout = SharedBuf::default();
+
+        let outcome = run_relay_between(
+            Cursor::new(b"piped".to_vec()),
+            stdout.clone(),
+            reader,
+            writer,
+            RelayOptions {
+                end_of_input: EndOfInput::Close,
+                ..Default::default()
+            },
+        );
+
+        assert!(outcome.into_error().is_none());
+        assert_eq!(*stdout.0.lock().unwrap(), b"PIPED");
+    }
+
+    #[test]
+    fn test_exec_endpoint_missing_program() {
+        let endpoint = ExecEndpoint::new("baton-test-no-such-program", Vec::new());
+        let result = endpoint.connect(&RetryPolicy::default());
+        assert!(matches!(result, Err(BatonError::ProcessSpawn(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_endpoint_missing_without_poll() {
+        let dir = tempfile::tempdir().unwrap();
+        let result =
+            UnixEndpoint::new(dir.path().join("missing.sock")).connect(&RetryPolicy::default());
+        assert!(matches!(result, Err(BatonError::SocketConnection(_))));
+    }
+}
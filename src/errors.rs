@@ -1,3 +1,5 @@
+use crate::relay::Termination;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -5,8 +7,8 @@ pub enum BatonError {
     #[error("Failed to connect to named pipe: {0}")]
     PipeConnection(#[source] std::io::Error),
 
-    #[error("Polling limit reached after {0} attempts")]
-    PollingLimitReached(u32),
+    #[error("Polling limit reached after {attempts} attempts ({elapsed:.1?})")]
+    PollingLimitReached { attempts: u32, elapsed: Duration },
 
     #[error("Failed to parse Assuan socket file: {0}")]
     AssuanParse(String),
@@ -14,10 +16,53 @@ pub enum BatonError {
     #[error("Failed to connect to Assuan TCP socket: {0}")]
     AssuanConnection(#[source] std::io::Error),
 
+    #[error("Unexpected Assuan greeting: {0}")]
+    AssuanGreeting(String),
+
+    #[error("Assuan server error {code}: {description}")]
+    AssuanCommand { code: u32, description: String },
+
+    #[error("Unexpected Assuan response: {0}")]
+    AssuanProtocol(String),
+
+    #[error("Failed to parse Cygwin socket file: {0}")]
+    CygwinParse(String),
+
+    #[error("Failed to connect to Cygwin socket: {0}")]
+    CygwinConnection(#[source] std::io::Error),
+
+    #[error("Failed to connect to socket: {0}")]
+    SocketConnection(#[source] std::io::Error),
+
+    #[error("Invalid target: {0}")]
+    InvalidTarget(String),
+
+    #[error("Failed to start command: {0}")]
+    ProcessSpawn(#[source] std::io::Error),
+
+    #[error("Failed to listen on socket: {0}")]
+    Listen(String),
+
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+
+    #[error("Session limit reached: {}", .0.name())]
+    SessionLimit(Termination),
+
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
 
+impl BatonError {
+    /// Process exit status for this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BatonError::SessionLimit(termination) => termination.exit_code(),
+            _ => 1,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -32,10 +77,14 @@ mod tests {
 
     #[test]
     fn test_polling_limit_error_display() {
-        let err = BatonError::PollingLimitReached(300);
+        let err = BatonError::PollingLimitReached {
+            attempts: 300,
+            elapsed: Duration::from_millis(59_800),
+        };
         let msg = format!("{}", err);
         assert!(msg.contains("300"));
         assert!(msg.contains("Polling limit reached"));
+        assert!(msg.contains("59.8s"), "{}", msg);
     }
 
     #[test]
@@ -56,6 +105,76 @@ mod tests {
         assert!(msg.contains("Assuan TCP socket"));
     }
 
+    #[test]
+    fn test_assuan_greeting_error_display() {
+        let err = BatonError::AssuanGreeting("expected OK, got 'ERR 1'".to_string());
+        assert_eq!(
+            err.to_string(),
+            "Unexpected Assuan greeting: expected OK, got 'ERR 1'"
+        );
+    }
+
+    #[test]
+    fn test_cygwin_errors_display() {
+        let err = BatonError::CygwinParse("invalid GUID".to_string());
+        assert!(err.to_string().contains("Cygwin socket file"));
+        let err = BatonError::CygwinConnection(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "server answered with a different GUID",
+        ));
+        assert!(err
+            .to_string()
+            .contains("Failed to connect to Cygwin socket"));
+    }
+
+    #[test]
+    fn test_socket_connection_error_display() {
+        let err =
+            BatonError::SocketConnection(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        let msg = format!("{}", err);
+        assert!(msg.contains("Failed to connect to socket"));
+    }
+
+    #[test]
+    fn test_invalid_target_error_display() {
+        let err = BatonError::InvalidTarget("unsupported scheme 'http'".to_string());
+        let msg = format!("{}", err);
+        assert!(msg.contains("Invalid target"));
+        assert!(msg.contains("http"));
+    }
+
+    #[test]
+    fn test_listen_error_display() {
+        let err = BatonError::Listen("/tmp/a.sock is already in use".to_string());
+        let msg = format!("{}", err);
+        assert!(msg.contains("Failed to listen"));
+        assert!(msg.contains("already in use"));
+    }
+
+    #[test]
+    fn test_invalid_argument_error_display() {
+        let err = BatonError::InvalidArgument("--record is not supported with listen".to_string());
+        let msg = format!("{}", err);
+        assert!(msg.contains("Invalid argument"));
+        assert!(msg.contains("--record"));
+    }
+
+    #[test]
+    fn test_session_limit_error() {
+        let err = BatonError::SessionLimit(Termination::IdleTimeout);
+        assert_eq!(err.to_string(), "Session limit reached: idle_timeout");
+        assert_eq!(err.exit_code(), 3);
+        assert_eq!(
+            BatonError::SessionLimit(Termination::MaxDuration).exit_code(),
+            4
+        );
+        assert_eq!(
+            BatonError::SessionLimit(Termination::MaxBytes).exit_code(),
+            5
+        );
+        assert_eq!(BatonError::InvalidTarget(String::new()).exit_code(), 1);
+    }
+
     #[test]
     fn test_io_error_from_conversion() {
         let io_err = io::Error::new(io::ErrorKind::NotFound, "file not found");
@@ -68,7 +187,10 @@ mod tests {
 
     #[test]
     fn test_error_debug_impl() {
-        let err = BatonError::PollingLimitReached(100);
+        let err = BatonError::PollingLimitReached {
+            attempts: 100,
+            elapsed: Duration::from_secs(20),
+        };
         let debug = format!("{:?}", err);
         assert!(debug.contains("PollingLimitReached"));
         assert!(debug.contains("100"));
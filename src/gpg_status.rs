@@ -0,0 +1,432 @@
+//! `baton gpg-status`: ask gpg-agent whether it is alive and which keys it
+//! holds, straight over its Assuan socket file.
+//!
+//! When gpg in WSL hangs, this tells apart an agent on the Windows side that
+//! is gone or stuck from a problem on the Linux side, without involving the
+//! Linux gpg at all. The report comes from `GETINFO version`, `GETINFO pid`,
+//! `KEYINFO --list` and `NOP`; a command the agent rejects is noted and the
+//! rest still run. After a timeout or a broken reply nothing more is sent,
+//! since a late reply would be taken for the answer to the next command.
+
+use crate::assuan::AssuanClient;
+use crate::errors::BatonError;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatusFormat {
+    /// A human-readable report
+    Text,
+    /// One JSON object
+    Json,
+}
+
+/// One key from `KEYINFO --list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentKey {
+    pub keygrip: String,
+    /// `disk`, `smartcard` or `unknown`.
+    pub storage: &'static str,
+    /// Serial number of the card holding the key.
+    pub serial: Option<String>,
+    /// Whether the passphrase is cached.
+    pub cached: bool,
+    /// `protected`, `clear` or `unknown`.
+    pub protection: &'static str,
+}
+
+impl AgentKey {
+    /// Parse the arguments of an `S KEYINFO` line: keygrip, type, serial
+    /// number, ID, cached, protection, then fields this report ignores.
+    fn parse(args: &str) -> Option<Self> {
+        let fields: Vec<&str> = args.split(' ').collect();
+        let [keygrip, kind, serial, _, cached, protection, ..] = fields[..] else {
+            return None;
+        };
+        Some(AgentKey {
+            keygrip: keygrip.to_string(),
+            storage: match kind {
+                "D" => "disk",
+                "T" => "smartcard",
+                _ => "unknown",
+            },
+            serial: (serial != "-").then(|| serial.to_string()),
+            cached: cached == "1",
+            protection: match protection {
+                "P" => "protected",
+                "C" => "clear",
+                _ => "unknown",
+            },
+        })
+    }
+}
+
+/// What the agent said about itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GpgStatus {
+    pub socket: String,
+    pub greeting: String,
+    pub version: Option<String>,
+    pub pid: Option<u32>,
+    /// `None` if `KEYINFO --list` failed.
+    pub keys: Option<Vec<AgentKey>>,
+    /// Whether the agent answered `NOP`.
+    pub alive: bool,
+    /// Commands that failed, and why.
+    pub errors: Vec<(String, String)>,
+}
+
+impl GpgStatus {
+    /// Run the diagnostic commands over `client`, then say goodbye unless
+    /// the connection is out of step.
+    pub fn query<R: BufRead, W: Write>(socket: String, client: &mut AssuanClient<R, W>) -> Self {
+        let mut status = GpgStatus {
+            socket,
+            greeting: client.greeting().to_string(),
+            ..Default::default()
+        };
+        let mut in_step = true;
+        let mut run = |command: &str| {
+            if !in_step {
+                let error = "not sent after an earlier error".to_string();
+                status.errors.push((command.to_string(), error));
+                return None;
+            }
+            match client.transact(command) {
+                Ok(response) => Some(response),
+                Err(e) => {
+                    // An ERR reply is a complete answer; anything else may
+                    // leave the reply, or part of it, still to come.
+                    in_step = matches!(e, BatonError::AssuanCommand { .. });
+                    status.errors.push((command.to_string(), e.to_string()));
+                    None
+                }
+            }
+        };
+
+        let version = run("GETINFO version");
+        let pid = run("GETINFO pid");
+        let keys = run("KEYINFO --list");
+        let alive = run("NOP").is_some();
+
+        status.version = version.map(|r| String::from_utf8_lossy(&r.data).into_owned());
+        status.pid = pid.and_then(|r| String::from_utf8_lossy(&r.data).trim().parse().ok());
+        status.keys = keys.map(|r| {
+            r.status
+                .iter()
+                .filter(|(keyword, _)| keyword == "KEYINFO")
+                .filter_map(|(_, args)| AgentKey::parse(args))
+                .collect()
+        });
+        status.alive = alive;
+
+        if in_step {
+            // Intentionally ignore: the report is complete either way.
+            let _ = client.transact("BYE");
+        }
+        status
+    }
+
+    pub fn to_json(&self) -> Value {
+        let keys = self.keys.as_ref().map(|keys| {
+            keys.iter()
+                .map(|key| {
+                    json!({
+                        "keygrip": key.keygrip,
+                        "storage": key.storage,
+                        "serial": key.serial,
+                        "cached": key.cached,
+                        "protection": key.protection,
+                    })
+                })
+                .collect::<Vec<_>>()
+        });
+        let errors: Vec<Value> = self
+            .errors
+            .iter()
+            .map(|(command, error)| json!({ "command": command, "error": error }))
+            .collect();
+        json!({
+            "socket": self.socket,
+            "alive": self.alive,
+            "greeting": self.greeting,
+            "version": self.version,
+            "pid": self.pid,
+            "keys": keys,
+            "errors": errors,
+        })
+    }
+
+    /// The report as printed, ending in a newline.
+    pub fn format(&self, format: StatusFormat) -> String {
+        match format {
+            StatusFormat::Text => self.to_string(),
+            StatusFormat::Json => format!("{}\n", self.to_json()),
+        }
+    }
+}
+
+impl fmt::Display for GpgStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alive = if self.alive { "alive" } else { "not answering" };
+        writeln!(f, "gpg-agent at {}: {}", self.socket, alive)?;
+        writeln!(f, "greeting: {}", self.greeting)?;
+        let unknown = || "unknown".to_string();
+        writeln!(
+            f,
+            "version: {}",
+            self.version.clone().unwrap_or_else(unknown)
+        )?;
+        writeln!(
+            f,
+            "pid: {}",
+            self.pid.map_or_else(unknown, |pid| pid.to_string())
+        )?;
+        match &self.keys {
+            Some(keys) => {
+                writeln!(f, "keys: {}", keys.len())?;
+                for key in keys {
+                    write!(f, "  {} {}", key.keygrip, key.storage)?;
+                    if let Some(serial) = &key.serial {
+                        write!(f, " {}", serial)?;
+                    }
+                    write!(f, ", {}", key.protection)?;
+                    if key.cached {
+                        write!(f, ", cached")?;
+                    }
+                    writeln!(f)?;
+                }
+            }
+            None => writeln!(f, "keys: unknown")?,
+        }
+        if !self.errors.is_empty() {
+            writeln!(f, "errors:")?;
+            for (command, error) in &self.errors {
+                writeln!(f, "  {}: {}", command, error)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::retry::RetryPolicy;
+    use std::io::{BufReader, Read};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    const KEYGRIP: &str = "0123456789ABCDEF0123456789ABCDEF01234567";
+    const CARD_KEYGRIP: &str = "89ABCDEF0123456789ABCDEF0123456789ABCDEF";
+
+    /// A scripted gpg-agent: for each command it reads, the reply given for
+    /// that command. An empty reply stands for an agent that hangs and only
+    /// answers `OK` once the client has given up. Returns the commands it saw.
+    fn scripted_agent(
+        script: &'static [(&'static str, &'static str)],
+    ) -> (tempfile::TempDir, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let mut contents = format!("{}\n", listener.local_addr().unwrap().port()).into_bytes();
+        contents.extend_from_slice(&[0u8; 16]);
+        std::fs::write(dir.path().join("S.gpg-agent"), contents).unwrap();
+
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let mut nonce = [0u8; 16];
+            stream.read_exact(&mut nonce).unwrap();
+            stream.write_all(b"OK Pleased to meet you\n").unwrap();
+
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut seen = Vec::new();
+            let mut line = String::new();
+            while matches!(reader.read_line(&mut line), Ok(n) if n > 0) {
+                let command = line.trim_end().to_string();
+                let reply = match script.iter().find(|(c, _)| *c == command) {
+                    Some((_, "")) => {
+                        thread::sleep(Duration::from_millis(300));
+                        "OK\n"
+                    }
+                    Some((_, reply)) => reply,
+                    None => "ERR 275 Unknown IPC command\n",
+                };
+                seen.push(command);
+                if stream.write_all(reply.as_bytes()).is_err() {
+                    break;
+                }
+                line.clear();
+            }
+            seen
+        });
+        (dir, server)
+    }
+
+    fn query(dir: &tempfile::TempDir, timeout: Duration) -> GpgStatus {
+        let path = dir.path().join("S.gpg-agent");
+        let mut client = AssuanClient::connect(&path, &RetryPolicy::default(), timeout).unwrap();
+        let status = GpgStatus::query("S.gpg-agent".to_string(), &mut client);
+        drop(client);
+        status
+    }
+
+    #[test]
+    fn test_query_scripted_agent() {
+        let (dir, server) = scripted_agent(&[
+            ("GETINFO version", "D 2.4.5\nOK\n"),
+            ("GETINFO pid", "D 4242\nOK\n"),
+            (
+                "KEYINFO --list",
+                "S KEYINFO 0123456789ABCDEF0123456789ABCDEF01234567 D - - 1 P - - -\n\
+                 S KEYINFO 89ABCDEF0123456789ABCDEF0123456789ABCDEF T D276000124 - - - - - -\n\
+                 OK\n",
+            ),
+            ("NOP", "OK\n"),
+            ("BYE", "OK closing connection\n"),
+        ]);
+
+        let status = query(&dir, Duration::from_secs(5));
+
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GETINFO version",
+                "GETINFO pid",
+                "KEYINFO --list",
+                "NOP",
+                "BYE"
+            ]
+        );
+        assert!(status.alive);
+        assert_eq!(status.greeting, "OK Pleased to meet you");
+        assert_eq!(status.version.as_deref(), Some("2.4.5"));
+        assert_eq!(status.pid, Some(4242));
+        assert_eq!(
+            status.keys.unwrap(),
+            [
+                AgentKey {
+                    keygrip: KEYGRIP.to_string(),
+                    storage: "disk",
+                    serial: None,
+                    cached: true,
+                    protection: "protected",
+                },
+                AgentKey {
+                    keygrip: CARD_KEYGRIP.to_string(),
+                    storage: "smartcard",
+                    serial: Some("D276000124".to_string()),
+                    cached: false,
+                    protection: "unknown",
+                },
+            ]
+        );
+        assert!(status.errors.is_empty(), "{:?}", status.errors);
+    }
+
+    #[test]
+    fn test_query_records_failed_commands() {
+        let (dir, server) =
+            scripted_agent(&[("GETINFO version", "D 2.2.%32%37\nOK\n"), ("NOP", "OK\n")]);
+
+        let status = query(&dir, Duration::from_secs(5));
+        server.join().unwrap();
+
+        assert!(status.alive);
+        assert_eq!(status.version.as_deref(), Some("2.2.27"));
+        assert_eq!(status.pid, None);
+        assert_eq!(status.keys, None);
+        assert_eq!(
+            status.errors,
+            [
+                (
+                    "GETINFO pid".to_string(),
+                    "Assuan server error 275: Unknown IPC command".to_string()
+                ),
+                (
+                    "KEYINFO --list".to_string(),
+                    "Assuan server error 275: Unknown IPC command".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_query_stops_after_timeout() {
+        let (dir, server) = scripted_agent(&[
+            ("GETINFO version", "D 2.4.5\nOK\n"),
+            ("GETINFO pid", ""),
+            ("KEYINFO --list", "OK\n"),
+            ("NOP", "OK\n"),
+        ]);
+
+        let status = query(&dir, Duration::from_millis(100));
+
+        // The late OK for GETINFO pid is never read as another answer.
+        assert_eq!(server.join().unwrap(), ["GETINFO version", "GETINFO pid"]);
+        assert!(!status.alive);
+        assert_eq!(status.version.as_deref(), Some("2.4.5"));
+        assert_eq!(status.keys, None);
+        let failed: Vec<&str> = status.errors.iter().map(|(c, _)| c.as_str()).collect();
+        assert_eq!(failed, ["GETINFO pid", "KEYINFO --list", "NOP"]);
+        assert!(
+            status.errors[0].1.contains("timed out"),
+            "{:?}",
+            status.errors
+        );
+        assert_eq!(status.errors[2].1, "not sent after an earlier error");
+    }
+
+    fn status() -> GpgStatus {
+        GpgStatus {
+            socket: "C:/gnupg/S.gpg-agent".to_string(),
+            greeting: "OK Pleased to meet you".to_string(),
+            version: Some("2.4.5".to_string()),
+            pid: Some(4242),
+            keys: Some(vec![AgentKey {
+                keygrip: KEYGRIP.to_string(),
+                storage: "smartcard",
+                serial: Some("D276000124".to_string()),
+                cached: true,
+                protection: "protected",
+            }]),
+            alive: true,
+            errors: vec![("NOP".to_string(), "timed out".to_string())],
+        }
+    }
+
+    #[test]
+    fn test_status_text() {
+        assert_eq!(
+            status().format(StatusFormat::Text),
+            format!(
+                "gpg-agent at C:/gnupg/S.gpg-agent: alive\n\
+                 greeting: OK Pleased to meet you\n\
+                 version: 2.4.5\n\
+                 pid: 4242\n\
+                 keys: 1\n  \
+                 {} smartcard D276000124, protected, cached\n\
+                 errors:\n  \
+                 NOP: timed out\n",
+                KEYGRIP
+            )
+        );
+    }
+
+    #[test]
+    fn test_status_json() {
+        let value: Value = serde_json::from_str(&status().format(StatusFormat::Json)).unwrap();
+        assert_eq!(value["alive"], true);
+        assert_eq!(value["version"], "2.4.5");
+        assert_eq!(value["pid"], 4242);
+        assert_eq!(value["keys"][0]["keygrip"], KEYGRIP);
+        assert_eq!(value["keys"][0]["serial"], "D276000124");
+        assert_eq!(value["keys"][0]["cached"], true);
+        assert_eq!(value["errors"][0]["command"], "NOP");
+
+        let unknown = GpgStatus::default().to_json();
+        assert_eq!(unknown["keys"], Value::Null);
+        assert_eq!(unknown["pid"], Value::Null);
+    }
+}
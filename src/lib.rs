@@ -1,10 +1,26 @@
 #![deny(warnings)]
 #![deny(clippy::all)]
 
+pub mod assuan;
+pub mod capture;
 pub mod cli;
+pub mod cygwin;
+pub mod decode;
+pub mod endpoint;
 pub mod errors;
+pub mod gpg_status;
+#[cfg(unix)]
+pub mod listen;
 pub mod logging;
+pub mod mux;
+pub mod pcapng;
+pub mod rate;
 pub mod relay;
+pub mod replay;
+pub mod retry;
+pub mod stats;
+pub mod target;
+pub mod transcript;
 
 #[cfg(windows)]
 pub mod win;
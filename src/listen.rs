@@ -0,0 +1,433 @@
+//! Built-in Unix socket listener (`baton listen`).
+//!
+//! This replaces `socat UNIX-LISTEN:<path>,fork EXEC:baton.exe ...`: every
+//! accepted connection gets its own thread, which connects the configured
+//! endpoint (for `exec:` targets that spawns one child per connection) and
+//! bridges the two with [`run_relay_between`].
+//!
+//! Shutdown is driven by a [`Stopper`]. Because `accept` cannot be
+//! interrupted portably, stopping sets a flag and then connects to the socket
+//! once to wake the accept loop up.
+
+use crate::endpoint::{Endpoint, HalfClose};
+use crate::errors::BatonError;
+use crate::logging;
+use crate::relay::{run_relay_between, RelayOptions};
+use crate::retry::RetryPolicy;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Clients = Arc<Mutex<HashMap<u64, UnixStream>>>;
+
+pub struct SocketListener {
+    listener: UnixListener,
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    clients: Clients,
+}
+
+impl SocketListener {
+    /// Bind `path`, replacing a stale socket file left behind by a previous
+    /// run, and restrict it to `mode` (e.g. `0o600`).
+    ///
+    /// The socket is bound inside a private `0700` directory next to `path`
+    /// and only renamed into place once it has `mode`, so nobody can connect
+    /// while it still has the permissions of the process umask.
+    pub fn bind(path: &Path, mode: u32) -> Result<Self, BatonError> {
+        remove_stale_socket(path)?;
+
+        let staging = StagingDir::create(path)?;
+        let staged = staging.path.join("socket");
+        let listener = UnixListener::bind(&staged)
+            .map_err(|e| BatonError::Listen(format!("cannot bind {}: {}", path.display(), e)))?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(mode))?;
+        fs::rename(&staged, path)?;
+
+        let listener = Self {
+            listener,
+            path: path.to_path_buf(),
+            stop: Arc::new(AtomicBool::new(false)),
+            clients: Arc::new(Mutex::new(HashMap::new())),
+        };
+        log::debug!("Listening on {} (mode {:o})", path.display(), mode);
+
+        Ok(listener)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn stopper(&self) -> Stopper {
+        Stopper {
+            path: self.path.clone(),
+            stop: Arc::clone(&self.stop),
+        }
+    }
+
+    /// Accept connections until stopped, relaying each one to `endpoint`.
+    /// Connections still open when the listener stops are shut down.
+    pub fn serve(
+        &self,
+        endpoint: Arc<dyn Endpoint>,
+        policy: RetryPolicy,
+        opts: RelayOptions,
+    ) -> Result<(), BatonError> {
+        let mut next_id = 0u64;
+
+        for stream in self.listener.incoming() {
+            if self.stop.load(Ordering::SeqCst) {
+                break;
+            }
+
+            let client = match stream {
+                Ok(client) => client,
+                Err(e) => {
+                    log::warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+
+            next_id += 1;
+            let id = next_id;
+            // Running out of descriptors only costs this client, not the
+            // listener.
+            match client.try_clone() {
+                Ok(handle) => self.clients.lock().unwrap().insert(id, handle),
+                Err(e) => {
+                    log::warn!("Dropping connection {}: {}", id, e);
+                    continue;
+                }
+            };
+
+            let endpoint = Arc::clone(&endpoint);
+            let policy = policy.clone();
+            let opts = opts.clone();
+            let clients = Arc::clone(&self.clients);
+            let session = format!("{}.{}", logging::session_id(), id);
+            thread::spawn(move || {
+                logging::set_thread_session(Some(session));
+                handle_client(id, client, endpoint.as_ref(), &policy, opts);
+                clients.lock().unwrap().remove(&id);
+            });
+        }
+
+        log::debug!("Listener on {} stopping", self.path.display());
+        for (_, client) in self.clients.lock().unwrap().drain() {
+            // Intentionally ignore: the client may already have gone away.
+            let _ = client.shutdown(Shutdown::Both);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for SocketListener {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("Failed to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Handle for stopping a [`SocketListener`] from another thread.
+#[derive(Debug, Clone)]
+pub struct Stopper {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+}
+
+impl Stopper {
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop so it notices the flag.
+        let _ = UnixStream::connect(&self.path);
+    }
+
+    /// Stop the listener when the process receives SIGTERM, SIGINT or SIGHUP.
+    pub fn stop_on_signals(self) -> io::Result<()> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+        use signal_hook::iterator::Signals;
+
+        let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+        thread::spawn(move || {
+            if let Some(signal) = signals.forever().next() {
+                log::debug!("Received signal {}, shutting down", signal);
+                self.stop();
+            }
+        });
+        Ok(())
+    }
+}
+
+fn handle_client(
+    id: u64,
+    client: UnixStream,
+    endpoint: &dyn Endpoint,
+    policy: &RetryPolicy,
+    opts: RelayOptions,
+) {
+    log::debug!("Connection {} accepted", id);
+
+    let result = endpoint.connect(policy).and_then(|(reader, writer)| {
+        let client_reader = client.try_clone()?;
+        // Shut down when the target finishes, so the client sees EOF.
+        let client_writer = HalfClose(client.try_clone()?);
+        Ok(run_relay_between(
+            client_reader,
+            client_writer,
+            reader,
+            writer,
+            opts,
+        ))
+    });
+
+    match result {
+        Ok(outcome) => log::debug!("Connection {} finished: {:?}", id, outcome),
+        Err(e) => log::warn!("Connection {} failed: {}", id, e),
+    }
+
+    // The relay may have returned with a thread still blocked reading the
+    // client; shutting the socket down releases it and tells the client.
+    let _ = client.shutdown(Shutdown::Both);
+}
+
+/// Private directory a socket is bound in before it is moved to its final
+/// path. Removed again on drop.
+struct StagingDir {
+    path: PathBuf,
+}
+
+impl StagingDir {
+    fn create(socket: &Path) -> Result<Self, BatonError> {
+        let parent = match socket.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let name = socket.file_name().unwrap_or_default().to_string_lossy();
+        let path = parent.join(format!(".{}.{}.tmp", name, std::process::id()));
+
+        fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&path)
+            .map_err(|e| BatonError::Listen(format!("cannot create {}: {}", path.display(), e)))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        // Removes the socket too if binding failed before the rename.
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            log::warn!("Failed to remove {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Remove `path` if it is a socket nobody is listening on any more. Refuse to
+/// touch anything else.
+fn remove_stale_socket(path: &Path) -> Result<(), BatonError> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return Err(BatonError::Listen(format!(
+            "{} exists and is not a socket",
+            path.display()
+        )));
+    }
+
+    if UnixStream::connect(path).is_ok() {
+        return Err(BatonError::Listen(format!(
+            "{} is already in use by another process",
+            path.display()
+        )));
+    }
+
+    log::debug!("Removing stale socket {}", path.display());
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::endpoint::UnixEndpoint;
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    fn upstream_upper(path: &Path, connections: usize) -> thread::JoinHandle<()> {
+        let listener = UnixListener::bind(path).unwrap();
+        thread::spawn(move || {
+            for _ in 0..connections {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = [0u8; 3];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(&request.to_ascii_uppercase()).unwrap();
+            }
+        })
+    }
+
+    /// Send `data` and collect the reply. Errors count as an empty reply, since
+    /// the listener may hang up before the request is written.
+    fn request(path: &Path, data: &[u8]) -> Vec<u8> {
+        let mut stream = UnixStream::connect(path).unwrap();
+        let _ = stream.write_all(data);
+        let _ = stream.shutdown(Shutdown::Write);
+        let mut reply = Vec::new();
+        let _ = stream.read_to_end(&mut reply);
+        reply
+    }
+
+    #[test]
+    fn test_bind_sets_mode() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mode.sock");
+
+        let listener = SocketListener::bind(&path, 0o600).unwrap();
+
+        let mode = fs::metadata(listener.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    #[test]
+    fn test_bind_never_exposes_umask_mode() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for mode in [0o600, 0o660, 0o666] {
+            let path = dir.path().join(format!("{:o}.sock", mode));
+            let listener = SocketListener::bind(&path, mode).unwrap();
+
+            // The socket already has its mode when bind returns, and the
+            // staging directory it was created in is gone.
+            let metadata = fs::symlink_metadata(&path).unwrap();
+            assert!(metadata.file_type().is_socket());
+            assert_eq!(metadata.permissions().mode() & 0o777, mode);
+            let entries: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+            assert_eq!(entries.len(), 1);
+
+            assert!(UnixStream::connect(&path).is_ok());
+            drop(listener);
+        }
+    }
+
+    #[test]
+    fn test_bind_removes_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stale.sock");
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        assert!(SocketListener::bind(&path, 0o600).is_ok());
+    }
+
+    #[test]
+    fn test_bind_refuses_live_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("live.sock");
+        let _live = UnixListener::bind(&path).unwrap();
+
+        let result = SocketListener::bind(&path, 0o600);
+        assert!(matches!(result, Err(BatonError::Listen(msg)) if msg.contains("already in use")));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_bind_refuses_regular_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.sock");
+        fs::write(&path, b"not a socket").unwrap();
+
+        let result = SocketListener::bind(&path, 0o600);
+        assert!(matches!(result, Err(BatonError::Listen(msg)) if msg.contains("not a socket")));
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+    }
+
+    #[test]
+    fn test_serve_relays_each_connection_then_stops() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream_path = dir.path().join("upstream.sock");
+        let listen_path = dir.path().join("listen.sock");
+        let upstream = upstream_upper(&upstream_path, 2);
+
+        let listener = SocketListener::bind(&listen_path, 0o600).unwrap();
+        let stopper = listener.stopper();
+        let endpoint: Arc<dyn Endpoint> = Arc::new(UnixEndpoint::new(&upstream_path));
+        let server = thread::spawn(move || {
+            listener.serve(endpoint, RetryPolicy::default(), RelayOptions::default())
+        });
+
+        assert_eq!(request(&listen_path, b"abc"), b"ABC");
+        assert_eq!(request(&listen_path, b"xyz"), b"XYZ");
+        upstream.join().unwrap();
+
+        stopper.stop();
+        server.join().unwrap().unwrap();
+        assert!(!listen_path.exists());
+    }
+
+    #[test]
+    fn test_serve_applies_session_limits_per_connection() {
+        let dir = tempfile::tempdir().unwrap();
+        let upstream_path = dir.path().join("upstream.sock");
+        let listen_path = dir.path().join("listen.sock");
+        // Accept connections and never answer or hang up.
+        let upstream = UnixListener::bind(&upstream_path).unwrap();
+        thread::spawn(move || {
+            let held: Vec<_> = upstream.incoming().collect();
+            drop(held);
+        });
+
+        let listener = SocketListener::bind(&listen_path, 0o600).unwrap();
+        let stopper = listener.stopper();
+        let endpoint: Arc<dyn Endpoint> = Arc::new(UnixEndpoint::new(&upstream_path));
+        let opts = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let server = thread::spawn(move || listener.serve(endpoint, RetryPolicy::default(), opts));
+
+        // Each idle client is hung up on, and the listener keeps serving.
+        for _ in 0..2 {
+            let mut stream = UnixStream::connect(&listen_path).unwrap();
+            let mut reply = Vec::new();
+            stream.read_to_end(&mut reply).unwrap();
+            assert!(reply.is_empty());
+        }
+
+        stopper.stop();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_serve_survives_unreachable_endpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let listen_path = dir.path().join("listen.sock");
+
+        let listener = SocketListener::bind(&listen_path, 0o600).unwrap();
+        let stopper = listener.stopper();
+        let endpoint: Arc<dyn Endpoint> =
+            Arc::new(UnixEndpoint::new(dir.path().join("missing.sock")));
+        let server = thread::spawn(move || {
+            listener.serve(endpoint, RetryPolicy::default(), RelayOptions::default())
+        });
+
+        // The client is simply disconnected; the listener keeps running.
+        assert!(request(&listen_path, b"abc").is_empty());
+        assert!(request(&listen_path, b"def").is_empty());
+
+        stopper.stop();
+        server.join().unwrap().unwrap();
+    }
+}
@@ -1,8 +1,400 @@
-pub fn init_logging(verbose: bool) {
-    if verbose {
+//! Log setup: plain text for people, or JSON lines (`--log-format json`) for
+//! log collectors.
+//!
+//! Every JSON record carries the session ID, so the interleaved output of
+//! many relays can be told apart. The ID comes from `BATON_SESSION_ID` when
+//! a parent set it, and is made up otherwise. Children started by `exec:`
+//! targets inherit it; `baton listen` gives each connection its own
+//! `<listener session>.<connection>` ID.
+//!
+//! With `--log-file`, records go to a file that is rotated by size instead of
+//! stderr. Problems with the file are reported on stderr once and otherwise
+//! ignored: logging never stops the relay.
+
+use serde_json::{Map, Value};
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// Environment variable a parent sets to pass its session ID on.
+pub const SESSION_ENV: &str = "BATON_SESSION_ID";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LogFormat {
+    /// env_logger's plain text
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Rotated log file settings (`--log-file`, `--log-max-size`, `--log-keep`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogFile {
+    /// File name, in which `{session}` and `{pid}` are replaced.
+    pub path: PathBuf,
+    /// Rotate before the file would grow past this many bytes.
+    pub max_size: u64,
+    /// Rotated files kept, as `<path>.1` (newest) to `<path>.<keep>`.
+    pub keep: u32,
+}
+
+/// Default `--log-max-size`.
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Default `--log-keep`.
+pub const DEFAULT_LOG_KEEP: u32 = 5;
+
+impl LogFile {
+    /// The path with the `{session}` and `{pid}` placeholders filled in.
+    pub fn expand_path(&self) -> PathBuf {
+        let path = self
+            .path
+            .to_string_lossy()
+            .replace("{session}", &process_session())
+            .replace("{pid}", &std::process::id().to_string());
+        PathBuf::from(path)
+    }
+}
+
+static PROCESS_SESSION: OnceLock<String> = OnceLock::new();
+
+thread_local! {
+    /// Overrides the process session on threads serving one connection.
+    static THREAD_SESSION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// The session ID of the current thread.
+pub fn session_id() -> String {
+    THREAD_SESSION
+        .with(|session| session.borrow().clone())
+        .unwrap_or_else(process_session)
+}
+
+/// Attribute what the current thread logs to `session`, or to the process
+/// session again with `None`. Threads start with the process session, so
+/// spawners pass theirs on with `set_thread_session(Some(session_id()))`.
+pub fn set_thread_session(session: Option<String>) {
+    THREAD_SESSION.with(|current| *current.borrow_mut() = session);
+}
+
+fn process_session() -> String {
+    PROCESS_SESSION
+        .get_or_init(|| match std::env::var(SESSION_ENV) {
+            Ok(id) if !id.trim().is_empty() => id.trim().to_string(),
+            _ => new_session_id(),
+        })
+        .clone()
+}
+
+/// 16 random hex digits.
+fn new_session_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(std::process::id());
+    format!("{:016x}", hasher.finish())
+}
+
+/// Set up logging to stderr, or to `file` if given and it can be opened.
+/// `target` is named in every JSON record.
+pub fn init_logging(
+    verbose: bool,
+    trace: bool,
+    format: LogFormat,
+    file: Option<&LogFile>,
+    target: &str,
+) {
+    if trace {
+        std::env::set_var("RUST_LOG", "baton=trace");
+    } else if verbose {
         std::env::set_var("RUST_LOG", "baton=debug");
     }
+
+    let mut builder = env_logger::Builder::from_default_env();
+    if format == LogFormat::Json {
+        let target = target.to_string();
+        builder.format(move |buf, record| {
+            let timestamp = buf.timestamp_micros().to_string();
+            writeln!(buf, "{}", json_record(record, &timestamp, &target))
+        });
+    }
+    if let Some(file) = file {
+        let path = file.expand_path();
+        match RotatingFile::open(&path, file.max_size, file.keep) {
+            Ok(file) => {
+                builder.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            Err(e) => eprintln!(
+                "baton: cannot open log file {}: {}; logging to stderr",
+                path.display(),
+                e
+            ),
+        }
+    }
     // Intentionally ignore: try_init fails if called twice (e.g., in tests),
     // which is harmless and expected.
-    let _ = env_logger::try_init();
+    let _ = builder.try_init();
+}
+
+/// Log file that is renamed to `<path>.1` once it reaches `max_size`,
+/// shifting older files up to `<path>.<keep>`. Each write is taken to be a
+/// whole record, as env_logger writes them, and is never split across
+/// files. Writes never fail: errors are reported on stderr once, and the file
+/// is reopened on the next record.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    keep: u32,
+    file: Option<File>,
+    size: u64,
+    reported: bool,
+}
+
+impl RotatingFile {
+    /// Open `path` for appending.
+    pub fn open(path: &Path, max_size: u64, keep: u32) -> io::Result<Self> {
+        let mut file = Self {
+            path: path.to_path_buf(),
+            max_size,
+            keep,
+            file: None,
+            size: 0,
+            reported: false,
+        };
+        file.reopen()?;
+        Ok(file)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.file = Some(file);
+        Ok(())
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file = None;
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.reopen()
+    }
+
+    fn write_record(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.file.is_none() {
+            self.reopen()?;
+        }
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                // Keep appending to the full file rather than lose records.
+                self.report(&e);
+                if self.file.is_none() {
+                    self.reopen()?;
+                }
+            }
+        }
+        let file = self.file.as_mut().expect("opened above");
+        file.write_all(buf)?;
+        self.size += buf.len() as u64;
+        Ok(())
+    }
+
+    fn report(&mut self, e: &io::Error) {
+        if !self.reported {
+            self.reported = true;
+            eprintln!("baton: log file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Err(e) = self.write_record(buf) {
+            self.report(&e);
+            self.file = None;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(file) = self.file.as_mut() {
+            // Intentionally ignore: a failed flush must not reach the relay.
+            let _ = file.flush();
+        }
+        Ok(())
+    }
+}
+
+/// A record as one line of JSON. Key-values logged with the record, such as
+/// `direction` and `event`, become fields of their own.
+fn json_record(record: &log::Record<'_>, timestamp: &str, target: &str) -> String {
+    let mut fields = Map::new();
+    fields.insert("ts".into(), timestamp.into());
+    fields.insert("level".into(), record.level().as_str().into());
+    fields.insert("session".into(), session_id().into());
+    fields.insert("target".into(), target.into());
+    fields.insert("pid".into(), std::process::id().into());
+    fields.insert("direction".into(), Value::Null);
+    fields.insert("event".into(), Value::Null);
+    fields.insert("module".into(), record.target().into());
+    fields.insert("message".into(), record.args().to_string().into());
+
+    struct Fields<'a>(&'a mut Map<String, Value>);
+
+    impl<'kvs> log::kv::VisitSource<'kvs> for Fields<'_> {
+        fn visit_pair(
+            &mut self,
+            key: log::kv::Key<'kvs>,
+            value: log::kv::Value<'kvs>,
+        ) -> Result<(), log::kv::Error> {
+            let value = match value.to_u64() {
+                Some(n) => n.into(),
+                None => value.to_string().into(),
+            };
+            self.0.insert(key.as_str().to_string(), value);
+            Ok(())
+        }
+    }
+
+    // Intentionally ignore: visiting an in-memory record cannot fail.
+    let _ = record.key_values().visit(&mut Fields(&mut fields));
+    Value::Object(fields).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_json_record() {
+        let kvs = [("direction", "stdin_to_pipe"), ("event", "read")];
+        let bytes = [("bytes", 5u64)];
+        let record = log::Record::builder()
+            .args(format_args!("Read 5 bytes from stdin"))
+            .level(log::Level::Debug)
+            .target("baton::relay")
+            .key_values(&kvs)
+            .build();
+        set_thread_session(Some("abc.1".to_string()));
+        let line = json_record(&record, "2026-01-02T03:04:05.000006Z", "tcp://host:2375");
+        set_thread_session(None);
+
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["ts"], "2026-01-02T03:04:05.000006Z");
+        assert_eq!(value["level"], "DEBUG");
+        assert_eq!(value["session"], "abc.1");
+        assert_eq!(value["target"], "tcp://host:2375");
+        assert_eq!(value["pid"], std::process::id());
+        assert_eq!(value["direction"], "stdin_to_pipe");
+        assert_eq!(value["event"], "read");
+        assert_eq!(value["module"], "baton::relay");
+        assert_eq!(value["message"], "Read 5 bytes from stdin");
+        assert!(!line.contains('\n'));
+
+        let record = log::Record::builder()
+            .args(format_args!("n"))
+            .key_values(&bytes)
+            .build();
+        let value: Value = serde_json::from_str(&json_record(&record, "t", "x")).unwrap();
+        assert_eq!(value["bytes"], 5);
+        assert_eq!(value["direction"], Value::Null);
+        assert_eq!(value["session"], session_id());
+    }
+
+    #[test]
+    fn test_thread_session() {
+        let process = session_id();
+        assert_eq!(process.len(), 16);
+        set_thread_session(Some(format!("{}.7", process)));
+        assert_eq!(session_id(), format!("{}.7", process));
+        std::thread::spawn(move || assert_eq!(session_id(), process))
+            .join()
+            .unwrap();
+        set_thread_session(None);
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baton.log");
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        for i in 0..10 {
+            let record = format!("record {} {}\n", i, "x".repeat(20));
+            file.write_all(record.as_bytes()).unwrap();
+        }
+
+        let read = |path: &Path| fs::read_to_string(path).unwrap();
+        let rotated = |n: u32| {
+            let mut name = path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        assert!(read(&path).starts_with("record 9 "));
+        assert!(read(&rotated(1)).starts_with("record 6 "));
+        assert!(read(&rotated(2)).starts_with("record 3 "));
+        assert!(!rotated(3).exists());
+        for path in [path.clone(), rotated(1), rotated(2)] {
+            assert!(read(&path).len() <= 100);
+        }
+
+        // Appends to an existing file and counts what is already there.
+        drop(file);
+        let mut file = RotatingFile::open(&path, 100, 2).unwrap();
+        file.write_all(&[b'y'; 80]).unwrap();
+        assert!(read(&rotated(1)).starts_with("record 9 "));
+    }
+
+    #[test]
+    fn test_rotating_file_failures_are_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("baton.log");
+        assert!(RotatingFile::open(&path, 100, 2).is_err());
+
+        fs::create_dir(dir.path().join("logs")).unwrap();
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        fs::remove_dir_all(dir.path().join("logs")).unwrap();
+        // Rotation and reopening fail; the writes still succeed.
+        for _ in 0..3 {
+            file.write_all(b"record\n").unwrap();
+            file.flush().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_log_file_expand_path() {
+        let file = LogFile {
+            path: PathBuf::from("/tmp/baton-{session}-{pid}.log"),
+            max_size: DEFAULT_LOG_MAX_SIZE,
+            keep: DEFAULT_LOG_KEEP,
+        };
+        assert_eq!(
+            file.expand_path(),
+            PathBuf::from(format!(
+                "/tmp/baton-{}-{}.log",
+                process_session(),
+                std::process::id()
+            ))
+        );
+    }
 }
@@ -1,30 +1,54 @@
 #![deny(warnings)]
 #![deny(clippy::all)]
 
-mod assuan;
+use baton::assuan::{AssuanClient, AssuanEndpoint};
+use baton::capture::{Capture, CapturingReader, RecordFormat};
+use baton::cygwin::CygwinEndpoint;
+use baton::decode::{self, DecodeCapture, Protocol, RedactingCapture, TraceCapture};
+use baton::endpoint::{Endpoint, ExecEndpoint, TcpEndpoint};
+use baton::errors::BatonError;
+use baton::gpg_status::GpgStatus;
+use baton::pcapng::{self, PcapngWriter};
+use baton::relay::{Direction, RelayOptions};
+use baton::replay::Side;
+use baton::stats::RelayStats;
+use baton::target::Target;
+use baton::transcript::{self, TranscriptWriter};
+use baton::{cli, logging, mux, relay, replay};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
 
-use baton::{cli, logging, relay};
-
-#[cfg(windows)]
 fn main() {
     if let Err(e) = real_main() {
         eprintln!("baton error: {e}");
-        std::process::exit(1);
+        let code = match e.downcast_ref::<BatonError>() {
+            Some(e) => e.exit_code(),
+            None => 1,
+        };
+        std::process::exit(code);
     }
 }
 
-#[cfg(not(windows))]
-fn main() {
-    eprintln!("baton is Windows-only (target a Windows triple to run).");
-    std::process::exit(1);
-}
-
-#[cfg(windows)]
 fn real_main() -> anyhow::Result<()> {
-    use baton::win::{hide_console_window, NamedPipe};
+    match cli::parse() {
+        cli::Command::Relay(config) => relay_stdio(config),
+        cli::Command::Listen(listen) => listen_socket(listen),
+        cli::Command::Replay(replay) => replay_transcript(replay),
+        cli::Command::Inspect(inspect) => inspect_transcript(inspect),
+        cli::Command::GpgStatus(status) => gpg_status(status),
+    }
+}
 
-    let config = cli::parse();
-    logging::init_logging(config.verbose);
+fn relay_stdio(config: cli::Config) -> anyhow::Result<()> {
+    let target = config.target.to_string();
+    logging::init_logging(
+        config.verbose,
+        config.trace,
+        config.log_format,
+        config.log_file.as_ref(),
+        &target,
+    );
 
     if config.bg {
         hide_console_window();
@@ -32,54 +56,293 @@ fn real_main() -> anyhow::Result<()> {
 
     log::debug!("Config: {:?}", config);
 
-    if config.assuan {
-        let stream = assuan::connect_assuan(&config)?;
-        let reader = stream.try_clone()?;
-        let writer = stream;
-        relay::run_relay(reader, writer, &config)?;
-    } else {
-        let pipe = NamedPipe::connect(&config)?;
-        let pool = pipe.pool();
-        let handle = pipe.handle();
+    if config.mux {
+        return serve_mux(&config);
+    }
 
-        let reader = PipeReader {
-            handle,
-            pool: pool.clone(),
-        };
-        let writer = PipeWriter { handle, pool };
+    let endpoint = endpoint_for(&config.target, config.verify_greeting)?;
+    let (reader, writer) = endpoint.connect(&config.retry)?;
+    let opts = RelayOptions::from(&config);
+    let outcome = match captures_for(&config)? {
+        Some(capture) => relay::run_relay_between(
+            CapturingReader::new(io::stdin(), Direction::StdinToPipe, capture.clone()),
+            ClosingStdout(io::stdout()),
+            CapturingReader::new(reader, Direction::PipeToStdout, capture),
+            writer,
+            opts,
+        ),
+        None => relay::run_relay_between(
+            io::stdin(),
+            ClosingStdout(io::stdout()),
+            reader,
+            writer,
+            opts,
+        ),
+    };
 
-        relay::run_relay(reader, writer, &config)?;
+    log::debug!("Relay finished: {:?}", outcome);
+    if let Some(format) = config.stats {
+        let stats = RelayStats::new(logging::session_id(), target, &outcome);
+        if let Err(e) = stats.emit(format, config.stats_file.as_deref()) {
+            log::warn!("Failed to write stats: {}", e);
+        }
+    }
+    let termination = outcome.termination;
+    if let Some(e) = outcome.into_error() {
+        return Err(e.into());
+    }
+    if termination.is_limit() {
+        return Err(BatonError::SessionLimit(termination).into());
     }
 
     Ok(())
 }
 
-#[cfg(windows)]
-struct PipeReader {
-    handle: baton::win::overlapped::OverlappedHandle,
-    pool: std::sync::Arc<baton::win::overlapped::EventPool>,
+/// The process's stdout, closed when dropped. The relay drops its writers as
+/// soon as their direction finishes, so whoever reads stdout sees EOF once
+/// the pipe side has finished, even while stdin is still open.
+struct ClosingStdout(io::Stdout);
+
+impl Write for ClosingStdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
 }
 
-#[cfg(windows)]
-impl std::io::Read for PipeReader {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        baton::win::overlapped::async_read(self.handle, buf, &self.pool)
+impl Drop for ClosingStdout {
+    fn drop(&mut self) {
+        // Intentionally ignore: the reader may already be gone.
+        let _ = self.0.flush();
+        close_stdout();
     }
 }
 
-#[cfg(windows)]
-struct PipeWriter {
-    handle: baton::win::overlapped::OverlappedHandle,
-    pool: std::sync::Arc<baton::win::overlapped::EventPool>,
+/// Point stdout at /dev/null rather than closing it outright, so that file
+/// descriptor 1 is not handed out again by the next `open`.
+#[cfg(unix)]
+fn close_stdout() {
+    use std::os::fd::AsRawFd;
+
+    if let Ok(null) = std::fs::OpenOptions::new().write(true).open("/dev/null") {
+        unsafe {
+            libc::dup2(null.as_raw_fd(), libc::STDOUT_FILENO);
+        }
+    }
 }
 
 #[cfg(windows)]
-impl std::io::Write for PipeWriter {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        baton::win::overlapped::async_write(self.handle, buf, &self.pool)
+fn close_stdout() {
+    baton::win::console::close_stdout();
+}
+
+/// What `--record`, `--decode` and `-vv` observe the relay with, if
+/// anything. Only what is logged is redacted: a recording must be faithful
+/// to be replayed.
+fn captures_for(config: &cli::Config) -> anyhow::Result<Option<Arc<dyn Capture>>> {
+    let mut captures: Vec<Arc<dyn Capture>> = Vec::new();
+    if let Some(path) = &config.record {
+        captures.push(open_recording(path, config)?);
+    }
+
+    let mut logged: Vec<Arc<dyn Capture>> = Vec::new();
+    if let Some(protocol) = config.decode {
+        logged.push(Arc::new(DecodeCapture::new(protocol)));
+    }
+    if config.trace {
+        logged.push(Arc::new(TraceCapture::new(config.trace_limit)));
+    }
+    if let Some(logged) = combine(logged) {
+        let protocol = config
+            .decode
+            .unwrap_or_else(|| Protocol::guess(&config.target.to_string()));
+        captures.push(Arc::new(RedactingCapture::new(protocol, logged)));
+    }
+    Ok(combine(captures))
+}
+
+fn combine(mut captures: Vec<Arc<dyn Capture>>) -> Option<Arc<dyn Capture>> {
+    match captures.len() {
+        0 => None,
+        1 => captures.pop(),
+        _ => Some(Arc::new(captures)),
+    }
+}
+
+fn open_recording(path: &Path, config: &cli::Config) -> anyhow::Result<Arc<dyn Capture>> {
+    let label = config.target.to_string();
+    let recording: io::Result<Arc<dyn Capture>> = match config.record_format {
+        RecordFormat::Transcript => TranscriptWriter::create(path, &label, config.record_limit)
+            .map(|transcript| Arc::new(transcript) as _),
+        RecordFormat::Pcapng => {
+            let command_line: Vec<String> = std::env::args().collect();
+            let comment = format!("{}\n{}", label, command_line.join(" "));
+            let port = match &config.target {
+                Target::Tcp(addr) => addr.rsplit(':').next().and_then(|port| port.parse().ok()),
+                _ => None,
+            };
+            let port = port.unwrap_or(pcapng::DEFAULT_SERVER_PORT);
+            PcapngWriter::create(path, &label, &comment, port, config.record_limit)
+                .map(|capture| Arc::new(capture) as _)
+        }
+    };
+    recording.map_err(|e| anyhow::anyhow!("cannot record to {}: {}", path.display(), e))
+}
+
+/// Serve channels multiplexed over stdin/stdout until the other side hangs up.
+fn serve_mux(config: &cli::Config) -> anyhow::Result<()> {
+    let endpoint: Arc<dyn Endpoint> =
+        Arc::from(endpoint_for(&config.target, config.verify_greeting)?);
+    let session = mux::Session::new(io::stdin(), io::stdout(), mux::Role::Server)?;
+    mux::serve(
+        &session,
+        endpoint,
+        config.retry.clone(),
+        RelayOptions::from(config),
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn listen_socket(listen: cli::ListenConfig) -> anyhow::Result<()> {
+    use baton::listen::SocketListener;
+
+    let config = listen.relay;
+    let target = config.target.to_string();
+    logging::init_logging(
+        config.verbose,
+        config.trace,
+        config.log_format,
+        config.log_file.as_ref(),
+        &target,
+    );
+    log::debug!("Listen config: {:?}", config);
+
+    let endpoint = endpoint_for(&config.target, config.verify_greeting)?;
+    let endpoint: Arc<dyn Endpoint> = if config.mux {
+        Arc::new(mux::MuxEndpoint::new(endpoint))
+    } else {
+        Arc::from(endpoint)
+    };
+    let listener = SocketListener::bind(&listen.socket, listen.socket_mode)?;
+    listener.stopper().stop_on_signals()?;
+    listener.serve(endpoint, config.retry.clone(), RelayOptions::from(&config))?;
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen_socket(_listen: cli::ListenConfig) -> anyhow::Result<()> {
+    anyhow::bail!("baton listen is only supported on Unix")
+}
+
+/// Replay the client side of a transcript and print how the target's
+/// responses compare with the recording.
+fn replay_transcript(config: cli::ReplayConfig) -> anyhow::Result<()> {
+    let (header, records) = read_transcript(&config.transcript)?;
+    let target = match &config.target {
+        Some(target) => target.clone(),
+        None => Target::parse(&header.label, false)?,
+    };
+    let label = target.to_string();
+    logging::init_logging(
+        config.verbose,
+        false,
+        config.log_format,
+        config.log_file.as_ref(),
+        &label,
+    );
+    log::debug!("Replay config: {:?}", config);
+    log::debug!("Replaying {} records against {}", records.len(), target);
+
+    let (reader, writer) = endpoint_for(&target, config.verify_greeting)?.connect(&config.retry)?;
+    let report = replay::replay(&records, Side::Client, reader, writer, &config.options);
+
+    println!("{}", report);
+    if !report.is_clean() {
+        anyhow::bail!("responses differ from the recording");
+    }
+
+    Ok(())
+}
+
+/// Print a transcript as decoded protocol messages.
+fn inspect_transcript(config: cli::InspectConfig) -> anyhow::Result<()> {
+    let (header, records) = read_transcript(&config.transcript)?;
+    let protocol = config
+        .protocol
+        .unwrap_or_else(|| Protocol::guess(&header.label));
+    println!(
+        "{} ({:?}), {} records",
+        header.label,
+        protocol,
+        records.len()
+    );
+
+    let mut decoder = protocol.decoder();
+    decode::inspect(&records, decoder.as_mut(), &mut io::stdout().lock())?;
+
+    Ok(())
+}
+
+/// Report on the gpg-agent behind an Assuan socket file.
+fn gpg_status(config: cli::GpgStatusConfig) -> anyhow::Result<()> {
+    logging::init_logging(
+        config.verbose,
+        false,
+        config.log_format,
+        config.log_file.as_ref(),
+        &format!("assuan://{}", config.socket),
+    );
+    log::debug!("gpg-status config: {:?}", config);
+
+    let mut client = AssuanClient::connect(&config.socket, &config.retry, config.timeout)?;
+    let status = GpgStatus::query(config.socket.clone(), &mut client);
+
+    print!("{}", status.format(config.format));
+    if !status.alive {
+        anyhow::bail!("gpg-agent did not answer NOP");
     }
 
-    fn flush(&mut self) -> std::io::Result<()> {
-        Ok(())
+    Ok(())
+}
+
+fn read_transcript(path: &Path) -> anyhow::Result<(transcript::Header, Vec<transcript::Record>)> {
+    match transcript::read_file(path) {
+        Ok(transcript) => Ok(transcript),
+        Err(e) => anyhow::bail!("cannot read {}: {}", path.display(), e),
     }
 }
+
+fn endpoint_for(target: &Target, verify_greeting: bool) -> anyhow::Result<Box<dyn Endpoint>> {
+    Ok(match target {
+        #[cfg(windows)]
+        Target::NamedPipe(name) => Box::new(baton::win::NamedPipeEndpoint::new(name)),
+        #[cfg(not(windows))]
+        Target::NamedPipe(_) => anyhow::bail!("named pipes are only supported on Windows"),
+        Target::Assuan(path) => {
+            Box::new(AssuanEndpoint::new(path).with_verify_greeting(verify_greeting))
+        }
+        Target::Cygwin(path) => Box::new(CygwinEndpoint::new(path)),
+        Target::Tcp(addr) => Box::new(TcpEndpoint::new(addr)),
+        #[cfg(unix)]
+        Target::Unix(path) => Box::new(baton::endpoint::UnixEndpoint::new(path)),
+        #[cfg(not(unix))]
+        Target::Unix(_) => anyhow::bail!("unix:// targets are not supported on Windows"),
+        Target::Exec { program, args } => Box::new(ExecEndpoint::new(program, args.clone())),
+    })
+}
+
+#[cfg(windows)]
+fn hide_console_window() {
+    baton::win::hide_console_window();
+}
+
+#[cfg(not(windows))]
+fn hide_console_window() {
+    log::debug!("--bg has no effect outside Windows");
+}
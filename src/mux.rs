@@ -0,0 +1,1127 @@
+//! Stream multiplexing over a single byte stream (`--mux`).
+//!
+//! Starting a Windows process for every connection costs hundreds of
+//! milliseconds, so instead the Linux side keeps one `baton.exe --mux`
+//! running and opens a logical channel over its stdin/stdout for each client.
+//! The Windows side connects every channel to its own instance of the target
+//! and relays it with the usual `-s`/`--ei`/`--ep` semantics.
+//!
+//! Every frame is a 9-byte header followed by the payload:
+//!
+//! | Field | Size | Notes |
+//! |-------|------|-------|
+//! | channel | u32, big-endian | 0 is reserved for `Hello` |
+//! | kind | u8 | See [`FrameKind`] |
+//! | length | u32, big-endian | At most [`MAX_FRAME_PAYLOAD`] |
+//!
+//! Both sides send `Hello` first, so a peer that was started without `--mux`
+//! is detected instead of being fed frames as if they were data.
+//!
+//! Flow control is credit based: a sender may have at most
+//! [`INITIAL_WINDOW`] unacknowledged bytes in flight per channel, and the
+//! receiver hands credit back with `WindowUpdate` as the bytes are consumed.
+//! A slow pipe therefore only stalls its own channel, never the shared stream.
+
+use crate::endpoint::{Endpoint, EndpointReader, EndpointWriter};
+use crate::errors::BatonError;
+use crate::relay::{run_relay_between, RelayOptions};
+use crate::retry::RetryPolicy;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+/// Payload of the `Hello` frame; bump the version on incompatible changes.
+pub const PROTOCOL: &[u8] = b"baton-mux/1";
+pub const MAX_FRAME_PAYLOAD: usize = 32 * 1024;
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+const HEADER_LEN: usize = 9;
+// Return credit in batches rather than after every read.
+const WINDOW_UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+const _: () = assert!(MAX_FRAME_PAYLOAD as u32 <= INITIAL_WINDOW);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Protocol greeting, sent once by each side on channel 0.
+    Hello,
+    /// Open a new channel (client to server).
+    Open,
+    /// The server could not connect the channel; payload is the reason.
+    OpenFailed,
+    Data,
+    /// Grant the peer more send credit; payload is a big-endian u32.
+    WindowUpdate,
+    /// The sender will not send more data on this channel.
+    Eof,
+    /// The channel is gone in both directions.
+    Close,
+}
+
+impl FrameKind {
+    fn to_byte(self) -> u8 {
+        match self {
+            FrameKind::Hello => 0,
+            FrameKind::Open => 1,
+            FrameKind::OpenFailed => 2,
+            FrameKind::Data => 3,
+            FrameKind::WindowUpdate => 4,
+            FrameKind::Eof => 5,
+            FrameKind::Close => 6,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        Some(match b {
+            0 => FrameKind::Hello,
+            1 => FrameKind::Open,
+            2 => FrameKind::OpenFailed,
+            3 => FrameKind::Data,
+            4 => FrameKind::WindowUpdate,
+            5 => FrameKind::Eof,
+            6 => FrameKind::Close,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub channel: u32,
+    pub kind: FrameKind,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(channel: u32, kind: FrameKind, payload: Vec<u8>) -> Self {
+        Self {
+            channel,
+            kind,
+            payload,
+        }
+    }
+
+    /// A frame without payload.
+    pub fn control(channel: u32, kind: FrameKind) -> Self {
+        Self::new(channel, kind, Vec::new())
+    }
+
+    pub fn window_update(channel: u32, credit: u32) -> Self {
+        Self::new(
+            channel,
+            FrameKind::WindowUpdate,
+            credit.to_be_bytes().to_vec(),
+        )
+    }
+
+    pub fn write_to<W: Write + ?Sized>(&self, writer: &mut W) -> io::Result<()> {
+        if self.payload.len() > MAX_FRAME_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "mux frame payload too large",
+            ));
+        }
+
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.extend_from_slice(&self.channel.to_be_bytes());
+        buf.push(self.kind.to_byte());
+        buf.extend_from_slice(&(self.payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        writer.write_all(&buf)?;
+        writer.flush()
+    }
+
+    /// Read one frame. Returns `None` on a clean EOF between frames.
+    pub fn read_from<R: Read + ?Sized>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut header = [0u8; HEADER_LEN];
+        let mut filled = 0;
+        while filled < HEADER_LEN {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let channel = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let kind = FrameKind::from_byte(header[4])
+            .ok_or_else(|| invalid_data(format!("unknown mux frame kind {}", header[4])))?;
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]) as usize;
+        if len > MAX_FRAME_PAYLOAD {
+            return Err(invalid_data(format!(
+                "mux frame of {} bytes is too large",
+                len
+            )));
+        }
+
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(Self::new(channel, kind, payload)))
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Which end of the stream a [`Session`] is. Clients open channels, servers
+/// accept them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+#[derive(Default)]
+struct ChannelState {
+    recv: VecDeque<u8>,
+    /// Bytes consumed locally that have not been credited back yet.
+    unacked: u32,
+    send_window: u32,
+    remote_eof: bool,
+    /// The peer closed or refused the channel, or the session ended.
+    peer_closed: bool,
+    local_closed: bool,
+    error: Option<String>,
+}
+
+struct ChannelShared {
+    id: u32,
+    state: Mutex<ChannelState>,
+    changed: Condvar,
+}
+
+impl ChannelShared {
+    fn new(id: u32) -> Arc<Self> {
+        Arc::new(Self {
+            id,
+            state: Mutex::new(ChannelState {
+                send_window: INITIAL_WINDOW,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn update(&self, f: impl FnOnce(&mut ChannelState)) {
+        f(&mut self.state.lock().unwrap());
+        self.changed.notify_all();
+    }
+}
+
+struct SessionInner {
+    role: Role,
+    // Taken on drop so the peer sees EOF even while channels are still open.
+    writer: Mutex<Option<Box<dyn Write + Send>>>,
+    channels: Mutex<HashMap<u32, Arc<ChannelShared>>>,
+    next_id: AtomicU32,
+    alive: AtomicBool,
+}
+
+impl SessionInner {
+    fn send(&self, frame: &Frame) -> io::Result<()> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => frame.write_to(writer),
+            None => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn channel(&self, id: u32) -> Option<Arc<ChannelShared>> {
+        self.channels.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Mark the session dead and fail every channel still open.
+    fn terminate(&self, reason: &str) {
+        self.alive.store(false, Ordering::SeqCst);
+        let channels: Vec<_> = self.channels.lock().unwrap().drain().collect();
+        for (_, channel) in channels {
+            channel.update(|s| {
+                s.peer_closed = true;
+                s.error.get_or_insert_with(|| reason.to_string());
+            });
+        }
+    }
+}
+
+/// One end of a multiplexed stream.
+pub struct Session {
+    inner: Arc<SessionInner>,
+    incoming: Mutex<mpsc::Receiver<Channel>>,
+}
+
+impl Session {
+    /// Send the greeting and start demultiplexing `reader` on a background
+    /// thread.
+    pub fn new<R, W>(mut reader: R, writer: W, role: Role) -> io::Result<Self>
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let inner = Arc::new(SessionInner {
+            role,
+            writer: Mutex::new(Some(Box::new(writer))),
+            channels: Mutex::new(HashMap::new()),
+            next_id: AtomicU32::new(1),
+            alive: AtomicBool::new(true),
+        });
+        inner.send(&Frame::new(0, FrameKind::Hello, PROTOCOL.to_vec()))?;
+
+        let (tx, rx) = mpsc::channel();
+        {
+            let inner = Arc::clone(&inner);
+            thread::spawn(move || {
+                let reason = match demux(&inner, &mut reader, &tx) {
+                    Ok(()) => "mux session ended".to_string(),
+                    Err(e) => {
+                        log::warn!("Mux session failed: {}", e);
+                        format!("mux session failed: {}", e)
+                    }
+                };
+                inner.terminate(&reason);
+            });
+        }
+
+        Ok(Self {
+            inner,
+            incoming: Mutex::new(rx),
+        })
+    }
+
+    pub fn is_alive(&self) -> bool {
+        self.inner.alive.load(Ordering::SeqCst)
+    }
+
+    /// Open a new channel. Data may be written right away; if the server
+    /// cannot connect the channel, reads fail with its reason.
+    pub fn open(&self) -> io::Result<Channel> {
+        if self.inner.role != Role::Client {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "only the client side opens mux channels",
+            ));
+        }
+        if !self.is_alive() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        let id = self.inner.next_id.fetch_add(1, Ordering::SeqCst);
+        let shared = ChannelShared::new(id);
+        self.inner
+            .channels
+            .lock()
+            .unwrap()
+            .insert(id, Arc::clone(&shared));
+        let channel = Channel::new(shared, Arc::clone(&self.inner));
+        self.inner.send(&Frame::control(id, FrameKind::Open))?;
+        log::debug!("Opened mux channel {}", id);
+        Ok(channel)
+    }
+
+    /// Wait for the peer to open a channel. Returns `None` once the session
+    /// has ended.
+    pub fn accept(&self) -> Option<Channel> {
+        self.incoming.lock().unwrap().recv().ok()
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Closing our half tells the peer to wind down, which in turn ends
+        // the demultiplexing thread.
+        self.inner.writer.lock().unwrap().take();
+    }
+}
+
+/// Read frames until the stream ends, routing each one to its channel.
+fn demux<R: Read>(
+    inner: &Arc<SessionInner>,
+    reader: &mut R,
+    incoming: &mpsc::Sender<Channel>,
+) -> io::Result<()> {
+    match Frame::read_from(reader)? {
+        Some(frame) if frame.kind == FrameKind::Hello && frame.payload == PROTOCOL => {}
+        Some(_) => {
+            return Err(invalid_data(
+                "peer does not speak the baton mux protocol (is --mux missing?)",
+            ))
+        }
+        None => return Ok(()),
+    }
+
+    while let Some(frame) = Frame::read_from(reader)? {
+        let id = frame.channel;
+
+        if frame.kind == FrameKind::Open {
+            if inner.role != Role::Server || id == 0 || inner.channel(id).is_some() {
+                log::warn!("Refusing unexpected open of mux channel {}", id);
+                inner.send(&Frame::new(
+                    id,
+                    FrameKind::OpenFailed,
+                    b"unexpected open".to_vec(),
+                ))?;
+                continue;
+            }
+            let shared = ChannelShared::new(id);
+            inner
+                .channels
+                .lock()
+                .unwrap()
+                .insert(id, Arc::clone(&shared));
+            log::debug!("Peer opened mux channel {}", id);
+            // Intentionally ignore: nobody accepting means the session is
+            // being torn down, and dropping the channel closes it.
+            let _ = incoming.send(Channel::new(shared, Arc::clone(inner)));
+            continue;
+        }
+
+        // Frames for a channel we already closed can still be in flight.
+        let Some(channel) = inner.channel(id) else {
+            log::debug!("Ignoring {:?} for unknown mux channel {}", frame.kind, id);
+            continue;
+        };
+
+        match frame.kind {
+            FrameKind::Data => {
+                let mut state = channel.state.lock().unwrap();
+                if state.recv.len() + frame.payload.len() > INITIAL_WINDOW as usize {
+                    return Err(invalid_data(format!(
+                        "peer overran the window of mux channel {}",
+                        id
+                    )));
+                }
+                state.recv.extend(frame.payload);
+                drop(state);
+                channel.changed.notify_all();
+            }
+            FrameKind::WindowUpdate => {
+                let credit: [u8; 4] = frame
+                    .payload
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| invalid_data("malformed mux window update"))?;
+                let credit = u32::from_be_bytes(credit);
+                channel.update(|s| s.send_window = s.send_window.saturating_add(credit));
+            }
+            FrameKind::Eof => channel.update(|s| s.remote_eof = true),
+            FrameKind::Close | FrameKind::OpenFailed => {
+                let reason = (frame.kind == FrameKind::OpenFailed)
+                    .then(|| String::from_utf8_lossy(&frame.payload).into_owned());
+                log::debug!("Peer closed mux channel {}", id);
+                inner.channels.lock().unwrap().remove(&id);
+                channel.update(|s| {
+                    s.peer_closed = true;
+                    s.error = reason;
+                });
+            }
+            FrameKind::Hello | FrameKind::Open => {
+                log::debug!("Ignoring unexpected {:?} on mux channel {}", frame.kind, id);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Closes the channel when the last handle to it goes away.
+struct ChannelHandle {
+    shared: Arc<ChannelShared>,
+    session: Arc<SessionInner>,
+}
+
+impl ChannelHandle {
+    fn send(&self, frame: &Frame) -> io::Result<()> {
+        self.session.send(frame)
+    }
+
+    fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.local_closed {
+            return;
+        }
+        state.local_closed = true;
+        let peer_closed = state.peer_closed;
+        drop(state);
+        self.shared.changed.notify_all();
+
+        self.session
+            .channels
+            .lock()
+            .unwrap()
+            .remove(&self.shared.id);
+        if !peer_closed {
+            log::debug!("Closing mux channel {}", self.shared.id);
+            // Intentionally ignore: if the session is gone the channel is
+            // closed anyway.
+            let _ = self.send(&Frame::control(self.shared.id, FrameKind::Close));
+        }
+    }
+}
+
+impl Drop for ChannelHandle {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A logical connection within a [`Session`].
+pub struct Channel {
+    handle: Arc<ChannelHandle>,
+}
+
+impl Channel {
+    fn new(shared: Arc<ChannelShared>, session: Arc<SessionInner>) -> Self {
+        Self {
+            handle: Arc::new(ChannelHandle { shared, session }),
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.handle.shared.id
+    }
+
+    /// Split into halves that can be driven from separate threads. Dropping
+    /// the writer sends EOF; the channel closes once both halves and every
+    /// [`ChannelCloser`] are gone.
+    pub fn split(self) -> (ChannelReader, ChannelWriter) {
+        (
+            ChannelReader {
+                handle: Arc::clone(&self.handle),
+            },
+            ChannelWriter {
+                handle: self.handle,
+            },
+        )
+    }
+
+    /// Handle for closing the channel while its halves are still in use.
+    pub fn closer(&self) -> ChannelCloser {
+        ChannelCloser {
+            handle: Arc::clone(&self.handle),
+        }
+    }
+
+    /// Tell the client this channel could not be connected.
+    pub fn reject(self, reason: &str) {
+        let reason = reason.as_bytes();
+        let reason = &reason[..reason.len().min(MAX_FRAME_PAYLOAD)];
+        self.handle.shared.update(|s| s.peer_closed = true);
+        // Intentionally ignore: without a session there is nobody to tell.
+        let _ = self.handle.send(&Frame::new(
+            self.id(),
+            FrameKind::OpenFailed,
+            reason.to_vec(),
+        ));
+    }
+}
+
+pub struct ChannelCloser {
+    handle: Arc<ChannelHandle>,
+}
+
+impl ChannelCloser {
+    /// Close both directions now. Blocked reads return EOF and writes fail.
+    pub fn close(&self) {
+        self.handle.close();
+    }
+}
+
+pub struct ChannelReader {
+    handle: Arc<ChannelHandle>,
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let shared = &self.handle.shared;
+        let mut state = shared.state.lock().unwrap();
+        while state.recv.is_empty() {
+            if state.remote_eof || state.local_closed {
+                return Ok(0);
+            }
+            if state.peer_closed {
+                return match &state.error {
+                    Some(reason) => Err(io::Error::new(
+                        io::ErrorKind::ConnectionAborted,
+                        reason.clone(),
+                    )),
+                    None => Ok(0),
+                };
+            }
+            state = shared.changed.wait(state).unwrap();
+        }
+
+        let n = buf.len().min(state.recv.len());
+        for (dst, src) in buf.iter_mut().zip(state.recv.drain(..n)) {
+            *dst = src;
+        }
+
+        state.unacked += n as u32;
+        if state.unacked >= WINDOW_UPDATE_THRESHOLD && !state.peer_closed {
+            let credit = std::mem::take(&mut state.unacked);
+            drop(state);
+            // Intentionally ignore: a dead session fails the next read instead.
+            let _ = self.handle.send(&Frame::window_update(shared.id, credit));
+        }
+
+        Ok(n)
+    }
+}
+
+pub struct ChannelWriter {
+    handle: Arc<ChannelHandle>,
+}
+
+impl Write for ChannelWriter {
+    /// Blocks while the peer has not granted enough credit. Zero-length
+    /// writes (`-s`) carry no meaning on a channel and are ignored.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let shared = &self.handle.shared;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.peer_closed || state.local_closed {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            if state.send_window > 0 {
+                break;
+            }
+            state = shared.changed.wait(state).unwrap();
+        }
+
+        let n = buf
+            .len()
+            .min(state.send_window as usize)
+            .min(MAX_FRAME_PAYLOAD);
+        state.send_window -= n as u32;
+        drop(state);
+
+        self.handle
+            .send(&Frame::new(shared.id, FrameKind::Data, buf[..n].to_vec()))?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ChannelWriter {
+    fn drop(&mut self) {
+        let state = self.handle.shared.state.lock().unwrap();
+        if state.peer_closed || state.local_closed {
+            return;
+        }
+        drop(state);
+        let _ = self
+            .handle
+            .send(&Frame::control(self.handle.shared.id, FrameKind::Eof));
+    }
+}
+
+/// Serve channels opened by the peer until the session ends, connecting each
+/// one to its own instance of `endpoint`.
+pub fn serve(
+    session: &Session,
+    endpoint: Arc<dyn Endpoint>,
+    policy: RetryPolicy,
+    opts: RelayOptions,
+) {
+    while let Some(channel) = session.accept() {
+        let endpoint = Arc::clone(&endpoint);
+        let policy = policy.clone();
+        let opts = opts.clone();
+        thread::spawn(move || serve_channel(channel, endpoint.as_ref(), &policy, opts));
+    }
+    log::debug!("Mux session ended");
+}
+
+fn serve_channel(
+    channel: Channel,
+    endpoint: &dyn Endpoint,
+    policy: &RetryPolicy,
+    opts: RelayOptions,
+) {
+    let id = channel.id();
+    let (reader, writer) = match endpoint.connect(policy) {
+        Ok(halves) => halves,
+        Err(e) => {
+            log::warn!("Mux channel {} failed to connect: {}", id, e);
+            channel.reject(&e.to_string());
+            return;
+        }
+    };
+
+    // The channel plays the role of stdin/stdout, so the relay flags mean
+    // the same per channel as they do for a standalone baton.
+    let closer = channel.closer();
+    let (channel_reader, channel_writer) = channel.split();
+    let outcome = run_relay_between(channel_reader, channel_writer, reader, writer, opts);
+    log::debug!("Mux channel {} finished: {:?}", id, outcome);
+
+    // With --ei/--ep the relay may return while a direction is still
+    // blocked; closing tells the client the channel is done.
+    closer.close();
+}
+
+/// Client side of `--mux`: connects the inner endpoint once (for `exec:`
+/// targets that starts `baton.exe --mux ...`) and opens a channel over it
+/// for every connection. A new session is started if the old one has died.
+pub struct MuxEndpoint {
+    inner: Box<dyn Endpoint>,
+    session: Mutex<Option<Session>>,
+}
+
+impl MuxEndpoint {
+    pub fn new(inner: Box<dyn Endpoint>) -> Self {
+        Self {
+            inner,
+            session: Mutex::new(None),
+        }
+    }
+}
+
+impl Endpoint for MuxEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let mut session = self.session.lock().unwrap();
+        let session = match session.take() {
+            Some(existing) if existing.is_alive() => session.insert(existing),
+            _ => {
+                let (reader, writer) = self.inner.connect(policy)?;
+                log::debug!("Started mux session");
+                session.insert(Session::new(reader, writer, Role::Client)?)
+            }
+        };
+
+        let (reader, writer) = session.open()?.split();
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::EndOfInput;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    /// In-memory byte stream; the reader sees EOF once the writer is dropped.
+    struct MemWriter(mpsc::Sender<Vec<u8>>);
+
+    struct MemReader {
+        rx: mpsc::Receiver<Vec<u8>>,
+        buf: Cursor<Vec<u8>>,
+    }
+
+    fn mem_pipe() -> (MemWriter, MemReader) {
+        let (tx, rx) = mpsc::channel();
+        (
+            MemWriter(tx),
+            MemReader {
+                rx,
+                buf: Cursor::new(Vec::new()),
+            },
+        )
+    }
+
+    impl Write for MemWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for MemReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            loop {
+                let n = self.buf.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                match self.rx.recv() {
+                    Ok(data) => self.buf = Cursor::new(data),
+                    Err(_) => return Ok(0),
+                }
+            }
+        }
+    }
+
+    fn session_pair() -> (Session, Session) {
+        let (client_out, server_in) = mem_pipe();
+        let (server_out, client_in) = mem_pipe();
+        let client = Session::new(client_in, client_out, Role::Client).unwrap();
+        let server = Session::new(server_in, server_out, Role::Server).unwrap();
+        (client, server)
+    }
+
+    /// Endpoint whose every connection upper-cases what it receives.
+    struct UpperEndpoint;
+
+    impl Endpoint for UpperEndpoint {
+        fn connect(
+            &self,
+            _policy: &RetryPolicy,
+        ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+            let (to_upper, mut upper_in) = mem_pipe();
+            let (mut upper_out, from_upper) = mem_pipe();
+            thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                loop {
+                    match upper_in.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => {
+                            if upper_out.write_all(&buf[..n].to_ascii_uppercase()).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            });
+            Ok((Box::new(from_upper), Box::new(to_upper)))
+        }
+    }
+
+    /// Endpoint that swallows its input and never sends anything back.
+    #[derive(Default)]
+    struct HangingEndpoint(Mutex<Vec<MemWriter>>);
+
+    impl Endpoint for HangingEndpoint {
+        fn connect(
+            &self,
+            _policy: &RetryPolicy,
+        ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+            let (writer, reader) = mem_pipe();
+            self.0.lock().unwrap().push(writer);
+            Ok((Box::new(reader), Box::new(io::sink())))
+        }
+    }
+
+    struct FailingEndpoint;
+
+    impl Endpoint for FailingEndpoint {
+        fn connect(
+            &self,
+            _policy: &RetryPolicy,
+        ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+            Err(BatonError::InvalidTarget("nothing here".to_string()))
+        }
+    }
+
+    fn serve_in_background(server: Session, endpoint: Arc<dyn Endpoint>) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            serve(
+                &server,
+                endpoint,
+                RetryPolicy::default(),
+                RelayOptions {
+                    end_of_input: EndOfInput::Close,
+                    ..Default::default()
+                },
+            )
+        })
+    }
+
+    fn request(session: &Session, data: &[u8]) -> io::Result<Vec<u8>> {
+        let (mut reader, mut writer) = session.open()?.split();
+        writer.write_all(data)?;
+        drop(writer);
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply)?;
+        Ok(reply)
+    }
+
+    #[test]
+    fn test_frame_round_trip() {
+        let frames = [
+            Frame::new(0, FrameKind::Hello, PROTOCOL.to_vec()),
+            Frame::control(7, FrameKind::Open),
+            Frame::new(7, FrameKind::Data, b"payload".to_vec()),
+            Frame::window_update(7, 4096),
+            Frame::control(7, FrameKind::Eof),
+            Frame::new(7, FrameKind::OpenFailed, b"no pipe".to_vec()),
+            Frame::control(u32::MAX, FrameKind::Close),
+        ];
+
+        let mut wire = Vec::new();
+        for frame in &frames {
+            frame.write_to(&mut wire).unwrap();
+        }
+
+        let mut reader = Cursor::new(wire);
+        for frame in &frames {
+            assert_eq!(Frame::read_from(&mut reader).unwrap().as_ref(), Some(frame));
+        }
+        assert_eq!(Frame::read_from(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_frame_header_layout() {
+        let mut wire = Vec::new();
+        Frame::new(0x01020304, FrameKind::Data, b"ab".to_vec())
+            .write_to(&mut wire)
+            .unwrap();
+        assert_eq!(wire, [1, 2, 3, 4, 3, 0, 0, 0, 2, b'a', b'b']);
+    }
+
+    #[test]
+    fn test_frame_truncated() {
+        let mut wire = Vec::new();
+        Frame::new(1, FrameKind::Data, b"abcdef".to_vec())
+            .write_to(&mut wire)
+            .unwrap();
+
+        let err = Frame::read_from(&mut Cursor::new(&wire[..4])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = Frame::read_from(&mut Cursor::new(&wire[..12])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_frame_rejects_bad_header() {
+        let oversized = [0, 0, 0, 1, 3, 0xff, 0xff, 0xff, 0xff];
+        let err = Frame::read_from(&mut Cursor::new(oversized)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let unknown_kind = [0, 0, 0, 1, 99, 0, 0, 0, 0];
+        let err = Frame::read_from(&mut Cursor::new(unknown_kind)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_channels_are_relayed_independently() {
+        let (client, server) = session_pair();
+        let server = serve_in_background(server, Arc::new(UpperEndpoint));
+
+        let client = Arc::new(client);
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let client = Arc::clone(&client);
+                thread::spawn(move || {
+                    let data = format!("channel number {}", i).repeat(1000);
+                    let reply = request(&client, data.as_bytes()).unwrap();
+                    assert_eq!(reply, data.to_ascii_uppercase().as_bytes());
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_large_transfer_respects_window() {
+        let (client, server) = session_pair();
+        let server = serve_in_background(server, Arc::new(UpperEndpoint));
+
+        // Several windows' worth of data must flow as credit comes back.
+        let data = vec![b'x'; INITIAL_WINDOW as usize * 4 + 123];
+        let reply = request(&client, &data).unwrap();
+        assert_eq!(reply.len(), data.len());
+        assert!(reply.iter().all(|&b| b == b'X'));
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_stalled_channel_does_not_block_others() {
+        let (client, server) = session_pair();
+
+        let (_stalled_reader, mut stalled_writer) = client.open().unwrap().split();
+        let stalled = server.accept().unwrap();
+
+        // Nobody reads the stalled channel, so its writer runs out of credit.
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let data = vec![0u8; INITIAL_WINDOW as usize + 1];
+            let result = stalled_writer.write_all(&data);
+            let _ = done_tx.send(result.is_ok());
+        });
+        assert!(done_rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+        // Another channel still gets through.
+        let (mut reader, mut writer) = client.open().unwrap().split();
+        let (mut server_reader, mut server_writer) = server.accept().unwrap().split();
+        writer.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        server_reader.read_exact(&mut buf).unwrap();
+        server_writer.write_all(b"pong").unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"pong");
+
+        // Draining the stalled channel returns credit and unblocks the writer.
+        let (mut stalled_reader, _stalled_writer) = stalled.split();
+        let mut received = 0;
+        while received <= INITIAL_WINDOW as usize {
+            received += stalled_reader.read(&mut [0u8; 8192]).unwrap();
+        }
+        assert!(done_rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    }
+
+    #[test]
+    fn test_eof_is_per_direction() {
+        let (client, server) = session_pair();
+        let (mut reader, mut writer) = client.open().unwrap().split();
+        let (mut server_reader, mut server_writer) = server.accept().unwrap().split();
+
+        writer.write_all(b"request").unwrap();
+        drop(writer);
+        let mut request = Vec::new();
+        server_reader.read_to_end(&mut request).unwrap();
+        assert_eq!(request, b"request");
+
+        // The other direction stays open after the client's EOF.
+        server_writer.write_all(b"response").unwrap();
+        drop(server_writer);
+        let mut response = Vec::new();
+        reader.read_to_end(&mut response).unwrap();
+        assert_eq!(response, b"response");
+    }
+
+    #[test]
+    fn test_close_unblocks_peer() {
+        let (client, server) = session_pair();
+        let (mut reader, mut writer) = client.open().unwrap().split();
+        let channel = server.accept().unwrap();
+        let closer = channel.closer();
+        let (_server_reader, _server_writer) = channel.split();
+
+        let blocked = thread::spawn(move || reader.read(&mut [0u8; 16]).unwrap());
+        closer.close();
+        assert_eq!(blocked.join().unwrap(), 0);
+
+        let err = writer.write(b"late").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_connect_failure_is_reported_to_client() {
+        let (client, server) = session_pair();
+        let server = serve_in_background(server, Arc::new(FailingEndpoint));
+
+        let err = request(&client, b"hello").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(err.to_string().contains("nothing here"), "{}", err);
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_exit_on_stdin_eof_closes_channel() {
+        let (client, server) = session_pair();
+        let opts = RelayOptions {
+            exit_on_stdin_eof: true,
+            ..Default::default()
+        };
+        let endpoint = Arc::new(HangingEndpoint::default());
+        let server = thread::spawn(move || serve(&server, endpoint, RetryPolicy::default(), opts));
+
+        // The endpoint never hangs up on its own, so only --ei ends this.
+        let (mut reader, writer) = client.open().unwrap().split();
+        drop(writer);
+        let mut reply = Vec::new();
+        reader.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+
+        drop(client);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_peer_without_mux_is_detected() {
+        let (mut peer_writer, reader) = mem_pipe();
+        peer_writer
+            .write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n")
+            .unwrap();
+        let (writer, _peer_reader) = mem_pipe();
+        let session = Session::new(reader, writer, Role::Client).unwrap();
+
+        let mut closed = false;
+        for _ in 0..100 {
+            if !session.is_alive() {
+                closed = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(closed);
+        assert!(session.open().is_err());
+    }
+
+    #[test]
+    fn test_session_end_fails_open_channels() {
+        let (client, server) = session_pair();
+        let (mut reader, _writer) = client.open().unwrap().split();
+        let _channel = server.accept().unwrap();
+
+        drop(server);
+        let err = reader.read(&mut [0u8; 16]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    #[test]
+    fn test_server_cannot_open() {
+        let (_client, server) = session_pair();
+        assert_eq!(
+            server.open().err().map(|e| e.kind()),
+            Some(io::ErrorKind::Unsupported)
+        );
+    }
+
+    #[test]
+    fn test_mux_endpoint_reuses_session() {
+        use std::sync::atomic::AtomicUsize;
+
+        /// Runs a mux server in-process for every connect, counting them.
+        struct MuxServerEndpoint(Arc<AtomicUsize>);
+
+        impl Endpoint for MuxServerEndpoint {
+            fn connect(
+                &self,
+                _policy: &RetryPolicy,
+            ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                let (client_out, server_in) = mem_pipe();
+                let (server_out, client_in) = mem_pipe();
+                let server = Session::new(server_in, server_out, Role::Server)?;
+                serve_in_background(server, Arc::new(UpperEndpoint));
+                Ok((Box::new(client_in), Box::new(client_out)))
+            }
+        }
+
+        let connects = Arc::new(AtomicUsize::new(0));
+        let endpoint = MuxEndpoint::new(Box::new(MuxServerEndpoint(Arc::clone(&connects))));
+
+        for word in [&b"one"[..], b"two", b"three"] {
+            let (mut reader, mut writer) = endpoint.connect(&RetryPolicy::default()).unwrap();
+            writer.write_all(word).unwrap();
+            drop(writer);
+            let mut reply = Vec::new();
+            reader.read_to_end(&mut reply).unwrap();
+            assert_eq!(reply, word.to_ascii_uppercase());
+        }
+        assert_eq!(connects.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,585 @@
+//! Relay sessions as pcapng captures (`--record-format pcapng`).
+//!
+//! Each direction's bytes become the payload of a synthetic TCP connection
+//! between two loopback ports, so Wireshark follows the session with its
+//! usual dissectors: a Docker-over-pipe session shows up as HTTP.
+//!
+//! - stdin→pipe is the client, `127.0.0.1:CLIENT_PORT`, and pipe→stdout
+//!   the server, `127.0.0.1:<server port>`.
+//! - The file starts with a three-way handshake. Every chunk read becomes
+//!   one `PSH|ACK` segment (split at the largest IPv4 packet) with correct
+//!   sequence and acknowledgement numbers.
+//! - EOF in a direction is a `FIN` from that side, and a read error a `RST`
+//!   carrying the error as a packet comment.
+//! - The section header's comment names the target and the command line.
+//!
+//! Like transcripts, the file stops at a size limit, ending with an empty
+//! segment commented "recording truncated".
+
+use crate::capture::{Capture, Event};
+use crate::relay::Direction;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Port of the client (stdin) end of the synthetic connection.
+pub const CLIENT_PORT: u16 = 49152;
+
+/// Server port used when the target has none. Wireshark dissects port 80
+/// as HTTP, which suits the Docker engine pipe.
+pub const DEFAULT_SERVER_PORT: u16 = 80;
+
+const BLOCK_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const BLOCK_INTERFACE: u32 = 1;
+const BLOCK_ENHANCED_PACKET: u32 = 6;
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Packets start with the IPv4 header, no link-layer framing.
+const LINKTYPE_RAW: u16 = 101;
+
+const OPT_END: u16 = 0;
+const OPT_COMMENT: u16 = 1;
+const OPT_IF_NAME: u16 = 2;
+const OPT_IF_DESCRIPTION: u16 = 3;
+const OPT_SHB_USERAPPL: u16 = 4;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+const HEADERS_LEN: usize = 40;
+/// Most payload one IPv4 packet can carry.
+const MAX_SEGMENT: usize = u16::MAX as usize - HEADERS_LEN;
+
+/// Size of the truncation marker: the packet block header (28), the
+/// headers (40), the comment option (24), `opt_endofopt` (4) and the
+/// trailing length (4). Kept free below the limit.
+const MARKER_RESERVE: u64 = 100;
+
+/// Initial sequence numbers of the client and the server.
+const CLIENT_ISN: u32 = 1_000_000;
+const SERVER_ISN: u32 = 2_000_000;
+
+/// Writes a pcapng capture as the relay runs. Each event goes to the output
+/// in a single write.
+pub struct PcapngWriter {
+    state: Mutex<WriterState>,
+}
+
+struct WriterState {
+    /// `None` once the limit was reached or a write failed.
+    out: Option<Box<dyn Write + Send>>,
+    /// Wall-clock time of `start`, since the Unix epoch.
+    started: Duration,
+    start: Instant,
+    written: u64,
+    limit: u64,
+    client: Peer,
+    server: Peer,
+    ip_id: u16,
+}
+
+/// One end of the synthetic connection.
+#[derive(Debug, Clone, Copy)]
+struct Peer {
+    port: u16,
+    /// Sequence number of the next byte this end sends.
+    next_seq: u32,
+}
+
+impl PcapngWriter {
+    /// Create (or truncate) `path` and write the headers and handshake.
+    pub fn create(
+        path: &Path,
+        target: &str,
+        comment: &str,
+        server_port: u16,
+        limit: u64,
+    ) -> io::Result<Self> {
+        Self::new(File::create(path)?, target, comment, server_port, limit)
+    }
+
+    /// `target` describes the interface and `comment` goes in the section
+    /// header.
+    pub fn new(
+        mut out: impl Write + Send + 'static,
+        target: &str,
+        comment: &str,
+        server_port: u16,
+        limit: u64,
+    ) -> io::Result<Self> {
+        let mut state = WriterState {
+            out: None,
+            started: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+            start: Instant::now(),
+            written: 0,
+            limit,
+            client: Peer {
+                port: CLIENT_PORT,
+                next_seq: CLIENT_ISN,
+            },
+            server: Peer {
+                port: server_port,
+                next_seq: SERVER_ISN,
+            },
+            ip_id: 0,
+        };
+
+        let mut head = section_header(comment);
+        head.extend(interface(target));
+        head.extend(state.packet(Direction::StdinToPipe, TCP_SYN, &[], None));
+        head.extend(state.packet(Direction::PipeToStdout, TCP_SYN | TCP_ACK, &[], None));
+        head.extend(state.packet(Direction::StdinToPipe, TCP_ACK, &[], None));
+        out.write_all(&head)?;
+
+        state.out = Some(Box::new(out));
+        state.written = head.len() as u64;
+        Ok(Self {
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Bytes written so far, headers included.
+    pub fn written(&self) -> u64 {
+        self.lock().written
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WriterState> {
+        // A panic elsewhere must not stop the relay from recording.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Capture for PcapngWriter {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        let mut state = self.lock();
+        if state.out.is_none() {
+            return;
+        }
+        let mut blocks = Vec::new();
+        match event {
+            Event::Data(data) => {
+                for segment in data.chunks(MAX_SEGMENT) {
+                    blocks.extend(state.packet(direction, TCP_PSH | TCP_ACK, segment, None));
+                }
+            }
+            Event::Eof => blocks = state.packet(direction, TCP_FIN | TCP_ACK, &[], None),
+            Event::Error(e) => {
+                let comment = format!("error: {}", e);
+                blocks = state.packet(direction, TCP_RST | TCP_ACK, &[], Some(&comment));
+            }
+        }
+        state.write(direction, blocks);
+    }
+}
+
+impl WriterState {
+    /// Write the blocks of one event, or the truncation marker instead if
+    /// they would not leave room for it under the limit.
+    fn write(&mut self, direction: Direction, mut blocks: Vec<u8>) {
+        let fits = self.written + blocks.len() as u64 + MARKER_RESERVE <= self.limit;
+        if !fits {
+            log::warn!(
+                "Recording stopped: capture reached its {} byte limit",
+                self.limit
+            );
+            blocks = self.marker(direction);
+        }
+
+        let Some(out) = self.out.as_mut() else { return };
+        match out.write_all(&blocks) {
+            Ok(()) => self.written += blocks.len() as u64,
+            Err(e) => {
+                log::warn!("Recording stopped: {}", e);
+                self.out = None;
+            }
+        }
+        if !fits {
+            self.out = None;
+        }
+    }
+
+    /// An empty segment marking the end of the capture. Data dropped for it
+    /// still advanced the sequence numbers, so Wireshark flags the gap.
+    fn marker(&mut self, direction: Direction) -> Vec<u8> {
+        self.packet(direction, TCP_ACK, &[], Some("recording truncated"))
+    }
+
+    /// An enhanced packet block holding one TCP segment sent in `direction`,
+    /// advancing the sender's sequence number.
+    fn packet(
+        &mut self,
+        direction: Direction,
+        flags: u8,
+        payload: &[u8],
+        comment: Option<&str>,
+    ) -> Vec<u8> {
+        let (from, to) = match direction {
+            Direction::StdinToPipe => (self.client, self.server),
+            Direction::PipeToStdout => (self.server, self.client),
+        };
+        // Nothing has been received from the server before its SYN.
+        let ack = if flags & TCP_ACK != 0 { to.next_seq } else { 0 };
+        let segment = tcp_packet(self.ip_id, from, to.port, ack, flags, payload);
+        self.ip_id = self.ip_id.wrapping_add(1);
+
+        let mut consumed = payload.len() as u32;
+        if flags & (TCP_SYN | TCP_FIN) != 0 {
+            consumed += 1;
+        }
+        let sender = match direction {
+            Direction::StdinToPipe => &mut self.client,
+            Direction::PipeToStdout => &mut self.server,
+        };
+        sender.next_seq = sender.next_seq.wrapping_add(consumed);
+
+        let micros = (self.started + self.start.elapsed()).as_micros() as u64;
+        let mut body = Vec::with_capacity(segment.len() + 32);
+        body.extend_from_slice(&0u32.to_le_bytes()); // interface
+        body.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        body.extend_from_slice(&(micros as u32).to_le_bytes());
+        body.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        body.extend_from_slice(&(segment.len() as u32).to_le_bytes());
+        push_padded(&mut body, &segment);
+        if let Some(comment) = comment {
+            body.extend(options(&[(OPT_COMMENT, comment.as_bytes())]));
+        }
+        block(BLOCK_ENHANCED_PACKET, &body)
+    }
+}
+
+fn section_header(comment: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+    body.extend_from_slice(&1u16.to_le_bytes()); // major version
+    body.extend_from_slice(&0u16.to_le_bytes()); // minor version
+    body.extend_from_slice(&(-1i64).to_le_bytes()); // section length unknown
+    let application = format!("baton {}", env!("CARGO_PKG_VERSION"));
+    body.extend(options(&[
+        (OPT_COMMENT, comment.as_bytes()),
+        (OPT_SHB_USERAPPL, application.as_bytes()),
+    ]));
+    block(BLOCK_SECTION_HEADER, &body)
+}
+
+fn interface(target: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    body.extend_from_slice(&0u16.to_le_bytes()); // reserved
+    body.extend_from_slice(&0u32.to_le_bytes()); // no snap length
+    body.extend(options(&[
+        (OPT_IF_NAME, b"baton"),
+        (OPT_IF_DESCRIPTION, target.as_bytes()),
+    ]));
+    block(BLOCK_INTERFACE, &body)
+}
+
+/// A block: type, total length, body, total length again.
+fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+    let total = (body.len() + 12) as u32;
+    let mut block = Vec::with_capacity(total as usize);
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&total.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&total.to_le_bytes());
+    block
+}
+
+/// An option list ending with `opt_endofopt`.
+fn options(options: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (code, value) in options {
+        out.extend_from_slice(&code.to_le_bytes());
+        out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        push_padded(&mut out, value);
+    }
+    out.extend_from_slice(&OPT_END.to_le_bytes());
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
+
+/// Append `data` padded with zeros to a multiple of 4 bytes.
+fn push_padded(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len() + (4 - data.len() % 4) % 4, 0);
+}
+
+/// An IPv4 packet from `from` to `to_port` on 127.0.0.1 carrying one TCP
+/// segment.
+fn tcp_packet(
+    ip_id: u16,
+    from: Peer,
+    to_port: u16,
+    ack: u32,
+    flags: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    const LOOPBACK: [u8; 4] = [127, 0, 0, 1];
+    let total = HEADERS_LEN + payload.len();
+
+    let mut packet = Vec::with_capacity(total);
+    packet.extend_from_slice(&[0x45, 0]); // IPv4, 20-byte header
+    packet.extend_from_slice(&(total as u16).to_be_bytes());
+    packet.extend_from_slice(&ip_id.to_be_bytes());
+    packet.extend_from_slice(&0x4000u16.to_be_bytes()); // don't fragment
+    packet.extend_from_slice(&[64, 6, 0, 0]); // TTL, TCP, checksum
+    packet.extend_from_slice(&LOOPBACK);
+    packet.extend_from_slice(&LOOPBACK);
+    let checksum = internet_checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    let mut tcp = Vec::with_capacity(total - 20);
+    tcp.extend_from_slice(&from.port.to_be_bytes());
+    tcp.extend_from_slice(&to_port.to_be_bytes());
+    tcp.extend_from_slice(&from.next_seq.to_be_bytes());
+    tcp.extend_from_slice(&ack.to_be_bytes());
+    tcp.extend_from_slice(&[5 << 4, flags]); // 20-byte header
+    tcp.extend_from_slice(&u16::MAX.to_be_bytes()); // window
+    tcp.extend_from_slice(&[0, 0, 0, 0]); // checksum, urgent pointer
+    tcp.extend_from_slice(payload);
+    let mut pseudo = Vec::with_capacity(12);
+    pseudo.extend_from_slice(&LOOPBACK);
+    pseudo.extend_from_slice(&LOOPBACK);
+    pseudo.extend_from_slice(&[0, 6]);
+    pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+    let checksum = internet_checksum(&[&pseudo, &tcp]);
+    tcp[16..18].copy_from_slice(&checksum.to_be_bytes());
+
+    packet.extend(tcp);
+    packet
+}
+
+/// RFC 1071 checksum over the concatenation of `parts`, each of which but
+/// the last has an even length.
+fn internet_checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum: u32 = 0;
+    for part in parts {
+        for word in part.chunks(2) {
+            let high = u32::from(word[0]) << 8;
+            let low = word.get(1).copied().map_or(0, u32::from);
+            sum += high | low;
+        }
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Output that can be read back after the writer took ownership of it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    struct Segment {
+        src_port: u16,
+        dst_port: u16,
+        seq: u32,
+        ack: u32,
+        flags: u8,
+        payload: Vec<u8>,
+        comment: Option<String>,
+    }
+
+    fn u16_le(b: &[u8]) -> u16 {
+        u16::from_le_bytes([b[0], b[1]])
+    }
+
+    fn u32_le(b: &[u8]) -> u32 {
+        u32::from_le_bytes(b[..4].try_into().unwrap())
+    }
+
+    fn u32_be(b: &[u8]) -> u32 {
+        u32::from_be_bytes(b[..4].try_into().unwrap())
+    }
+
+    fn parse_options(mut data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut options = Vec::new();
+        while !data.is_empty() {
+            let (code, len) = (u16_le(data), u16_le(&data[2..]) as usize);
+            if code == OPT_END {
+                break;
+            }
+            options.push((code, data[4..4 + len].to_vec()));
+            data = &data[4 + len.div_ceil(4) * 4..];
+        }
+        options
+    }
+
+    /// Split a capture into blocks, checking the framing of each.
+    fn blocks(data: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let total = u32_le(&rest[4..]) as usize;
+            assert_eq!(total % 4, 0);
+            assert_eq!(u32_le(&rest[total - 4..]) as usize, total);
+            blocks.push((u32_le(rest), rest[8..total - 4].to_vec()));
+            rest = &rest[total..];
+        }
+        blocks
+    }
+
+    fn segments(data: &[u8]) -> Vec<Segment> {
+        blocks(data)
+            .into_iter()
+            .filter(|(kind, _)| *kind == BLOCK_ENHANCED_PACKET)
+            .map(|(_, body)| {
+                let len = u32_le(&body[12..]) as usize;
+                let ip = &body[20..20 + len];
+                assert_eq!(internet_checksum(&[&ip[..20]]), 0);
+                let tcp = &ip[20..];
+                let mut pseudo = vec![127, 0, 0, 1, 127, 0, 0, 1, 0, 6];
+                pseudo.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+                assert_eq!(internet_checksum(&[&pseudo, tcp]), 0);
+
+                let comment = parse_options(&body[20 + len.div_ceil(4) * 4..])
+                    .into_iter()
+                    .find(|(code, _)| *code == OPT_COMMENT)
+                    .map(|(_, value)| String::from_utf8(value).unwrap());
+                Segment {
+                    src_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+                    dst_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+                    seq: u32_be(&tcp[4..]),
+                    ack: u32_be(&tcp[8..]),
+                    flags: tcp[13],
+                    payload: tcp[20..].to_vec(),
+                    comment,
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_headers() {
+        let out = Shared::default();
+        PcapngWriter::new(
+            out.clone(),
+            "npipe:////./pipe/docker_engine",
+            "baton -ep",
+            80,
+            1 << 20,
+        )
+        .unwrap();
+        let data = out.0.lock().unwrap().clone();
+        let blocks = blocks(&data);
+
+        let (kind, shb) = &blocks[0];
+        assert_eq!(*kind, BLOCK_SECTION_HEADER);
+        assert_eq!(u32_le(shb), BYTE_ORDER_MAGIC);
+        let options = parse_options(&shb[16..]);
+        assert_eq!(options[0], (OPT_COMMENT, b"baton -ep".to_vec()));
+        assert_eq!(options[1].0, OPT_SHB_USERAPPL);
+
+        let (kind, idb) = &blocks[1];
+        assert_eq!(*kind, BLOCK_INTERFACE);
+        assert_eq!(u16_le(idb), LINKTYPE_RAW);
+        assert!(parse_options(&idb[8..]).contains(&(
+            OPT_IF_DESCRIPTION,
+            b"npipe:////./pipe/docker_engine".to_vec()
+        )));
+        assert_eq!(blocks.len(), 5);
+    }
+
+    #[test]
+    fn test_segments_follow_the_conversation() {
+        let out = Shared::default();
+        let writer = PcapngWriter::new(out.clone(), "t", "", 2375, 1 << 20).unwrap();
+        writer.record(
+            Direction::StdinToPipe,
+            Event::Data(b"GET /_ping HTTP/1.1\r\n\r\n"),
+        );
+        writer.record(Direction::PipeToStdout, Event::Data(b"HTTP/1.1 200 OK\r\n"));
+        writer.record(Direction::PipeToStdout, Event::Data(b"\r\n"));
+        writer.record(Direction::StdinToPipe, Event::Eof);
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed");
+        writer.record(Direction::PipeToStdout, Event::Error(&broken));
+
+        let segments = segments(&out.0.lock().unwrap());
+        let summary: Vec<_> = segments
+            .iter()
+            .map(|s| {
+                (
+                    s.src_port,
+                    s.dst_port,
+                    s.flags,
+                    s.seq,
+                    s.ack,
+                    s.payload.len(),
+                )
+            })
+            .collect();
+        let (c, s) = (CLIENT_ISN, SERVER_ISN);
+        assert_eq!(
+            summary,
+            [
+                (CLIENT_PORT, 2375, TCP_SYN, c, 0, 0),
+                (2375, CLIENT_PORT, TCP_SYN | TCP_ACK, s, c + 1, 0),
+                (CLIENT_PORT, 2375, TCP_ACK, c + 1, s + 1, 0),
+                (CLIENT_PORT, 2375, TCP_PSH | TCP_ACK, c + 1, s + 1, 23),
+                (2375, CLIENT_PORT, TCP_PSH | TCP_ACK, s + 1, c + 24, 17),
+                (2375, CLIENT_PORT, TCP_PSH | TCP_ACK, s + 18, c + 24, 2),
+                (CLIENT_PORT, 2375, TCP_FIN | TCP_ACK, c + 24, s + 20, 0),
+                (2375, CLIENT_PORT, TCP_RST | TCP_ACK, s + 20, c + 25, 0),
+            ]
+        );
+        assert_eq!(segments[4].payload, b"HTTP/1.1 200 OK\r\n");
+        assert_eq!(segments[7].comment.as_deref(), Some("error: pipe closed"));
+    }
+
+    #[test]
+    fn test_large_chunks_are_split() {
+        let out = Shared::default();
+        let writer = PcapngWriter::new(out.clone(), "t", "", 80, 1 << 20).unwrap();
+        writer.record(Direction::PipeToStdout, Event::Data(&[7; MAX_SEGMENT + 10]));
+
+        let segments = segments(&out.0.lock().unwrap());
+        assert_eq!(segments[3].payload.len(), MAX_SEGMENT);
+        assert_eq!(segments[4].payload.len(), 10);
+        assert_eq!(segments[4].seq, SERVER_ISN + 1 + MAX_SEGMENT as u32);
+    }
+
+    #[test]
+    fn test_limit_ends_with_marker() {
+        let out = Shared::default();
+        let writer = PcapngWriter::new(out.clone(), "t", "", 80, 1024).unwrap();
+        for _ in 0..20 {
+            writer.record(Direction::StdinToPipe, Event::Data(&[b'x'; 100]));
+        }
+        assert!(writer.written() <= 1024);
+        assert_eq!(
+            writer.lock().marker(Direction::StdinToPipe).len() as u64,
+            MARKER_RESERVE
+        );
+        assert_eq!(writer.written(), out.0.lock().unwrap().len() as u64);
+
+        let segments = segments(&out.0.lock().unwrap());
+        let last = segments.last().unwrap();
+        assert_eq!(last.comment.as_deref(), Some("recording truncated"));
+        assert!(last.payload.is_empty());
+        assert!(segments[..segments.len() - 1]
+            .iter()
+            .all(|s| s.comment.is_none()));
+    }
+}
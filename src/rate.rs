@@ -0,0 +1,258 @@
+//! Token-bucket bandwidth limiting for `--rate-in` and `--rate-out`.
+//!
+//! A bucket holds up to `burst` bytes of credit and refills at `rate` bytes
+//! per second. Sending a chunk takes its size from the bucket; a chunk larger
+//! than the credit left puts the bucket in debt, and the sender sleeps until
+//! the debt would be paid off. Chunks are never split, so a relay read of up
+//! to [`crate::relay::BUFFER_SIZE`] bytes goes out in one write.
+//!
+//! One limiter is shared by every session relayed with the same
+//! [`RelayOptions`](crate::relay::RelayOptions), so `baton listen` and
+//! `--mux` cap their connections together rather than each one separately.
+//! Time comes from a [`Clock`], which makes the limiter deterministic under
+//! test.
+//!
+//! Long waits are slept in steps of at most [`SLEEP_STEP`], so a session that
+//! ends while throttled stops waiting promptly.
+
+use crate::retry::{Clock, SystemClock};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Longest single sleep between checks for cancellation.
+pub const SLEEP_STEP: Duration = Duration::from_millis(100);
+
+pub struct RateLimiter {
+    rate: u64,
+    burst: u64,
+    clock: Arc<dyn Clock>,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Bytes that may be sent now; negative while in debt.
+    credit: f64,
+    refilled: Instant,
+}
+
+impl RateLimiter {
+    /// A limiter for `rate` bytes per second that starts with a full bucket
+    /// of `burst` bytes.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self::with_clock(rate, burst, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(rate: u64, burst: u64, clock: Arc<dyn Clock>) -> Self {
+        assert!(rate > 0, "rate must be positive");
+        let bucket = Bucket {
+            credit: burst as f64,
+            refilled: clock.now(),
+        };
+        RateLimiter {
+            rate,
+            burst,
+            clock,
+            bucket: Mutex::new(bucket),
+        }
+    }
+
+    /// Bytes per second.
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Take `n` bytes from the bucket, sleeping first if that leaves it in
+    /// debt. Returns how long the caller slept.
+    ///
+    /// Once `cancelled` is set the sleep is cut short and the bytes are given
+    /// back, since the caller is not going to send them.
+    pub fn acquire(&self, n: usize, cancelled: &AtomicBool) -> Duration {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = self.clock.now();
+            let elapsed = now.saturating_duration_since(bucket.refilled);
+            bucket.refilled = now;
+            bucket.credit =
+                (bucket.credit + elapsed.as_secs_f64() * self.rate as f64).min(self.burst as f64);
+            bucket.credit -= n as f64;
+            if bucket.credit < 0.0 {
+                Duration::from_secs_f64(-bucket.credit / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        // Sleep outside the lock: other senders queue up behind the debt this
+        // one left, rather than behind the mutex.
+        let mut slept = Duration::ZERO;
+        while slept < wait {
+            if cancelled.load(Ordering::SeqCst) {
+                self.bucket.lock().unwrap().credit += n as f64;
+                break;
+            }
+            let step = (wait - slept).min(SLEEP_STEP);
+            self.clock.sleep(step);
+            slept += step;
+        }
+        slept
+    }
+}
+
+impl fmt::Debug for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("rate", &self.rate)
+            .field("burst", &self.burst)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock that only moves when something sleeps on it or a test advances
+    /// it.
+    struct FakeClock {
+        now: Mutex<Instant>,
+    }
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                now: Mutex::new(Instant::now()),
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.advance(duration);
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    #[test]
+    fn test_burst_is_free() {
+        let limiter = RateLimiter::with_clock(1000, 4000, FakeClock::new());
+        assert_eq!(limiter.acquire(1000, &RUNNING), Duration::ZERO);
+        assert_eq!(limiter.acquire(3000, &RUNNING), Duration::ZERO);
+        assert_eq!(limiter.acquire(500, &RUNNING), ms(500));
+    }
+
+    #[test]
+    fn test_steady_rate() {
+        let clock = FakeClock::new();
+        let limiter = RateLimiter::with_clock(1000, 0, clock.clone());
+        let start = clock.now();
+        for _ in 0..10 {
+            assert_eq!(limiter.acquire(250, &RUNNING), ms(250));
+        }
+        assert_eq!(clock.now() - start, Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn test_chunk_larger_than_burst() {
+        let limiter = RateLimiter::with_clock(1000, 100, FakeClock::new());
+        // 100 bytes of credit, then 1900 bytes of debt.
+        assert_eq!(limiter.acquire(2000, &RUNNING), ms(1900));
+        assert_eq!(limiter.acquire(100, &RUNNING), ms(100));
+    }
+
+    #[test]
+    fn test_idle_time_refills_up_to_burst() {
+        let clock = FakeClock::new();
+        let limiter = RateLimiter::with_clock(1000, 500, clock.clone());
+        assert_eq!(limiter.acquire(500, &RUNNING), Duration::ZERO);
+
+        clock.advance(ms(200));
+        assert_eq!(limiter.acquire(200, &RUNNING), Duration::ZERO);
+
+        // A long pause only refills to the burst size.
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(limiter.acquire(500, &RUNNING), Duration::ZERO);
+        assert_eq!(limiter.acquire(100, &RUNNING), ms(100));
+    }
+
+    #[test]
+    fn test_long_wait_is_slept_in_steps() {
+        let clock = FakeClock::new();
+        let limiter = RateLimiter::with_clock(1000, 0, clock.clone());
+        let start = clock.now();
+
+        assert_eq!(limiter.acquire(1250, &RUNNING), ms(1250));
+        assert_eq!(clock.now() - start, ms(1250));
+    }
+
+    #[test]
+    fn test_cancel_cuts_wait_short_and_refunds() {
+        /// Clock that cancels the sender on its second sleep.
+        struct Cancelling {
+            clock: Arc<FakeClock>,
+            sleeps: Mutex<u32>,
+            cancelled: Arc<AtomicBool>,
+        }
+        impl Clock for Cancelling {
+            fn now(&self) -> Instant {
+                self.clock.now()
+            }
+            fn sleep(&self, duration: Duration) {
+                let mut sleeps = self.sleeps.lock().unwrap();
+                *sleeps += 1;
+                if *sleeps == 2 {
+                    self.cancelled.store(true, Ordering::SeqCst);
+                }
+                self.clock.sleep(duration);
+            }
+        }
+
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let clock = Arc::new(Cancelling {
+            clock: FakeClock::new(),
+            sleeps: Mutex::new(0),
+            cancelled: cancelled.clone(),
+        });
+        let limiter = RateLimiter::with_clock(1000, 500, clock);
+
+        // A 30s wait stops after two steps.
+        assert_eq!(limiter.acquire(30_500, &cancelled), SLEEP_STEP * 2);
+
+        // The bytes that were never sent do not hold up the next sender.
+        assert_eq!(limiter.acquire(500, &RUNNING), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_shared_limiter_queues_senders() {
+        // Two senders that acquire before either has slept: the second waits
+        // for the first one's debt as well as its own.
+        struct Frozen(Instant);
+        impl Clock for Frozen {
+            fn now(&self) -> Instant {
+                self.0
+            }
+            fn sleep(&self, _: Duration) {}
+        }
+
+        let limiter = RateLimiter::with_clock(1000, 0, Arc::new(Frozen(Instant::now())));
+        assert_eq!(limiter.acquire(300, &RUNNING), ms(300));
+        assert_eq!(limiter.acquire(300, &RUNNING), ms(600));
+    }
+}
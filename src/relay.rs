@@ -4,14 +4,26 @@
 //! AtomicBool flags coordinate shutdown when either side reaches EOF or errors.
 //! This design allows immediate response to EOF on either side without complex
 //! async machinery.
+//!
+//! [`run_relay_between`] accepts arbitrary read/write halves for both sides;
+//! [`run_relay`] is the stdio wrapper used by the binary. Neither exits the
+//! process: they return a [`RelayOutcome`] and leave that decision to the
+//! caller.
 
 use crate::cli::Config;
+use crate::logging;
+use crate::rate::RateLimiter;
+use crate::retry::{Clock, SystemClock};
+use std::fmt;
 use std::io::{self, Read, Write};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
-const BUFFER_SIZE: usize = 32768;
+/// Largest chunk a copy loop reads at once.
+pub const BUFFER_SIZE: usize = 32768;
 
 // Validate buffer size is reasonable at compile time
 const _: () = assert!(BUFFER_SIZE >= 4096 && BUFFER_SIZE <= 1024 * 1024);
@@ -19,15 +31,154 @@ const _: () = assert!(BUFFER_SIZE >= 4096 && BUFFER_SIZE <= 1024 * 1024);
 pub struct RelayState {
     pub stdin_done: AtomicBool,
     pub pipe_done: AtomicBool,
+    /// Set once the relay has returned; abandoned threads stop writing.
+    pub cancelled: AtomicBool,
+    pub stdin_to_pipe_bytes: AtomicU64,
+    pub pipe_to_stdout_bytes: AtomicU64,
+    /// Chunks written in each direction.
+    pub stdin_to_pipe_chunks: AtomicU64,
+    pub pipe_to_stdout_chunks: AtomicU64,
+    pub started: Instant,
+    /// Time from `started` to the first byte read in each direction.
+    pub stdin_first_byte: OnceLock<Duration>,
+    pub pipe_first_byte: OnceLock<Duration>,
+    /// The direction whose source reached EOF first.
+    pub first_eof: OnceLock<Direction>,
+    // Microseconds from `started` to the latest read, for `idle_timeout`.
+    last_read: AtomicU64,
+    // Directions currently waiting for a rate limiter; the session is not
+    // idle while one is.
+    throttled: AtomicUsize,
+    // Bytes taken from the `max_bytes` quota by both directions.
+    quota_used: AtomicU64,
+    clock: Arc<dyn Clock>,
 }
 
 impl RelayState {
     pub fn new() -> Self {
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
         Self {
             stdin_done: AtomicBool::new(false),
             pipe_done: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            stdin_to_pipe_bytes: AtomicU64::new(0),
+            pipe_to_stdout_bytes: AtomicU64::new(0),
+            stdin_to_pipe_chunks: AtomicU64::new(0),
+            pipe_to_stdout_chunks: AtomicU64::new(0),
+            started: clock.now(),
+            stdin_first_byte: OnceLock::new(),
+            pipe_first_byte: OnceLock::new(),
+            first_eof: OnceLock::new(),
+            last_read: AtomicU64::new(0),
+            throttled: AtomicUsize::new(0),
+            quota_used: AtomicU64::new(0),
+            clock,
         }
     }
+
+    /// Time since the session started.
+    pub fn elapsed(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.started)
+    }
+
+    /// Counters so far for `direction`; `end` is left `None`.
+    pub fn snapshot(&self, direction: Direction) -> DirectionOutcome {
+        let (bytes, chunks, first_byte) = match direction {
+            Direction::StdinToPipe => (
+                &self.stdin_to_pipe_bytes,
+                &self.stdin_to_pipe_chunks,
+                &self.stdin_first_byte,
+            ),
+            Direction::PipeToStdout => (
+                &self.pipe_to_stdout_bytes,
+                &self.pipe_to_stdout_chunks,
+                &self.pipe_first_byte,
+            ),
+        };
+        DirectionOutcome {
+            bytes: bytes.load(Ordering::SeqCst),
+            chunks: chunks.load(Ordering::SeqCst),
+            first_byte: first_byte.get().copied(),
+            end: None,
+        }
+    }
+
+    /// Note a chunk of `n` bytes read in `direction`.
+    fn read(&self, direction: Direction, n: usize) {
+        let first_byte = match direction {
+            Direction::StdinToPipe => &self.stdin_first_byte,
+            Direction::PipeToStdout => &self.pipe_first_byte,
+        };
+        let elapsed = self.elapsed();
+        first_byte.get_or_init(|| elapsed);
+        self.touch();
+        log::debug!(direction = direction.name(), event = "read", bytes = n; "Read {} bytes from {}", n, source_name(direction));
+    }
+
+    /// Restart the idle clock.
+    fn touch(&self) {
+        self.last_read
+            .fetch_max(self.elapsed().as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// How long neither direction has read anything. Time spent waiting for
+    /// a rate limiter does not count.
+    pub fn idle(&self) -> Duration {
+        if self.throttled.load(Ordering::SeqCst) > 0 {
+            return Duration::ZERO;
+        }
+        let last_read = Duration::from_micros(self.last_read.load(Ordering::SeqCst));
+        self.elapsed().saturating_sub(last_read)
+    }
+
+    /// Wait until `limiter` lets `n` bytes through, or the relay returns.
+    fn throttle(&self, limiter: &RateLimiter, n: usize) {
+        self.throttled.fetch_add(1, Ordering::SeqCst);
+        limiter.acquire(n, &self.cancelled);
+        // Idle time starts over once the chunk may go out.
+        self.touch();
+        self.throttled.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Take up to `n` bytes from the `max_bytes` quota shared by both
+    /// directions, returning how many may be written.
+    fn claim(&self, n: usize, max_bytes: Option<u64>) -> usize {
+        let Some(max_bytes) = max_bytes else {
+            return n;
+        };
+        let mut granted = 0;
+        // Intentionally ignore: the closure never returns None.
+        let _ = self
+            .quota_used
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+                granted = max_bytes.saturating_sub(used).min(n as u64);
+                Some(used + granted)
+            });
+        granted as usize
+    }
+
+    /// Note that `n` bytes read in `direction` were written out.
+    fn wrote(&self, direction: Direction, n: usize) {
+        if n == 0 {
+            return;
+        }
+        let (bytes, chunks) = match direction {
+            Direction::StdinToPipe => (&self.stdin_to_pipe_bytes, &self.stdin_to_pipe_chunks),
+            Direction::PipeToStdout => (&self.pipe_to_stdout_bytes, &self.pipe_to_stdout_chunks),
+        };
+        bytes.fetch_add(n as u64, Ordering::SeqCst);
+        chunks.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Stop both directions from writing any further data. A write already
+    /// in progress is not waited for: it may be blocked for good on a peer
+    /// that stopped reading.
+    fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
 }
 
 impl Default for RelayState {
@@ -36,134 +187,539 @@ impl Default for RelayState {
     }
 }
 
-pub fn run_relay<R, W>(
-    mut pipe_reader: R,
-    mut pipe_writer: W,
-    config: &Config,
-) -> io::Result<()>
+/// How the relay tells the pipe side that stdin has ended.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum EndOfInput {
+    /// Close the pipe writer. What the target sees depends on the endpoint:
+    /// a socket is shut down for writing, a child's stdin is closed and a mux
+    /// channel sends EOF. A named pipe cannot be half-closed, so it sees
+    /// nothing until the relay ends.
+    Close,
+    /// Write a 0-byte message, then close (`-s`). Message-mode named pipes
+    /// deliver it as an empty message.
+    ZeroMessage,
+    /// Write these bytes, then close, for protocols that mark the end of
+    /// input in-band.
+    Sentinel(Vec<u8>),
+    /// Keep the pipe writer open until the relay returns.
+    #[default]
+    Keep,
+}
+
+/// EOF handling and limits for a relay session, mirroring the `-s`, `--eof`,
+/// `--ei`, `--ep`, `--idle-timeout`, `--max-duration`, `--max-bytes` and `--rate-*`
+/// flags.
+///
+/// Clones share their rate limiters, so every session relayed with clones
+/// of one `RelayOptions` draws from the same buckets.
+#[derive(Clone)]
+pub struct RelayOptions {
+    pub end_of_input: EndOfInput,
+    pub exit_on_stdin_eof: bool,
+    pub exit_on_pipe_eof: bool,
+    /// End the session once neither direction has read anything for this long.
+    pub idle_timeout: Option<Duration>,
+    /// End the session this long after it started.
+    pub max_duration: Option<Duration>,
+    /// End the session rather than relay more than this many bytes in total.
+    pub max_bytes: Option<u64>,
+    /// Bandwidth limit for stdin to pipe.
+    pub rate_in: Option<Arc<RateLimiter>>,
+    /// Bandwidth limit for pipe to stdout.
+    pub rate_out: Option<Arc<RateLimiter>>,
+    /// Where the session limits read the time; [`SystemClock`] outside of
+    /// tests.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            end_of_input: EndOfInput::default(),
+            exit_on_stdin_eof: false,
+            exit_on_pipe_eof: false,
+            idle_timeout: None,
+            max_duration: None,
+            max_bytes: None,
+            rate_in: None,
+            rate_out: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl fmt::Debug for RelayOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RelayOptions")
+            .field("end_of_input", &self.end_of_input)
+            .field("exit_on_stdin_eof", &self.exit_on_stdin_eof)
+            .field("exit_on_pipe_eof", &self.exit_on_pipe_eof)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("max_duration", &self.max_duration)
+            .field("max_bytes", &self.max_bytes)
+            .field("rate_in", &self.rate_in)
+            .field("rate_out", &self.rate_out)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RelayOptions {
+    /// The time limit that runs out next, and how long until it does.
+    fn next_limit(&self, state: &RelayState) -> Option<(Termination, Duration)> {
+        let idle = self.idle_timeout.map(|timeout| {
+            (
+                Termination::IdleTimeout,
+                timeout.saturating_sub(state.idle()),
+            )
+        });
+        let duration = self.max_duration.map(|max| {
+            let left = max.saturating_sub(state.elapsed());
+            (Termination::MaxDuration, left)
+        });
+        idle.into_iter()
+            .chain(duration)
+            .min_by_key(|&(_, left)| left)
+    }
+}
+
+impl From<&Config> for RelayOptions {
+    fn from(config: &Config) -> Self {
+        Self {
+            end_of_input: config.end_of_input.clone(),
+            exit_on_stdin_eof: config.exit_on_stdin_eof,
+            exit_on_pipe_eof: config.exit_on_pipe_eof,
+            idle_timeout: config.idle_timeout,
+            max_duration: config.max_duration,
+            max_bytes: config.max_bytes,
+            rate_in: config.rate_limiter(config.rate_in),
+            rate_out: config.rate_limiter(config.rate_out),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+/// One of the two copy loops that make up a relay session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    StdinToPipe,
+    PipeToStdout,
+}
+
+impl Direction {
+    /// Name used in structured logs.
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::StdinToPipe => "stdin_to_pipe",
+            Direction::PipeToStdout => "pipe_to_stdout",
+        }
+    }
+}
+
+/// What a direction reads from, as named in log messages.
+fn source_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::StdinToPipe => "stdin",
+        Direction::PipeToStdout => "pipe",
+    }
+}
+
+/// Why a copy loop stopped.
+#[derive(Debug)]
+pub enum EndReason {
+    /// The source returned 0 bytes.
+    Eof,
+    /// The pipe was closed or disconnected by the other end.
+    BrokenPipe,
+    /// The relay finished first and told this loop to stop.
+    Cancelled,
+    /// The `max_bytes` quota ran out; the rest of the chunk was dropped.
+    MaxBytes,
+    /// Any other I/O error.
+    Error(io::Error),
+}
+
+impl EndReason {
+    /// Name used in statistics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            EndReason::Eof => "eof",
+            EndReason::BrokenPipe => "broken_pipe",
+            EndReason::Cancelled => "cancelled",
+            EndReason::MaxBytes => "max_bytes",
+            EndReason::Error(_) => "error",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct DirectionOutcome {
+    /// Bytes written to the destination.
+    pub bytes: u64,
+    /// Chunks written to the destination, one per read.
+    pub chunks: u64,
+    /// Time from the start of the relay to the first byte read.
+    pub first_byte: Option<Duration>,
+    /// `None` if the loop was still blocked in a read when the relay returned.
+    pub end: Option<EndReason>,
+}
+
+/// Why the relay returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// Both directions finished.
+    Finished,
+    /// Stdin reached EOF with `--ei`.
+    ExitOnStdinEof,
+    /// The pipe finished with `--ep`.
+    ExitOnPipeEof,
+    /// Nothing was read for `idle_timeout`.
+    IdleTimeout,
+    /// The session ran for `max_duration`.
+    MaxDuration,
+    /// More than `max_bytes` were to be relayed.
+    MaxBytes,
+}
+
+impl Termination {
+    /// Name used in structured logs and statistics.
+    pub fn name(self) -> &'static str {
+        match self {
+            Termination::Finished => "finished",
+            Termination::ExitOnStdinEof => "exit_on_stdin_eof",
+            Termination::ExitOnPipeEof => "exit_on_pipe_eof",
+            Termination::IdleTimeout => "idle_timeout",
+            Termination::MaxDuration => "max_duration",
+            Termination::MaxBytes => "max_bytes",
+        }
+    }
+
+    /// Whether the session was cut short by one of the limits.
+    pub fn is_limit(self) -> bool {
+        self.exit_code() != 0
+    }
+
+    /// Process exit status for a session that ended this way.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Termination::Finished | Termination::ExitOnStdinEof | Termination::ExitOnPipeEof => 0,
+            Termination::IdleTimeout => 3,
+            Termination::MaxDuration => 4,
+            Termination::MaxBytes => 5,
+        }
+    }
+}
+
+/// Result of a relay session: which direction finished first, how each
+/// direction ended and what it carried.
+#[derive(Debug)]
+pub struct RelayOutcome {
+    pub first: Direction,
+    /// The direction whose source reached EOF first, if either did.
+    pub first_eof: Option<Direction>,
+    pub termination: Termination,
+    /// Time from the start of the relay until it returned.
+    pub duration: Duration,
+    pub stdin_to_pipe: DirectionOutcome,
+    pub pipe_to_stdout: DirectionOutcome,
+}
+
+impl RelayOutcome {
+    pub fn direction(&self, direction: Direction) -> &DirectionOutcome {
+        match direction {
+            Direction::StdinToPipe => &self.stdin_to_pipe,
+            Direction::PipeToStdout => &self.pipe_to_stdout,
+        }
+    }
+
+    /// Why the first direction to finish stopped.
+    pub fn reason(&self) -> Option<&EndReason> {
+        self.direction(self.first).end.as_ref()
+    }
+
+    /// The first I/O error either direction hit, if any.
+    pub fn into_error(self) -> Option<io::Error> {
+        let (first, second) = match self.first {
+            Direction::StdinToPipe => (self.stdin_to_pipe, self.pipe_to_stdout),
+            Direction::PipeToStdout => (self.pipe_to_stdout, self.stdin_to_pipe),
+        };
+        [first.end, second.end]
+            .into_iter()
+            .find_map(|end| match end {
+                Some(EndReason::Error(e)) => Some(e),
+                _ => None,
+            })
+    }
+}
+
+/// Relay between the process's stdin/stdout and a pipe. Stdout stays open
+/// when the relay returns; the binary closes it early itself, which a
+/// library caller would not want done to its process.
+pub fn run_relay<R, W>(pipe_reader: R, pipe_writer: W, config: &Config) -> RelayOutcome
 where
     R: Read + Send + 'static,
     W: Write + Send + 'static,
 {
-    let state = Arc::new(RelayState::new());
-    let state_clone = Arc::clone(&state);
+    run_relay_between(
+        io::stdin(),
+        io::stdout(),
+        pipe_reader,
+        pipe_writer,
+        RelayOptions::from(config),
+    )
+}
 
-    let send_zero = config.send_zero;
-    let exit_on_stdin_eof = config.exit_on_stdin_eof;
-    let exit_on_pipe_eof = config.exit_on_pipe_eof;
+/// Relay between two arbitrary endpoints.
+///
+/// Side A plays the role of stdin/stdout and side B the role of the pipe, so
+/// `opts` applies exactly as it does to [`run_relay`]: `a_reader` is copied to
+/// `b_writer` and `b_reader` is copied to `a_writer`, each on its own thread.
+/// Each writer is dropped as soon as its direction finishes (except with
+/// [`EndOfInput::Keep`]), which is how the end of input is passed on.
+///
+/// Without `exit_on_*` flags or limits this returns once both directions have
+/// finished. With them it returns as soon as the matching side hits EOF or a
+/// limit is reached. A thread still blocked in a read or a write is left
+/// behind and will not write anything further.
+pub fn run_relay_between<AR, AW, BR, BW>(
+    mut a_reader: AR,
+    mut a_writer: AW,
+    mut b_reader: BR,
+    mut b_writer: BW,
+    opts: RelayOptions,
+) -> RelayOutcome
+where
+    AR: Read + Send + 'static,
+    AW: Write + Send + 'static,
+    BR: Read + Send + 'static,
+    BW: Write + Send + 'static,
+{
+    let state = Arc::new(RelayState::with_clock(Arc::clone(&opts.clock)));
+    let (tx, rx) = mpsc::channel();
+    // Holds the pipe writer with `EndOfInput::Keep` until the relay returns.
+    let kept = Arc::new(Mutex::new(None));
+    let session = logging::session_id();
+
+    {
+        let state = Arc::clone(&state);
+        let tx = tx.clone();
+        let session = session.clone();
+        let opts = opts.clone();
+        let kept = Arc::clone(&kept);
+        thread::spawn(move || {
+            logging::set_thread_session(Some(session));
+            let end = stdin_to_pipe(&mut a_reader, &mut b_writer, &opts, &state);
+            if opts.end_of_input == EndOfInput::Keep {
+                *kept.lock().unwrap() = Some(b_writer);
+            } else {
+                drop(b_writer);
+            }
+            // Intentionally ignore: the receiver is gone once the relay has
+            // returned, and then nobody is interested in this result.
+            let _ = tx.send((Direction::StdinToPipe, end));
+        });
+    }
+    {
+        let state = Arc::clone(&state);
+        let opts = opts.clone();
+        thread::spawn(move || {
+            logging::set_thread_session(Some(session));
+            let end = pipe_to_stdout(&mut b_reader, &mut a_writer, &opts, &state);
+            drop(a_writer);
+            let _ = tx.send((Direction::PipeToStdout, end));
+        });
+    }
 
-    let stdin_thread = thread::spawn(move || {
-        stdin_to_pipe(&mut pipe_writer, send_zero, exit_on_stdin_eof, &state_clone)
-    });
+    let mut first = None;
+    let mut stdin_end = None;
+    let mut pipe_end = None;
+    let mut termination = Termination::Finished;
 
-    let result = pipe_to_stdout(&mut pipe_reader, exit_on_pipe_eof, &state);
+    loop {
+        // Each thread sends exactly once, so recv only fails if one panicked.
+        let received = match opts.next_limit(&state) {
+            Some((limit, left)) if left.is_zero() => {
+                log::debug!(event = "exit"; "Session limit reached: {}", limit.name());
+                termination = limit;
+                break;
+            }
+            Some((_, left)) => match rx.recv_timeout(left) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                received => received.ok(),
+            },
+            None => rx.recv().ok(),
+        };
+        let Some((direction, end)) = received else {
+            break;
+        };
+
+        first.get_or_insert(direction);
+        if matches!(end, EndReason::MaxBytes) {
+            log::debug!(direction = direction.name(), event = "exit"; "Session limit reached: max_bytes");
+            termination = Termination::MaxBytes;
+        }
+        let exit_now = match direction {
+            Direction::StdinToPipe => {
+                let exit = opts.exit_on_stdin_eof && matches!(end, EndReason::Eof);
+                if exit {
+                    log::debug!(direction = direction.name(), event = "exit"; "Exiting immediately on stdin EOF (-ei)");
+                    termination = Termination::ExitOnStdinEof;
+                }
+                stdin_end = Some(end);
+                exit
+            }
+            Direction::PipeToStdout => {
+                let exit = opts.exit_on_pipe_eof && !termination.is_limit();
+                if exit {
+                    log::debug!(direction = direction.name(), event = "exit"; "Exiting immediately on pipe EOF (-ep)");
+                    termination = Termination::ExitOnPipeEof;
+                }
+                pipe_end = Some(end);
+                exit
+            }
+        };
 
-    if !exit_on_pipe_eof {
-        // Intentionally ignore: thread panic would have been logged; we only
-        // care about graceful shutdown, not propagating panics here.
-        let _ = stdin_thread.join();
+        if exit_now || termination.is_limit() || (stdin_end.is_some() && pipe_end.is_some()) {
+            break;
+        }
     }
 
-    result
+    state.cancel();
+    drop(kept);
+
+    RelayOutcome {
+        first: first.unwrap_or(Direction::PipeToStdout),
+        first_eof: state.first_eof.get().copied(),
+        termination,
+        duration: state.elapsed(),
+        stdin_to_pipe: DirectionOutcome {
+            end: stdin_end,
+            ..state.snapshot(Direction::StdinToPipe)
+        },
+        pipe_to_stdout: DirectionOutcome {
+            end: pipe_end,
+            ..state.snapshot(Direction::PipeToStdout)
+        },
+    }
 }
 
-fn stdin_to_pipe<W: Write>(
+fn stdin_to_pipe<R: Read, W: Write>(
+    stdin: &mut R,
     pipe: &mut W,
-    send_zero: bool,
-    exit_immediately: bool,
+    opts: &RelayOptions,
     state: &RelayState,
-) -> io::Result<()> {
-    let mut stdin = io::stdin().lock();
+) -> EndReason {
+    let direction = Direction::StdinToPipe.name();
     let mut buffer = vec![0u8; BUFFER_SIZE];
 
     loop {
         if state.pipe_done.load(Ordering::SeqCst) {
-            log::debug!("Pipe closed, stopping stdin reader");
-            break;
+            log::debug!(direction, event = "cancelled"; "Pipe closed, stopping stdin reader");
+            return EndReason::Cancelled;
         }
 
         match stdin.read(&mut buffer) {
             Ok(0) => {
-                log::debug!("EOF on stdin");
+                log::debug!(direction, event = "eof"; "EOF on stdin");
                 state.stdin_done.store(true, Ordering::SeqCst);
+                let _ = state.first_eof.set(Direction::StdinToPipe);
 
-                if send_zero {
-                    log::debug!("Sending 0-byte message to pipe");
-                    if let Err(e) = pipe.write(&[]) {
-                        log::warn!("Failed to send 0-byte message: {}", e);
+                match &opts.end_of_input {
+                    EndOfInput::ZeroMessage => {
+                        log::debug!(direction, event = "zero_message"; "Sending 0-byte message to pipe");
+                        if let Err(e) = pipe.write(&[]) {
+                            log::warn!(direction, event = "error"; "Failed to send 0-byte message: {}", e);
+                        }
                     }
+                    EndOfInput::Sentinel(sentinel) => {
+                        log::debug!(direction, event = "sentinel"; "Sending {}-byte end-of-input sentinel to pipe", sentinel.len());
+                        if let Err(e) = pipe.write_all(sentinel).and_then(|()| pipe.flush()) {
+                            log::warn!(direction, event = "error"; "Failed to send end-of-input sentinel: {}", e);
+                        }
+                    }
+                    EndOfInput::Close | EndOfInput::Keep => {}
                 }
-
-                if exit_immediately {
-                    log::debug!("Exiting immediately on stdin EOF (-ei)");
-                    std::process::exit(0);
-                }
-                break;
+                return EndReason::Eof;
             }
             Ok(n) => {
-                log::debug!("Read {} bytes from stdin", n);
-                if let Err(e) = pipe.write_all(&buffer[..n]) {
+                state.read(Direction::StdinToPipe, n);
+                let allowed = state.claim(n, opts.max_bytes);
+                if let Some(limiter) = &opts.rate_in {
+                    state.throttle(limiter, allowed);
+                }
+                if state.cancelled.load(Ordering::SeqCst) {
+                    return EndReason::Cancelled;
+                }
+                if let Err(e) = pipe.write_all(&buffer[..allowed]) {
                     if is_broken_pipe(&e) {
-                        log::debug!("Pipe broken while writing");
+                        log::debug!(direction, event = "broken_pipe"; "Pipe broken while writing");
                         state.pipe_done.store(true, Ordering::SeqCst);
-                        break;
+                        return EndReason::BrokenPipe;
                     }
-                    return Err(e);
+                    return EndReason::Error(e);
+                }
+                state.wrote(Direction::StdinToPipe, allowed);
+                if allowed < n {
+                    log::debug!(direction, event = "max_bytes"; "Byte limit reached, dropping {} bytes from stdin", n - allowed);
+                    return EndReason::MaxBytes;
                 }
             }
             Err(e) => {
-                log::warn!("Error reading stdin: {}", e);
+                log::warn!(direction, event = "error"; "Error reading stdin: {}", e);
                 state.stdin_done.store(true, Ordering::SeqCst);
-                break;
+                return EndReason::Error(e);
             }
         }
     }
-
-    Ok(())
 }
 
-fn pipe_to_stdout<R: Read>(
+fn pipe_to_stdout<R: Read, W: Write>(
     pipe: &mut R,
-    exit_immediately: bool,
+    stdout: &mut W,
+    opts: &RelayOptions,
     state: &RelayState,
-) -> io::Result<()> {
-    let mut stdout = io::stdout().lock();
+) -> EndReason {
+    let direction = Direction::PipeToStdout.name();
     let mut buffer = vec![0u8; BUFFER_SIZE];
 
     loop {
         match pipe.read(&mut buffer) {
             Ok(0) => {
-                log::debug!("EOF on pipe (0 bytes read)");
+                log::debug!(direction, event = "eof"; "EOF on pipe (0 bytes read)");
                 state.pipe_done.store(true, Ordering::SeqCst);
-
-                if exit_immediately {
-                    log::debug!("Exiting immediately on pipe EOF (-ep)");
-                    std::process::exit(0);
-                }
-                break;
+                let _ = state.first_eof.set(Direction::PipeToStdout);
+                return EndReason::Eof;
             }
             Ok(n) => {
-                log::debug!("Read {} bytes from pipe", n);
-                stdout.write_all(&buffer[..n])?;
-                stdout.flush()?;
+                state.read(Direction::PipeToStdout, n);
+                let allowed = state.claim(n, opts.max_bytes);
+                if let Some(limiter) = &opts.rate_out {
+                    state.throttle(limiter, allowed);
+                }
+                if state.cancelled.load(Ordering::SeqCst) {
+                    return EndReason::Cancelled;
+                }
+                if let Err(e) = stdout
+                    .write_all(&buffer[..allowed])
+                    .and_then(|()| stdout.flush())
+                {
+                    return EndReason::Error(e);
+                }
+                state.wrote(Direction::PipeToStdout, allowed);
+                if allowed < n {
+                    log::debug!(direction, event = "max_bytes"; "Byte limit reached, dropping {} bytes from pipe", n - allowed);
+                    return EndReason::MaxBytes;
+                }
             }
             Err(e) => {
+                state.pipe_done.store(true, Ordering::SeqCst);
                 if is_broken_pipe(&e) {
-                    log::debug!("Pipe broken");
-                    state.pipe_done.store(true, Ordering::SeqCst);
-
-                    if exit_immediately {
-                        log::debug!("Exiting immediately on pipe EOF (-ep)");
-                        std::process::exit(0);
-                    }
-                    break;
+                    log::debug!(direction, event = "broken_pipe"; "Pipe broken");
+                    return EndReason::BrokenPipe;
                 }
-                return Err(e);
+                return EndReason::Error(e);
             }
         }
     }
-
-    Ok(())
 }
 
 /// Check if an I/O error indicates the pipe is broken/disconnected.
@@ -180,6 +736,9 @@ fn is_broken_pipe(e: &io::Error) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cli::CliArgs;
+    use crate::rate::SLEEP_STEP;
+    use clap::Parser;
     use std::io::{Cursor, ErrorKind};
 
     #[test]
@@ -285,4 +844,747 @@ mod tests {
         assert_eq!(n, 9);
         assert_eq!(&buf, data);
     }
+
+    /// Writer that records each individual `write` call, including zero-byte
+    /// writes, and can be inspected after the relay thread has finished.
+    #[derive(Clone, Default)]
+    struct SharedWriter {
+        writes: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl SharedWriter {
+        fn data(&self) -> Vec<u8> {
+            self.writes.lock().unwrap().concat()
+        }
+
+        fn zero_writes(&self) -> usize {
+            self.writes
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|w| w.is_empty())
+                .count()
+        }
+    }
+
+    impl Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.lock().unwrap().push(buf.to_vec());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Pipe reader that yields its data, then holds back EOF until `done`
+    /// returns true, like a server that only hangs up after the client is
+    /// finished.
+    struct EofWhen<F: Fn() -> bool> {
+        data: Cursor<Vec<u8>>,
+        done: F,
+    }
+
+    impl<F: Fn() -> bool> Read for EofWhen<F> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.data.read(buf)?;
+            if n == 0 {
+                while !(self.done)() {
+                    thread::sleep(std::time::Duration::from_millis(1));
+                }
+            }
+            Ok(n)
+        }
+    }
+
+    /// Reader that blocks until the sender is dropped and only then yields
+    /// its data, like a peer that stays silent for the whole session.
+    struct Stalled {
+        data: Cursor<Vec<u8>>,
+        release: mpsc::Receiver<()>,
+    }
+
+    impl Stalled {
+        fn new(data: &[u8]) -> (Self, mpsc::Sender<()>) {
+            let (tx, rx) = mpsc::channel();
+            let reader = Self {
+                data: Cursor::new(data.to_vec()),
+                release: rx,
+            };
+            (reader, tx)
+        }
+    }
+
+    impl Read for Stalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let _ = self.release.recv();
+            self.data.read(buf)
+        }
+    }
+
+    /// Writer whose first write never returns, like a peer that stopped
+    /// reading. Entering it drops `entered`, releasing a [`Stalled`] reader.
+    struct Blocked {
+        entered: Option<mpsc::Sender<()>>,
+    }
+
+    impl Blocked {
+        fn new() -> Self {
+            Self { entered: None }
+        }
+
+        fn releasing(entered: mpsc::Sender<()>) -> Self {
+            Self {
+                entered: Some(entered),
+            }
+        }
+    }
+
+    impl Write for Blocked {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            drop(self.entered.take());
+            loop {
+                thread::park();
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relay_between_copies_both_directions() {
+        let pipe_in = SharedWriter::default();
+        let observed = pipe_in.clone();
+        let stdout = SharedWriter::default();
+
+        let outcome = run_relay_between(
+            Cursor::new(b"request".to_vec()),
+            stdout.clone(),
+            EofWhen {
+                data: Cursor::new(b"response".to_vec()),
+                done: move || observed.data().len() == 7,
+            },
+            pipe_in.clone(),
+            RelayOptions::default(),
+        );
+
+        assert_eq!(pipe_in.data(), b"request");
+        assert_eq!(stdout.data(), b"response");
+        assert_eq!(pipe_in.zero_writes(), 0);
+        assert_eq!(outcome.first, Direction::StdinToPipe);
+        assert!(matches!(outcome.reason(), Some(EndReason::Eof)));
+        assert_eq!(outcome.stdin_to_pipe.bytes, 7);
+        assert_eq!(outcome.pipe_to_stdout.bytes, 8);
+        assert_eq!(outcome.stdin_to_pipe.chunks, 1);
+        assert_eq!(outcome.pipe_to_stdout.chunks, 1);
+        assert!(outcome.stdin_to_pipe.first_byte.unwrap() <= outcome.duration);
+        assert!(outcome.pipe_to_stdout.first_byte.is_some());
+        assert_eq!(outcome.first_eof, Some(Direction::StdinToPipe));
+        assert_eq!(outcome.termination, Termination::Finished);
+        assert!(matches!(outcome.pipe_to_stdout.end, Some(EndReason::Eof)));
+        assert!(outcome.into_error().is_none());
+    }
+
+    #[test]
+    fn test_relay_between_send_zero() {
+        let pipe_in = SharedWriter::default();
+        let observed = pipe_in.clone();
+        let opts = RelayOptions {
+            end_of_input: EndOfInput::ZeroMessage,
+            ..Default::default()
+        };
+
+        run_relay_between(
+            Cursor::new(b"data".to_vec()),
+            io::sink(),
+            EofWhen {
+                data: Cursor::new(Vec::new()),
+                done: move || observed.zero_writes() == 1,
+            },
+            pipe_in.clone(),
+            opts,
+        );
+
+        assert_eq!(pipe_in.data(), b"data");
+        assert_eq!(pipe_in.zero_writes(), 1);
+    }
+
+    /// Writer that notes when it is dropped.
+    struct DropFlag(SharedWriter, Arc<AtomicBool>);
+
+    impl Write for DropFlag {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.1.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn test_relay_between_closes_pipe_writer_at_end_of_input() {
+        let pipe_in = SharedWriter::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let observed = Arc::clone(&closed);
+
+        // The pipe side only answers once it has seen the end of input.
+        let outcome = run_relay_between(
+            Cursor::new(b"request".to_vec()),
+            io::sink(),
+            EofWhen {
+                data: Cursor::new(Vec::new()),
+                done: move || observed.load(Ordering::SeqCst),
+            },
+            DropFlag(pipe_in.clone(), closed),
+            RelayOptions {
+                end_of_input: EndOfInput::Close,
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(pipe_in.data(), b"request");
+        assert_eq!(outcome.termination, Termination::Finished);
+    }
+
+    #[test]
+    fn test_relay_between_sentinel() {
+        let pipe_in = SharedWriter::default();
+        let closed = Arc::new(AtomicBool::new(false));
+        let observed = Arc::clone(&closed);
+        let opts = RelayOptions {
+            end_of_input: EndOfInput::Sentinel(b"\x04".to_vec()),
+            ..Default::default()
+        };
+
+        run_relay_between(
+            Cursor::new(b"data".to_vec()),
+            io::sink(),
+            EofWhen {
+                data: Cursor::new(Vec::new()),
+                done: move || observed.load(Ordering::SeqCst),
+            },
+            DropFlag(pipe_in.clone(), closed),
+            opts,
+        );
+
+        assert_eq!(pipe_in.data(), b"data\x04");
+        assert_eq!(pipe_in.zero_writes(), 0);
+    }
+
+    #[test]
+    fn test_relay_between_keep_holds_pipe_writer_open() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let opts = RelayOptions {
+            end_of_input: EndOfInput::Keep,
+            ..Default::default()
+        };
+        let (pipe, release) = Stalled::new(b"bye");
+        let observed = Arc::clone(&closed);
+        let stdout = SharedWriter::default();
+
+        // Release the pipe side once stdin has ended; the writer must still
+        // be open then, and closed once the relay has returned.
+        let watcher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let open = !observed.load(Ordering::SeqCst);
+            drop(release);
+            open
+        });
+        run_relay_between(
+            Cursor::new(b"data".to_vec()),
+            stdout.clone(),
+            pipe,
+            DropFlag(SharedWriter::default(), Arc::clone(&closed)),
+            opts,
+        );
+
+        assert!(watcher.join().unwrap());
+        assert!(closed.load(Ordering::SeqCst));
+        assert_eq!(stdout.data(), b"bye");
+    }
+
+    #[test]
+    fn test_relay_between_closes_stdout_when_pipe_finishes() {
+        let closed = Arc::new(AtomicBool::new(false));
+        let observed = Arc::clone(&closed);
+        let (stdin, release) = Stalled::new(b"");
+
+        // Stdin stays open until stdout has been closed.
+        let watcher = thread::spawn(move || {
+            while !observed.load(Ordering::SeqCst) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            drop(release);
+        });
+        run_relay_between(
+            stdin,
+            DropFlag(SharedWriter::default(), Arc::clone(&closed)),
+            Cursor::new(b"reply".to_vec()),
+            SharedWriter::default(),
+            RelayOptions::default(),
+        );
+
+        watcher.join().unwrap();
+        assert!(closed.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_relay_between_broken_pipe_on_write() {
+        struct Broken(Arc<AtomicBool>);
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                self.0.store(true, Ordering::SeqCst);
+                Err(io::Error::new(ErrorKind::BrokenPipe, "mock error"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let broken = Arc::new(AtomicBool::new(false));
+        let observed = Arc::clone(&broken);
+        let stdout = SharedWriter::default();
+
+        let outcome = run_relay_between(
+            Cursor::new(b"data".to_vec()),
+            stdout.clone(),
+            EofWhen {
+                data: Cursor::new(b"reply".to_vec()),
+                done: move || observed.load(Ordering::SeqCst),
+            },
+            Broken(broken),
+            RelayOptions::default(),
+        );
+
+        assert_eq!(outcome.first, Direction::StdinToPipe);
+        assert!(matches!(outcome.reason(), Some(EndReason::BrokenPipe)));
+        assert_eq!(outcome.stdin_to_pipe.bytes, 0);
+        assert_eq!(stdout.data(), b"reply");
+        assert!(outcome.into_error().is_none());
+    }
+
+    #[test]
+    fn test_relay_between_exit_on_stdin_eof() {
+        let stdout = SharedWriter::default();
+        let (pipe, _release) = Stalled::new(b"");
+        let opts = RelayOptions {
+            exit_on_stdin_eof: true,
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            Cursor::new(b"bye".to_vec()),
+            stdout.clone(),
+            pipe,
+            SharedWriter::default(),
+            opts,
+        );
+
+        assert_eq!(outcome.first, Direction::StdinToPipe);
+        assert!(matches!(outcome.reason(), Some(EndReason::Eof)));
+        assert_eq!(outcome.stdin_to_pipe.bytes, 3);
+        assert!(outcome.pipe_to_stdout.end.is_none());
+        assert_eq!(outcome.pipe_to_stdout.first_byte, None);
+        assert_eq!(outcome.termination, Termination::ExitOnStdinEof);
+    }
+
+    #[test]
+    fn test_relay_between_exit_on_pipe_eof() {
+        let stdout = SharedWriter::default();
+        let (stdin, _release) = Stalled::new(b"");
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            stdin,
+            stdout.clone(),
+            Cursor::new(b"done".to_vec()),
+            SharedWriter::default(),
+            opts,
+        );
+
+        assert_eq!(outcome.first, Direction::PipeToStdout);
+        assert!(matches!(outcome.reason(), Some(EndReason::Eof)));
+        assert!(outcome.stdin_to_pipe.end.is_none());
+        assert_eq!(stdout.data(), b"done");
+        assert_eq!(outcome.pipe_to_stdout.chunks, 1);
+        assert_eq!(outcome.first_eof, Some(Direction::PipeToStdout));
+        assert_eq!(outcome.termination, Termination::ExitOnPipeEof);
+    }
+
+    #[test]
+    fn test_relay_between_idle_timeout() {
+        let (stdin, _release_stdin) = Stalled::new(b"");
+        let (pipe, _release_pipe) = Stalled::new(b"");
+        let opts = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(stdin, io::sink(), pipe, io::sink(), opts);
+
+        assert_eq!(outcome.termination, Termination::IdleTimeout);
+        assert!(outcome.duration >= Duration::from_millis(50));
+        assert!(outcome.stdin_to_pipe.end.is_none());
+        assert!(outcome.pipe_to_stdout.end.is_none());
+        assert!(outcome.into_error().is_none());
+    }
+
+    #[test]
+    fn test_relay_between_max_duration_despite_activity() {
+        // A byte every 10ms keeps the session from going idle.
+        struct Trickle;
+        impl Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(10));
+                buf[0] = b'.';
+                Ok(1)
+            }
+        }
+
+        let (pipe, _release) = Stalled::new(b"");
+        let pipe_in = SharedWriter::default();
+        let opts = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            max_duration: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(Trickle, io::sink(), pipe, pipe_in.clone(), opts);
+
+        assert_eq!(outcome.termination, Termination::MaxDuration);
+        assert!(outcome.duration >= Duration::from_millis(200));
+        assert!(outcome.stdin_to_pipe.chunks > 1);
+        assert_eq!(pipe_in.data().len() as u64, outcome.stdin_to_pipe.bytes);
+    }
+
+    #[test]
+    fn test_relay_between_max_bytes() {
+        let (pipe, _release) = Stalled::new(b"");
+        let pipe_in = SharedWriter::default();
+        let opts = RelayOptions {
+            max_bytes: Some(5),
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            Cursor::new(b"hello world".to_vec()),
+            io::sink(),
+            pipe,
+            pipe_in.clone(),
+            opts,
+        );
+
+        assert_eq!(outcome.termination, Termination::MaxBytes);
+        assert_eq!(pipe_in.data(), b"hello");
+        assert_eq!(outcome.stdin_to_pipe.bytes, 5);
+        assert!(matches!(outcome.reason(), Some(EndReason::MaxBytes)));
+    }
+
+    #[test]
+    fn test_relay_between_max_bytes_is_shared() {
+        let pipe_in = SharedWriter::default();
+        let observed = pipe_in.clone();
+        let stdout = SharedWriter::default();
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            max_bytes: Some(10),
+            ..Default::default()
+        };
+
+        // The pipe answers only once the request has used up 7 bytes.
+        struct Reply(SharedWriter, Cursor<Vec<u8>>);
+        impl Read for Reply {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                while self.0.data().len() < 7 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                self.1.read(buf)
+            }
+        }
+
+        let outcome = run_relay_between(
+            Cursor::new(b"request".to_vec()),
+            stdout.clone(),
+            Reply(observed, Cursor::new(b"response".to_vec())),
+            pipe_in.clone(),
+            opts,
+        );
+
+        // The quota is not -ep's pipe EOF, so it decides the termination.
+        assert_eq!(outcome.termination, Termination::MaxBytes);
+        assert_eq!(pipe_in.data(), b"request");
+        assert_eq!(stdout.data(), b"res");
+        assert!(matches!(
+            outcome.pipe_to_stdout.end,
+            Some(EndReason::MaxBytes)
+        ));
+    }
+
+    #[test]
+    fn test_relay_between_rate_limits_each_direction() {
+        use crate::retry::Clock;
+
+        /// Clock that stands still and records what it is asked to sleep.
+        #[derive(Default)]
+        struct Recording(Mutex<Vec<Duration>>);
+        impl Clock for Recording {
+            fn now(&self) -> Instant {
+                Instant::now()
+            }
+            fn sleep(&self, duration: Duration) {
+                self.0.lock().unwrap().push(duration);
+            }
+        }
+
+        let clock = Arc::new(Recording::default());
+        let limiter = |rate| Some(Arc::new(RateLimiter::with_clock(rate, 1000, clock.clone())));
+        let pipe_in = SharedWriter::default();
+        let observed = pipe_in.clone();
+        let stdout = SharedWriter::default();
+        let opts = RelayOptions {
+            rate_in: limiter(1000),
+            rate_out: limiter(2000),
+            ..Default::default()
+        };
+
+        run_relay_between(
+            Cursor::new(vec![b'a'; 3000]),
+            stdout.clone(),
+            EofWhen {
+                data: Cursor::new(vec![b'b'; 500]),
+                done: move || observed.data().len() == 3000,
+            },
+            pipe_in.clone(),
+            opts,
+        );
+
+        assert_eq!(pipe_in.data().len(), 3000);
+        assert_eq!(stdout.data().len(), 500);
+        // Only stdin to pipe went past its burst: 2000 bytes at 1000/s, slept
+        // in steps.
+        let sleeps = clock.0.lock().unwrap().clone();
+        assert!(sleeps.iter().all(|&sleep| sleep <= SLEEP_STEP));
+        let slept: Duration = sleeps.iter().sum();
+        assert!(slept.abs_diff(Duration::from_secs(2)) < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_relay_between_throttled_time_is_not_idle() {
+        use crate::retry::Clock;
+
+        /// Clock that only moves when something sleeps on it.
+        struct Fake(Mutex<Instant>);
+        impl Clock for Fake {
+            fn now(&self) -> Instant {
+                *self.0.lock().unwrap()
+            }
+            fn sleep(&self, duration: Duration) {
+                *self.0.lock().unwrap() += duration;
+            }
+        }
+
+        let clock = Arc::new(Fake(Mutex::new(Instant::now())));
+        let pipe_in = SharedWriter::default();
+        let observed = pipe_in.clone();
+        let opts = RelayOptions {
+            // 3000 bytes at 1000/s are throttled for three idle timeouts.
+            rate_in: Some(Arc::new(RateLimiter::with_clock(1000, 0, clock.clone()))),
+            idle_timeout: Some(Duration::from_secs(1)),
+            clock: clock.clone(),
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            Cursor::new(vec![b'a'; 3000]),
+            SharedWriter::default(),
+            EofWhen {
+                data: Cursor::new(Vec::new()),
+                done: move || observed.data().len() == 3000,
+            },
+            pipe_in.clone(),
+            opts,
+        );
+
+        assert_eq!(outcome.termination, Termination::Finished);
+        assert_eq!(pipe_in.data().len(), 3000);
+        assert_eq!(outcome.duration, Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_relay_between_cancelled_direction_stops_writing() {
+        let stdout = SharedWriter::default();
+        let (stdin, release) = Stalled::new(b"late");
+        let pipe_in = SharedWriter::default();
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+
+        run_relay_between(
+            stdin,
+            stdout,
+            Cursor::new(Vec::new()),
+            pipe_in.clone(),
+            opts,
+        );
+        drop(release);
+        thread::sleep(std::time::Duration::from_millis(20));
+
+        assert!(pipe_in.data().is_empty());
+    }
+
+    #[test]
+    fn test_relay_between_exit_on_pipe_eof_leaves_blocked_write() {
+        // The pipe hangs up once the stdin to pipe write is stuck.
+        let (pipe_out, entered) = Stalled::new(b"");
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            Cursor::new(b"request".to_vec()),
+            io::sink(),
+            pipe_out,
+            Blocked::releasing(entered),
+            opts,
+        );
+
+        assert_eq!(outcome.termination, Termination::ExitOnPipeEof);
+        assert!(outcome.stdin_to_pipe.end.is_none());
+    }
+
+    #[test]
+    fn test_relay_between_time_limits_leave_blocked_write() {
+        for (opts, termination) in [
+            (
+                RelayOptions {
+                    idle_timeout: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                Termination::IdleTimeout,
+            ),
+            (
+                RelayOptions {
+                    max_duration: Some(Duration::from_millis(300)),
+                    ..Default::default()
+                },
+                Termination::MaxDuration,
+            ),
+        ] {
+            // The target never reads what it is sent, nor answers.
+            let (pipe_out, _release) = Stalled::new(b"");
+            let started = Instant::now();
+
+            let outcome = run_relay_between(
+                Cursor::new(b"request".to_vec()),
+                io::sink(),
+                pipe_out,
+                Blocked::new(),
+                opts,
+            );
+
+            assert_eq!(outcome.termination, termination);
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+    }
+
+    #[test]
+    fn test_relay_between_max_bytes_leaves_blocked_write() {
+        // Stdout has stopped reading; stdin then goes over the quota.
+        let (stdin, entered) = Stalled::new(b"0123456789");
+        let pipe_in = SharedWriter::default();
+        let opts = RelayOptions {
+            max_bytes: Some(5),
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(
+            stdin,
+            Blocked::releasing(entered),
+            Cursor::new(b"abc".to_vec()),
+            pipe_in.clone(),
+            opts,
+        );
+
+        assert_eq!(outcome.termination, Termination::MaxBytes);
+        assert_eq!(pipe_in.data(), b"01");
+        assert!(outcome.pipe_to_stdout.end.is_none());
+    }
+
+    #[test]
+    fn test_relay_between_pipe_error_is_reported() {
+        struct Failing;
+        impl Read for Failing {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+
+        let (stdin, _release) = Stalled::new(b"");
+        let opts = RelayOptions {
+            exit_on_pipe_eof: true,
+            ..Default::default()
+        };
+
+        let outcome = run_relay_between(stdin, io::sink(), Failing, io::sink(), opts);
+
+        assert!(matches!(outcome.reason(), Some(EndReason::Error(_))));
+        let err = outcome.into_error().unwrap();
+        assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_relay_between_socketpair() {
+        use std::os::unix::net::UnixStream;
+
+        let (relay_side, mut server) = UnixStream::pair().unwrap();
+        let relay_reader = relay_side.try_clone().unwrap();
+
+        let server_thread = thread::spawn(move || {
+            let mut request = [0u8; 4];
+            server.read_exact(&mut request).unwrap();
+            assert_eq!(&request, b"ping");
+            server.write_all(b"pong").unwrap();
+            server.shutdown(std::net::Shutdown::Both).unwrap();
+        });
+
+        let stdout = SharedWriter::default();
+        let outcome = run_relay_between(
+            Cursor::new(b"ping".to_vec()),
+            stdout.clone(),
+            relay_reader,
+            relay_side,
+            RelayOptions::default(),
+        );
+
+        server_thread.join().unwrap();
+        assert_eq!(stdout.data(), b"pong");
+        assert_eq!(outcome.stdin_to_pipe.bytes, 4);
+        assert_eq!(outcome.pipe_to_stdout.bytes, 4);
+    }
+
+    #[test]
+    fn test_relay_options_from_config() {
+        let args = CliArgs::try_parse_from(["baton", "-s", "--ep", "//./pipe/test"]).unwrap();
+        let config = Config::try_from(args).unwrap();
+        let opts = RelayOptions::from(&config);
+        assert_eq!(opts.end_of_input, EndOfInput::ZeroMessage);
+        assert!(opts.exit_on_pipe_eof);
+        assert!(!opts.exit_on_stdin_eof);
+    }
 }
@@ -0,0 +1,615 @@
+//! Playing back recorded sessions (`baton replay`).
+//!
+//! A transcript holds both sides of a session. Replay plays one [`Side`]:
+//! it sends that side's recorded chunks, pausing between them as recorded
+//! (or faster, see [`Timing`]), and checks that what the peer sends matches
+//! the other side of the recording. Chunk boundaries are not compared, only
+//! the byte streams, because reads are split differently on every run.
+//!
+//! `baton replay` plays the client against a live target. Tests can play the
+//! server instead ([`serve_tcp`]) and run the relay against it, so a golden
+//! transcript catches relay regressions.
+
+use crate::endpoint::HalfClose;
+use crate::relay::{Direction, BUFFER_SIZE};
+use crate::transcript::{Record, RecordEvent};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for each recorded chunk from the peer by default.
+pub const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes shown on each side of a mismatch.
+const SNIPPET_LEN: usize = 32;
+
+/// Which end of the recorded session to play.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Send what arrived on stdin; expect what came from the pipe.
+    Client,
+    /// Send what came from the pipe; expect what arrived on stdin.
+    Server,
+}
+
+impl Side {
+    fn sends(self) -> Direction {
+        match self {
+            Side::Client => Direction::StdinToPipe,
+            Side::Server => Direction::PipeToStdout,
+        }
+    }
+}
+
+/// Pauses between sent chunks. Recorded gaps are divided by `speed`, then
+/// capped at `max_gap`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    pub speed: f64,
+    pub max_gap: Option<Duration>,
+}
+
+impl Timing {
+    /// Send without pausing.
+    pub fn immediate() -> Self {
+        Self {
+            speed: 1.0,
+            max_gap: Some(Duration::ZERO),
+        }
+    }
+
+    pub fn delay(&self, gap: Duration) -> Duration {
+        let delay = gap.div_f64(self.speed);
+        self.max_gap.map_or(delay, |max| delay.min(max))
+    }
+}
+
+impl Default for Timing {
+    /// Pauses as recorded.
+    fn default() -> Self {
+        Self {
+            speed: 1.0,
+            max_gap: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    pub timing: Timing,
+    /// How long to wait for each recorded chunk or EOF from the peer.
+    pub response_timeout: Duration,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            timing: Timing::default(),
+            response_timeout: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+/// A point where the peer did not behave as recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the transcript record being replayed.
+    pub record: usize,
+    /// Offset into the stream expected from the peer.
+    pub offset: u64,
+    pub kind: DivergenceKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DivergenceKind {
+    /// Different bytes arrived. Both snippets start at the first difference.
+    Mismatch { expected: Vec<u8>, actual: Vec<u8> },
+    /// The peer closed its side before sending this many recorded bytes.
+    Closed { missing: usize },
+    /// The recorded bytes did not arrive in time.
+    Timeout { missing: usize },
+    /// The peer sent data where the recording ends its stream.
+    Extra(Vec<u8>),
+    /// The recording ends the peer's stream here but the peer kept it open.
+    MissingEof,
+    /// Sending a recorded chunk failed.
+    SendFailed(String),
+}
+
+impl DivergenceKind {
+    /// Whether the two streams are still aligned afterwards. Replay stops
+    /// after a divergence that loses alignment.
+    fn keeps_alignment(&self) -> bool {
+        matches!(
+            self,
+            DivergenceKind::Mismatch { .. } | DivergenceKind::MissingEof
+        )
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "record {} (offset {}): ", self.record, self.offset)?;
+        match &self.kind {
+            DivergenceKind::Mismatch { expected, actual } => write!(
+                f,
+                "expected \"{}\", got \"{}\"",
+                expected.escape_ascii(),
+                actual.escape_ascii()
+            ),
+            DivergenceKind::Closed { missing } => {
+                write!(f, "peer closed with {} recorded bytes missing", missing)
+            }
+            DivergenceKind::Timeout { missing } => {
+                write!(f, "timed out waiting for {} recorded bytes", missing)
+            }
+            DivergenceKind::Extra(data) => {
+                write!(f, "unexpected data \"{}\"", snippet(data).escape_ascii())
+            }
+            DivergenceKind::MissingEof => write!(f, "expected EOF, peer kept the stream open"),
+            DivergenceKind::SendFailed(e) => write!(f, "send failed: {}", e),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub sent: u64,
+    pub received: u64,
+    pub divergences: Vec<Divergence>,
+    /// The transcript hit its size limit, so only its start was replayed.
+    pub truncated: bool,
+}
+
+impl ReplayReport {
+    pub fn is_clean(&self) -> bool {
+        self.divergences.is_empty()
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sent {} bytes, received {} bytes, {} divergence(s)",
+            self.sent,
+            self.received,
+            self.divergences.len()
+        )?;
+        if self.truncated {
+            write!(
+                f,
+                "\ntranscript was truncated; replay stopped where it ends"
+            )?;
+        }
+        for divergence in &self.divergences {
+            write!(f, "\n{}", divergence)?;
+        }
+        Ok(())
+    }
+}
+
+/// Play `side` of `records` against a peer reachable through `reader` and
+/// `writer`. `writer` is dropped where the recording has this side's EOF, so
+/// it should close its direction when dropped.
+pub fn replay<R, W>(
+    records: &[Record],
+    side: Side,
+    reader: R,
+    writer: W,
+    options: &ReplayOptions,
+) -> ReplayReport
+where
+    R: Read + Send + 'static,
+    W: Write,
+{
+    let mut report = ReplayReport::default();
+    let mut incoming = Incoming::spawn(reader);
+    let mut writer = Some(writer);
+    let mut previous = Duration::ZERO;
+
+    for (index, record) in records.iter().enumerate() {
+        let gap = record.at.saturating_sub(previous);
+        previous = record.at;
+        let offset = report.received;
+
+        let divergence = match (&record.event, record.direction == side.sends()) {
+            (RecordEvent::Truncated, _) => {
+                report.truncated = true;
+                break;
+            }
+            (RecordEvent::Data(data), true) => {
+                thread::sleep(options.timing.delay(gap));
+                let Some(out) = writer.as_mut() else { continue };
+                match out.write_all(data).and_then(|()| out.flush()) {
+                    Ok(()) => {
+                        report.sent += data.len() as u64;
+                        None
+                    }
+                    Err(e) => Some(DivergenceKind::SendFailed(e.to_string())),
+                }
+            }
+            (RecordEvent::Eof | RecordEvent::Error(_), true) => {
+                thread::sleep(options.timing.delay(gap));
+                writer = None;
+                None
+            }
+            (RecordEvent::Data(data), false) => {
+                let result = incoming.expect(data, options.response_timeout);
+                if !matches!(
+                    result,
+                    Some(DivergenceKind::Closed { .. } | DivergenceKind::Timeout { .. })
+                ) {
+                    report.received += data.len() as u64;
+                }
+                result
+            }
+            (RecordEvent::Eof | RecordEvent::Error(_), false) => {
+                incoming.expect_end(options.response_timeout)
+            }
+        };
+
+        if let Some(kind) = divergence {
+            let aligned = kind.keeps_alignment();
+            report.divergences.push(Divergence {
+                record: index,
+                offset,
+                kind,
+            });
+            if !aligned {
+                break;
+            }
+        }
+    }
+
+    report
+}
+
+/// Accept one connection on `listener` and play the server side of
+/// `records` to it. For tests: point the relay at the listener.
+pub fn serve_tcp(
+    listener: &TcpListener,
+    records: &[Record],
+    options: &ReplayOptions,
+) -> io::Result<ReplayReport> {
+    let (stream, _) = listener.accept()?;
+    let reader = stream.try_clone()?;
+    Ok(replay(
+        records,
+        Side::Server,
+        reader,
+        HalfClose(stream),
+        options,
+    ))
+}
+
+/// Data from the peer, read on a separate thread so waits can time out.
+struct Incoming {
+    /// An empty chunk means EOF; read errors count as EOF too.
+    chunks: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    closed: bool,
+}
+
+impl Incoming {
+    fn spawn<R: Read + Send + 'static>(mut reader: R) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = vec![0u8; BUFFER_SIZE];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(n) => buf[..n].to_vec(),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        log::debug!("Replay read failed: {}", e);
+                        Vec::new()
+                    }
+                };
+                let eof = chunk.is_empty();
+                if tx.send(chunk).is_err() || eof {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            chunks,
+            pending: VecDeque::new(),
+            closed: false,
+        }
+    }
+
+    /// Wait for `expected.len()` bytes and compare them with `expected`.
+    fn expect(&mut self, expected: &[u8], timeout: Duration) -> Option<DivergenceKind> {
+        let deadline = Instant::now() + timeout;
+        while self.pending.len() < expected.len() {
+            let missing = expected.len() - self.pending.len();
+            if self.closed {
+                return Some(DivergenceKind::Closed { missing });
+            }
+            match self.recv(deadline) {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(()) => return Some(DivergenceKind::Timeout { missing }),
+            }
+        }
+
+        let actual: Vec<u8> = self.pending.drain(..expected.len()).collect();
+        let first_difference = expected.iter().zip(&actual).position(|(e, a)| e != a)?;
+        Some(DivergenceKind::Mismatch {
+            expected: snippet(&expected[first_difference..]).to_vec(),
+            actual: snippet(&actual[first_difference..]).to_vec(),
+        })
+    }
+
+    /// Wait for the peer to end its stream.
+    fn expect_end(&mut self, timeout: Duration) -> Option<DivergenceKind> {
+        if !self.pending.is_empty() {
+            let extra: Vec<u8> = self.pending.drain(..).collect();
+            return Some(DivergenceKind::Extra(extra));
+        }
+        if self.closed {
+            return None;
+        }
+        match self.recv(Instant::now() + timeout) {
+            Ok(chunk) if chunk.is_empty() => None,
+            Ok(chunk) => Some(DivergenceKind::Extra(chunk)),
+            Err(()) => Some(DivergenceKind::MissingEof),
+        }
+    }
+
+    /// The next chunk, empty once the peer has closed. `Err` on timeout.
+    fn recv(&mut self, deadline: Instant) -> Result<Vec<u8>, ()> {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        match self.chunks.recv_timeout(timeout) {
+            Ok(chunk) => {
+                self.closed = chunk.is_empty();
+                Ok(chunk)
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.closed = true;
+                Ok(Vec::new())
+            }
+            Err(RecvTimeoutError::Timeout) => Err(()),
+        }
+    }
+}
+
+fn snippet(data: &[u8]) -> &[u8] {
+    &data[..data.len().min(SNIPPET_LEN)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reader that never returns, like a peer that keeps the stream open.
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            thread::park();
+            Ok(0)
+        }
+    }
+
+    fn record(direction: Direction, millis: u64, event: RecordEvent) -> Record {
+        Record {
+            direction,
+            at: Duration::from_millis(millis),
+            event,
+        }
+    }
+
+    fn data(direction: Direction, millis: u64, data: &[u8]) -> Record {
+        record(direction, millis, RecordEvent::Data(data.to_vec()))
+    }
+
+    /// A request, a reply split over two reads, and both EOFs.
+    fn session() -> Vec<Record> {
+        vec![
+            data(Direction::StdinToPipe, 0, b"ping"),
+            data(Direction::PipeToStdout, 5, b"PO"),
+            data(Direction::PipeToStdout, 6, b"NG"),
+            record(Direction::StdinToPipe, 7, RecordEvent::Eof),
+            record(Direction::PipeToStdout, 8, RecordEvent::Eof),
+        ]
+    }
+
+    fn fast() -> ReplayOptions {
+        ReplayOptions {
+            timing: Timing::immediate(),
+            response_timeout: Duration::from_millis(200),
+        }
+    }
+
+    #[test]
+    fn test_timing_delay() {
+        let gap = Duration::from_millis(400);
+        assert_eq!(Timing::default().delay(gap), gap);
+        assert_eq!(Timing::immediate().delay(gap), Duration::ZERO);
+
+        let timing = Timing {
+            speed: 4.0,
+            max_gap: Some(Duration::from_millis(50)),
+        };
+        assert_eq!(
+            timing.delay(Duration::from_millis(100)),
+            Duration::from_millis(25)
+        );
+        assert_eq!(timing.delay(gap), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_replay_client_matches() {
+        let sent = SharedBuf::default();
+        let report = replay(
+            &session(),
+            Side::Client,
+            Cursor::new(b"PONG".to_vec()),
+            sent.clone(),
+            &fast(),
+        );
+
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(*sent.0.lock().unwrap(), b"ping");
+        assert_eq!((report.sent, report.received), (4, 4));
+    }
+
+    #[test]
+    fn test_replay_server_side() {
+        let sent = SharedBuf::default();
+        let report = replay(
+            &session(),
+            Side::Server,
+            Cursor::new(b"ping".to_vec()),
+            sent.clone(),
+            &fast(),
+        );
+
+        assert!(report.is_clean(), "{}", report);
+        assert_eq!(*sent.0.lock().unwrap(), b"PONG");
+    }
+
+    #[test]
+    fn test_replay_reports_mismatch_and_continues() {
+        let report = replay(
+            &session(),
+            Side::Client,
+            Cursor::new(b"PANG".to_vec()),
+            io::sink(),
+            &fast(),
+        );
+
+        assert_eq!(
+            report.divergences,
+            [Divergence {
+                record: 1,
+                offset: 0,
+                kind: DivergenceKind::Mismatch {
+                    expected: b"O".to_vec(),
+                    actual: b"A".to_vec(),
+                },
+            }]
+        );
+        assert_eq!(report.received, 4);
+        assert!(report.to_string().contains("expected \"O\", got \"A\""));
+    }
+
+    #[test]
+    fn test_replay_reports_early_close() {
+        let report = replay(
+            &session(),
+            Side::Client,
+            Cursor::new(b"PON".to_vec()),
+            io::sink(),
+            &fast(),
+        );
+
+        assert_eq!(report.divergences.len(), 1);
+        assert_eq!(report.divergences[0].record, 2);
+        assert_eq!(report.divergences[0].offset, 2);
+        assert_eq!(
+            report.divergences[0].kind,
+            DivergenceKind::Closed { missing: 1 }
+        );
+    }
+
+    #[test]
+    fn test_replay_reports_extra_data() {
+        let report = replay(
+            &session(),
+            Side::Client,
+            Cursor::new(b"PONG!".to_vec()),
+            io::sink(),
+            &fast(),
+        );
+
+        assert_eq!(
+            report.divergences[0].kind,
+            DivergenceKind::Extra(b"!".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_replay_times_out() {
+        let report = replay(&session(), Side::Client, Silent, io::sink(), &fast());
+
+        assert_eq!(
+            report.divergences,
+            [Divergence {
+                record: 1,
+                offset: 0,
+                kind: DivergenceKind::Timeout { missing: 2 },
+            }]
+        );
+    }
+
+    #[test]
+    fn test_replay_stops_at_truncation() {
+        let mut records = session();
+        records.insert(1, record(Direction::StdinToPipe, 1, RecordEvent::Truncated));
+        let sent = SharedBuf::default();
+        let report = replay(&records, Side::Client, Silent, sent.clone(), &fast());
+
+        assert!(report.truncated);
+        assert!(report.is_clean());
+        assert_eq!(*sent.0.lock().unwrap(), b"ping");
+    }
+
+    #[test]
+    fn test_replay_keeps_recorded_gaps() {
+        let records = vec![
+            data(Direction::StdinToPipe, 0, b"a"),
+            data(Direction::StdinToPipe, 100, b"b"),
+        ];
+        let options = ReplayOptions {
+            timing: Timing {
+                speed: 2.0,
+                max_gap: None,
+            },
+            ..fast()
+        };
+        let start = Instant::now();
+        replay(&records, Side::Client, Silent, io::sink(), &options);
+
+        assert!(start.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn test_serve_tcp_plays_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || serve_tcp(&listener, &session(), &fast()).unwrap());
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"ping").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+
+        assert_eq!(reply, b"PONG");
+        let report = server.join().unwrap();
+        assert!(report.is_clean(), "{}", report);
+    }
+}
@@ -0,0 +1,515 @@
+//! Connection retry policy shared by every endpoint.
+//!
+//! `-p` turns retrying on and `-l` caps it at 300 attempts, matching
+//! npiperelay. The `--retry-*` flags refine that with exponential backoff,
+//! jitter, an attempt limit and a wall-clock deadline, and `--connect-timeout`
+//! bounds each individual attempt.
+//!
+//! Time is read and spent through a [`Clock`] so the retry loop can be tested
+//! without real sleeps.
+
+use crate::errors::BatonError;
+use std::fmt;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(200);
+pub const DEFAULT_MAX_INTERVAL: Duration = Duration::from_secs(10);
+/// Attempt limit selected by `-l` (~60 seconds at the default interval).
+pub const LIMITED_ATTEMPTS: u32 = 300;
+
+/// Source of time for the retry loop.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+    fn sleep(&self, duration: Duration);
+}
+
+/// The real clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Backoff {
+    /// Wait the same interval between every attempt.
+    #[default]
+    Fixed,
+    /// Double the interval after every attempt, up to the maximum interval.
+    Exponential,
+}
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Retry at all; without this the first failure is final.
+    pub poll: bool,
+    pub backoff: Backoff,
+    /// Delay after the first failed attempt.
+    pub interval: Duration,
+    /// Upper bound on the delay for exponential backoff.
+    pub max_interval: Duration,
+    /// Fraction (0.0 to 1.0) of each delay that is randomly shaved off, so
+    /// many clients started together do not retry in lockstep.
+    pub jitter: f64,
+    pub max_attempts: Option<u32>,
+    /// Give up once this much time has passed since the first attempt.
+    pub deadline: Option<Duration>,
+    /// Limit on a single attempt, for transports that can block while
+    /// connecting (TCP and Assuan).
+    pub connect_timeout: Option<Duration>,
+    /// Where time comes from; [`SystemClock`] outside of tests.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            poll: false,
+            backoff: Backoff::Fixed,
+            interval: DEFAULT_INTERVAL,
+            max_interval: DEFAULT_MAX_INTERVAL,
+            jitter: 0.0,
+            max_attempts: None,
+            deadline: None,
+            connect_timeout: None,
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("poll", &self.poll)
+            .field("backoff", &self.backoff)
+            .field("interval", &self.interval)
+            .field("max_interval", &self.max_interval)
+            .field("jitter", &self.jitter)
+            .field("max_attempts", &self.max_attempts)
+            .field("deadline", &self.deadline)
+            .field("connect_timeout", &self.connect_timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why [`RetryPolicy::run`] gave up.
+#[derive(Debug)]
+pub enum RetryError<E> {
+    /// The last attempt failed and is not going to be retried, either
+    /// because polling is off or because the error is not retryable.
+    Failed(E),
+    /// The attempt limit or deadline was reached.
+    LimitReached { attempts: u32, elapsed: Duration },
+}
+
+impl<E> RetryError<E> {
+    /// Convert to a [`BatonError`], using `failed` for the transport's own
+    /// connection error.
+    pub fn into_baton(self, failed: impl FnOnce(E) -> BatonError) -> BatonError {
+        match self {
+            RetryError::Failed(e) => failed(e),
+            RetryError::LimitReached { attempts, elapsed } => {
+                BatonError::PollingLimitReached { attempts, elapsed }
+            }
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Retry with the npiperelay defaults: every 200ms, capped at 300
+    /// attempts when `limited`.
+    pub fn polling(limited: bool) -> Self {
+        Self {
+            poll: true,
+            max_attempts: limited.then_some(LIMITED_ATTEMPTS),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Delay after the `failures`-th failed attempt. `sample` is a random
+    /// number in `[0, 1)` that scales the jitter.
+    pub fn delay(&self, failures: u32, sample: f64) -> Duration {
+        let base = match self.backoff {
+            Backoff::Fixed => self.interval,
+            Backoff::Exponential => {
+                let factor = 2u32.checked_pow(failures.saturating_sub(1));
+                factor
+                    .and_then(|factor| self.interval.checked_mul(factor))
+                    .map_or(self.max_interval, |delay| delay.min(self.max_interval))
+            }
+        };
+        base.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * sample)
+    }
+
+    /// Call `attempt` until it succeeds, fails with an error `is_retryable`
+    /// rejects, or the policy runs out. `attempt` receives the time limit
+    /// for that attempt, if any.
+    pub fn run<T, E: fmt::Display>(
+        &self,
+        target: &str,
+        mut attempt: impl FnMut(Option<Duration>) -> Result<T, E>,
+        is_retryable: impl Fn(&E) -> bool,
+    ) -> Result<T, RetryError<E>> {
+        let start = self.clock.now();
+        let mut failures = 0;
+
+        loop {
+            let elapsed = self.clock.now().saturating_duration_since(start);
+            let err = match attempt(self.attempt_timeout(elapsed)) {
+                Ok(connection) => {
+                    log::debug!("Connected to {}", target);
+                    return Ok(connection);
+                }
+                Err(e) => e,
+            };
+
+            if !self.poll || !is_retryable(&err) {
+                return Err(RetryError::Failed(err));
+            }
+
+            failures += 1;
+            let elapsed = self.clock.now().saturating_duration_since(start);
+            let limit_reached = RetryError::LimitReached {
+                attempts: failures,
+                elapsed,
+            };
+            if self.max_attempts.is_some_and(|max| failures >= max) {
+                return Err(limit_reached);
+            }
+
+            let mut delay = if self.jitter > 0.0 {
+                self.delay(failures, random_sample())
+            } else {
+                self.delay(failures, 0.0)
+            };
+            if let Some(deadline) = self.deadline {
+                let remaining = deadline.saturating_sub(elapsed);
+                if remaining.is_zero() {
+                    return Err(limit_reached);
+                }
+                // Make one last attempt right at the deadline.
+                delay = delay.min(remaining);
+            }
+
+            log::debug!(
+                "Connection attempt {} to {} failed: {}, retrying in {:?}",
+                failures,
+                target,
+                err,
+                delay
+            );
+            self.clock.sleep(delay);
+        }
+    }
+
+    /// The connect timeout, shortened so an attempt cannot overrun the
+    /// deadline.
+    fn attempt_timeout(&self, elapsed: Duration) -> Option<Duration> {
+        let remaining = self
+            .deadline
+            .map(|deadline| deadline.saturating_sub(elapsed));
+        let timeout = match (self.connect_timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+        // A zero timeout is rejected by the OS; use the smallest real one.
+        timeout.map(|t| t.max(Duration::from_millis(1)))
+    }
+}
+
+/// A random number in `[0, 1)`, good enough to spread out retries.
+fn random_sample() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::sync::Mutex;
+
+    /// Clock that only moves when something sleeps on it.
+    struct FakeClock {
+        now: Mutex<Instant>,
+        sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl FakeClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self {
+                now: Mutex::new(Instant::now()),
+                sleeps: Mutex::new(Vec::new()),
+            })
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+
+        fn sleeps(&self) -> Vec<Duration> {
+            self.sleeps.lock().unwrap().clone()
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+            self.advance(duration);
+        }
+    }
+
+    fn refused() -> io::Error {
+        io::ErrorKind::ConnectionRefused.into()
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Run `policy` against an attempt that fails `failures` times.
+    fn run_failing(
+        policy: &RetryPolicy,
+        failures: usize,
+    ) -> (Result<usize, RetryError<io::Error>>, usize) {
+        let mut calls = 0;
+        let result = policy.run(
+            "test",
+            |_| {
+                calls += 1;
+                if calls > failures {
+                    Ok(calls)
+                } else {
+                    Err(refused())
+                }
+            },
+            |_| true,
+        );
+        (result, calls)
+    }
+
+    #[test]
+    fn test_no_poll_fails_immediately() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy::default().with_clock(clock.clone());
+
+        let (result, calls) = run_failing(&policy, 1);
+        assert!(matches!(result, Err(RetryError::Failed(_))));
+        assert_eq!(calls, 1);
+        assert!(clock.sleeps().is_empty());
+    }
+
+    #[test]
+    fn test_fixed_backoff_until_success() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy::polling(false).with_clock(clock.clone());
+
+        let (result, calls) = run_failing(&policy, 3);
+        assert_eq!(result.unwrap(), 4);
+        assert_eq!(calls, 4);
+        assert_eq!(clock.sleeps(), vec![DEFAULT_INTERVAL; 3]);
+    }
+
+    #[test]
+    fn test_limited_polling_reports_attempts_and_elapsed() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy::polling(true).with_clock(clock.clone());
+
+        let (result, calls) = run_failing(&policy, usize::MAX);
+        match result {
+            Err(RetryError::LimitReached { attempts, elapsed }) => {
+                assert_eq!(attempts, LIMITED_ATTEMPTS);
+                assert_eq!(elapsed, DEFAULT_INTERVAL * (LIMITED_ATTEMPTS - 1));
+            }
+            other => panic!("expected limit, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(calls, LIMITED_ATTEMPTS as usize);
+    }
+
+    #[test]
+    fn test_exponential_backoff_is_capped() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy {
+            backoff: Backoff::Exponential,
+            interval: ms(100),
+            max_interval: ms(1000),
+            max_attempts: Some(7),
+            ..RetryPolicy::polling(false)
+        }
+        .with_clock(clock.clone());
+
+        let (result, _) = run_failing(&policy, usize::MAX);
+        assert!(matches!(
+            result,
+            Err(RetryError::LimitReached { attempts: 7, .. })
+        ));
+        assert_eq!(
+            clock.sleeps(),
+            vec![ms(100), ms(200), ms(400), ms(800), ms(1000), ms(1000)]
+        );
+    }
+
+    #[test]
+    fn test_exponential_backoff_does_not_overflow() {
+        let policy = RetryPolicy {
+            backoff: Backoff::Exponential,
+            ..RetryPolicy::polling(false)
+        };
+        assert_eq!(policy.delay(u32::MAX, 0.0), DEFAULT_MAX_INTERVAL);
+        assert_eq!(policy.delay(64, 0.0), DEFAULT_MAX_INTERVAL);
+    }
+
+    #[test]
+    fn test_jitter_shortens_delay() {
+        let policy = RetryPolicy {
+            interval: ms(1000),
+            jitter: 0.5,
+            ..RetryPolicy::polling(false)
+        };
+        assert_eq!(policy.delay(1, 0.0), ms(1000));
+        assert_eq!(policy.delay(1, 0.5), ms(750));
+        assert!(policy.delay(1, 0.999) > ms(500));
+
+        for _ in 0..100 {
+            let sample = random_sample();
+            assert!((0.0..1.0).contains(&sample));
+        }
+    }
+
+    #[test]
+    fn test_deadline_stops_retrying() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy {
+            deadline: Some(ms(500)),
+            ..RetryPolicy::polling(false)
+        }
+        .with_clock(clock.clone());
+
+        let (result, calls) = run_failing(&policy, usize::MAX);
+        match result {
+            Err(RetryError::LimitReached { attempts, elapsed }) => {
+                assert_eq!(attempts, 4);
+                assert_eq!(elapsed, ms(500));
+            }
+            other => panic!("expected limit, got {:?}", other.map(|_| ())),
+        }
+        assert_eq!(calls, 4);
+        // The last sleep is cut short so the final attempt lands on the deadline.
+        assert_eq!(clock.sleeps(), vec![ms(200), ms(200), ms(100)]);
+    }
+
+    #[test]
+    fn test_slow_attempts_count_against_deadline() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy {
+            deadline: Some(ms(1000)),
+            ..RetryPolicy::polling(false)
+        }
+        .with_clock(clock.clone());
+
+        let result: Result<(), _> = policy.run(
+            "test",
+            |_| {
+                clock.advance(ms(600));
+                Err(refused())
+            },
+            |_| true,
+        );
+        assert!(matches!(
+            result,
+            Err(RetryError::LimitReached { attempts: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_non_retryable_error_is_final() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy::polling(false).with_clock(clock.clone());
+
+        let mut calls = 0;
+        let result: Result<(), _> = policy.run(
+            "test",
+            |_| {
+                calls += 1;
+                Err(io::Error::from(io::ErrorKind::PermissionDenied))
+            },
+            |e| e.kind() != io::ErrorKind::PermissionDenied,
+        );
+        assert!(matches!(result, Err(RetryError::Failed(_))));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn test_attempt_timeout() {
+        let clock = FakeClock::new();
+        let policy = RetryPolicy {
+            connect_timeout: Some(ms(300)),
+            deadline: Some(ms(500)),
+            max_attempts: Some(3),
+            ..RetryPolicy::polling(false)
+        }
+        .with_clock(clock.clone());
+
+        let mut timeouts = Vec::new();
+        let result: Result<(), _> = policy.run(
+            "test",
+            |timeout| {
+                timeouts.push(timeout);
+                clock.advance(timeout.unwrap());
+                Err(refused())
+            },
+            |_| true,
+        );
+        assert!(result.is_err());
+        // The second attempt starts right at the deadline, after the first
+        // one used 300ms and the sleep was cut to the remaining 200ms.
+        assert_eq!(timeouts, vec![Some(ms(300)), Some(ms(1))]);
+    }
+
+    #[test]
+    fn test_attempt_timeout_without_deadline() {
+        let policy = RetryPolicy {
+            connect_timeout: Some(ms(300)),
+            ..Default::default()
+        };
+        assert_eq!(policy.attempt_timeout(Duration::ZERO), Some(ms(300)));
+        assert_eq!(RetryPolicy::default().attempt_timeout(Duration::ZERO), None);
+    }
+
+    #[test]
+    fn test_into_baton() {
+        let err = RetryError::<io::Error>::LimitReached {
+            attempts: 3,
+            elapsed: ms(600),
+        }
+        .into_baton(BatonError::SocketConnection);
+        assert!(matches!(
+            err,
+            BatonError::PollingLimitReached { attempts: 3, .. }
+        ));
+
+        let err = RetryError::Failed(refused()).into_baton(BatonError::SocketConnection);
+        assert!(matches!(err, BatonError::SocketConnection(_)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Per-session statistics (`--stats`): what a relay carried in each
+//! direction, how quickly the first byte arrived, and how it ended.
+//!
+//! One line is written per session, either to stderr or appended to a
+//! `--stats-file`, as plain text or as a JSON object.
+
+use crate::relay::{Direction, DirectionOutcome, RelayOutcome};
+use serde_json::{json, Value};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StatsFormat {
+    /// One human-readable line
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Statistics for one relay session, ready to be written out.
+pub struct RelayStats<'a> {
+    pub session: String,
+    pub target: String,
+    pub outcome: &'a RelayOutcome,
+}
+
+impl<'a> RelayStats<'a> {
+    pub fn new(session: String, target: String, outcome: &'a RelayOutcome) -> Self {
+        RelayStats {
+            session,
+            target,
+            outcome,
+        }
+    }
+
+    /// The statistics as a JSON object. Durations are in milliseconds.
+    pub fn to_json(&self) -> Value {
+        let direction = |outcome: &DirectionOutcome| {
+            json!({
+                "bytes": outcome.bytes,
+                "chunks": outcome.chunks,
+                "first_byte_ms": outcome.first_byte.map(millis),
+                "end": outcome.end.as_ref().map(|end| end.name()),
+            })
+        };
+        json!({
+            "session": self.session,
+            "target": self.target,
+            "duration_ms": millis(self.outcome.duration),
+            "termination": self.outcome.termination.name(),
+            "first_eof": self.outcome.first_eof.map(Direction::name),
+            "stdin_to_pipe": direction(&self.outcome.stdin_to_pipe),
+            "pipe_to_stdout": direction(&self.outcome.pipe_to_stdout),
+        })
+    }
+
+    /// The statistics as one line in `format`, without the newline.
+    pub fn format(&self, format: StatsFormat) -> String {
+        match format {
+            StatsFormat::Text => self.to_string(),
+            StatsFormat::Json => self.to_json().to_string(),
+        }
+    }
+
+    /// Append the statistics as one line to `path`, or print them to stderr.
+    pub fn emit(&self, format: StatsFormat, path: Option<&Path>) -> io::Result<()> {
+        let line = format!("{}\n", self.format(format));
+        match path {
+            Some(path) => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(line.as_bytes()),
+            None => io::stderr().lock().write_all(line.as_bytes()),
+        }
+    }
+}
+
+impl fmt::Display for RelayStats<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let outcome = self.outcome;
+        write!(
+            f,
+            "baton stats: session {}, {}, {:.1?}, {}",
+            self.session,
+            self.target,
+            outcome.duration,
+            outcome.termination.name()
+        )?;
+        if let Some(first_eof) = outcome.first_eof {
+            write!(f, ", first EOF {}", first_eof.name())?;
+        }
+        for direction in [Direction::StdinToPipe, Direction::PipeToStdout] {
+            let stats = outcome.direction(direction);
+            write!(
+                f,
+                "; {} {} bytes in {} chunks",
+                direction.name(),
+                stats.bytes,
+                stats.chunks
+            )?;
+            if let Some(first_byte) = stats.first_byte {
+                write!(f, ", first byte after {:.1?}", first_byte)?;
+            }
+            match &stats.end {
+                Some(end) => write!(f, ", {}", end.name())?,
+                None => write!(f, ", open")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::relay::{EndReason, Termination};
+
+    fn outcome() -> RelayOutcome {
+        RelayOutcome {
+            first: Direction::StdinToPipe,
+            first_eof: Some(Direction::StdinToPipe),
+            termination: Termination::ExitOnStdinEof,
+            duration: Duration::from_millis(1500),
+            stdin_to_pipe: DirectionOutcome {
+                bytes: 12,
+                chunks: 2,
+                first_byte: Some(Duration::from_micros(250)),
+                end: Some(EndReason::Eof),
+            },
+            pipe_to_stdout: DirectionOutcome {
+                bytes: 0,
+                chunks: 0,
+                first_byte: None,
+                end: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_stats_text() {
+        let outcome = outcome();
+        let stats = RelayStats::new("abc".into(), "tcp://h:1".into(), &outcome);
+        assert_eq!(
+            stats.format(StatsFormat::Text),
+            "baton stats: session abc, tcp://h:1, 1.5s, exit_on_stdin_eof, \
+             first EOF stdin_to_pipe; stdin_to_pipe 12 bytes in 2 chunks, \
+             first byte after 250.0µs, eof; pipe_to_stdout 0 bytes in 0 chunks, open"
+        );
+    }
+
+    #[test]
+    fn test_stats_json() {
+        let outcome = outcome();
+        let stats = RelayStats::new("abc".into(), "tcp://h:1".into(), &outcome);
+        let value: Value = serde_json::from_str(&stats.format(StatsFormat::Json)).unwrap();
+        assert_eq!(value["session"], "abc");
+        assert_eq!(value["target"], "tcp://h:1");
+        assert_eq!(value["duration_ms"], 1500.0);
+        assert_eq!(value["termination"], "exit_on_stdin_eof");
+        assert_eq!(value["first_eof"], "stdin_to_pipe");
+        assert_eq!(value["stdin_to_pipe"]["bytes"], 12);
+        assert_eq!(value["stdin_to_pipe"]["chunks"], 2);
+        assert_eq!(value["stdin_to_pipe"]["first_byte_ms"], 0.25);
+        assert_eq!(value["stdin_to_pipe"]["end"], "eof");
+        assert_eq!(value["pipe_to_stdout"]["first_byte_ms"], Value::Null);
+        assert_eq!(value["pipe_to_stdout"]["end"], Value::Null);
+    }
+
+    #[test]
+    fn test_stats_emit_appends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.jsonl");
+        let outcome = outcome();
+        let stats = RelayStats::new("abc".into(), "x".into(), &outcome);
+        stats.emit(StatsFormat::Json, Some(&path)).unwrap();
+        stats.emit(StatsFormat::Json, Some(&path)).unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 2);
+    }
+}
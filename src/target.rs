@@ -0,0 +1,408 @@
+//! Parsing of the positional target argument.
+//!
+//! A target is either a URL-style string with a scheme or, for npiperelay
+//! compatibility, a bare path:
+//!
+//! | Form | Meaning |
+//! |------|---------|
+//! | `npipe:////./pipe/docker_engine` | Windows named pipe (Docker's `DOCKER_HOST` form) |
+//! | `assuan://C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent` | Assuan file socket |
+//! | `cygwin://C:/Users/me/AppData/Local/Temp/ssh-XXXX/agent.1234` | Cygwin/MSYS2 socket file |
+//! | `tcp://127.0.0.1:2375` | TCP connection |
+//! | `unix:///run/foo.sock` | Unix domain socket |
+//! | `exec:cmd args` | Child process stdin/stdout |
+//! | `//./pipe/name`, `\\.\pipe\name` | Named pipe, or an Assuan socket file with `-a` |
+
+use crate::errors::BatonError;
+use std::borrow::Cow;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    NamedPipe(String),
+    Assuan(String),
+    Cygwin(String),
+    Tcp(String),
+    Unix(PathBuf),
+    Exec { program: String, args: Vec<String> },
+}
+
+impl Target {
+    /// Parse a target string. Bare paths are named pipes, or Assuan socket
+    /// files when `assuan` (`-a`) is set.
+    pub fn parse(s: &str, assuan: bool) -> Result<Self, BatonError> {
+        let target = match split_scheme(s) {
+            Some(("npipe", rest)) => Target::NamedPipe(parse_pipe_name(rest)?),
+            Some(("assuan", rest)) => Target::Assuan(non_empty(rest, "assuan")?.to_string()),
+            Some(("cygwin", rest)) => Target::Cygwin(non_empty(rest, "cygwin")?.to_string()),
+            Some(("tcp", rest)) => Target::Tcp(parse_tcp_addr(rest)?),
+            Some(("unix", rest)) => Target::Unix(PathBuf::from(non_empty(rest, "unix")?)),
+            Some(("exec", rest)) => {
+                let mut words = split_command(rest)?.into_iter();
+                let program = words
+                    .next()
+                    .ok_or_else(|| invalid("exec: target needs a command"))?;
+                Target::Exec {
+                    program,
+                    args: words.collect(),
+                }
+            }
+            Some((scheme, _)) => return Err(invalid(format!("unsupported scheme '{}'", scheme))),
+            None if s.is_empty() => return Err(invalid("target must not be empty")),
+            None if assuan => return Ok(Target::Assuan(s.to_string())),
+            None => return Ok(Target::NamedPipe(s.to_string())),
+        };
+
+        if assuan && !matches!(target, Target::Assuan(_)) {
+            return Err(invalid(format!("-a cannot be combined with '{}'", s)));
+        }
+        Ok(target)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::NamedPipe(name) => write!(f, "npipe://{}", name.replace('\\', "/")),
+            Target::Assuan(path) => write!(f, "assuan://{}", path),
+            Target::Cygwin(path) => write!(f, "cygwin://{}", path),
+            Target::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Target::Unix(path) => write!(f, "unix://{}", path.display()),
+            Target::Exec { program, args } => {
+                write!(f, "exec:{}", quote_word(program))?;
+                for arg in args {
+                    write!(f, " {}", quote_word(arg))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Quote `word` so that [`split_command`] reads it back unchanged. Plain words
+/// are left alone.
+fn quote_word(word: &str) -> Cow<'_, str> {
+    let plain = !word.is_empty()
+        && !word
+            .chars()
+            .any(|c| c.is_whitespace() || c == '\'' || c == '"');
+    if plain {
+        Cow::Borrowed(word)
+    } else if !word.contains('\'') {
+        Cow::Owned(format!("'{}'", word))
+    } else {
+        Cow::Owned(format!(
+            "\"{}\"",
+            word.replace('\\', "\\\\").replace('"', "\\\"")
+        ))
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> BatonError {
+    BatonError::InvalidTarget(msg.into())
+}
+
+/// Split `scheme://rest` (or `exec:rest`) into its parts. Anything else,
+/// including drive-letter paths like `C:/Users`, is a bare path.
+fn split_scheme(s: &str) -> Option<(&str, &str)> {
+    if let Some(rest) = s.strip_prefix("exec:") {
+        return Some(("exec", rest));
+    }
+
+    let (scheme, rest) = s.split_once("://")?;
+    let is_scheme = scheme.len() > 1
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.'));
+    is_scheme.then_some((scheme, rest))
+}
+
+fn non_empty<'a>(rest: &'a str, scheme: &str) -> Result<&'a str, BatonError> {
+    if rest.is_empty() {
+        Err(invalid(format!("{}:// target needs a path", scheme)))
+    } else {
+        Ok(rest)
+    }
+}
+
+/// Turn the part after `npipe://` into a pipe path. Docker writes
+/// `npipe:////./pipe/name`, but `npipe://./pipe/name` and a bare pipe name
+/// are accepted as well.
+fn parse_pipe_name(rest: &str) -> Result<String, BatonError> {
+    let rest = non_empty(rest, "npipe")?;
+    if rest.starts_with("//") || rest.starts_with("\\\\") {
+        Ok(rest.to_string())
+    } else if rest.contains(['/', '\\']) {
+        Ok(format!("//{}", rest))
+    } else {
+        Ok(format!("//./pipe/{}", rest))
+    }
+}
+
+fn parse_tcp_addr(rest: &str) -> Result<String, BatonError> {
+    let addr = rest.trim_end_matches('/');
+    let (host, port) = addr
+        .rsplit_once(':')
+        .ok_or_else(|| invalid(format!("tcp:// target '{}' needs a port", rest)))?;
+    if host.is_empty() {
+        return Err(invalid(format!("tcp:// target '{}' needs a host", rest)));
+    }
+    port.parse::<u16>()
+        .map_err(|e| invalid(format!("invalid port '{}': {}", port, e)))?;
+    Ok(addr.to_string())
+}
+
+/// Split an `exec:` command line into words. Whitespace separates words,
+/// single quotes are literal and double quotes allow `\"` and `\\`.
+/// Backslashes outside quotes are kept as-is so Windows paths survive.
+fn split_command(s: &str) -> Result<Vec<String>, BatonError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            '\'' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(invalid("unterminated ' in exec: command")),
+                    }
+                }
+            }
+            '"' => {
+                in_word = true;
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(invalid("unterminated \" in exec: command")),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(invalid("unterminated \" in exec: command")),
+                    }
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Target {
+        Target::parse(s, false).unwrap()
+    }
+
+    #[test]
+    fn test_bare_pipe_paths() {
+        assert_eq!(
+            parse("//./pipe/docker_engine"),
+            Target::NamedPipe("//./pipe/docker_engine".to_string())
+        );
+        assert_eq!(
+            parse("\\\\.\\pipe\\openssh-ssh-agent"),
+            Target::NamedPipe("\\\\.\\pipe\\openssh-ssh-agent".to_string())
+        );
+    }
+
+    #[test]
+    fn test_bare_path_with_assuan_flag() {
+        let target = Target::parse("C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent", true).unwrap();
+        assert_eq!(
+            target,
+            Target::Assuan("C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent".to_string())
+        );
+    }
+
+    #[test]
+    fn test_drive_letter_is_not_a_scheme() {
+        assert_eq!(
+            parse("C:\\Users\\me\\S.gpg-agent"),
+            Target::NamedPipe("C:\\Users\\me\\S.gpg-agent".to_string())
+        );
+    }
+
+    #[test]
+    fn test_npipe_docker_form() {
+        assert_eq!(
+            parse("npipe:////./pipe/docker_engine"),
+            Target::NamedPipe("//./pipe/docker_engine".to_string())
+        );
+    }
+
+    #[test]
+    fn test_npipe_short_forms() {
+        assert_eq!(
+            parse("npipe://./pipe/docker_engine"),
+            Target::NamedPipe("//./pipe/docker_engine".to_string())
+        );
+        assert_eq!(
+            parse("npipe://docker_engine"),
+            Target::NamedPipe("//./pipe/docker_engine".to_string())
+        );
+    }
+
+    #[test]
+    fn test_assuan_scheme() {
+        assert_eq!(
+            parse("assuan://C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent"),
+            Target::Assuan("C:/Users/me/AppData/Roaming/gnupg/S.gpg-agent".to_string())
+        );
+        // -a is redundant but harmless with an explicit assuan:// target
+        assert!(Target::parse("assuan:///home/me/.gnupg/S.gpg-agent", true).is_ok());
+    }
+
+    #[test]
+    fn test_cygwin_scheme() {
+        assert_eq!(
+            parse("cygwin://C:/Users/me/AppData/Local/Temp/ssh-a1b2/agent.1234"),
+            Target::Cygwin("C:/Users/me/AppData/Local/Temp/ssh-a1b2/agent.1234".to_string())
+        );
+        assert!(Target::parse("cygwin://", false).is_err());
+        assert!(Target::parse("cygwin://C:/tmp/agent.1", true).is_err());
+    }
+
+    #[test]
+    fn test_tcp_scheme() {
+        assert_eq!(
+            parse("tcp://127.0.0.1:2375"),
+            Target::Tcp("127.0.0.1:2375".to_string())
+        );
+        assert_eq!(
+            parse("tcp://[::1]:22/"),
+            Target::Tcp("[::1]:22".to_string())
+        );
+    }
+
+    #[test]
+    fn test_tcp_scheme_invalid() {
+        assert!(Target::parse("tcp://127.0.0.1", false).is_err());
+        assert!(Target::parse("tcp://:2375", false).is_err());
+        assert!(Target::parse("tcp://localhost:99999", false).is_err());
+    }
+
+    #[test]
+    fn test_unix_scheme() {
+        assert_eq!(
+            parse("unix:///run/foo.sock"),
+            Target::Unix(PathBuf::from("/run/foo.sock"))
+        );
+        assert!(Target::parse("unix://", false).is_err());
+    }
+
+    #[test]
+    fn test_exec_scheme() {
+        assert_eq!(
+            parse("exec:socat - UNIX-CONNECT:/tmp/a.sock"),
+            Target::Exec {
+                program: "socat".to_string(),
+                args: vec!["-".to_string(), "UNIX-CONNECT:/tmp/a.sock".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_exec_quoting() {
+        assert_eq!(
+            parse(r#"exec:C:\tools\baton.exe -ep "//./pipe/my pipe" 'a "b"' "q\"x""#),
+            Target::Exec {
+                program: r"C:\tools\baton.exe".to_string(),
+                args: vec![
+                    "-ep".to_string(),
+                    "//./pipe/my pipe".to_string(),
+                    r#"a "b""#.to_string(),
+                    r#"q"x"#.to_string(),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_exec_invalid() {
+        assert!(Target::parse("exec:", false).is_err());
+        assert!(Target::parse("exec:   ", false).is_err());
+        assert!(Target::parse("exec:cat 'oops", false).is_err());
+        assert!(Target::parse("exec:cat \"oops", false).is_err());
+    }
+
+    #[test]
+    fn test_unknown_scheme() {
+        let err = Target::parse("http://localhost", false).unwrap_err();
+        assert!(err.to_string().contains("unsupported scheme 'http'"));
+    }
+
+    #[test]
+    fn test_assuan_flag_conflicts_with_other_schemes() {
+        assert!(Target::parse("tcp://127.0.0.1:1234", true).is_err());
+        assert!(Target::parse("npipe:////./pipe/x", true).is_err());
+    }
+
+    #[test]
+    fn test_empty_target() {
+        assert!(Target::parse("", false).is_err());
+    }
+
+    #[test]
+    fn test_display_round_trip() {
+        for s in [
+            "npipe:////./pipe/docker_engine",
+            "assuan://C:/gnupg/S.gpg-agent",
+            "cygwin://C:/tmp/ssh-a1b2/agent.1234",
+            "tcp://127.0.0.1:2375",
+            "unix:///run/foo.sock",
+            "exec:cat -u",
+        ] {
+            assert_eq!(parse(s).to_string(), s);
+            assert_eq!(parse(&parse(s).to_string()), parse(s));
+        }
+    }
+
+    #[test]
+    fn test_display_round_trip_quotes_exec_args() {
+        let exec = |program: &str, args: &[&str]| Target::Exec {
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        };
+
+        for target in [
+            exec(r"C:\Program Files\baton.exe", &["-ep", "//./pipe/my pipe"]),
+            exec("sh", &["-c", "echo 'hi there'"]),
+            exec("printf", &[r#"a "b" \c"#, "it's", ""]),
+            exec("cat", &[r"C:\tmp\x"]),
+        ] {
+            assert_eq!(parse(&target.to_string()), target, "{}", target);
+        }
+
+        assert_eq!(
+            exec("ssh", &["my host", "it's"]).to_string(),
+            r#"exec:ssh 'my host' "it's""#
+        );
+    }
+}
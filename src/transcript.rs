@@ -0,0 +1,469 @@
+//! Binary transcripts of relay sessions (`--record`).
+//!
+//! A transcript is a header followed by one record per chunk read by either
+//! copy loop, in the order the chunks were read:
+//!
+//! ```text
+//! header: "BATONREC" | version (u8) | start time (u64 BE, µs since the Unix epoch)
+//!         | label length (varint) | label (UTF-8, usually the target)
+//! record: tag (u8) | µs since the previous record (varint) | length (varint) | payload
+//! ```
+//!
+//! The low bit of the tag is the direction (0 stdin→pipe, 1 pipe→stdout)
+//! and the rest is the kind: 0 data, 1 EOF, 2 error (payload is the message),
+//! 3 truncated. Varints are unsigned LEB128. Timestamps come from a monotonic
+//! clock, so they never go backwards even if the wall clock does.
+//!
+//! The writer stops at a size limit, ending the file with a truncated record,
+//! so recording can be left on without filling the disk.
+
+use crate::capture::{Capture, Event};
+use crate::relay::Direction;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8; 8] = b"BATONREC";
+pub const VERSION: u8 = 1;
+
+/// Size limit used when `--record-limit` is not given.
+pub const DEFAULT_LIMIT: u64 = 64 * 1024 * 1024;
+
+const KIND_DATA: u8 = 0;
+const KIND_EOF: u8 = 1;
+const KIND_ERROR: u8 = 2;
+const KIND_TRUNCATED: u8 = 3;
+
+/// Largest encoding of a record without payload: tag, 10-byte varint and a
+/// 1-byte zero length. Kept free below the limit for the truncated marker.
+const MARKER_RESERVE: u64 = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    /// Wall-clock time the recording started.
+    pub started: SystemTime,
+    /// Free-form description of the session, normally the target.
+    pub label: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordEvent {
+    Data(Vec<u8>),
+    Eof,
+    Error(String),
+    /// The size limit was reached; nothing after this point was recorded.
+    Truncated,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub direction: Direction,
+    /// Time since the recording started.
+    pub at: Duration,
+    pub event: RecordEvent,
+}
+
+/// Writes a transcript as the relay runs. Each record goes to the output in
+/// a single write, so a crash loses at most the record being written.
+pub struct TranscriptWriter {
+    state: Mutex<WriterState>,
+}
+
+struct WriterState {
+    /// `None` once the limit was reached or a write failed.
+    out: Option<Box<dyn Write + Send>>,
+    start: Instant,
+    last: Duration,
+    written: u64,
+    limit: u64,
+}
+
+impl TranscriptWriter {
+    /// Create (or truncate) `path` and write the header.
+    pub fn create(path: &Path, label: &str, limit: u64) -> io::Result<Self> {
+        Self::new(File::create(path)?, label, limit)
+    }
+
+    pub fn new(mut out: impl Write + Send + 'static, label: &str, limit: u64) -> io::Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&started.to_be_bytes());
+        write_varint(&mut header, label.len() as u64);
+        header.extend_from_slice(label.as_bytes());
+        out.write_all(&header)?;
+
+        Ok(Self {
+            state: Mutex::new(WriterState {
+                out: Some(Box::new(out)),
+                start: Instant::now(),
+                last: Duration::ZERO,
+                written: header.len() as u64,
+                limit,
+            }),
+        })
+    }
+
+    /// Bytes written so far, header included.
+    pub fn written(&self) -> u64 {
+        self.lock().written
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, WriterState> {
+        // A panic elsewhere must not stop the relay from recording.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl WriterState {
+    fn push(&mut self, direction: Direction, kind: u8, payload: &[u8]) {
+        if self.out.is_none() {
+            return;
+        }
+        let now = self.start.elapsed();
+        let delta = now.saturating_sub(self.last);
+        self.last = now;
+
+        let mut record = encode_record(direction, kind, delta, payload);
+        let fits = self.written + record.len() as u64 + MARKER_RESERVE <= self.limit;
+        if !fits {
+            log::warn!(
+                "Recording stopped: transcript reached its {} byte limit",
+                self.limit
+            );
+            record = encode_record(direction, KIND_TRUNCATED, delta, &[]);
+        }
+
+        let Some(out) = self.out.as_mut() else { return };
+        match out.write_all(&record) {
+            Ok(()) => self.written += record.len() as u64,
+            Err(e) => {
+                log::warn!("Recording stopped: {}", e);
+                self.out = None;
+            }
+        }
+        if !fits {
+            self.out = None;
+        }
+    }
+}
+
+impl Capture for TranscriptWriter {
+    fn record(&self, direction: Direction, event: Event<'_>) {
+        let mut state = self.lock();
+        match event {
+            Event::Data(data) => state.push(direction, KIND_DATA, data),
+            Event::Eof => state.push(direction, KIND_EOF, &[]),
+            Event::Error(e) => state.push(direction, KIND_ERROR, e.to_string().as_bytes()),
+        }
+    }
+}
+
+fn encode_record(direction: Direction, kind: u8, delta: Duration, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(payload.len() + MARKER_RESERVE as usize + 10);
+    record.push(kind << 1 | direction_bit(direction));
+    write_varint(&mut record, delta.as_micros() as u64);
+    write_varint(&mut record, payload.len() as u64);
+    record.extend_from_slice(payload);
+    record
+}
+
+fn direction_bit(direction: Direction) -> u8 {
+    match direction {
+        Direction::StdinToPipe => 0,
+        Direction::PipeToStdout => 1,
+    }
+}
+
+/// Reads a transcript record by record.
+pub struct TranscriptReader<R> {
+    inner: R,
+    header: Header,
+    at: Duration,
+}
+
+impl TranscriptReader<BufReader<File>> {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Self::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TranscriptReader<R> {
+    /// Read and check the header.
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        inner.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a baton transcript"));
+        }
+        let version = read_u8(&mut inner)?;
+        if version != VERSION {
+            return Err(invalid(&format!(
+                "unsupported transcript version {}",
+                version
+            )));
+        }
+        let mut started = [0u8; 8];
+        inner.read_exact(&mut started)?;
+        let started = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(started));
+        let label = read_payload(&mut inner)?;
+        let label = String::from_utf8(label).map_err(|_| invalid("label is not UTF-8"))?;
+
+        Ok(Self {
+            inner,
+            header: Header {
+                version,
+                started,
+                label,
+            },
+            at: Duration::ZERO,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The next record, or `None` at the end of the transcript. A record cut
+    /// short (for example by a crash while writing it) is an error.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut tag = [0u8; 1];
+        if self.inner.read(&mut tag)? == 0 {
+            return Ok(None);
+        }
+        let direction = if tag[0] & 1 == 0 {
+            Direction::StdinToPipe
+        } else {
+            Direction::PipeToStdout
+        };
+        self.at += Duration::from_micros(read_varint(&mut self.inner)?);
+        let payload = read_payload(&mut self.inner)?;
+        let event = match tag[0] >> 1 {
+            KIND_DATA => RecordEvent::Data(payload),
+            KIND_EOF => RecordEvent::Eof,
+            KIND_ERROR => RecordEvent::Error(String::from_utf8_lossy(&payload).into_owned()),
+            KIND_TRUNCATED => RecordEvent::Truncated,
+            kind => return Err(invalid(&format!("unknown record kind {}", kind))),
+        };
+
+        Ok(Some(Record {
+            direction,
+            at: self.at,
+            event,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for TranscriptReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Read a whole transcript file.
+pub fn read_file(path: &Path) -> io::Result<(Header, Vec<Record>)> {
+    let mut reader = TranscriptReader::open(path)?;
+    let records = reader.by_ref().collect::<io::Result<Vec<_>>>()?;
+    Ok((reader.header, records))
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(r: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(r)?;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("varint is too long"))
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0u8; 1];
+    r.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_payload(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_varint(r)?;
+    // Grow as data arrives instead of trusting the length up front.
+    let mut payload = Vec::new();
+    r.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(payload)
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// Output that can be read back after the writer took ownership of it.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn records(&self) -> (Header, Vec<Record>) {
+            let bytes = self.0.lock().unwrap().clone();
+            let mut reader = TranscriptReader::new(&bytes[..]).unwrap();
+            let records = reader.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+            (reader.header().clone(), records)
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let out = Shared::default();
+        let writer =
+            TranscriptWriter::new(out.clone(), "tcp://127.0.0.1:2375", DEFAULT_LIMIT).unwrap();
+        writer.record(Direction::StdinToPipe, Event::Data(b"GET /"));
+        writer.record(Direction::PipeToStdout, Event::Data(b"200"));
+        writer.record(Direction::StdinToPipe, Event::Eof);
+        let broken = io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed");
+        writer.record(Direction::PipeToStdout, Event::Error(&broken));
+
+        let (header, records) = out.records();
+        assert_eq!(header.version, VERSION);
+        assert_eq!(header.label, "tcp://127.0.0.1:2375");
+        assert!(header.started <= SystemTime::now());
+
+        let events: Vec<_> = records
+            .iter()
+            .map(|r| (r.direction, r.event.clone()))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (Direction::StdinToPipe, RecordEvent::Data(b"GET /".to_vec())),
+                (Direction::PipeToStdout, RecordEvent::Data(b"200".to_vec())),
+                (Direction::StdinToPipe, RecordEvent::Eof),
+                (
+                    Direction::PipeToStdout,
+                    RecordEvent::Error("pipe closed".to_string())
+                ),
+            ]
+        );
+        assert!(records.windows(2).all(|w| w[0].at <= w[1].at));
+        assert_eq!(writer.written(), out.0.lock().unwrap().len() as u64);
+    }
+
+    #[test]
+    fn test_limit_truncates() {
+        let out = Shared::default();
+        let limit = 200;
+        let writer = TranscriptWriter::new(out.clone(), "x", limit).unwrap();
+        for _ in 0..50 {
+            writer.record(Direction::StdinToPipe, Event::Data(&[0xaa; 16]));
+        }
+
+        assert!(out.0.lock().unwrap().len() as u64 <= limit);
+        let (_, records) = out.records();
+        assert_eq!(records.last().unwrap().event, RecordEvent::Truncated);
+        assert_eq!(
+            records
+                .iter()
+                .filter(|r| r.event == RecordEvent::Truncated)
+                .count(),
+            1
+        );
+        assert!(records.len() > 2);
+    }
+
+    #[test]
+    fn test_write_failure_stops_recording() {
+        /// Accepts the header, then fails like a full disk.
+        struct Full(Shared, bool);
+
+        impl Write for Full {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                if std::mem::replace(&mut self.1, true) {
+                    return Err(io::Error::other("disk full"));
+                }
+                self.0.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let out = Shared::default();
+        let writer = TranscriptWriter::new(Full(out.clone(), false), "x", DEFAULT_LIMIT).unwrap();
+        let header_len = writer.written();
+        writer.record(Direction::StdinToPipe, Event::Data(b"lost"));
+        writer.record(Direction::StdinToPipe, Event::Eof);
+
+        assert_eq!(writer.written(), header_len);
+        assert!(out.records().1.is_empty());
+    }
+
+    #[test]
+    fn test_rejects_other_files() {
+        let err = TranscriptReader::new(&b"PCAPNG\0\0\x01"[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut bad_version = MAGIC.to_vec();
+        bad_version.push(9);
+        let err = TranscriptReader::new(&bad_version[..]).err().unwrap();
+        assert!(err.to_string().contains("version 9"));
+    }
+
+    #[test]
+    fn test_cut_record_is_an_error() {
+        let out = Shared::default();
+        let writer = TranscriptWriter::new(out.clone(), "", DEFAULT_LIMIT).unwrap();
+        writer.record(Direction::PipeToStdout, Event::Data(b"abcdef"));
+        let mut bytes = out.0.lock().unwrap().clone();
+        bytes.truncate(bytes.len() - 2);
+
+        let mut reader = TranscriptReader::new(&bytes[..]).unwrap();
+        let err = reader.next_record().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_varint() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut buf = Vec::new();
+            write_varint(&mut buf, value);
+            assert_eq!(read_varint(&mut &buf[..]).unwrap(), value);
+        }
+        let mut buf = Vec::new();
+        write_varint(&mut buf, 300);
+        assert_eq!(buf, [0xac, 0x02]);
+    }
+}
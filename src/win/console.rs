@@ -1,4 +1,7 @@
-use windows_sys::Win32::System::Console::GetConsoleWindow;
+use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::System::Console::{
+    GetConsoleWindow, GetStdHandle, SetStdHandle, STD_OUTPUT_HANDLE,
+};
 use windows_sys::Win32::UI::WindowsAndMessaging::{ShowWindow, SW_HIDE};
 
 pub fn hide_console_window() {
@@ -9,3 +12,15 @@ pub fn hide_console_window() {
         }
     }
 }
+
+/// Close stdout so that its reader sees EOF. The standard handle is cleared
+/// first; Rust's stdout then treats writes as going nowhere.
+pub fn close_stdout() {
+    unsafe {
+        let handle = GetStdHandle(STD_OUTPUT_HANDLE);
+        if !handle.is_null() && handle != INVALID_HANDLE_VALUE {
+            SetStdHandle(STD_OUTPUT_HANDLE, std::ptr::null_mut());
+            CloseHandle(handle);
+        }
+    }
+}
@@ -4,5 +4,5 @@ pub mod pipe;
 pub mod pipes_enum;
 
 pub use console::hide_console_window;
-pub use pipe::NamedPipe;
+pub use pipe::{NamedPipe, NamedPipeEndpoint};
 pub use pipes_enum::{enumerate_pipes, filter_pipes, EnumeratedPipe};
@@ -8,13 +8,12 @@
 //! cannot be interrupted, which would prevent graceful shutdown when either
 //! stdin or the pipe closes.
 
-use crate::cli::Config;
+use crate::endpoint::{Endpoint, EndpointReader, EndpointWriter};
 use crate::errors::BatonError;
+use crate::retry::RetryPolicy;
 use crate::win::overlapped::{async_read, async_write, EventPool, OverlappedHandle};
 use std::io::{self, Read, Write};
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
 use windows_sys::Win32::Foundation::{CloseHandle, GetLastError, INVALID_HANDLE_VALUE};
 use windows_sys::Win32::Storage::FileSystem::{CreateFileW, FILE_FLAG_OVERLAPPED, OPEN_EXISTING};
 
@@ -28,8 +27,6 @@ const SECURITY_ANONYMOUS: u32 = 0;
 
 const ERROR_FILE_NOT_FOUND: u32 = 2;
 const ERROR_PIPE_BUSY: u32 = 231;
-const POLL_INTERVAL_MS: u64 = 200;
-const MAX_POLL_ATTEMPTS: u32 = 300;
 
 pub struct NamedPipe {
     handle: OverlappedHandle,
@@ -37,19 +34,13 @@ pub struct NamedPipe {
 }
 
 impl NamedPipe {
-    pub fn connect(config: &Config) -> Result<Self, BatonError> {
-        let pipe_path = normalize_pipe_path(&config.pipe_name);
+    pub fn connect(pipe_name: &str, policy: &RetryPolicy) -> Result<Self, BatonError> {
+        let pipe_path = normalize_pipe_path(pipe_name);
         let wide_path = to_wide_string(&pipe_path);
         let pool = Arc::new(EventPool::new());
 
-        let max_attempts = if config.limited_poll {
-            MAX_POLL_ATTEMPTS
-        } else {
-            u32::MAX
-        };
-
-        let mut attempts = 0;
-        loop {
+        // Opening a pipe never blocks, so there is no per-attempt timeout.
+        let open = |_timeout| {
             let raw_handle = unsafe {
                 CreateFileW(
                     wide_path.as_ptr(),
@@ -62,35 +53,24 @@ impl NamedPipe {
                 )
             };
 
-            if raw_handle != INVALID_HANDLE_VALUE {
-                log::debug!("Connected to named pipe: {}", config.pipe_name);
-                // SAFETY: raw_handle is valid and was opened with FILE_FLAG_OVERLAPPED
-                let handle = unsafe { OverlappedHandle::from_raw(raw_handle) };
-                return Ok(Self { handle, pool });
-            }
-
-            let err = unsafe { GetLastError() };
-            let is_retryable = err == ERROR_FILE_NOT_FOUND || err == ERROR_PIPE_BUSY;
-
-            if !config.poll || !is_retryable {
-                return Err(BatonError::PipeConnection(io::Error::from_raw_os_error(
-                    err as i32,
-                )));
-            }
-
-            attempts += 1;
-            if attempts >= max_attempts {
-                return Err(BatonError::PollingLimitReached(attempts));
+            if raw_handle == INVALID_HANDLE_VALUE {
+                let err = unsafe { GetLastError() };
+                return Err(io::Error::from_raw_os_error(err as i32));
             }
+            // SAFETY: raw_handle is valid and was opened with FILE_FLAG_OVERLAPPED
+            Ok(unsafe { OverlappedHandle::from_raw(raw_handle) })
+        };
+        let is_retryable = |e: &io::Error| {
+            matches!(
+                e.raw_os_error().map(|code| code as u32),
+                Some(ERROR_FILE_NOT_FOUND | ERROR_PIPE_BUSY)
+            )
+        };
 
-            log::debug!(
-                "Pipe not available (error {}), attempt {}, retrying in {}ms",
-                err,
-                attempts,
-                POLL_INTERVAL_MS
-            );
-            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
-        }
+        let handle = policy
+            .run(pipe_name, open, is_retryable)
+            .map_err(|e| e.into_baton(BatonError::PipeConnection))?;
+        Ok(Self { handle, pool })
     }
 
     pub fn pool(&self) -> Arc<EventPool> {
@@ -126,6 +106,54 @@ impl Drop for NamedPipe {
     }
 }
 
+/// Named pipe target such as `//./pipe/docker_engine`.
+#[derive(Debug, Clone)]
+pub struct NamedPipeEndpoint {
+    pub pipe_name: String,
+}
+
+impl NamedPipeEndpoint {
+    pub fn new(pipe_name: impl Into<String>) -> Self {
+        Self {
+            pipe_name: pipe_name.into(),
+        }
+    }
+}
+
+impl Endpoint for NamedPipeEndpoint {
+    fn connect(
+        &self,
+        policy: &RetryPolicy,
+    ) -> Result<(EndpointReader, EndpointWriter), BatonError> {
+        let pipe = Arc::new(NamedPipe::connect(&self.pipe_name, policy)?);
+        let reader = PipeReader(Arc::clone(&pipe));
+        let writer = PipeWriter(pipe);
+        Ok((Box::new(reader), Box::new(writer)))
+    }
+}
+
+/// Read half of a shared pipe. The handle is closed once both halves drop.
+pub struct PipeReader(Arc<NamedPipe>);
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        async_read(self.0.handle, buf, &self.0.pool)
+    }
+}
+
+/// Write half of a shared pipe.
+pub struct PipeWriter(Arc<NamedPipe>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        async_write(self.0.handle, buf, &self.0.pool)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 fn normalize_pipe_path(path: &str) -> String {
     path.replace('/', "\\")
 }
@@ -188,7 +216,11 @@ mod tests {
         assert_eq!(GENERIC_WRITE, 0x40000000);
         assert_eq!(ERROR_FILE_NOT_FOUND, 2);
         assert_eq!(ERROR_PIPE_BUSY, 231);
-        assert_eq!(POLL_INTERVAL_MS, 200);
-        assert_eq!(MAX_POLL_ATTEMPTS, 300);
+        // Polling defaults now live in the shared retry policy.
+        assert_eq!(
+            crate::retry::DEFAULT_INTERVAL,
+            std::time::Duration::from_millis(200)
+        );
+        assert_eq!(crate::retry::LIMITED_ATTEMPTS, 300);
     }
 }
@@ -0,0 +1,678 @@
+//! End-to-end tests that run the `baton` binary against local sockets.
+
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::process::{Command, Output, Stdio};
+use std::thread;
+
+/// Run baton with `args`, feed it `input` on stdin and collect its output.
+fn run_baton(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// Read one request of `len` bytes and reply with it upper-cased.
+fn serve_upper<S: Read + Write>(mut stream: S, len: usize) -> Vec<u8> {
+    let mut request = vec![0u8; len];
+    stream.read_exact(&mut request).unwrap();
+    stream.write_all(&request.to_ascii_uppercase()).unwrap();
+    request
+}
+
+#[test]
+fn test_relay_unix_socket() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("agent.sock");
+    let listener = UnixListener::bind(&path).unwrap();
+    let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0, 5));
+
+    let target = format!("unix://{}", path.display());
+    let output = run_baton(&["--ep", &target], b"hello");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap(), b"hello");
+    assert_eq!(output.stdout, b"HELLO");
+}
+
+#[test]
+fn test_relay_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0, 4));
+
+    let output = run_baton(&["--ep", &target], b"ping");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap(), b"ping");
+    assert_eq!(output.stdout, b"PING");
+}
+
+#[test]
+fn test_relay_tcp_half_closes_at_end_of_input() {
+    // The server reads the whole request before replying, so it only answers
+    // once baton has shut down its side of the connection, which sockets only
+    // get with --eof close.
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let mut stream = listener.accept().unwrap().0;
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        stream.write_all(&request.to_ascii_uppercase()).unwrap();
+        request
+    });
+
+    let output = run_baton(&["--eof", "close", &target], b"ping");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap(), b"ping");
+    assert_eq!(output.stdout, b"PING");
+}
+
+#[test]
+fn test_relay_sentinel_end_of_input() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0, 5));
+
+    let output = run_baton(&["--ep", "--eof", r"sentinel:\n", &target], b"ping");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap(), b"ping\n");
+    assert_eq!(output.stdout, b"PING\n");
+}
+
+#[test]
+fn test_relay_closes_stdout_when_target_finishes() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .arg("exec:echo hi")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.take().unwrap();
+
+    // Stdout reaches EOF while baton's stdin is still open.
+    let mut stdout = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut stdout)
+        .unwrap();
+    assert_eq!(stdout, b"hi\n");
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_relay_accepts_npiperelay_flags() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0, 4));
+
+    let output = run_baton(&["-ep", "-s", &target], b"pong");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap(), b"pong");
+    assert_eq!(output.stdout, b"PONG");
+}
+
+#[test]
+fn test_relay_assuan_nonce_file() {
+    let nonce: Vec<u8> = (1..=16).collect();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket_file = dir.path().join("S.gpg-agent");
+    let mut contents = format!("{}\n", port).into_bytes();
+    contents.extend_from_slice(&nonce);
+    std::fs::write(&socket_file, contents).unwrap();
+
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut received = [0u8; 16];
+        stream.read_exact(&mut received).unwrap();
+        stream.write_all(b"OK Pleased to meet you\n").unwrap();
+        received
+    });
+
+    let output = run_baton(&["--ep", "-a", socket_file.to_str().unwrap()], b"");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(server.join().unwrap().to_vec(), nonce);
+    assert_eq!(output.stdout, b"OK Pleased to meet you\n");
+}
+
+#[test]
+fn test_relay_assuan_verify_greeting() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dir = tempfile::tempdir().unwrap();
+    let socket_file = dir.path().join("S.gpg-agent");
+    let mut contents = format!("{}\n", port).into_bytes();
+    contents.extend_from_slice(&[7u8; 16]);
+    std::fs::write(&socket_file, contents).unwrap();
+
+    // The first connection is turned away as with a stale nonce; the second
+    // is greeted and answers one command.
+    let server = thread::spawn(move || {
+        drop(listener.accept().unwrap());
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut nonce = [0u8; 16];
+        stream.read_exact(&mut nonce).unwrap();
+        stream.write_all(b"OK Pleased to meet you\n").unwrap();
+        let mut command = [0u8; 4];
+        stream.read_exact(&mut command).unwrap();
+        stream.write_all(b"OK\n").unwrap();
+    });
+
+    let path = socket_file.to_str().unwrap();
+    let output = run_baton(&["--verify-greeting", "-a", path], b"");
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unexpected Assuan greeting"), "{}", stderr);
+
+    let output = run_baton(&["--ep", "--verify-greeting", "-a", path], b"BYE\n");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"OK Pleased to meet you\nOK\n");
+    server.join().unwrap();
+}
+
+#[test]
+fn test_gpg_status() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let dir = tempfile::tempdir().unwrap();
+    let socket_file = dir.path().join("S.gpg-agent");
+    let mut contents = format!("{}\n", port).into_bytes();
+    contents.extend_from_slice(&[7u8; 16]);
+    std::fs::write(&socket_file, contents).unwrap();
+
+    // A gpg-agent that knows GETINFO and NOP, and nothing else.
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut nonce = [0u8; 16];
+        stream.read_exact(&mut nonce).unwrap();
+        stream.write_all(b"OK Pleased to meet you\n").unwrap();
+        let reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        for command in std::io::BufRead::lines(reader) {
+            let reply: &[u8] = match command.unwrap().as_str() {
+                "GETINFO version" => b"D 2.4.5\nOK\n",
+                "GETINFO pid" => b"D 4242\nOK\n",
+                "NOP" | "BYE" => b"OK\n",
+                _ => b"ERR 275 Unknown IPC command\n",
+            };
+            stream.write_all(reply).unwrap();
+        }
+    });
+
+    let path = socket_file.to_str().unwrap();
+    let output = run_baton(&["gpg-status", "--format", "json", path], b"");
+
+    assert!(output.status.success(), "{:?}", output);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["alive"], true);
+    assert_eq!(report["version"], "2.4.5");
+    assert_eq!(report["pid"], 4242);
+    assert_eq!(report["keys"], serde_json::Value::Null);
+    assert_eq!(report["errors"][0]["command"], "KEYINFO --list");
+    server.join().unwrap();
+}
+
+#[test]
+fn test_relay_cygwin_socket_file() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let dir = tempfile::tempdir().unwrap();
+    let socket_file = dir.path().join("agent.1234");
+    let contents = format!("!<socket >{} s 04030201-08070605-0C0B0A09-100F0E0D\0", port);
+    std::fs::write(&socket_file, contents).unwrap();
+
+    // A fake Cygwin server: echo the GUID, swap credentials, then serve.
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut guid = [0u8; 16];
+        stream.read_exact(&mut guid).unwrap();
+        stream.write_all(&guid).unwrap();
+        let mut credentials = [0u8; 12];
+        stream.read_exact(&mut credentials).unwrap();
+        stream.write_all(&[0u8; 12]).unwrap();
+        serve_upper(stream, 4);
+        guid
+    });
+
+    let target = format!("cygwin://{}", socket_file.display());
+    let output = run_baton(&["--ep", &target], b"ping");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        server.join().unwrap().to_vec(),
+        (1..=16).collect::<Vec<u8>>()
+    );
+    assert_eq!(output.stdout, b"PING");
+}
+
+#[test]
+fn test_relay_records_transcript() {
+    use baton::relay::Direction;
+    use baton::transcript::{self, RecordEvent};
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.rec");
+    let output = run_baton(
+        &["--record", path.to_str().unwrap(), "exec:tr a-z A-Z"],
+        b"hello",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"HELLO");
+
+    let (header, records) = transcript::read_file(&path).unwrap();
+    assert_eq!(header.label, "exec:tr a-z A-Z");
+    for direction in [Direction::StdinToPipe, Direction::PipeToStdout] {
+        let events: Vec<_> = records
+            .iter()
+            .filter(|r| r.direction == direction)
+            .collect();
+        let data: Vec<u8> = events
+            .iter()
+            .filter_map(|r| match &r.event {
+                RecordEvent::Data(data) => Some(data.clone()),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        let expected: &[u8] = match direction {
+            Direction::StdinToPipe => b"hello",
+            Direction::PipeToStdout => b"HELLO",
+        };
+        assert_eq!(data, expected);
+        assert_eq!(events.last().unwrap().event, RecordEvent::Eof);
+    }
+}
+
+#[test]
+fn test_relay_records_pcapng() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.pcapng");
+    let output = run_baton(
+        &["--record", path.to_str().unwrap(), "exec:tr a-z A-Z"],
+        b"hello",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"HELLO");
+
+    let capture = std::fs::read(&path).unwrap();
+    assert_eq!(capture[..4], [0x0a, 0x0d, 0x0d, 0x0a]);
+    let contains = |needle: &[u8]| capture.windows(needle.len()).any(|w| w == needle);
+    assert!(contains(b"exec:tr a-z A-Z"));
+    assert!(contains(b"hello"));
+    assert!(contains(b"HELLO"));
+}
+
+#[test]
+fn test_trace_hexdump_is_redacted() {
+    let request = b"GET /_ping HTTP/1.1\r\nAuthorization: Bearer s3cr3t-token\r\n\r\n";
+    let output = run_baton(&["-vv", "--decode", "http", "exec:cat"], request);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, request);
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("bytes from stdin"), "{}", stderr);
+    assert!(stderr.contains("|GET /_ping HTTP/"), "{}", stderr);
+    assert!(stderr.contains("-->"), "{}", stderr);
+    assert!(!stderr.contains("s3cr3t"), "{}", stderr);
+
+    let output = run_baton(&["-vv", "--trace-limit", "8", "exec:cat"], request);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("trace limit of 8 bytes reached"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_json_logs_carry_session_id() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .args([
+            "-v",
+            "--log-format",
+            "json",
+            "exec:sh -c 'cat; echo $BATON_SESSION_ID'",
+        ])
+        .env("BATON_SESSION_ID", "bridge-42")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let pid = child.id();
+    child.stdin.take().unwrap().write_all(b"hi\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    // The child was handed the same session ID.
+    assert_eq!(output.stdout, b"hi\nbridge-42\n");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let records: Vec<serde_json::Value> = stderr
+        .lines()
+        .map(|line| serde_json::from_str(line).expect(line))
+        .collect();
+    assert!(!records.is_empty());
+    for record in &records {
+        assert_eq!(record["session"], "bridge-42");
+        assert!(record["target"].as_str().unwrap().starts_with("exec:sh -c"));
+        assert_eq!(record["pid"], pid);
+    }
+    assert!(records
+        .iter()
+        .any(|r| r["direction"] == "stdin_to_pipe" && r["event"] == "read" && r["bytes"] == 3));
+    assert!(records
+        .iter()
+        .any(|r| r["direction"] == "pipe_to_stdout" && r["event"] == "eof"));
+}
+
+#[test]
+fn test_log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let template = dir.path().join("baton-{session}.log");
+    let output = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .args(["-v", "--log-file", template.to_str().unwrap(), "exec:cat"])
+        .env("BATON_SESSION_ID", "s1")
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(output.stderr.is_empty(), "{:?}", output);
+    let log = std::fs::read_to_string(dir.path().join("baton-s1.log")).unwrap();
+    assert!(log.contains("EOF on stdin"), "{}", log);
+
+    // A log file that cannot be opened does not stop the relay.
+    let missing = dir.path().join("missing").join("baton.log");
+    let output = run_baton(
+        &["-v", "--log-file", missing.to_str().unwrap(), "exec:cat"],
+        b"still relayed",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"still relayed");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cannot open log file"), "{}", stderr);
+}
+
+#[test]
+fn test_stats() {
+    let output = run_baton(&["--stats", "text", "exec:cat"], b"hello");
+    assert!(output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("stdin_to_pipe 5 bytes in 1 chunks"),
+        "{}",
+        stderr
+    );
+
+    // -ep returns at once, but the session is still reported.
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("stats.jsonl");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || serve_upper(listener.accept().unwrap().0, 4));
+    let output = run_baton(
+        &[
+            "--ep",
+            "--stats",
+            "json",
+            "--stats-file",
+            path.to_str().unwrap(),
+            &target,
+        ],
+        b"ping",
+    );
+    assert!(output.status.success(), "{:?}", output);
+    server.join().unwrap();
+    let line = std::fs::read_to_string(&path).unwrap();
+    let stats: serde_json::Value = serde_json::from_str(&line).unwrap();
+    assert_eq!(stats["termination"], "exit_on_pipe_eof");
+    assert!(stats["first_eof"].is_string(), "{}", line);
+    assert_eq!(stats["stdin_to_pipe"]["bytes"], 4);
+    assert_eq!(stats["pipe_to_stdout"]["bytes"], 4);
+    assert_eq!(stats["pipe_to_stdout"]["end"], "eof");
+}
+
+#[test]
+fn test_session_limits() {
+    let output = run_baton(&["--idle-timeout", "200ms", "exec:sleep 1"], b"");
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("idle_timeout"), "{}", stderr);
+
+    let output = run_baton(&["--max-duration", "200ms", "exec:sleep 1"], b"");
+    assert_eq!(output.status.code(), Some(4), "{:?}", output);
+
+    let output = run_baton(
+        &["--max-bytes", "4", "--stats", "json", "exec:cat"],
+        b"0123456789",
+    );
+    assert_eq!(output.status.code(), Some(5), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(r#""termination":"max_bytes""#),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_replay_recorded_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.rec");
+    let rec = path.to_str().unwrap();
+    let output = run_baton(&["--record", rec, "exec:tr a-z A-Z"], b"hello");
+    assert!(output.status.success(), "{:?}", output);
+
+    // Against the recorded target the responses match.
+    let output = run_baton(&["replay", "--max-gap", "0s", rec], b"");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(
+        stdout.contains("sent 5 bytes, received 5 bytes, 0 divergence(s)"),
+        "{}",
+        stdout
+    );
+
+    // A target that behaves differently is reported.
+    let output = run_baton(&["replay", "--max-gap", "0s", rec, "exec:cat"], b"");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stdout.contains("expected \"HELLO\", got \"hello\""),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn test_inspect_recorded_assuan_session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0u8; 16];
+        stream.read_exact(&mut request).unwrap();
+        stream.write_all(b"D 2.4.0%0A\nOK\n").unwrap();
+    });
+
+    let dir = tempfile::tempdir().unwrap();
+    let rec = dir.path().join("gpg.rec");
+    let rec = rec.to_str().unwrap();
+    let output = run_baton(&["--ep", "--record", rec, &target], b"GETINFO version\n");
+    assert!(output.status.success(), "{:?}", output);
+    server.join().unwrap();
+
+    let output = run_baton(&["inspect", "--protocol", "assuan", rec], b"");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(stdout.contains("(Assuan)"), "{}", stdout);
+    assert!(stdout.contains("--> GETINFO version"), "{}", stdout);
+    assert!(stdout.contains("<-- D 2.4.0\\n"), "{}", stdout);
+    assert!(stdout.contains("<-- OK"), "{}", stdout);
+    assert!(stdout.contains("<-- EOF"), "{}", stdout);
+}
+
+#[test]
+fn test_relay_against_golden_transcript() {
+    use baton::relay::Direction;
+    use baton::replay::{self, ReplayOptions, Timing};
+    use baton::transcript::{Record, RecordEvent};
+    use std::time::Duration;
+
+    let record = |direction, millis, event| Record {
+        direction,
+        at: Duration::from_millis(millis),
+        event,
+    };
+    let golden = vec![
+        record(
+            Direction::StdinToPipe,
+            0,
+            RecordEvent::Data(b"GET /_ping".to_vec()),
+        ),
+        record(
+            Direction::PipeToStdout,
+            3,
+            RecordEvent::Data(b"OK".to_vec()),
+        ),
+        record(Direction::PipeToStdout, 4, RecordEvent::Eof),
+    ];
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = format!("tcp://{}", listener.local_addr().unwrap());
+    let options = ReplayOptions {
+        timing: Timing::immediate(),
+        ..Default::default()
+    };
+    let server = thread::spawn(move || replay::serve_tcp(&listener, &golden, &options).unwrap());
+
+    let output = run_baton(&["--ep", &target], b"GET /_ping");
+
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(output.stdout, b"OK");
+    let report = server.join().unwrap();
+    assert!(report.is_clean(), "{}", report);
+}
+
+#[test]
+fn test_named_pipe_rejected_outside_windows() {
+    let output = run_baton(&["//./pipe/docker_engine"], b"");
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("only supported on Windows"), "{}", stderr);
+}
+
+#[test]
+fn test_unix_socket_not_listening() {
+    let dir = tempfile::tempdir().unwrap();
+    let target = format!("unix://{}", dir.path().join("missing.sock").display());
+
+    let output = run_baton(&[&target], b"");
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to connect to socket"), "{}", stderr);
+}
+
+/// Wait for `path` to appear, since the listener binds asynchronously.
+fn wait_for(path: &std::path::Path) {
+    for _ in 0..200 {
+        if path.exists() {
+            return;
+        }
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+    panic!("{} never appeared", path.display());
+}
+
+#[test]
+fn test_listen_spawns_command_per_connection_and_stops_on_sigterm() {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("listen.sock");
+    let mut listener = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .args(["listen", path.to_str().unwrap(), "--", "tr", "a-z", "A-Z"])
+        .spawn()
+        .unwrap();
+    wait_for(&path);
+
+    for word in [&b"first"[..], b"second"] {
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(word).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, word.to_ascii_uppercase());
+    }
+
+    let status = Command::new("kill")
+        .args(["-TERM", &listener.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(listener.wait().unwrap().success());
+    assert!(!path.exists());
+}
+
+#[test]
+fn test_listen_mux_relays_connections_through_one_child() {
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("mux.sock");
+    let mut listener = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .args(["listen", "--mux", path.to_str().unwrap(), "--"])
+        .args([env!("CARGO_BIN_EXE_baton"), "--mux", "exec:tr a-z A-Z"])
+        .spawn()
+        .unwrap();
+    wait_for(&path);
+
+    let clients: Vec<_> = (0..4)
+        .map(|i| {
+            let path = path.clone();
+            thread::spawn(move || {
+                let word = format!("client {}", i);
+                let mut client = UnixStream::connect(&path).unwrap();
+                client.write_all(word.as_bytes()).unwrap();
+                client.shutdown(Shutdown::Write).unwrap();
+                let mut reply = String::new();
+                client.read_to_string(&mut reply).unwrap();
+                assert_eq!(reply, word.to_uppercase());
+            })
+        })
+        .collect();
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    Command::new("kill")
+        .args(["-TERM", &listener.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(listener.wait().unwrap().success());
+}
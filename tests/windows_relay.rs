@@ -0,0 +1,32 @@
+//! End-to-end tests that run the `baton` binary on Windows.
+
+#![cfg(windows)]
+
+use std::io::Read;
+use std::process::{Command, Stdio};
+
+#[test]
+fn test_relay_closes_stdout_when_target_finishes() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_baton"))
+        .arg("exec:cmd /c echo hi")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let stdin = child.stdin.take().unwrap();
+
+    // Stdout reaches EOF while baton's stdin is still open, and baton keeps
+    // running without a standard output handle.
+    let mut stdout = Vec::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut stdout)
+        .unwrap();
+    assert_eq!(stdout, b"hi\r\n");
+
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+}